@@ -0,0 +1,100 @@
+use std::collections::HashMap;
+use std::fmt::Debug;
+
+use mail::Resource;
+use template::EmbeddedWithCId;
+
+use ::utils::file_name_of_iri;
+
+/// Trait for rewriting references to embedding files in html bodies to `cid:` references
+///
+/// If a `CidReferenceRewriter` is set on a `RenderTemplateEngine` it is
+/// called with each rendered `text/html` body and a mapping from the file
+/// names of the embeddings usable in the body to their `cid:` urls (e.g.
+/// `"logo.png" => "cid:ab32fe.r73rc20@company_a.test"`). This allows
+/// using html which references images like `<img src="logo.png">`.
+pub trait CidReferenceRewriter: Debug + Send + Sync {
+
+    /// rewrites all references to files in `file_name_to_cid_url` in the html
+    fn rewrite(&self, html: String, file_name_to_cid_url: &HashMap<String, String>) -> String;
+}
+
+/// A `CidReferenceRewriter` rewriting `src` and `href` attributes
+///
+/// This does not parse the html, it just replaces all occurrences
+/// of `src="<file_name>"` and `href="<file_name>"` (with `"` or `'`
+/// quotes) with the matching `cid:` url. E.g. whitespace around
+/// the `=` or other attributes like `srcset` are not handled.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SimpleCidReferenceRewriter;
+
+impl CidReferenceRewriter for SimpleCidReferenceRewriter {
+
+    fn rewrite(&self, html: String, file_name_to_cid_url: &HashMap<String, String>) -> String {
+        let mut html = html;
+        for (file_name, cid_url) in file_name_to_cid_url {
+            for attribute in &["src", "href"] {
+                for quote in &['"', '\'']  {
+                    let reference = format!("{}={}{}{}", attribute, quote, file_name, quote);
+                    if html.contains(&*reference) {
+                        let replacement = format!("{}={}{}{}", attribute, quote, cid_url, quote);
+                        html = html.replace(&*reference, &*replacement);
+                    }
+                }
+            }
+        }
+        html
+    }
+}
+
+/// collects the source file names of the embeddings and maps them to `cid:` urls
+///
+/// Embeddings without a source or for which no file name can be derived
+/// from the source IRI are skipped. If multiple embeddings have the same
+/// file name the one from the first map containing it is used.
+pub(crate) fn file_name_to_cid_url(
+    sources: &[(&HashMap<String, Resource>, &HashMap<String, EmbeddedWithCId>)]
+) -> HashMap<String, String> {
+    let mut mapping = HashMap::new();
+    for &(resources, embeddings) in sources {
+        for (name, resource) in resources {
+            let file_name = resource.source()
+                .and_then(|source| file_name_of_iri(source.iri.as_str()));
+            let embedding = embeddings.get(name);
+            if let (Some(file_name), Some(embedding)) = (file_name, embedding) {
+                mapping.entry(file_name)
+                    .or_insert_with(|| format!("cid:{}", embedding.content_id().as_str()));
+            }
+        }
+    }
+    mapping
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use ::utils::file_name_of_iri;
+    use super::{CidReferenceRewriter, SimpleCidReferenceRewriter};
+
+    #[test]
+    fn rewrite_src_and_href() {
+        let mut mapping = HashMap::new();
+        mapping.insert("logo.png".to_owned(), "cid:a1@b.c".to_owned());
+        mapping.insert("terms.pdf".to_owned(), "cid:a2@b.c".to_owned());
+
+        let html = r#"<img src="logo.png"><img src='logo.png'><a href="terms.pdf">x</a><img src="other.png">"#;
+        let out = SimpleCidReferenceRewriter.rewrite(html.to_owned(), &mapping);
+        assert_eq!(
+            out,
+            r#"<img src="cid:a1@b.c"><img src='cid:a1@b.c'><a href="cid:a2@b.c">x</a><img src="other.png">"#
+        );
+    }
+
+    #[test]
+    fn file_names_from_iris() {
+        assert_eq!(file_name_of_iri("path:./templates/a/html/logo.png"), Some("logo.png".to_owned()));
+        assert_eq!(file_name_of_iri("path:logo.png"), Some("logo.png".to_owned()));
+        assert_eq!(file_name_of_iri("nothing"), None);
+    }
+}
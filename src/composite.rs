@@ -0,0 +1,347 @@
+use std::collections::HashSet;
+use std::fmt::{self, Display};
+
+use failure::Fail;
+use serde_json::{Map, Value};
+use vec1::Vec1;
+
+use headers::components::MediaType;
+
+use ::error::{EncodingError, ErrorKind, RenderErrorKind};
+use ::spec::{TemplateSpec, SubTemplateSpec};
+use ::traits::{RenderEngineBase, RenderEngine, AdditionalCIds, InheritanceSupport};
+
+/// A render engine combining two render engines.
+///
+/// Each sub-template is handled by the primary engine unless it's media
+/// type was routed to the secondary engine using `route_to_secondary`.
+/// This allows e.g. rendering html bodies with one engine and plain
+/// text bodies with another (simpler) one while still having both in
+/// the same `TemplateSpec`.
+///
+/// Loading a `TemplateSpec` loads the sub-templates of each engine
+/// into that engine, so both engines only ever see "their" templates.
+#[derive(Debug)]
+pub struct CompositeRenderEngine<P, S> {
+    primary: P,
+    secondary: S,
+    secondary_media_types: HashSet<String>
+}
+
+impl<P, S> CompositeRenderEngine<P, S>
+    where P: RenderEngineBase, S: RenderEngineBase
+{
+
+    /// creates a new instance, initially all sub-templates are handled by `primary`
+    pub fn new(primary: P, secondary: S) -> Self {
+        CompositeRenderEngine {
+            primary, secondary,
+            secondary_media_types: HashSet::new()
+        }
+    }
+
+    /// makes sub-templates with the given media type be handled by the secondary engine
+    ///
+    /// Only the type and subtype are compared (case insensitive), so
+    /// e.g. `"text/plain"` matches `text/plain; charset=utf-8`.
+    ///
+    /// Note that this should be done before any templates are loaded.
+    pub fn route_to_secondary(&mut self, media_type: &str) {
+        self.secondary_media_types.insert(media_type.to_lowercase());
+    }
+
+    /// returns true if sub-templates with the given media type are handled by the secondary engine
+    pub fn uses_secondary(&self, media_type: &MediaType) -> bool {
+        let full_type = media_type.full_type().to_string().to_lowercase();
+        self.secondary_media_types.contains(&full_type)
+    }
+
+    pub fn primary(&self) -> &P {
+        &self.primary
+    }
+
+    pub fn primary_mut(&mut self) -> &mut P {
+        &mut self.primary
+    }
+
+    pub fn secondary(&self) -> &S {
+        &self.secondary
+    }
+
+    pub fn secondary_mut(&mut self) -> &mut S {
+        &mut self.secondary
+    }
+
+    /// splits the spec into one spec for each engine
+    ///
+    /// The split specs only contain what the engines need to load/unload
+    /// the templates i.e. the sources and media types. Header templates
+    /// are included as (`text/plain`) sub-templates. Partials are given
+    /// to the primary engine (if it has any sub-templates to load).
+    ///
+    /// The source ids of a split spec are a subset of the ones of `spec`,
+    /// so if they are not unique (which is only possible if they were changed
+    /// after creating `spec`) no split spec is returned for the engine.
+    fn split_spec(&self, spec: &TemplateSpec) -> (Option<TemplateSpec>, Option<TemplateSpec>) {
+        let (secondary, primary): (Vec<_>, Vec<_>) = all_sub_specs(spec)
+            .map(|sub_spec| {
+                // UNWRAP_SAFE: the media type is the one of an existing sub-template
+                let mut copy = SubTemplateSpec::new_with_template_source(
+                    sub_spec.source().clone(), sub_spec.media_type().clone(), Default::default())
+                    .unwrap();
+                for (key, source) in sub_spec.variants() {
+                    copy.insert_variant(key.clone(), source.clone());
+                }
+                copy
+            })
+            .partition(|sub_spec| self.uses_secondary(sub_spec.media_type()));
+
+        let to_spec = |sub_specs: Vec<SubTemplateSpec>| {
+            Vec1::from_vec(sub_specs).ok()
+                .and_then(|sub_specs| TemplateSpec::new(sub_specs).ok())
+        };
+        let mut primary = to_spec(primary);
+        if let Some(primary) = primary.as_mut() {
+            for &(ref name, ref source) in spec.partials() {
+                primary.insert_partial(name.clone(), source.clone());
+            }
+        }
+        (primary, to_spec(secondary))
+    }
+}
+
+/// the sub-templates of the spec followed by it's header templates
+fn all_sub_specs<'a>(spec: &'a TemplateSpec) -> impl Iterator<Item=&'a SubTemplateSpec> + 'a {
+    spec.sub_specs().iter()
+        .chain(spec.header_sub_specs().map(|(_, sub_spec)| sub_spec))
+}
+
+impl<P, S> RenderEngineBase for CompositeRenderEngine<P, S>
+    where P: RenderEngineBase, S: RenderEngineBase
+{
+    const PRODUCES_VALID_NEWLINES: bool =
+        P::PRODUCES_VALID_NEWLINES && S::PRODUCES_VALID_NEWLINES;
+
+    type RenderError = CompositeError<P::RenderError, S::RenderError>;
+    type LoadingError = CompositeError<P::LoadingError, S::LoadingError>;
+
+    fn load_templates(&mut self, spec: &TemplateSpec) -> Result<Vec<String>, Self::LoadingError> {
+        let (primary_spec, secondary_spec) = self.split_spec(spec);
+
+        let primary_ids =
+            if let Some(primary_spec) = primary_spec.as_ref() {
+                self.primary.load_templates(primary_spec)
+                    .map_err(CompositeError::Primary)?
+            } else {
+                Vec::new()
+            };
+
+        let secondary_ids =
+            if let Some(secondary_spec) = secondary_spec.as_ref() {
+                match self.secondary.load_templates(secondary_spec) {
+                    Ok(ids) => ids,
+                    Err(err) => {
+                        if let Some(primary_spec) = primary_spec.as_ref() {
+                            self.primary.unload_templates(primary_spec);
+                        }
+                        return Err(CompositeError::Secondary(err));
+                    }
+                }
+            } else {
+                Vec::new()
+            };
+
+        // return the ids in the order of the sub-templates
+        let mut primary_ids = primary_ids.into_iter();
+        let mut secondary_ids = secondary_ids.into_iter();
+        let mut loaded = Vec::new();
+        for sub_spec in all_sub_specs(spec) {
+            let ids =
+                if self.uses_secondary(sub_spec.media_type()) {
+                    &mut secondary_ids
+                } else {
+                    &mut primary_ids
+                };
+            loaded.extend(ids.take(sub_spec.all_sources().count()));
+        }
+        Ok(loaded)
+    }
+
+    fn unload_templates(&mut self, spec: &TemplateSpec) {
+        let (primary_spec, secondary_spec) = self.split_spec(spec);
+        if let Some(primary_spec) = primary_spec {
+            self.primary.unload_templates(&primary_spec);
+        }
+        if let Some(secondary_spec) = secondary_spec {
+            self.secondary.unload_templates(&secondary_spec);
+        }
+    }
+
+    fn prewarm(&mut self) -> Result<(), Self::LoadingError> {
+        self.primary.prewarm().map_err(CompositeError::Primary)?;
+        self.secondary.prewarm().map_err(CompositeError::Secondary)
+    }
+
+    /// the inheritance support of the primary engine, if it has none the one of the secondary
+    fn supports_inheritance(&self) -> InheritanceSupport {
+        match self.primary.supports_inheritance() {
+            InheritanceSupport::None => self.secondary.supports_inheritance(),
+            support => support
+        }
+    }
+
+    /// registers the base template with both engines (if they support it)
+    /// sets the data key of both engines, returns true if both use it
+    fn set_data_key(&mut self, key: &str) -> bool {
+        let primary = self.primary.set_data_key(key);
+        let secondary = self.secondary.set_data_key(key);
+        primary && secondary
+    }
+
+    fn register_base_template(&mut self, name: &str, source: &str)
+        -> Option<Result<(), Self::LoadingError>>
+    {
+        let primary_supported =
+            match self.primary.register_base_template(name, source) {
+                Some(Err(err)) => return Some(Err(CompositeError::Primary(err))),
+                res => res.is_some()
+            };
+        let secondary_supported =
+            match self.secondary.register_base_template(name, source) {
+                Some(Err(err)) => return Some(Err(CompositeError::Secondary(err))),
+                res => res.is_some()
+            };
+        if primary_supported || secondary_supported {
+            Some(Ok(()))
+        } else {
+            None
+        }
+    }
+
+    fn unknown_template_id_error(id: &str) -> Self::RenderError {
+        CompositeError::Primary(P::unknown_template_id_error(id))
+    }
+
+    fn encoding_error(err: EncodingError) -> Self::RenderError {
+        CompositeError::Primary(P::encoding_error(err))
+    }
+
+    fn undefined_variable(&self, err: &Self::RenderError) -> Option<String> {
+        match *err {
+            CompositeError::Primary(ref err) => self.primary.undefined_variable(err),
+            CompositeError::Secondary(ref err) => self.secondary.undefined_variable(err)
+        }
+    }
+
+    fn render_preserialized(
+        &self,
+        template: &SubTemplateSpec,
+        data: &Value,
+        additional_cids: AdditionalCIds,
+        globals: &Map<String, Value>
+    ) -> Option<Result<String, Self::RenderError>> {
+        if self.uses_secondary(template.media_type()) {
+            self.secondary.render_preserialized(template, data, additional_cids, globals)
+                .map(|res| res.map_err(CompositeError::Secondary))
+        } else {
+            self.primary.render_preserialized(template, data, additional_cids, globals)
+                .map(|res| res.map_err(CompositeError::Primary))
+        }
+    }
+
+    /// the render context of the primary engine, if it has none the one of the secondary
+    fn render_context(
+        &self,
+        data: &Value,
+        additional_cids: AdditionalCIds,
+        globals: &Map<String, Value>
+    ) -> Option<Value> {
+        self.primary.render_context(data, additional_cids, globals)
+            .or_else(|| self.secondary.render_context(data, additional_cids, globals))
+    }
+}
+
+impl<D, P, S> RenderEngine<D> for CompositeRenderEngine<P, S>
+    where P: RenderEngine<D>, S: RenderEngine<D>
+{
+    fn render(
+        &self,
+        template: &SubTemplateSpec,
+        data: &D,
+        additional_cids: AdditionalCIds
+    ) -> Result<String, Self::RenderError> {
+        if self.uses_secondary(template.media_type()) {
+            self.secondary.render(template, data, additional_cids)
+                .map_err(CompositeError::Secondary)
+        } else {
+            self.primary.render(template, data, additional_cids)
+                .map_err(CompositeError::Primary)
+        }
+    }
+
+    fn render_with_globals(
+        &self,
+        template: &SubTemplateSpec,
+        data: &D,
+        additional_cids: AdditionalCIds,
+        globals: &Map<String, Value>
+    ) -> Result<String, Self::RenderError> {
+        if self.uses_secondary(template.media_type()) {
+            self.secondary.render_with_globals(template, data, additional_cids, globals)
+                .map_err(CompositeError::Secondary)
+        } else {
+            self.primary.render_with_globals(template, data, additional_cids, globals)
+                .map_err(CompositeError::Primary)
+        }
+    }
+
+    /// uses the data pre-serialized by the primary engine, or else the secondary engine
+    ///
+    /// If one of the engines doesn't support pre-serialized data the
+    /// `RenderTemplateEngine` falls back to the original data for
+    /// bodies rendered by it.
+    fn preserialize(&self, data: &D) -> Option<Value> {
+        self.primary.preserialize(data)
+            .or_else(|| self.secondary.preserialize(data))
+    }
+}
+
+/// error of a `CompositeRenderEngine`, i.e. the error of one of the wrapped engines
+#[derive(Debug)]
+pub enum CompositeError<P, S> {
+    Primary(P),
+    Secondary(S)
+}
+
+impl<P, S> Display for CompositeError<P, S>
+    where P: Display, S: Display
+{
+    fn fmt(&self, fter: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CompositeError::Primary(ref err) => write!(fter, "primary render engine: {}", err),
+            CompositeError::Secondary(ref err) => write!(fter, "secondary render engine: {}", err)
+        }
+    }
+}
+
+impl<P, S> RenderErrorKind for CompositeError<P, S>
+    where P: RenderErrorKind, S: RenderErrorKind
+{
+    fn kind(&self) -> ErrorKind {
+        match *self {
+            CompositeError::Primary(ref err) => err.kind(),
+            CompositeError::Secondary(ref err) => err.kind()
+        }
+    }
+}
+
+impl<P, S> Fail for CompositeError<P, S>
+    where P: Fail, S: Fail
+{
+    fn cause(&self) -> Option<&Fail> {
+        match *self {
+            CompositeError::Primary(ref err) => Some(err),
+            CompositeError::Secondary(ref err) => Some(err)
+        }
+    }
+}
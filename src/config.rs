@@ -0,0 +1,70 @@
+/// The (plain value) settings of a `RenderTemplateEngine`
+///
+/// This bundles the settings which otherwise have to be set one by one
+/// with the `set_*` methods of the `RenderTemplateEngine`, see
+/// `RenderTemplateEngine::with_config`. The fields have the same
+/// meaning as the corresponding setters. Hooks (e.g. the markdown
+/// renderer), globals and body wrappers are not part of it.
+///
+/// The default config is the config used by `RenderTemplateEngine::new`.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct RenderTemplateEngineConfig {
+    /// if newlines are fixed, see `RenderTemplateEngine::set_fix_newlines`
+    ///
+    /// If `None` (the default) newlines are fixed if the render engine does
+    /// not guarantee to produce valid newlines (`PRODUCES_VALID_NEWLINES`).
+    pub fix_newlines: Option<bool>,
+
+    /// see `RenderTemplateEngine::set_strict_newlines`
+    pub strict_newlines: bool,
+
+    /// see `RenderTemplateEngine::set_strict_embeddings`
+    pub strict_embeddings: bool,
+
+    /// see `RenderTemplateEngine::set_empty_body_policy`
+    pub empty_body_policy: EmptyBodyPolicy,
+
+    /// see `RenderTemplateEngine::set_prune_unreferenced_embeddings`
+    pub prune_unreferenced_embeddings: bool,
+
+    /// the key the data is passed to templates under, see `RenderEngineBase::set_data_key`
+    ///
+    /// If `None` (the default) the render engine is not changed, i.e. it
+    /// uses it's own setting (e.g. `TeraRenderEngine::set_flatten_data`).
+    /// If the render engine doesn't support it it's ignored and `config`
+    /// returns `None` for it.
+    pub data_key: Option<String>,
+
+    /// see `RenderTemplateEngine::set_deterministic_content_ids`
+    pub deterministic_cids: bool,
+
+    /// see `RenderTemplateEngine::set_cid_descriptors`
+    pub cid_descriptors: bool,
+
+    /// see `RenderTemplateEngine::set_gzip_attachments_above`
+    pub gzip_attachments_above: Option<u64>,
+
+    /// see `RenderTemplateEngine::set_text_line_wrap`
+    pub text_line_wrap: Option<usize>
+}
+
+/// what happens with bodies which are empty or only contain whitespace
+///
+/// Sub-templates can opt-out through `SubTemplateSpec::set_allow_empty`,
+/// in which case their bodies are always kept.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EmptyBodyPolicy {
+    /// empty bodies are used like any other body (the default)
+    Keep,
+    /// rendering fails with an `EncodingError::EmptyBody`
+    Reject,
+    /// empty bodies are left out of the mail, if all bodies are empty
+    /// rendering fails with an `EncodingError::EmptyBody`
+    Omit
+}
+
+impl Default for EmptyBodyPolicy {
+    fn default() -> Self {
+        EmptyBodyPolicy::Keep
+    }
+}
@@ -0,0 +1,43 @@
+use std::process;
+
+use failure::{Fail, Context};
+use soft_ascii_string::SoftAsciiString;
+
+use headers::HeaderTryFrom;
+use headers::components::Domain;
+use mail::default_impl::simple_context;
+
+/// A simple `Context` implementation which can be used to render templates.
+///
+/// It's the `Context` from `mail::default_impl::simple_context`, which
+/// is re-exported for convenience, use `new_simple_context` to create it.
+pub use mail::default_impl::simple_context::Context as SimpleContext;
+
+#[derive(Debug, Fail)]
+pub enum SimpleContextError {
+    #[fail(display = "invalid domain for generating content ids: {:?}", domain)]
+    InvalidDomain { domain: String },
+
+    #[fail(display = "setting up the simple context failed")]
+    SetupFailed
+}
+
+/// creates a `SimpleContext` generating content ids under the given domain
+///
+/// This is meant to make it easy to render templates without having to
+/// setup a context manually, e.g. when only rendering templates or in
+/// tests. The unique part of generated content ids (and message ids)
+/// is derived from the process id, so content ids are unique for a given
+/// process but not necessary across multiple processes on different machines.
+pub fn new_simple_context(domain: &str)
+    -> Result<SimpleContext, Context<SimpleContextError>>
+{
+    let domain_comp = Domain::try_from(domain)
+        .map_err(|err| err.context(SimpleContextError::InvalidDomain { domain: domain.to_owned() }))?;
+
+    // UNWRAP_SAFE: a string containing only ascii letters and numbers
+    let unique_part = SoftAsciiString::from_string(format!("rte{}", process::id())).unwrap();
+
+    simple_context::new(domain_comp, unique_part)
+        .map_err(|err| err.context(SimpleContextError::SetupFailed))
+}
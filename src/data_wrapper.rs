@@ -0,0 +1,97 @@
+use serde::{Serialize, Serializer};
+use serde::ser::SerializeMap;
+use serde_json::{Map, Value};
+
+use ::traits::{AdditionalCIds, CIdDescriptors};
+
+/// the data passed to the tera and handlebars templates
+///
+/// Depending on the `flatten_data` setting of the render engine the user
+/// data is either directly at the top level (e.g. `{{ name }}`, the default)
+/// or accessible through the data key (by default `data`, e.g. `{{ data.name }}`,
+/// see `RenderEngineBase::set_data_key`). In both cases the content
+/// ids are accessible through `cids` (and their descriptors through `cid_info`,
+/// if enabled) and engine-wide globals through `globals`.
+#[derive(Serialize)]
+#[serde(untagged)]
+pub(crate) enum DataWrapper<'a, D: Serialize + 'a> {
+    Nested(NestedDataWrapper<'a, D>),
+    Flat(FlatDataWrapper<'a, D>)
+}
+
+impl<'a, D> DataWrapper<'a, D>
+    where D: Serialize + 'a
+{
+    pub(crate) fn new(
+        data: &'a D,
+        cids: AdditionalCIds<'a>,
+        globals: &'a Map<String, Value>,
+        data_key: Option<&'a str>
+    ) -> Self {
+        let cid_info = cids.descriptors();
+        if let Some(data_key) = data_key {
+            DataWrapper::Nested(NestedDataWrapper { data_key, data, cids, cid_info, globals })
+        } else {
+            DataWrapper::Flat(FlatDataWrapper { data, cids, cid_info, globals })
+        }
+    }
+}
+
+/// maps the path of a variable used in a template to the path in the user data
+///
+/// Returns `None` if the path doesn't point into the user data, e.g.
+/// for `cids.logo` or (if the data is nested under a key) for `name`.
+pub(crate) fn path_in_data<'p>(path: &'p str, data_key: Option<&str>) -> Option<&'p str> {
+    if let Some(data_key) = data_key {
+        if path.starts_with(data_key) && path[data_key.len()..].starts_with('.') {
+            Some(&path[data_key.len() + 1..])
+        } else {
+            None
+        }
+    } else {
+        let is_reserved = |name: &str| {
+            path == name || (path.starts_with(name) && path[name.len()..].starts_with('.'))
+        };
+        if is_reserved("cids") || is_reserved("cid_info") || is_reserved("globals") {
+            None
+        } else {
+            Some(path)
+        }
+    }
+}
+
+/// serializes as map with the data under `data_key`
+pub(crate) struct NestedDataWrapper<'a, D: Serialize + 'a> {
+    data_key: &'a str,
+    data: &'a D,
+    cids: AdditionalCIds<'a>,
+    cid_info: Option<CIdDescriptors<'a>>,
+    globals: &'a Map<String, Value>
+}
+
+impl<'a, D> Serialize for NestedDataWrapper<'a, D>
+    where D: Serialize + 'a
+{
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+        where S: Serializer
+    {
+        let mut map = serializer.serialize_map(None)?;
+        map.serialize_entry(self.data_key, self.data)?;
+        map.serialize_entry("cids", &self.cids)?;
+        if let Some(cid_info) = self.cid_info.as_ref() {
+            map.serialize_entry("cid_info", cid_info)?;
+        }
+        map.serialize_entry("globals", self.globals)?;
+        map.end()
+    }
+}
+
+#[derive(Serialize)]
+pub(crate) struct FlatDataWrapper<'a, D: Serialize + 'a> {
+    #[serde(flatten)]
+    data: &'a D,
+    cids: AdditionalCIds<'a>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cid_info: Option<CIdDescriptors<'a>>,
+    globals: &'a Map<String, Value>
+}
@@ -4,11 +4,77 @@ use std::ops::Deref;
 use std::cmp::PartialEq;
 use std::io;
 use std::ffi::{OsStr, OsString};
+use std::time::Duration;
 
 use failure::{Fail, Backtrace, Context};
 //circular dependency (error <-> rte) but ok here
 use ::spec::TemplateSpec;
 
+/// Implements `From<$ty>` for `Box<std::error::Error>` (+ `Send + Sync`).
+///
+/// As `failure` implements `Fail` for all `std::error::Error` types (which
+/// are `Send + Sync + 'static`) a type deriving `Fail` can not implement
+/// `std::error::Error` itself, so the conversions go through `failure::Compat`.
+/// This allows using `?` with this crates errors in functions returning
+/// `Result<_, Box<std::error::Error>>`.
+///
+/// Generics can be passed in brackets before the type, e.g.
+/// `impl_into_boxed_std_error!([E: Fail] LoadingError<E>)`.
+macro_rules! impl_into_boxed_std_error {
+    ([$($gen:tt)*] $ty:ty) => (
+        impl<$($gen)*> From<$ty> for Box<::std::error::Error + Send + Sync> {
+            fn from(err: $ty) -> Self {
+                Box::new(::failure::Fail::compat(err))
+            }
+        }
+
+        impl<$($gen)*> From<$ty> for Box<::std::error::Error> {
+            fn from(err: $ty) -> Self {
+                Box::new(::failure::Fail::compat(err))
+            }
+        }
+    );
+    ($ty:ty) => (
+        impl_into_boxed_std_error!([] $ty);
+    );
+}
+
+/// a coarse classification of errors, e.g. to map them to HTTP statuses
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ErrorKind {
+    /// the template (or a part of it) does not exist
+    NotFound,
+    /// the template (spec) is malformed or can not be loaded
+    InvalidTemplate,
+    /// the data the template is rendered with is not usable with it
+    InvalidData,
+    /// everything else, e.g. io errors or bugs
+    Internal
+}
+
+impl ErrorKind {
+
+    /// the HTTP status code matching the kind
+    ///
+    /// `NotFound` is 404, `InvalidData` is 400 and everything else is 500
+    /// as broken templates are an error on the serving side.
+    pub fn http_status(&self) -> u16 {
+        match *self {
+            ErrorKind::NotFound => 404,
+            ErrorKind::InvalidData => 400,
+            ErrorKind::InvalidTemplate | ErrorKind::Internal => 500
+        }
+    }
+}
+
+/// Trait for errors which can be classified with an `ErrorKind`
+///
+/// It's implemented for the errors of this crate and the render/loading
+/// errors of the provided render engines.
+pub trait RenderErrorKind {
+    fn kind(&self) -> ErrorKind;
+}
+
 #[derive(Debug, Fail)]
 pub enum LoadingError<E: Fail> {
     #[fail(display = "{}", _0)]
@@ -41,6 +107,42 @@ impl<E> From<io::Error> for LoadingError<E>
     }
 }
 
+impl_into_boxed_std_error!([E: Fail] LoadingError<E>);
+
+impl<E> RenderErrorKind for LoadingError<E>
+    where E: Fail + RenderErrorKind
+{
+    fn kind(&self) -> ErrorKind {
+        match *self {
+            LoadingError::SpecCreation(ref err) => err.kind(),
+            LoadingError::SpecUsage(ref err) => err.kind()
+        }
+    }
+}
+
+/// error returned by `SharedRenderTemplateEngine::use_template_with_timeout`
+#[derive(Debug, Fail)]
+pub enum RenderTimeoutError<E: Fail> {
+    /// rendering did not finish within the render timeout
+    #[fail(display = "rendering the template did not finish within {:?}", timeout)]
+    TimedOut { timeout: Duration },
+    #[fail(display = "{}", _0)]
+    Render(E)
+}
+
+impl_into_boxed_std_error!([E: Fail] RenderTimeoutError<E>);
+
+impl<E> RenderErrorKind for RenderTimeoutError<E>
+    where E: Fail + RenderErrorKind
+{
+    fn kind(&self) -> ErrorKind {
+        match *self {
+            RenderTimeoutError::TimedOut { .. } => ErrorKind::Internal,
+            RenderTimeoutError::Render(ref err) => err.kind()
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct InsertionError<E: Fail> {
     pub error: E,
@@ -48,6 +150,37 @@ pub struct InsertionError<E: Fail> {
     pub old_value: Option<TemplateSpec>
 }
 
+impl<E> InsertionError<E>
+    where E: Fail
+{
+    /// the error which caused the insertion to fail
+    pub fn error(&self) -> &E {
+        &self.error
+    }
+
+    /// the spec which was meant to be inserted
+    pub fn failed_spec(&self) -> &TemplateSpec {
+        &self.failed_new_value
+    }
+
+    /// the spec which was previously inserted with the same id, if there was one
+    ///
+    /// Note that this spec was removed from the `RenderTemplateEngine`.
+    pub fn old_spec(&self) -> Option<&TemplateSpec> {
+        self.old_value.as_ref()
+    }
+
+    /// turns the error into the spec which was meant to be inserted, e.g. to retry
+    pub fn into_failed_spec(self) -> TemplateSpec {
+        self.failed_new_value
+    }
+
+    /// turns the error into the spec previously inserted with the same id, if there was one
+    pub fn into_old_spec(self) -> Option<TemplateSpec> {
+        self.old_value
+    }
+}
+
 impl<E> Fail for InsertionError<E>
     where E: Fail
 {
@@ -68,6 +201,16 @@ impl<E> Display for InsertionError<E>
     }
 }
 
+impl_into_boxed_std_error!([E: Fail] InsertionError<E>);
+
+impl<E> RenderErrorKind for InsertionError<E>
+    where E: Fail + RenderErrorKind
+{
+    fn kind(&self) -> ErrorKind {
+        self.error.kind()
+    }
+}
+
 
 
 #[derive(Debug)]
@@ -115,12 +258,112 @@ impl From<io::Error> for CreatingSpecError {
     }
 }
 
+impl_into_boxed_std_error!(CreatingSpecError);
+
+impl RenderErrorKind for CreatingSpecError {
+    fn kind(&self) -> ErrorKind {
+        use self::CreatingSpecErrorVariant::*;
+        match *self.variant() {
+            NoSuchAttachment { .. } | NoSuchEmbedding { .. } => ErrorKind::NotFound,
+            InvalidSampleData { .. } => ErrorKind::InvalidData,
+            IoError | FetchingFailed { .. } | NoResourceFetcher { .. } => ErrorKind::Internal,
+            _ => ErrorKind::InvalidTemplate
+        }
+    }
+}
+
+/// error returned when a rendered body can not be used as mail body
+///
+/// E.g. because it can not be encoded with the charset of it's media type.
+#[derive(Debug, Fail, Clone, PartialEq, Eq)]
+pub enum EncodingError {
+
+    #[fail(display = "unsupported charset for bodies: {}", charset)]
+    UnsupportedCharset { charset: String },
+
+    #[fail(display = "rendered body contains {:?} which can not be represented in {}", ch, charset)]
+    UnrepresentableChar { ch: char, charset: String },
+
+    /// the body contains a `\r` or `\n` which is not part of a `\r\n`
+    ///
+    /// This is only returned if `RenderTemplateEngine::set_strict_newlines`
+    /// is enabled while fixing newlines is disabled.
+    #[fail(display = "rendered {} body contains a lone \\r or \\n", media_type)]
+    OrphanNewline { media_type: String },
+
+    /// the body is empty or only contains whitespace
+    ///
+    /// This is only returned if the `EmptyBodyPolicy` of the `RenderTemplateEngine`
+    /// rejects (or omits all) empty bodies and the sub-template doesn't allow empty bodies.
+    #[fail(display = "rendered {} body is empty", media_type)]
+    EmptyBody { media_type: String },
+
+    /// the spec has a markdown body and a body it's expanded into
+    ///
+    /// This is only returned if a `MarkdownRenderer` is set, which expands
+    /// `text/markdown` bodies into a `text/plain` and `text/html` body.
+    #[fail(display = "markdown body conflicts with {} body", media_type)]
+    ConflictingMarkdownBody { media_type: String },
+
+    /// a embedding of a sub-template has the same name as a shared embedding
+    ///
+    /// This is only returned if `RenderTemplateEngine::set_strict_embeddings` is enabled.
+    #[fail(display = "sub-template embedding shadows shared embedding: {}", name)]
+    ShadowedEmbedding { name: String },
+
+    /// reading or compressing a attachment failed, see `RenderTemplateEngine::set_gzip_attachments_above`
+    #[fail(display = "compressing attachment {} failed: {}", file, cause)]
+    AttachmentCompressionFailed { file: String, cause: String },
+
+    /// reading a stylesheet embedding failed, see `RenderTemplateEngine::set_css_inliner`
+    #[fail(display = "reading stylesheet {} failed: {}", file, cause)]
+    StylesheetReadFailed { file: String, cause: String },
+
+    /// the render engine can't return the data templates are rendered with
+    ///
+    /// See `RenderTemplateEngine::debug_render_context`.
+    #[fail(display = "render engine does not support returning the render context")]
+    RenderContextNotSupported,
+
+    /// a stylesheet embedding was not read when it's spec was loaded, see `TemplateFs::resource`
+    #[fail(display = "stylesheet embedding not loaded: {}", name)]
+    StylesheetNotLoaded { name: String },
+
+    /// a body post-processor failed, see `RenderTemplateEngine::add_body_post_processor`
+    #[fail(display = "post-processing {} body failed: {}", media_type, cause)]
+    PostProcessingFailed { media_type: String, cause: String },
+}
+
+impl_into_boxed_std_error!(EncodingError);
+
+impl RenderErrorKind for EncodingError {
+    fn kind(&self) -> ErrorKind {
+        match *self {
+            EncodingError::UnsupportedCharset { .. } => ErrorKind::InvalidTemplate,
+            EncodingError::UnrepresentableChar { .. }
+            | EncodingError::OrphanNewline { .. }
+            | EncodingError::EmptyBody { .. } => ErrorKind::InvalidData,
+            EncodingError::ShadowedEmbedding { .. }
+            | EncodingError::ConflictingMarkdownBody { .. }
+            | EncodingError::StylesheetNotLoaded { .. } => ErrorKind::InvalidTemplate,
+            EncodingError::AttachmentCompressionFailed { .. }
+            | EncodingError::StylesheetReadFailed { .. }
+            | EncodingError::RenderContextNotSupported
+            | EncodingError::PostProcessingFailed { .. } => ErrorKind::Internal
+        }
+    }
+}
+
 
 
 #[derive(Debug, Fail)]
 pub enum CreatingSpecErrorVariant {
 
-    #[fail(display = "path must also be valid string, got: {}", _0)]
+    /// the path is not valid UTF-8
+    ///
+    /// Paths of templates are used as template ids by the render engines,
+    /// which are strings, so all paths have to be valid UTF-8.
+    #[fail(display = "path is not valid UTF-8, but paths are used as template ids by the render engine which requires UTF-8 strings. Path (non UTF-8 parts replaced): {}", _0)]
     NonStringPath(DisplayPath),
 
     #[fail(display =  "no type info in settings for: {:?}", type_name)]
@@ -135,11 +378,31 @@ pub enum CreatingSpecErrorVariant {
     #[fail(display = "multiple embeddings with the in-template name {:?} where found", name)]
     DuplicateEmbeddingName { name: String },
 
+    /// multiple templates have the same id, the paths are the dirs they where loaded from (if known)
+    #[fail(display = "multiple templates with the id {:?} where found: {:?}", id, paths)]
+    DuplicateTemplateId { id: String, paths: Vec<DisplayPath> },
+
+    /// multiple sources (sub-templates, variants or header templates) of one spec have the same id
+    #[fail(display = "multiple template sources with the id {:?} in the same template spec", id)]
+    DuplicateSourceId { id: String },
+
+    /// multiple files in the partials dir would create a partial with the same name
+    #[fail(display = "multiple partials with the name {:?} in dir: {}", name, dir)]
+    DuplicatePartialName { name: String, dir: DisplayPath },
+
     #[fail(display = "template dir has to contain at last one sub-template. dir: {}", dir)]
     NoSubTemplatesFound { dir: DisplayPath },
 
-    #[fail(display = "sub-template folder does not contain a template file: {}", dir)]
-    TemplateFileMissing { dir: DisplayPath },
+    #[fail(display = "a template spec has to have at last one sub-template")]
+    NoSubTemplates,
+
+    /// the sub-template folder contains files, but none of them is a template file (`mail.*`)
+    #[fail(display = "sub-template folder does not contain a template file (mail.*): {}, found files: {:?}",
+        dir, found_files)]
+    TemplateFileMissing { dir: DisplayPath, found_files: Vec<String> },
+
+    #[fail(display = "sub-template folder is empty: {}", dir)]
+    EmptyTemplateDir { dir: DisplayPath },
 
     #[fail(display = "sub-template folder does contain more than one template file: {}", dir)]
     MultipleTemplateFiles { dir: DisplayPath },
@@ -170,6 +433,48 @@ pub enum CreatingSpecErrorVariant {
     #[fail(display = "the media type generated by a media type sniffer is invalid")]
     NotAMediaType,
 
+    #[fail(display = "path placeholder ${{{}}} is not defined in the settings", name)]
+    UndefinedPathPlaceholder { name: String },
+
+    #[fail(display = "path contains an unterminated placeholder: {:?}", path)]
+    MalformedPathPlaceholder { path: String },
+
+    #[fail(display = "template has no attachment with index {}", index)]
+    NoSuchAttachment { index: usize },
+
+    #[fail(display = "template has no embedding with the name {:?}", name)]
+    NoSuchEmbedding { name: String },
+
+    #[fail(display = "resource was not created from a source (e.g. a file)")]
+    SourcelessResource,
+
+    #[fail(display = "invalid glob pattern: {:?}", pattern)]
+    InvalidGlobPattern { pattern: String },
+
+    #[fail(display = "can not determine template dir of body file: {}", file)]
+    NoTemplateDir { file: DisplayPath },
+
+    #[fail(display = "no type info in settings for body file: {}", file)]
+    NoTypeForBodyFile { file: DisplayPath },
+
+    #[fail(display = "sample data file does not contain valid json: {}", file)]
+    InvalidSampleData { file: DisplayPath },
+
+    #[fail(display = "multipart media types can not be used for bodies: {}", media_type)]
+    MultipartMediaType { media_type: String },
+
+    #[fail(display = "invalid media type parameter: {}={:?}", name, value)]
+    InvalidMediaTypeParam { name: String, value: String },
+
+    #[fail(display = "format=flowed can only be used with text/plain bodies, not: {}", media_type)]
+    NotPlainText { media_type: String },
+
+    #[fail(display = "fetching the resource at {} failed", url)]
+    FetchingFailed { url: String },
+
+    #[fail(display = "a resource fetcher has to be set to declare embeddings with urls: {}", url)]
+    NoResourceFetcher { url: String },
+
     #[fail(display = "constructing a IRI with the scheme {} and the path {} failed", scheme, tail)]
     IRIConstructionFailed {
         scheme: &'static str,
@@ -185,6 +490,11 @@ impl DisplayPath {
     pub fn as_path(&self) -> &Path {
         self.0.as_path()
     }
+
+    /// the path as string, with non UTF-8 parts replaced with `U+FFFD`
+    pub fn to_string_lossy(&self) -> String {
+        self.0.to_string_lossy().into_owned()
+    }
 }
 
 impl Display for DisplayPath {
@@ -0,0 +1,157 @@
+use std::fmt::Debug;
+use std::path::Path;
+use std::io;
+#[cfg(feature="http")]
+use std::io::Read;
+#[cfg(feature="http")]
+use std::time::Duration;
+
+use failure::Fail;
+use mail::Resource;
+use mail::file_buffer::FileBuffer;
+
+use ::error::{CreatingSpecError, CreatingSpecErrorVariant};
+use ::utils::media_type_from_extension;
+
+/// Trait for fetching embeddings which are declared with a `http://` or `https://` url
+///
+/// If a fetcher is set (see `LoadSpecSettings::set_resource_fetcher`) explicitly
+/// declared embeddings (e.g. `TemplateSpec::insert_embedding_from_path`) can be
+/// urls instead of paths. The content is fetched when the spec is created and
+/// then used like the content of any other file, i.e. it's fetched only once.
+///
+/// Note that fetching resources at load time means that loading templates
+/// depends on the availability of the remote host. Implementations should
+/// use a timeout and limit the size of the fetched content, and urls should
+/// only be declared for trusted hosts (the content ends up in the mails).
+///
+/// With the `http` feature the `HttpFetcher` implementation is available.
+pub trait ResourceFetcher: Debug + Send + Sync {
+
+    /// returns the content of the resource at the url
+    fn fetch(&self, url: &str) -> io::Result<Vec<u8>>;
+}
+
+/// returns true if the declared path is a `http://` or `https://` url
+pub(crate) fn is_url(path: &str) -> bool {
+    let lowercase = path.trim_left().to_lowercase();
+    lowercase.starts_with("http://") || lowercase.starts_with("https://")
+}
+
+/// fetches the url into a resource without source
+///
+/// The media type is determined by the extension of the last segment of
+/// the urls path.
+pub(crate) fn resource_from_url(url: &str, fetcher: &ResourceFetcher)
+    -> Result<Resource, CreatingSpecError>
+{
+    let media_type = media_type_from_extension(Path::new(url_file_name(url)))?;
+    let content = fetcher.fetch(url)
+        .map_err(|err| err.context(CreatingSpecErrorVariant::FetchingFailed { url: url.to_owned() }))?;
+    Ok(Resource::sourceless_from_buffer(FileBuffer::new(media_type, content)))
+}
+
+/// returns the last segment of the path of the url, without query and fragment
+fn url_file_name(url: &str) -> &str {
+    let without_scheme = url.splitn(2, "://").nth(1).unwrap_or(url);
+    let end = without_scheme.find(|ch| ch == '?' || ch == '#')
+        .unwrap_or(without_scheme.len());
+    let path = &without_scheme[..end];
+    path.rsplit('/').next().unwrap_or(path)
+}
+
+/// A `ResourceFetcher` using blocking http(s) requests.
+///
+/// Requests which take longer than the timeout fail, as do responses with
+/// a non-success status code and responses with a body larger than the
+/// size limit (only up to `max_size + 1` bytes are read).
+#[cfg(feature="http")]
+#[derive(Debug, Clone)]
+pub struct HttpFetcher {
+    timeout: Duration,
+    max_size: u64
+}
+
+#[cfg(feature="http")]
+impl HttpFetcher {
+
+    /// creates a new fetcher with a timeout of 10 seconds and a size limit of 5MiB
+    pub fn new() -> Self {
+        HttpFetcher {
+            timeout: Duration::from_secs(10),
+            max_size: 5 * 1024 * 1024
+        }
+    }
+
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    /// sets the maximal size (in bytes) of fetched resources
+    pub fn set_max_size(&mut self, max_size: u64) {
+        self.max_size = max_size;
+    }
+
+    pub fn max_size(&self) -> u64 {
+        self.max_size
+    }
+}
+
+#[cfg(feature="http")]
+impl Default for HttpFetcher {
+    fn default() -> Self {
+        HttpFetcher::new()
+    }
+}
+
+#[cfg(feature="http")]
+impl ResourceFetcher for HttpFetcher {
+
+    fn fetch(&self, url: &str) -> io::Result<Vec<u8>> {
+        let client = ::reqwest::Client::builder()
+            .timeout(self.timeout)
+            .build()
+            .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+
+        let response = client.get(url)
+            .send()
+            .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+
+        if !response.status().is_success() {
+            return Err(io::Error::new(io::ErrorKind::Other,
+                format!("unexpected status {} when fetching {}", response.status(), url)));
+        }
+
+        let mut content = Vec::new();
+        response.take(self.max_size + 1).read_to_end(&mut content)?;
+        if content.len() as u64 > self.max_size {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                format!("resource at {} is larger than {} bytes", url, self.max_size)));
+        }
+        Ok(content)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{is_url, url_file_name};
+
+    #[test]
+    fn detect_urls() {
+        assert!(is_url("https://cdn.example.com/logo.png"));
+        assert!(is_url("HTTP://cdn.example.com/logo.png"));
+        assert!(!is_url("./https/logo.png"));
+        assert!(!is_url("${ASSET_ROOT}/logo.png"));
+    }
+
+    #[test]
+    fn file_name_of_url() {
+        assert_eq!(url_file_name("https://cdn.example.com/img/logo.png"), "logo.png");
+        assert_eq!(url_file_name("https://cdn.example.com/logo.png?v=2#top"), "logo.png");
+        assert_eq!(url_file_name("https://cdn.example.com/"), "");
+    }
+}
@@ -0,0 +1,58 @@
+use std::collections::HashMap;
+use std::fmt::{self, Debug};
+use std::sync::RwLock;
+
+use mail::Resource;
+
+/// The compressed attachments by template id, see `RenderTemplateEngine::set_gzip_attachments_above`
+///
+/// For each attachment of a template it contains the compressed copy
+/// or `None` if the attachment is not compressed (e.g. as it's too small).
+#[derive(Default)]
+pub(crate) struct GzipCache {
+    entries: RwLock<HashMap<String, Vec<Option<Resource>>>>
+}
+
+impl GzipCache {
+
+    /// returns the compressed attachments of the template, creating them if they are not cached
+    pub(crate) fn get_or_create<F, E>(&self, template_id: &str, create: F)
+        -> Result<Vec<Option<Resource>>, E>
+        where F: FnOnce() -> Result<Vec<Option<Resource>>, E>
+    {
+        // a poisoned lock is treated like a cache miss
+        let cached = self.entries.read().ok()
+            .and_then(|entries| entries.get(template_id).cloned());
+        if let Some(compressed) = cached {
+            return Ok(compressed);
+        }
+
+        let compressed = create()?;
+        if let Ok(mut entries) = self.entries.write() {
+            entries.insert(template_id.to_owned(), compressed.clone());
+        }
+        Ok(compressed)
+    }
+
+    /// removes the compressed attachments of the given template
+    pub(crate) fn remove_template(&self, template_id: &str) {
+        if let Ok(mut entries) = self.entries.write() {
+            entries.remove(template_id);
+        }
+    }
+
+    pub(crate) fn clear(&self) {
+        if let Ok(mut entries) = self.entries.write() {
+            entries.clear();
+        }
+    }
+}
+
+impl Debug for GzipCache {
+    fn fmt(&self, fter: &mut fmt::Formatter) -> fmt::Result {
+        let len = self.entries.read().map(|entries| entries.len()).ok();
+        fter.debug_struct("GzipCache")
+            .field("len", &len)
+            .finish()
+    }
+}
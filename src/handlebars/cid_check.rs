@@ -0,0 +1,118 @@
+use std::collections::BTreeSet;
+
+use handlebars_crate::template::{
+    Template, TemplateElement, Parameter,
+    HelperTemplate, Directive
+};
+
+/// returns the names of all embeddings referenced through `cids.<name>` in the template
+///
+/// This only finds direct references (incl. ones in helper parameters and
+/// nested blocks), a reference through e.g. `{{#with cids}}{{logo}}{{/with}}`
+/// can not be detected.
+pub(crate) fn referenced_cids(template: &Template) -> BTreeSet<String> {
+    let mut names = BTreeSet::new();
+    collect_from_template(template, &mut names);
+    names
+}
+
+fn collect_from_template(template: &Template, names: &mut BTreeSet<String>) {
+    for element in template.elements.iter() {
+        collect_from_element(element, names);
+    }
+}
+
+fn collect_from_element(element: &TemplateElement, names: &mut BTreeSet<String>) {
+    use self::TemplateElement::*;
+    match *element {
+        Expression(ref param) | HTMLExpression(ref param) => {
+            collect_from_param(param, names);
+        },
+        HelperExpression(ref helper) | HelperBlock(ref helper) => {
+            collect_from_helper(helper, names);
+        },
+        DirectiveExpression(ref directive) | DirectiveBlock(ref directive)
+        | PartialExpression(ref directive) | PartialBlock(ref directive) => {
+            collect_from_directive(directive, names);
+        },
+        RawString(_) | Comment(_) => {}
+    }
+}
+
+fn collect_from_helper(helper: &HelperTemplate, names: &mut BTreeSet<String>) {
+    for param in helper.params.iter().chain(helper.hash.values()) {
+        collect_from_param(param, names);
+    }
+    if let Some(ref template) = helper.template {
+        collect_from_template(template, names);
+    }
+    if let Some(ref inverse) = helper.inverse {
+        collect_from_template(inverse, names);
+    }
+}
+
+fn collect_from_directive(directive: &Directive, names: &mut BTreeSet<String>) {
+    for param in directive.params.iter().chain(directive.hash.values()) {
+        collect_from_param(param, names);
+    }
+    if let Some(ref template) = directive.template {
+        collect_from_template(template, names);
+    }
+}
+
+fn collect_from_param(param: &Parameter, names: &mut BTreeSet<String>) {
+    match *param {
+        Parameter::Name(ref path) => {
+            if let Some(name) = cid_name_of(path) {
+                names.insert(name.to_owned());
+            }
+        },
+        Parameter::Subexpression(ref sub) => {
+            collect_from_element(&sub.element, names);
+        },
+        Parameter::Literal(_) => {}
+    }
+}
+
+/// returns the embedding name if the path refers to `cids.<name>`
+fn cid_name_of(path: &str) -> Option<&str> {
+    let mut path = path;
+    loop {
+        let stripped = ["../", "./", "this.", "this/"].iter()
+            .find(|prefix| path.starts_with(*prefix))
+            .map(|prefix| &path[prefix.len()..]);
+        match stripped {
+            Some(rest) => path = rest,
+            None => break
+        }
+    }
+
+    if !(path.starts_with("cids.") || path.starts_with("cids/")) {
+        return None;
+    }
+    let rest = &path["cids.".len()..];
+    let name =
+        if rest.starts_with('[') {
+            rest[1..].split(']').next()
+        } else {
+            rest.split(|ch| ch == '.' || ch == '/').next()
+        };
+    name.filter(|name| !name.is_empty())
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::cid_name_of;
+
+    #[test]
+    fn cid_names_from_paths() {
+        assert_eq!(cid_name_of("cids.logo"), Some("logo"));
+        assert_eq!(cid_name_of("cids/logo"), Some("logo"));
+        assert_eq!(cid_name_of("../cids.logo"), Some("logo"));
+        assert_eq!(cid_name_of("this.cids.[my logo]"), Some("my logo"));
+        assert_eq!(cid_name_of("data.cids.logo"), None);
+        assert_eq!(cid_name_of("cids"), None);
+        assert_eq!(cid_name_of("cids."), None);
+    }
+}
@@ -1,5 +1,7 @@
 use std::{io as std_io};
-use handlebars_crate::{TemplateError, TemplateFileError};
+use handlebars_crate::{RenderError, TemplateError, TemplateFileError};
+
+use ::error::{ErrorKind, RenderErrorKind};
 
 #[derive(Debug, Fail)]
 pub enum LoadingError {
@@ -13,7 +15,42 @@ pub enum LoadingError {
     TemplateParsing(TemplateError),
 
     #[fail(display="Template {}: {}", template, err)]
-    Io { err: std_io::Error, template: String }
+    Io { err: std_io::Error, template: String },
+
+    #[fail(display="template {} references the content id of the unknown embedding {:?}", template, name)]
+    UnknownCidReference { template: String, name: String },
+
+    /// the helper could not be created, `cause` is the message of the error returned when creating it
+    #[fail(display="creating the helper {} failed: {}", name, cause)]
+    HelperCreationFailed { name: String, cause: String }
+}
+
+impl_into_boxed_std_error!(LoadingError);
+
+impl RenderErrorKind for LoadingError {
+    fn kind(&self) -> ErrorKind {
+        match *self {
+            LoadingError::Io { .. } | LoadingError::HelperCreationFailed { .. } => ErrorKind::Internal,
+            _ => ErrorKind::InvalidTemplate
+        }
+    }
+}
+
+/// classifies render errors based on their description
+///
+/// Errors created by this crate (unknown template ids and encoding errors)
+/// start with `*Mail*`, undefined variables (in strict mode) are reported
+/// as invalid data.
+impl RenderErrorKind for RenderError {
+    fn kind(&self) -> ErrorKind {
+        if self.desc.starts_with("*Mail* Template not found") {
+            ErrorKind::NotFound
+        } else if self.desc.starts_with("*Mail* ") || self.desc.contains("\" not found") {
+            ErrorKind::InvalidData
+        } else {
+            ErrorKind::InvalidTemplate
+        }
+    }
 }
 
 impl From<TemplateError> for LoadingError {
@@ -2,25 +2,31 @@ use std::collections::HashSet;
 use std::path::Path;
 use std::io::Read;
 use std::ops::Deref;
+use std::fmt::Display;
 
 use serde::Serialize;
+use serde_json::{self, Map, Value};
 use handlebars_crate::{
-    Handlebars, RenderError,
+    Handlebars, RenderError, TemplateRenderError,
     HelperDef, DecoratorDef
 };
 
+use ::data_wrapper::{DataWrapper, path_in_data};
+use ::error::EncodingError;
 use ::{
     RenderEngineBase, RenderEngine,
-    AdditionalCIds,
+    AdditionalCIds, InheritanceSupport,
     TemplateSpec, SubTemplateSpec,
     TemplateSource
 };
 
 
-use self::error::LoadingError;
+pub use self::error::LoadingError;
+use self::cid_check::referenced_cids;
 
 
 mod error;
+mod cid_check;
 
 /// Render Engine using Handlebars for rendering
 ///
@@ -44,7 +50,10 @@ mod error;
 #[derive(Debug)]
 pub struct HandlebarsRenderEngine {
     handlebars: Handlebars,
-    free_templates: HashSet<String>
+    free_templates: HashSet<String>,
+    validate_cid_references: bool,
+    flatten_data: bool,
+    data_key: String
 }
 
 impl HandlebarsRenderEngine {
@@ -64,6 +73,58 @@ impl HandlebarsRenderEngine {
         self.handlebars.set_strict_mode(enabled)
     }
 
+    /// if enabled loading a spec checks that all referenced embeddings exist
+    ///
+    /// After the templates of a spec are loaded their AST is walked and for
+    /// each `cids.<name>` reference it's checked that the spec has an embedding
+    /// with that name (a shared one or one of the sub-template the template
+    /// belongs to). If not the templates of the spec are unloaded again and
+    /// an `UnknownCidReference` error is returned. This catches typos like
+    /// `{{cids.logoo}}` when loading the templates instead of when rendering
+    /// (or not at all, depending on the strict mode).
+    ///
+    /// Only direct references can be checked, e.g. references done through
+    /// `{{#with cids}}{{logo}}{{/with}}` are not found.
+    ///
+    /// This is disabled by default.
+    pub fn set_validate_cid_references(&mut self, enabled: bool) {
+        self.validate_cid_references = enabled;
+    }
+
+    /// returns true if cid references are validated, see `set_validate_cid_references`
+    pub fn validate_cid_references(&self) -> bool {
+        self.validate_cid_references
+    }
+
+    /// if enabled (the default) the data is passed to templates at the top level
+    ///
+    /// By default the fields of the data are at the top level in the templates
+    /// (e.g. `{{ name }}`), if disabled the data is nested in `data` instead
+    /// (e.g. `{{ data.name }}`, see `RenderEngineBase::set_data_key` for using
+    /// another key), which was the behavior of previous versions. In both cases
+    /// content ids are accessible through `cids` and globals through `globals`.
+    ///
+    /// If enabled the data has to serialize to a map (e.g. be a struct) and
+    /// `cids`, `cid_info` and `globals` are reserved, if the data has a field
+    /// with one of this names it's overridden.
+    pub fn set_flatten_data(&mut self, enabled: bool) {
+        self.flatten_data = enabled;
+    }
+
+    /// returns true if the data is passed at the top level, see `set_flatten_data`
+    pub fn flatten_data(&self) -> bool {
+        self.flatten_data
+    }
+
+    /// the key the data is nested under, or `None` if it's passed at the top level
+    fn nested_data_key(&self) -> Option<&str> {
+        if self.flatten_data {
+            None
+        } else {
+            Some(&self.data_key)
+        }
+    }
+
     /// get a mut reference to inner handlebars object
     ///
     /// Note that using some methods of the inner object
@@ -166,11 +227,36 @@ impl HandlebarsRenderEngine {
     ///
     /// ... then nothing is done.
     pub fn unregister_free_template(&mut self, name: &str) {
-        if self.free_templates.contains(name) {
+        if self.free_templates.remove(name) {
             self.handlebars.unregister_template(name);
         }
     }
 
+    /// Unregister all free templates whose name starts with the given prefix
+    ///
+    /// E.g. with the prefix `"layout/"` the free templates `layout/header`
+    /// and `layout/footer` are unregistered. Non free templates are never
+    /// unregistered, even if they start with the prefix.
+    pub fn unregister_free_templates_with_prefix(&mut self, prefix: &str) {
+        let to_remove = self.free_templates.iter()
+            .filter(|name| name.starts_with(prefix))
+            .cloned()
+            .collect::<Vec<_>>();
+
+        for name in to_remove {
+            self.unregister_free_template(&name);
+        }
+    }
+
+    /// Returns the names of all free templates (sorted).
+    pub fn free_template_ids(&self) -> Vec<&str> {
+        let mut ids = self.free_templates.iter()
+            .map(|name| name.as_str())
+            .collect::<Vec<_>>();
+        ids.sort();
+        ids
+    }
+
     /// Unregister all free templates
     pub fn clear_free_templates(&mut self) {
         for id in self.free_templates.drain() {
@@ -187,6 +273,26 @@ impl HandlebarsRenderEngine {
         self.handlebars.register_helper(name, def)
     }
 
+    /// Register an helper which creation can fail, e.g. as it reads a file.
+    ///
+    /// The helper is created by calling `create`, if it fails a
+    /// `LoadingError::HelperCreationFailed` is returned and no helper is
+    /// registered, else the previously registered helper (if any) is returned.
+    pub fn try_register_helper<F, E>(
+        &mut self,
+        name: &str,
+        create: F
+    ) -> Result<Option<Box<HelperDef + 'static>>, LoadingError>
+        where F: FnOnce() -> Result<Box<HelperDef + 'static>, E>, E: Display
+    {
+        let def = create()
+            .map_err(|err| LoadingError::HelperCreationFailed {
+                name: name.to_owned(),
+                cause: err.to_string()
+            })?;
+        Ok(self.register_helper(name, def))
+    }
+
     /// Register an decorator to the inner `Handlebars` instance.
     pub fn register_decorator(
         &mut self,
@@ -211,6 +317,26 @@ impl HandlebarsRenderEngine {
         self.handlebars.unregister_escape_fn()
     }
 
+    /// Renders a template string without registering it.
+    ///
+    /// This wraps `Handlebars::render_template`, i.e. registered helpers,
+    /// decorators, (free) templates and partials and the strict mode
+    /// setting are used. The data is passed to the template the same way
+    /// as in `render` i.e. it's accessible at the top level (or through
+    /// `data`, see `set_flatten_data`) and the content ids through `cids`.
+    pub fn render_template<D>(
+        &self,
+        source: &str,
+        data: &D,
+        cids: AdditionalCIds
+    ) -> Result<String, TemplateRenderError>
+        where D: Serialize
+    {
+        let globals = Map::new();
+        let data = &DataWrapper::new(data, cids, &globals, self.nested_data_key());
+        self.handlebars.render_template(source, data)
+    }
+
     fn check_new_free_template_name(&self, name: &str) -> Result<(), LoadingError> {
         if !self.free_templates.contains(name) && self.handlebars.get_template(name).is_some() {
             Err(LoadingError::FreeTemplateIdCollision { id: name.to_owned() })
@@ -219,6 +345,71 @@ impl HandlebarsRenderEngine {
         }
     }
 
+    fn check_cid_references(&self, spec: &TemplateSpec) -> Result<(), LoadingError> {
+        for sub_spec in spec.sub_specs() {
+            for source in sub_spec.all_sources() {
+                let template =
+                    if let Some(template) = self.handlebars.get_template(source.id()) {
+                        template
+                    } else {
+                        continue;
+                    };
+
+                let unknown = referenced_cids(template).into_iter()
+                    .find(|name| {
+                        !sub_spec.embeddings().contains_key(name)
+                            && !spec.embeddings().contains_key(name)
+                    });
+
+                if let Some(name) = unknown {
+                    return Err(LoadingError::UnknownCidReference {
+                        template: source.id().to_owned(),
+                        name
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn load_spec_templates(&mut self, spec: &TemplateSpec) -> Result<Vec<String>, LoadingError> {
+        implement_load_helper! {
+            input::<Handlebars>(spec, &mut self.handlebars);
+            error(LoadingError);
+            collision_error_fn(|id| { LoadingError::TemplateIdCollision { id } });
+            has_template_fn(|hbs, id| { hbs.get_template(id).is_some() });
+            remove_fn(|hbs, id| { hbs.unregister_template(id) });
+            add_file_fn(|hbs, path| { Ok(hbs.register_template_file(path, path)?) });
+            add_content_fn(|hbs, id, content| { Ok(hbs.register_template_string(id, content)?) });
+        }
+    }
+
+    /// registers the partials of the spec as templates named like the partials
+    ///
+    /// If registering a partial fails the partials registered before are removed again.
+    fn load_partials(&mut self, spec: &TemplateSpec) -> Result<(), LoadingError> {
+        for (idx, &(ref name, ref source)) in spec.partials().iter().enumerate() {
+            let result =
+                if self.handlebars.get_template(name).is_some() {
+                    Err(LoadingError::TemplateIdCollision { id: name.clone() })
+                } else {
+                    match *source {
+                        TemplateSource::Path(ref path) =>
+                            self.handlebars.register_template_file(name, path).map_err(LoadingError::from),
+                        TemplateSource::Source { ref content, .. } =>
+                            self.handlebars.register_template_string(name, content).map_err(LoadingError::from)
+                    }
+                };
+            if let Err(err) = result {
+                for &(ref name, _) in &spec.partials()[..idx] {
+                    self.handlebars.unregister_template(name);
+                }
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+
     fn insert_free_template<F>(&mut self, name: &str, insert_fn: F) -> Result<(), LoadingError>
         where F: FnOnce(&mut Handlebars) -> Result<(), LoadingError>
     {
@@ -237,33 +428,88 @@ impl RenderEngineBase for HandlebarsRenderEngine {
     type RenderError = RenderError;
     type LoadingError = LoadingError;
 
-    fn load_templates(&mut self, spec: &TemplateSpec) -> Result<(), Self::LoadingError> {
-        implement_load_helper! {
-            input::<Handlebars>(spec, &mut self.handlebars);
-            error(LoadingError);
-            collision_error_fn(|id| { LoadingError::TemplateIdCollision { id } });
-            has_template_fn(|hbs, id| { hbs.get_template(id).is_some() });
-            remove_fn(|hbs, id| { hbs.unregister_template(id) });
-            add_file_fn(|hbs, path| { Ok(hbs.register_template_file(path, path)?) });
-            add_content_fn(|hbs, id, content| { Ok(hbs.register_template_string(id, content)?) });
+    fn load_templates(&mut self, spec: &TemplateSpec) -> Result<Vec<String>, Self::LoadingError> {
+        self.load_partials(spec)?;
+        let loaded =
+            match self.load_spec_templates(spec) {
+                Ok(loaded) => loaded,
+                Err(err) => {
+                    for &(ref name, _) in spec.partials() {
+                        self.handlebars.unregister_template(name);
+                    }
+                    return Err(err);
+                }
+            };
+        if self.validate_cid_references {
+            if let Err(err) = self.check_cid_references(spec) {
+                self.unload_templates(spec);
+                return Err(err);
+            }
         }
+        Ok(loaded)
     }
 
     fn unload_templates(&mut self, spec: &TemplateSpec) {
-        for sub_spec in spec.sub_specs() {
-            self.handlebars.unregister_template(sub_spec.source().id());
+        for source in spec.all_sources() {
+            self.handlebars.unregister_template(source.id());
+        }
+        for &(ref name, _) in spec.partials() {
+            self.handlebars.unregister_template(name);
         }
     }
 
+    fn supports_inheritance(&self) -> InheritanceSupport {
+        InheritanceSupport::Partials
+    }
+
+    /// disables `flatten_data` and nests the data under the key instead of `data`
+    fn set_data_key(&mut self, key: &str) -> bool {
+        self.flatten_data = false;
+        self.data_key = key.to_owned();
+        true
+    }
+
+    /// registers the base template as free partial, see `register_free_partial`
+    fn register_base_template(&mut self, name: &str, source: &str)
+        -> Option<Result<(), Self::LoadingError>>
+    {
+        Some(self.register_free_partial(name, source))
+    }
+
     fn unknown_template_id_error(id: &str) -> Self::RenderError {
         RenderError::new(format!("*Mail* Template not found: {}", id))
     }
-}
 
-#[derive(Serialize)]
-struct DataWrapper<'a,D: Serialize + 'a> {
-    data: &'a D,
-    cids: AdditionalCIds<'a>
+    fn encoding_error(err: EncodingError) -> Self::RenderError {
+        RenderError::new(format!("*Mail* {}", err))
+    }
+
+    /// detects the errors produced by handlebars strict mode for missing variables
+    fn undefined_variable(&self, err: &Self::RenderError) -> Option<String> {
+        let start = err.desc.find("Variable \"")? + "Variable \"".len();
+        let rest = &err.desc[start..];
+        let end = rest.find("\" not found")?;
+        path_in_data(&rest[..end], self.nested_data_key()).map(|path| path.to_owned())
+    }
+
+    fn render_preserialized(
+        &self,
+        spec: &SubTemplateSpec,
+        data: &Value,
+        cids: AdditionalCIds,
+        globals: &Map<String, Value>
+    ) -> Option<Result<String, Self::RenderError>> {
+        Some(RenderEngine::<Value>::render_with_globals(self, spec, data, cids, globals))
+    }
+
+    fn render_context(
+        &self,
+        data: &Value,
+        cids: AdditionalCIds,
+        globals: &Map<String, Value>
+    ) -> Option<Value> {
+        serde_json::to_value(&DataWrapper::new(data, cids, globals, self.nested_data_key())).ok()
+    }
 }
 
 impl<D> RenderEngine<D> for HandlebarsRenderEngine
@@ -273,10 +519,28 @@ impl<D> RenderEngine<D> for HandlebarsRenderEngine
     fn render(&self, spec: &SubTemplateSpec, data: &D, cids: AdditionalCIds)
         -> Result<String, Self::RenderError>
     {
-        let data = &DataWrapper { data, cids };
+        self.render_with_globals(spec, data, cids, &Map::new())
+    }
+
+    fn render_with_globals(
+        &self,
+        spec: &SubTemplateSpec,
+        data: &D,
+        cids: AdditionalCIds,
+        globals: &Map<String, Value>
+    ) -> Result<String, Self::RenderError> {
+        let data = &DataWrapper::new(data, cids, globals, self.nested_data_key());
         let id = spec.source().id();
         Ok(self.handlebars.render(id, data)?)
     }
+
+    /// serializes the data into a `serde_json::Value`
+    ///
+    /// If serialization fails `None` is returned so that the error
+    /// is reported when rendering.
+    fn preserialize(&self, data: &D) -> Option<Value> {
+        serde_json::to_value(data).ok()
+    }
 }
 
 /// Turns a Handlebars into a HandlebarsRenderEngine
@@ -289,7 +553,12 @@ impl From<Handlebars> for HandlebarsRenderEngine {
             free_templates.insert(name.clone());
         }
         handlebars.set_strict_mode(true);
-        HandlebarsRenderEngine { handlebars, free_templates }
+        HandlebarsRenderEngine {
+            handlebars, free_templates,
+            validate_cid_references: false,
+            flatten_data: true,
+            data_key: "data".to_owned()
+        }
     }
 }
 
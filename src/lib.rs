@@ -10,10 +10,18 @@ extern crate futures;
 extern crate soft_ascii_string;
 #[macro_use]
 extern crate vec1;
+#[cfg(feature="fs")]
 extern crate conduit_mime_types;
+#[cfg(feature="fs")]
+extern crate glob;
+extern crate flate2;
+#[cfg(feature="fs")]
 #[macro_use]
 extern crate lazy_static;
+#[macro_use]
+extern crate log;
 extern crate serde;
+extern crate serde_json;
 
 
 #[cfg(any(feature="tera-engine", feature="handlebars-engine"))]
@@ -23,26 +31,55 @@ extern crate serde_derive;
 extern crate tera as tera_crate;
 #[cfg(feature="handlebars-engine")]
 extern crate handlebars as handlebars_crate;
+#[cfg(feature="http")]
+extern crate reqwest;
 
 // ordered by possible "dependentness",
 // any module further down in the list
 // can import from any module above it.
 // But a module depending on a module later
 // in the ordering _should_ not happen.
+#[macro_use]
 pub mod error;
 mod utils;
+mod context;
+#[cfg(feature="fs")]
+mod fetch;
+#[cfg(feature="fs")]
 mod settings;
 mod spec;
 //TODO rename
 #[macro_use]
 mod traits;
+mod composite;
+mod cid_rewrite;
+mod config;
+mod render_cache;
+mod gzip_cache;
 mod rte;
+mod shared;
+mod mail_engine;
+#[cfg(any(feature="tera-engine", feature="handlebars-engine"))]
+mod data_wrapper;
 #[cfg(feature="tera-engine")]
 pub mod tera;
 #[cfg(feature="handlebars-engine")]
 pub mod handlebars;
+#[cfg(feature="test-util")]
+pub mod test_support;
 
+#[cfg(feature="fs")]
+pub use self::fetch::ResourceFetcher;
+#[cfg(all(feature="fs", feature="http"))]
+pub use self::fetch::HttpFetcher;
+#[cfg(feature="fs")]
 pub use self::settings::*;
 pub use self::spec::*;
 pub use self::traits::*;
-pub use self::rte::*;
\ No newline at end of file
+pub use self::composite::{CompositeRenderEngine, CompositeError};
+pub use self::cid_rewrite::{CidReferenceRewriter, SimpleCidReferenceRewriter};
+pub use self::config::{RenderTemplateEngineConfig, EmptyBodyPolicy};
+pub use self::rte::*;
+pub use self::shared::SharedRenderTemplateEngine;
+pub use self::mail_engine::{MailRenderEngine, MailTemplateEngine};
+pub use self::context::*;
\ No newline at end of file
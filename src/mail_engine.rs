@@ -0,0 +1,84 @@
+use mail::{Mail, Context};
+use template::{TemplateEngine, MailParts, MailSendData, InspectEmbeddedResources};
+use template::error::CompositionError;
+
+/// A template engine producing complete `Mail`s instead of `MailParts`
+///
+/// `TemplateEngine::use_template` only returns the `MailParts` (bodies,
+/// embeddings and attachments), assembling them into a `Mail` with the
+/// headers from the `MailSendData` is done by `MailSendData::compose`.
+/// This wraps a template engine (e.g. a `RenderTemplateEngine` or a
+/// `SharedRenderTemplateEngine`) and implements `MailTemplateEngine` which
+/// does both in one step. It still implements `TemplateEngine` by
+/// delegating to the wrapped engine, so it can be used wherever the
+/// wrapped engine was used before.
+#[derive(Debug, Clone)]
+pub struct MailRenderEngine<E> {
+    inner: E
+}
+
+impl<E> MailRenderEngine<E> {
+
+    pub fn new(inner: E) -> Self {
+        MailRenderEngine { inner }
+    }
+
+    pub fn inner(&self) -> &E {
+        &self.inner
+    }
+
+    pub fn inner_mut(&mut self) -> &mut E {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> E {
+        self.inner
+    }
+}
+
+impl<E> From<E> for MailRenderEngine<E> {
+    fn from(inner: E) -> Self {
+        MailRenderEngine::new(inner)
+    }
+}
+
+/// Trait for template engines which produce complete `Mail`s, see `MailRenderEngine`
+pub trait MailTemplateEngine<C, D>: TemplateEngine<C, D>
+    where C: Context
+{
+    /// renders the template and assembles the mail with the headers from the send data
+    fn render_mail<'a>(
+        &self,
+        send_data: MailSendData<'a, <Self as TemplateEngine<C, D>>::TemplateId, D>,
+        ctx: &C
+    ) -> Result<Mail, CompositionError<<Self as TemplateEngine<C, D>>::Error>>;
+}
+
+impl<C, D, E> TemplateEngine<C, D> for MailRenderEngine<E>
+    where C: Context, E: TemplateEngine<C, D>
+{
+    type TemplateId = E::TemplateId;
+    type Error = E::Error;
+
+    fn use_template(
+        &self,
+        template_id: &Self::TemplateId,
+        data: &D,
+        ctx: &C,
+    ) -> Result<MailParts, Self::Error>
+    {
+        self.inner.use_template(template_id, data, ctx)
+    }
+}
+
+impl<C, D, E> MailTemplateEngine<C, D> for MailRenderEngine<E>
+    where C: Context, D: InspectEmbeddedResources, E: TemplateEngine<C, D>
+{
+    fn render_mail<'a>(
+        &self,
+        send_data: MailSendData<'a, E::TemplateId, D>,
+        ctx: &C
+    ) -> Result<Mail, CompositionError<E::Error>> {
+        send_data.compose(ctx, &self.inner)
+    }
+}
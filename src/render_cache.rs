@@ -0,0 +1,94 @@
+use std::collections::HashMap;
+use std::fmt::{self, Debug};
+use std::sync::RwLock;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// (template id, serialized render input)
+pub(crate) type CacheKey = (String, Vec<u8>);
+
+struct CacheEntry<V> {
+    last_used: AtomicUsize,
+    value: V
+}
+
+/// A LRU cache for rendered bodies, see `RenderTemplateEngine::enable_render_cache`
+pub(crate) struct RenderCache<V> {
+    capacity: usize,
+    use_counter: AtomicUsize,
+    entries: RwLock<HashMap<CacheKey, CacheEntry<V>>>
+}
+
+impl<V> RenderCache<V>
+    where V: Clone
+{
+
+    pub(crate) fn new(capacity: usize) -> Self {
+        RenderCache {
+            capacity,
+            use_counter: AtomicUsize::new(0),
+            entries: RwLock::new(HashMap::with_capacity(capacity))
+        }
+    }
+
+    pub(crate) fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub(crate) fn get(&self, key: &CacheKey) -> Option<V> {
+        // a poisoned lock is treated like a cache miss
+        let entries = self.entries.read().ok()?;
+        let entry = entries.get(key)?;
+        entry.last_used.store(self.next_use(), Ordering::Relaxed);
+        Some(entry.value.clone())
+    }
+
+    pub(crate) fn insert(&self, key: CacheKey, value: V) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut entries =
+            match self.entries.write() {
+                Ok(entries) => entries,
+                Err(_) => return
+            };
+
+        if entries.len() >= self.capacity && !entries.contains_key(&key) {
+            let least_recently_used = entries.iter()
+                .min_by_key(|&(_, entry)| entry.last_used.load(Ordering::Relaxed))
+                .map(|(key, _)| key.clone());
+            if let Some(lru_key) = least_recently_used {
+                entries.remove(&lru_key);
+            }
+        }
+
+        let last_used = AtomicUsize::new(self.next_use());
+        entries.insert(key, CacheEntry { last_used, value });
+    }
+
+    /// removes all entries of the given template
+    pub(crate) fn remove_template(&self, template_id: &str) {
+        if let Ok(mut entries) = self.entries.write() {
+            entries.retain(|key, _| key.0 != template_id);
+        }
+    }
+
+    pub(crate) fn clear(&self) {
+        if let Ok(mut entries) = self.entries.write() {
+            entries.clear();
+        }
+    }
+
+    fn next_use(&self) -> usize {
+        self.use_counter.fetch_add(1, Ordering::Relaxed)
+    }
+}
+
+impl<V> Debug for RenderCache<V> {
+    fn fmt(&self, fter: &mut fmt::Formatter) -> fmt::Result {
+        let len = self.entries.read().map(|entries| entries.len()).ok();
+        fter.debug_struct("RenderCache")
+            .field("capacity", &self.capacity)
+            .field("len", &len)
+            .finish()
+    }
+}
@@ -1,28 +1,73 @@
 use std::collections::HashMap;
 use std::path::Path;
+use std::fs;
+use std::io;
+use std::mem::replace;
+
+use vec1::Vec1;
+use serde_json::{self, Map, Value};
 
 use mail::{Resource, Context};
 use mail::file_buffer::FileBuffer;
+use headers::components::{MediaType, ContentId, Disposition, FileMeta};
 
 use template::TemplateEngine;
 use template::{
-    EmbeddedWithCId,
+    Embedded, EmbeddedWithCId,
     BodyPart, MailParts
 };
 
-use ::error::{LoadingError, InsertionError};
-use ::utils::fix_newlines;
-use ::spec::TemplateSpec;
-use ::traits::{RenderEngine, RenderEngineBase, AdditionalCIds};
+use ::error::{LoadingError, InsertionError, EncodingError};
+use ::utils::{deep_merge, insert_placeholder, has_same_essence, file_name_of_iri, gzip, stable_hash, fix_newlines, has_orphan_newlines, wrap_lines, format_flowed, is_format_flowed, encode_body};
+use ::spec::{TemplateSpec, SubTemplateSpec, TemplateSource, EmbeddingDisposition, TransferEncoding, check_unique_template_ids};
+use ::traits::{
+    RenderEngine, RenderEngineBase, AdditionalCIds,
+    MarkdownRenderer, BodyResourceFactory, SpecTransform, CssInliner, BodyPostProcessor,
+    body_resource
+};
+#[cfg(feature="fs")]
 use ::settings::LoadSpecSettings;
+use ::config::{RenderTemplateEngineConfig, EmptyBodyPolicy};
+use ::cid_rewrite::{CidReferenceRewriter, file_name_to_cid_url};
+use ::render_cache::{RenderCache, CacheKey};
+use ::gzip_cache::GzipCache;
+
+const TEXT_PLAIN: &str = "text/plain; charset=utf-8";
+const TEXT_HTML: &str = "text/html; charset=utf-8";
+const TEXT_MARKDOWN: &str = "text/markdown; charset=utf-8";
+const APPLICATION_GZIP: &str = "application/gzip";
+
+/// the maximal number of placeholders `render_preview_lenient` inserts
+const MAX_PREVIEW_PLACEHOLDERS: usize = 100;
 
 #[derive(Debug)]
 pub struct RenderTemplateEngine<R>
     where R: RenderEngineBase
 {
     fix_newlines: bool,
+    strict_newlines: bool,
+    strict_embeddings: bool,
+    empty_body_policy: EmptyBodyPolicy,
+    prune_unreferenced_embeddings: bool,
+    data_key: Option<String>,
+    deterministic_cids: bool,
+    gzip_attachments_above: Option<u64>,
+    gzip_cache: GzipCache,
+    cid_descriptors: bool,
     render_engine: R,
     id2spec: HashMap<String, TemplateSpec>,
+    markdown_renderer: Option<Box<MarkdownRenderer>>,
+    cid_rewriter: Option<Box<CidReferenceRewriter>>,
+    css_inliner: Option<Box<CssInliner>>,
+    body_post_processors: Vec<Box<BodyPostProcessor>>,
+    body_resource_factory: Option<Box<BodyResourceFactory>>,
+    spec_transform: Option<Box<SpecTransform>>,
+    globals: Map<String, Value>,
+    render_defaults: Option<Value>,
+    body_wrappers: Vec<BodyWrapper>,
+    text_line_wrap: Option<usize>,
+    render_cache: Option<RenderCache<RenderedParts>>,
+    media_types: ConstantMediaTypes,
 }
 
 
@@ -30,14 +75,78 @@ impl<R> RenderTemplateEngine<R>
     where R: RenderEngineBase
 {
 
+    /// create a new `RenderTemplateEngine` with the default config
     pub fn new(render_engine: R) -> Self {
+        RenderTemplateEngine::with_config(render_engine, Default::default())
+    }
+
+    /// create a new `RenderTemplateEngine` with the given config
+    pub fn with_config(mut render_engine: R, config: RenderTemplateEngineConfig) -> Self {
+        let RenderTemplateEngineConfig {
+            fix_newlines, strict_newlines, strict_embeddings,
+            empty_body_policy, prune_unreferenced_embeddings, data_key,
+            deterministic_cids, cid_descriptors, gzip_attachments_above,
+            text_line_wrap
+        } = config;
+
+        let data_key = data_key.filter(|key| render_engine.set_data_key(key));
+
         RenderTemplateEngine {
             render_engine,
             id2spec: Default::default(),
-            fix_newlines: !R::PRODUCES_VALID_NEWLINES,
+            fix_newlines: fix_newlines.unwrap_or(!R::PRODUCES_VALID_NEWLINES),
+            strict_newlines,
+            strict_embeddings,
+            empty_body_policy,
+            prune_unreferenced_embeddings,
+            data_key,
+            deterministic_cids,
+            gzip_attachments_above,
+            gzip_cache: GzipCache::default(),
+            cid_descriptors,
+            markdown_renderer: None,
+            cid_rewriter: None,
+            css_inliner: None,
+            body_post_processors: Vec::new(),
+            body_resource_factory: None,
+            spec_transform: None,
+            globals: Map::new(),
+            render_defaults: None,
+            body_wrappers: Vec::new(),
+            text_line_wrap,
+            render_cache: None,
+            media_types: ConstantMediaTypes::new(),
         }
     }
 
+    /// returns the current config, see `with_config`
+    pub fn config(&self) -> RenderTemplateEngineConfig {
+        RenderTemplateEngineConfig {
+            fix_newlines: Some(self.fix_newlines),
+            strict_newlines: self.strict_newlines,
+            strict_embeddings: self.strict_embeddings,
+            empty_body_policy: self.empty_body_policy,
+            prune_unreferenced_embeddings: self.prune_unreferenced_embeddings,
+            data_key: self.data_key.clone(),
+            deterministic_cids: self.deterministic_cids,
+            cid_descriptors: self.cid_descriptors,
+            gzip_attachments_above: self.gzip_attachments_above,
+            text_line_wrap: self.text_line_wrap
+        }
+    }
+
+    /// create a new `RenderTemplateEngine` with space for `capacity` specs
+    pub fn with_capacity(render_engine: R, capacity: usize) -> Self {
+        let mut rte = RenderTemplateEngine::new(render_engine);
+        rte.id2spec.reserve(capacity);
+        rte
+    }
+
+    /// reserves space for at last `additional` more specs
+    pub fn reserve(&mut self, additional: usize) {
+        self.id2spec.reserve(additional)
+    }
+
     pub fn set_fix_newlines(&mut self, should_fix_newlines: bool) {
         self.fix_newlines = should_fix_newlines
     }
@@ -46,6 +155,508 @@ impl<R> RenderTemplateEngine<R>
         self.fix_newlines
     }
 
+    /// makes rendering fail if a body contains a lone `\r` or `\n` and newlines are not fixed
+    ///
+    /// If fixing newlines is disabled (see `set_fix_newlines`) and strict
+    /// newlines are enabled each rendered (and post-processed) body is
+    /// checked and a `EncodingError::OrphanNewline` (converted with
+    /// `RenderEngineBase::encoding_error`) is returned instead of producing
+    /// a body which is invalid for mail transport. If newlines are fixed
+    /// this has no effect.
+    ///
+    /// This is disabled by default.
+    pub fn set_strict_newlines(&mut self, strict_newlines: bool) {
+        self.strict_newlines = strict_newlines
+    }
+
+    /// returns true if strict newlines are enabled, see `set_strict_newlines`
+    pub fn strict_newlines(&self) -> bool {
+        self.strict_newlines
+    }
+
+    /// sets what happens with bodies which are empty or only contain whitespace
+    ///
+    /// A blank body usually means that the data didn't match any of the
+    /// conditional parts of the template. With `EmptyBodyPolicy::Reject` an
+    /// `EncodingError::EmptyBody` (converted with `RenderEngineBase::encoding_error`)
+    /// is returned instead of producing a mail with a blank body, with
+    /// `EmptyBodyPolicy::Omit` the body is left out of the mail. Sub-templates
+    /// can opt-out through `SubTemplateSpec::set_allow_empty`.
+    ///
+    /// The default is `EmptyBodyPolicy::Keep`.
+    pub fn set_empty_body_policy(&mut self, policy: EmptyBodyPolicy) {
+        self.empty_body_policy = policy
+    }
+
+    /// returns what happens with empty bodies, see `set_empty_body_policy`
+    pub fn empty_body_policy(&self) -> EmptyBodyPolicy {
+        self.empty_body_policy
+    }
+
+    /// makes rendering fail if a sub-template embedding shadows a shared embedding
+    ///
+    /// Embeddings of a sub-template and shared embeddings (of the spec) are
+    /// accessible through the same `cids` map, if both have a embedding with
+    /// the same name the one of the sub-template is used. If enabled an
+    /// `EncodingError::ShadowedEmbedding` (converted with
+    /// `RenderEngineBase::encoding_error`) is returned instead.
+    ///
+    /// This is disabled by default.
+    pub fn set_strict_embeddings(&mut self, strict: bool) {
+        self.strict_embeddings = strict
+    }
+
+    /// returns true if shadowing shared embeddings fails, see `set_strict_embeddings`
+    pub fn strict_embeddings(&self) -> bool {
+        self.strict_embeddings
+    }
+
+    /// if true inline embeddings not referenced by any body are left out of the mail
+    ///
+    /// An embedding is referenced by a body if the body contains it's content
+    /// id (e.g. through `{{ cids.logo }}`). Embeddings of a sub-template are
+    /// only checked against the bodies of the sub-template, shared embeddings
+    /// against all bodies. Embeddings with a attachment disposition (see
+    /// `TemplateSpec::set_embedding_disposition`) are always kept.
+    ///
+    /// This is disabled by default.
+    pub fn set_prune_unreferenced_embeddings(&mut self, prune: bool) {
+        self.prune_unreferenced_embeddings = prune
+    }
+
+    /// returns true if unreferenced embeddings are pruned, see `set_prune_unreferenced_embeddings`
+    pub fn prune_unreferenced_embeddings(&self) -> bool {
+        self.prune_unreferenced_embeddings
+    }
+
+    /// if true the content ids of embeddings are the same for every render
+    ///
+    /// By default a new content id is generated through the `Context` each
+    /// time an embedding is used. If enabled the left part of the content id
+    /// is instead derived from the embeddings name and the IRI of it's source
+    /// (only the domain is taken from a content id generated by the `Context`),
+    /// so the same embedding has the same content id for every render. This
+    /// makes rendered bodies comparable, e.g. for snapshot tests or caching.
+    /// The content ids of attachments are not affected.
+    ///
+    /// Note that content ids are meant to be world-unique, so this should not
+    /// be used for mails which are actually send.
+    ///
+    /// This is disabled by default.
+    pub fn set_deterministic_content_ids(&mut self, enable: bool) {
+        self.deterministic_cids = enable
+    }
+
+    /// returns true if content ids are deterministic, see `set_deterministic_content_ids`
+    pub fn deterministic_content_ids(&self) -> bool {
+        self.deterministic_cids
+    }
+
+    /// if true descriptors of the content ids are passed to templates as `cid_info`
+    ///
+    /// The content ids are always passed as a `name => content id` map,
+    /// i.e. they are used like `{{ cids.logo }}`. If enabled each name
+    /// is additionally mapped to a descriptor in `cid_info`, i.e.
+    /// `{{ cid_info.logo.cid }}`, `{{ cid_info.logo.mime }}` and
+    /// `{{ cid_info.logo.filename }}` can be used (see `AdditionalCIds::descriptors`).
+    ///
+    /// This is disabled by default.
+    pub fn set_cid_descriptors(&mut self, enable: bool) {
+        self.cid_descriptors = enable
+    }
+
+    /// returns true if content ids are passed as descriptors, see `set_cid_descriptors`
+    pub fn cid_descriptors(&self) -> bool {
+        self.cid_descriptors
+    }
+
+    /// gzip attachments which are larger than the given size (in bytes)
+    ///
+    /// Attachments larger than `min_size` are compressed the first time the
+    /// template is used, the compressed attachments are cached until the spec
+    /// is replaced or removed. The content is taken from the attachments buffer
+    /// if it's loaded (e.g. for attachments loaded from a `MemoryFs` or fetched
+    /// from an url), else from the file it refers to (i.e. it's `path:` IRI).
+    /// They are added with the media type `application/gzip` and `.gz` appended
+    /// to their file name. Inline embeddings are never compressed.
+    ///
+    /// Note that recipients have to be able to decompress the attachments,
+    /// which is not a given for all mail clients/users.
+    ///
+    /// This is disabled (`None`) by default.
+    pub fn set_gzip_attachments_above(&mut self, min_size: Option<u64>) {
+        self.gzip_attachments_above = min_size;
+        self.gzip_cache.clear();
+    }
+
+    /// returns the size above which attachments are compressed, see `set_gzip_attachments_above`
+    pub fn gzip_attachments_above(&self) -> Option<u64> {
+        self.gzip_attachments_above
+    }
+
+    /// sets the `MarkdownRenderer` used for `text/markdown` sub-templates
+    ///
+    /// If no markdown renderer is set (the default) `text/markdown`
+    /// sub-templates are used as `text/markdown` bodies. Else they are
+    /// turned into a `text/plain` and a `text/html` body, see
+    /// `MarkdownRenderer` for more details. Rendering a spec which has
+    /// a `text/markdown` body and a `text/plain` or `text/html` body
+    /// then fails with a `ConflictingMarkdownBody` error.
+    ///
+    /// Note that `from_dir` only loads `markdown` dirs if the settings have
+    /// a type for them, e.g. with the `MARKDOWN_SETTINGS`.
+    pub fn set_markdown_renderer(&mut self, renderer: Option<Box<MarkdownRenderer>>) {
+        self.markdown_renderer = renderer
+    }
+
+    pub fn markdown_renderer(&self) -> Option<&MarkdownRenderer> {
+        self.markdown_renderer.as_ref().map(|renderer| &**renderer)
+    }
+
+    /// sets the `CidReferenceRewriter` used for `text/html` bodies
+    ///
+    /// By default no rewriter is set. If one is set it's used to rewrite
+    /// references to the files of embeddings (e.g. `<img src="logo.png">`)
+    /// to `cid:` references after rendering. This includes the html
+    /// body produced by the `MarkdownRenderer`.
+    pub fn set_cid_reference_rewriter(&mut self, rewriter: Option<Box<CidReferenceRewriter>>) {
+        self.cid_rewriter = rewriter
+    }
+
+    pub fn cid_reference_rewriter(&self) -> Option<&CidReferenceRewriter> {
+        self.cid_rewriter.as_ref().map(|rewriter| &**rewriter)
+    }
+
+    /// sets the `CssInliner` used for `text/html` bodies
+    ///
+    /// By default no css inliner is set, as inlining styles is comparatively
+    /// expensive. If one is set it's used after rendering (and rewriting cid
+    /// references) to inline the styles of the html body and of the stylesheet
+    /// embeddings (`.css` files) usable in the body, see `CssInliner`.
+    ///
+    /// Stylesheet embeddings are still added to the mail, use e.g. an embedding
+    /// filter (see `use_template_with_filter`) to leave them out.
+    pub fn set_css_inliner(&mut self, inliner: Option<Box<CssInliner>>) {
+        self.css_inliner = inliner
+    }
+
+    pub fn css_inliner(&self) -> Option<&CssInliner> {
+        self.css_inliner.as_ref().map(|inliner| &**inliner)
+    }
+
+    /// sets the `BodyResourceFactory` used to turn encoded bodies into resources
+    ///
+    /// By default (`None`) each body becomes a resource without source
+    /// wrapping a `FileBuffer` with the bodies media type, see `body_resource`.
+    pub fn set_body_resource_factory(&mut self, factory: Option<Box<BodyResourceFactory>>) {
+        self.body_resource_factory = factory
+    }
+
+    pub fn body_resource_factory(&self) -> Option<&BodyResourceFactory> {
+        self.body_resource_factory.as_ref().map(|factory| &**factory)
+    }
+
+    /// sets the `SpecTransform` applied to specs before their templates are loaded
+    ///
+    /// By default no transform is set. Specs which are already inserted
+    /// are not transformed, only specs inserted afterwards.
+    pub fn set_spec_transform(&mut self, transform: Option<Box<SpecTransform>>) {
+        self.spec_transform = transform
+    }
+
+    pub fn spec_transform(&self) -> Option<&SpecTransform> {
+        self.spec_transform.as_ref().map(|transform| &**transform)
+    }
+
+    /// sets a global value which is available when rendering any template
+    ///
+    /// Globals are passed to the render engine with every render call, the
+    /// tera and handlebars render engines make them accessible through
+    /// `globals`, e.g. `{{ globals.app_version }}`. This is useful for values
+    /// which are the same for all mails, like an unsubscribe base url.
+    ///
+    /// Returns the previous value of the global, if there was one.
+    pub fn set_global<N>(&mut self, name: N, value: Value) -> Option<Value>
+        where N: Into<String>
+    {
+        self.globals.insert(name.into(), value)
+    }
+
+    /// removes a global value, see `set_global`
+    pub fn remove_global(&mut self, name: &str) -> Option<Value> {
+        self.globals.remove(name)
+    }
+
+    /// returns all global values, see `set_global`
+    pub fn globals(&self) -> &Map<String, Value> {
+        &self.globals
+    }
+
+    /// sets default data which is merged under the data of every render
+    ///
+    /// Unlike globals the defaults are merged (deeply) with the data passed
+    /// in when rendering, with the passed in data taking precedence, e.g.
+    /// with the defaults `{"company": {"name": "ACME", "support": "help@acme.test"}}`
+    /// and the data `{"company": {"name": "ACME Ltd."}}` the template sees
+    /// `company.name` as `"ACME Ltd."` and `company.support` as `"help@acme.test"`.
+    ///
+    /// This requires the render engine to support pre-serialized data (see
+    /// `RenderEngine::preserialize`, e.g. the tera and handlebars engines),
+    /// else the defaults are ignored.
+    ///
+    /// By default (`None`) there are no defaults.
+    pub fn set_render_defaults(&mut self, defaults: Option<Value>) -> Option<Value> {
+        replace(&mut self.render_defaults, defaults)
+    }
+
+    /// returns the render defaults, see `set_render_defaults`
+    pub fn render_defaults(&self) -> Option<&Value> {
+        self.render_defaults.as_ref()
+    }
+
+    /// enables caching of rendered bodies in `use_template`
+    ///
+    /// The cache keeps the rendered bodies of at most `capacity` different
+    /// (template id, data) combinations, if it's full the least recently
+    /// used entry is removed. Calling this again replaces the cache with a
+    /// new empty one. The cache is keyed by the template id, the data
+    /// (merged over the render defaults) and globals serialized as json
+    /// and the domain of the content ids generated by the `Context`, so
+    /// it's only used if the render engine supports pre-serialized data.
+    ///
+    /// The rendered bodies contain the content ids of the embeddings, so the
+    /// cache is only used if the content ids are deterministic (see
+    /// `set_deterministic_content_ids`), i.e. if rendering again would produce
+    /// the same content ids anyway. Attachments are created on each use.
+    ///
+    /// The entries of a template are removed when it's spec is inserted or
+    /// removed (incl. through `load_templates`) and all entries are removed on
+    /// `specs_mut`. Changes to other settings or the render engine are not
+    /// tracked, use `clear_render_cache` for them.
+    pub fn enable_render_cache(&mut self, capacity: usize) {
+        self.render_cache = Some(RenderCache::new(capacity));
+    }
+
+    pub fn disable_render_cache(&mut self) {
+        self.render_cache = None;
+    }
+
+    /// returns the capacity of the render cache if it's enabled
+    pub fn render_cache_capacity(&self) -> Option<usize> {
+        self.render_cache.as_ref().map(|cache| cache.capacity())
+    }
+
+    /// removes all entries from the render cache (if it's enabled)
+    pub fn clear_render_cache(&self) {
+        if let Some(cache) = self.render_cache.as_ref() {
+            cache.clear();
+        }
+    }
+
+    /// removes the entries of the template from the render cache (if it's enabled)
+    /// and it's compressed attachments
+    fn invalidate_caches(&self, template_id: &str) {
+        if let Some(cache) = self.render_cache.as_ref() {
+            cache.remove_template(template_id);
+        }
+        self.gzip_cache.remove_template(template_id);
+    }
+
+    /// sets a template which is rendered and added before/after every body with the media type
+    ///
+    /// E.g. a legal footer can be appended to all `text/plain` and `text/html`
+    /// bodies without including it in every template. The wrapper is rendered
+    /// with the same data, globals and content ids as the body and is added
+    /// as is (e.g. an html footer is appended after the closing `</html>`)
+    /// before newlines are fixed and other post-processing is done. Only the
+    /// type and subtype of the media type are used to match bodies, bodies
+    /// generated from markdown are wrapped as `text/plain`/`text/html` bodies.
+    ///
+    /// Setting a wrapper for a media type and position which already has one
+    /// replaces it.
+    ///
+    /// # Error
+    ///
+    /// An error is returned if the media type can not be used for a body or
+    /// if loading the template into the render engine fails, in which case
+    /// a previous wrapper for the media type and position is removed.
+    pub fn set_body_wrapper(
+        &mut self,
+        media_type: MediaType,
+        position: WrapperPosition,
+        source: &str
+    ) -> Result<(), LoadingError<R::LoadingError>> {
+        let full_type = media_type.full_type().to_string().to_lowercase();
+        self.remove_body_wrapper(&full_type, position);
+
+        let position_name = match position {
+            WrapperPosition::Prepend => "prepend",
+            WrapperPosition::Append => "append"
+        };
+        let source = TemplateSource::Source {
+            id: format!("__body_wrapper__/{}/{}/mail.{}",
+                position_name, full_type.replace('/', "_"), source_suffix(&media_type)),
+            content: source.to_owned()
+        };
+        let sub_spec = SubTemplateSpec::new_with_template_source(source, media_type, HashMap::new())?;
+        let spec = TemplateSpec::new(vec1![sub_spec])?;
+        if let Err(error) = self.render_engine.load_templates(&spec) {
+            return Err(InsertionError { error, failed_new_value: spec, old_value: None }.into());
+        }
+        self.body_wrappers.push(BodyWrapper { full_type, position, spec });
+        Ok(())
+    }
+
+    /// removes the body wrapper for the media type (e.g. `"text/html"`) and position
+    ///
+    /// Returns true if there was such a wrapper.
+    pub fn remove_body_wrapper(&mut self, full_type: &str, position: WrapperPosition) -> bool {
+        let full_type = full_type.to_lowercase();
+        let idx = self.body_wrappers.iter()
+            .position(|wrapper| wrapper.full_type == full_type && wrapper.position == position);
+        if let Some(idx) = idx {
+            let wrapper = self.body_wrappers.remove(idx);
+            self.render_engine.unload_templates(&wrapper.spec);
+            true
+        } else {
+            false
+        }
+    }
+
+    fn rewrite_cid_references(
+        &self,
+        html: String,
+        sources: &[(&HashMap<String, Resource>, &HashMap<String, EmbeddedWithCId>)]
+    ) -> String {
+        if let Some(rewriter) = self.cid_rewriter.as_ref() {
+            rewriter.rewrite(html, &file_name_to_cid_url(sources))
+        } else {
+            html
+        }
+    }
+
+    /// inlines the styles of the html using the css inliner (if one is set)
+    ///
+    /// The stylesheets are the `text/css` embeddings of the sources which
+    /// are usable in the body (i.e. which passed the embedding filter), their
+    /// content is read from the resources (see `TemplateFs::resource`).
+    fn inline_css(
+        &self,
+        html: String,
+        sources: &[(&HashMap<String, Resource>, &HashMap<String, EmbeddedWithCId>)]
+    ) -> Result<String, EncodingError> {
+        let inliner =
+            match self.css_inliner.as_ref() {
+                Some(inliner) => inliner,
+                None => return Ok(html)
+            };
+
+        let mut named_stylesheets = Vec::new();
+        for &(resources, used) in sources {
+            for (name, resource) in resources.iter().filter(|&(name, _)| used.contains_key(name)) {
+                if let Some(stylesheet) = stylesheet(name, resource)? {
+                    named_stylesheets.push((name, stylesheet));
+                }
+            }
+        }
+        named_stylesheets.sort();
+
+        let stylesheets = named_stylesheets.into_iter()
+            .map(|(_, stylesheet)| stylesheet)
+            .collect::<Vec<_>>();
+        Ok(inliner.inline(html, &stylesheets))
+    }
+
+    /// wraps lines of `text/plain` bodies longer than `width` chars
+    ///
+    /// If set, after rendering (and fixing newlines) lines which are
+    /// longer than `width` are wrapped at spaces, existing line breaks
+    /// are kept. This is only applied to `text/plain` bodies (incl. the
+    /// plain text body generated from markdown), never to e.g. html.
+    ///
+    /// Bodies with a `format=flowed` media type (see `SubTemplateSpec::set_format_flowed`)
+    /// are always formatted as flowed text, if a width is set they are wrapped using
+    /// soft line breaks.
+    ///
+    /// By default no lines are wrapped. A common width is 78.
+    pub fn set_text_line_wrap(&mut self, width: Option<usize>) {
+        self.text_line_wrap = width
+    }
+
+    /// returns the width at which lines are wrapped, see `set_text_line_wrap`
+    pub fn text_line_wrap(&self) -> Option<usize> {
+        self.text_line_wrap
+    }
+
+    /// adds a processor applied to all bodies after rendering
+    ///
+    /// Bodies are processed in this order:
+    ///
+    /// 1. rendering incl. body wrappers, cid reference rewriting and css inlining
+    /// 2. the added processors in the order they where added
+    /// 3. wrapping `text/plain` bodies (see `set_text_line_wrap`)
+    /// 4. fixing newlines (see `set_fix_newlines`)
+    /// 5. checking that the body is not empty and has no orphan newlines (see
+    ///    `set_empty_body_policy` and `set_strict_newlines`)
+    ///
+    /// As newlines are fixed after the processors are applied, processors
+    /// can use `\n` for newlines they add.
+    pub fn add_body_post_processor(&mut self, processor: Box<BodyPostProcessor>) {
+        self.body_post_processors.push(processor)
+    }
+
+    /// removes all added body post-processors
+    pub fn clear_body_post_processors(&mut self) {
+        self.body_post_processors.clear()
+    }
+
+    /// the added body post-processors in the order they are applied
+    pub fn body_post_processors(&self) -> &[Box<BodyPostProcessor>] {
+        &self.body_post_processors
+    }
+
+    fn post_process_body(
+        &self,
+        media_type: &MediaType,
+        body: String,
+        allow_empty: bool
+    ) -> Result<String, EncodingError> {
+        let body = self.body_post_processors.process(media_type, body)?;
+        let body = self.wrap_text_body(media_type, body);
+        let body =
+            if self.fix_newlines {
+                fix_newlines(body)
+            } else {
+                body
+            };
+        if self.empty_body_policy == EmptyBodyPolicy::Reject && !allow_empty && body.trim().is_empty() {
+            return Err(EncodingError::EmptyBody {
+                media_type: media_type.full_type().to_string()
+            });
+        }
+        if self.strict_newlines && !self.fix_newlines && has_orphan_newlines(&body) {
+            return Err(EncodingError::OrphanNewline {
+                media_type: media_type.full_type().to_string()
+            });
+        }
+        Ok(body)
+    }
+
+    /// wraps the lines of `text/plain` bodies, see `set_text_line_wrap`
+    fn wrap_text_body(&self, media_type: &MediaType, body: String) -> String {
+        if media_type.full_type() != "text/plain" {
+            return body;
+        }
+        let newline = if self.fix_newlines { "\r\n" } else { "\n" };
+        if is_format_flowed(media_type) {
+            format_flowed(&body, self.text_line_wrap, newline)
+        } else if let Some(width) = self.text_line_wrap {
+            wrap_lines(body, width, newline)
+        } else {
+            body
+        }
+    }
+
     /// add a `TemplateSpec`, loading all templates in it
     ///
     /// If a template with the same name is contained it
@@ -65,12 +676,18 @@ impl<R> RenderTemplateEngine<R>
     /// I.e. it's guaranteed that if `insert` errors there will no
     /// longer be an template associated with the given id.
     ///
+    /// If a `SpecTransform` is set (see `set_spec_transform`) the spec is
+    /// transformed before it's templates are loaded.
     pub fn insert_spec(
         &mut self,
         id: String,
-        spec: TemplateSpec
+        mut spec: TemplateSpec
     ) -> Result<Option<TemplateSpec>, InsertionError<R::LoadingError>> {
         use std::collections::hash_map::Entry::*;
+        if let Some(transform) = self.spec_transform.as_ref() {
+            transform.transform(&id, &mut spec);
+        }
+        self.invalidate_caches(&id);
         match self.id2spec.entry(id) {
             Occupied(mut entry) => {
                 let old = entry.insert(spec);
@@ -101,11 +718,60 @@ impl<R> RenderTemplateEngine<R>
         }
     }
 
+    /// inserts a spec with a single body created from the given source string
+    ///
+    /// This is a shortcut for creating a `TemplateSpec` with one `SubTemplateSpec`
+    /// with a `TemplateSource::Source` and inserting it with `insert_spec`. The id
+    /// of the source is `<id>/mail.<suffix>` where the suffix is `html` for
+    /// `text/html`, `txt` for `text/plain` and the subtype for any other media type
+    /// (some render engines decide e.g. if html escaping is used based on it).
+    ///
+    /// # Error
+    ///
+    /// An error is returned if the media type can not be used for a body (e.g.
+    /// because it's multipart) or if inserting the spec fails, see `insert_spec`.
+    pub fn insert_spec_str(
+        &mut self,
+        id: &str,
+        media_type: MediaType,
+        source: &str
+    ) -> Result<Option<TemplateSpec>, LoadingError<R::LoadingError>> {
+        let source = TemplateSource::Source {
+            id: format!("{}/mail.{}", id, source_suffix(&media_type)),
+            content: source.to_owned()
+        };
+        let sub_spec = SubTemplateSpec::new_with_template_source(source, media_type, HashMap::new())?;
+        let spec = TemplateSpec::new(vec1![sub_spec])?;
+        Ok(self.insert_spec(id.to_owned(), spec)?)
+    }
+
+    /// inserts all given specs using `insert_spec`
+    ///
+    /// # Error
+    ///
+    /// If multiple of the given specs have the same id a `DuplicateTemplateId`
+    /// error is returned and no spec is inserted.
+    ///
+    /// If inserting a spec fails the error is returned and no further specs
+    /// are inserted, but specs inserted before are not removed.
+    pub fn insert_specs<I>(&mut self, specs: I) -> Result<(), LoadingError<R::LoadingError>>
+        where I: IntoIterator<Item=(String, TemplateSpec)>
+    {
+        let specs = specs.into_iter().collect::<Vec<_>>();
+        check_unique_template_ids(specs.iter().map(|&(ref id, ref spec)| (id.as_str(), spec.base_path())))?;
+        self.id2spec.reserve(specs.len());
+        for (id, spec) in specs {
+            self.insert_spec(id, spec)?;
+        }
+        Ok(())
+    }
+
     /// removes and unload the spec associated with the given id
     ///
     /// If no spec is associated with the given id nothing is done
     /// (and `None` is returned).
     pub fn remove_spec(&mut self, id: &str) -> Option<TemplateSpec> {
+        self.invalidate_caches(id);
         let res =  self.id2spec.remove(id);
         if let Some(spec) = res.as_ref() {
             self.render_engine.unload_templates(spec);
@@ -113,11 +779,40 @@ impl<R> RenderTemplateEngine<R>
         res
     }
 
+    /// removes and unloads all specs which ids match the predicate
+    ///
+    /// E.g. `remove_specs_matching(|id| id.starts_with("marketing."))` removes
+    /// all specs in the `marketing` namespace. The removed specs are returned
+    /// sorted by id.
+    pub fn remove_specs_matching<F>(&mut self, pred: F) -> Vec<(String, TemplateSpec)>
+        where F: Fn(&str) -> bool
+    {
+        let mut ids = self.id2spec.keys()
+            .filter(|id| pred(id))
+            .cloned()
+            .collect::<Vec<_>>();
+        ids.sort();
+
+        let mut removed = Vec::with_capacity(ids.len());
+        for id in ids {
+            if let Some(spec) = self.remove_spec(&id) {
+                removed.push((id, spec));
+            }
+        }
+        removed
+    }
+
     pub fn specs(&self) -> &HashMap<String, TemplateSpec> {
         &self.id2spec
     }
 
+    /// returns the specs for modifying them in place
+    ///
+    /// This clears the render cache (if it's enabled) and the compressed
+    /// attachments, as any spec might be changed.
     pub fn specs_mut(&mut self) -> impl Iterator<Item=(&String, &mut TemplateSpec)> {
+        self.clear_render_cache();
+        self.gzip_cache.clear();
         self.id2spec.iter_mut()
     }
 
@@ -133,18 +828,60 @@ impl<R> RenderTemplateEngine<R>
     /// them. If such an error occurs all previously added Spec are not removed,
     /// i.e. if an error happens some spec and embeddings might be added others
     /// might not.
+    #[cfg(feature="fs")]
     pub fn load_templates(
         &mut self,
         templates_dir: impl AsRef<Path>,
         settings: &LoadSpecSettings
     ) -> Result<(), LoadingError<R::LoadingError>> {
-        for (name, spec) in TemplateSpec::from_dirs(templates_dir.as_ref(), settings)? {
-            self.insert_spec(name, spec)?;
-        }
-        Ok(())
+        let specs = TemplateSpec::from_dirs(templates_dir.as_ref(), settings)?;
+        Ok(self.insert_specs(specs)?)
+    }
+
+    /// loads all templates which can be found with `TemplateSpec::from_glob`
+    ///
+    /// # Error
+    ///
+    /// The same as for `load_templates`, i.e. if an error happens some
+    /// specs might have been added while others might not.
+    #[cfg(feature="fs")]
+    pub fn load_templates_from_glob(
+        &mut self,
+        pattern: &str,
+        settings: &LoadSpecSettings
+    ) -> Result<(), LoadingError<R::LoadingError>> {
+        let specs = TemplateSpec::from_glob(pattern, settings)?;
+        Ok(self.insert_specs(specs)?)
+    }
+
+    /// registers a template (e.g. a layout) which can be used by the templates of all specs
+    ///
+    /// See `RenderEngineBase::register_base_template` and
+    /// `RenderEngineBase::supports_inheritance`, returns `None` if the render
+    /// engine doesn't support base templates.
+    pub fn register_base_template(&mut self, name: &str, source: &str)
+        -> Option<Result<(), R::LoadingError>>
+    {
+        self.render_engine.register_base_template(name, source)
+    }
+
+    /// makes sure all loaded templates are fully parsed/compiled
+    ///
+    /// This can be called after (bulk) loading specs so that the cost
+    /// for this is paid at startup instead of on the first render of
+    /// each template, see `RenderEngineBase::prewarm`.
+    pub fn prewarm(&mut self) -> Result<(), R::LoadingError> {
+        self.render_engine.prewarm()
     }
 }
 
+/// Uses the `RenderTemplateEngine` to produce `MailParts`
+///
+/// The content ids of all embeddings (body specific and shared
+/// ones) are generated through the passed in `Context`, this crate
+/// does not generate or alter content ids on it's own. So if content
+/// ids have to be e.g. under a specific domain the `Context` passed to
+/// `use_template` has to be setup to generate them that way.
 impl<C, D, R> TemplateEngine<C, D> for RenderTemplateEngine<R>
     where C: Context, R: RenderEngine<D>
 {
@@ -161,59 +898,1032 @@ impl<C, D, R> TemplateEngine<C, D> for RenderTemplateEngine<R>
         let spec = self.lookup_spec(template_id)
             .ok_or_else(|| R::unknown_template_id_error(template_id))?;
 
-        //OPTIMIZE there should be a more efficient way
-        // maybe use Rc<str> as keys? and Rc<Resource> for embeddings?
-        let shared_embeddings = spec.embeddings().iter()
-            .map(|(key, resource)| create_embedding(key, resource, ctx))
-            .collect::<HashMap<_,_>>();
+        let cached = self.render_cache.as_ref()
+            .filter(|_| self.deterministic_cids)
+            .and_then(|cache| self.render_cache_key(template_id, data, ctx).map(|key| (cache, key)));
 
-        let bodies = spec.sub_specs().try_mapped_ref(|sub_spec| {
+        let parts =
+            if let Some((cache, key)) = cached {
+                if let Some(parts) = cache.get(&key) {
+                    parts
+                } else {
+                    let parts = self.render_parts(spec, None, data, ctx)?;
+                    cache.insert(key, parts.clone());
+                    parts
+                }
+            } else {
+                self.render_parts(spec, None, data, ctx)?
+            };
+        self.create_mail_parts(template_id, spec, parts, ctx).map_err(R::encoding_error)
+    }
+}
 
-            let embeddings = sub_spec.embeddings().iter()
-                .map(|(key, resource)| create_embedding(key, resource, ctx))
-                .collect::<HashMap<_,_>>();
+/// where a body wrapper is added, see `RenderTemplateEngine::set_body_wrapper`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum WrapperPosition {
+    /// before the body
+    Prepend,
+    /// after the body
+    Append
+}
 
-            let rendered = {
-                let embeddings = &[&embeddings, &shared_embeddings];
-                let additional_cids = AdditionalCIds::new(embeddings);
-                self.render_engine.render(sub_spec, data, additional_cids)?
-            };
+/// the media types of the constants, parsed once when creating the engine
+#[derive(Debug)]
+struct ConstantMediaTypes {
+    text_plain: MediaType,
+    text_html: MediaType,
+    text_markdown: MediaType,
+    application_gzip: MediaType
+}
 
-            let rendered =
-                if self.fix_newlines {
-                    fix_newlines(rendered)
-                } else {
-                    rendered
-                };
+impl ConstantMediaTypes {
+    fn new() -> Self {
+        ConstantMediaTypes {
+            text_plain: constant_media_type(TEXT_PLAIN),
+            text_html: constant_media_type(TEXT_HTML),
+            text_markdown: constant_media_type(TEXT_MARKDOWN),
+            application_gzip: constant_media_type(APPLICATION_GZIP)
+        }
+    }
+}
 
-            let buffer = FileBuffer::new(sub_spec.media_type().clone(), rendered.into());
-            let resource = Resource::sourceless_from_buffer(buffer);
+/// a template rendered before/after all bodies with a given media type
+#[derive(Debug)]
+struct BodyWrapper {
+    full_type: String,
+    position: WrapperPosition,
+    spec: TemplateSpec
+}
 
-            Ok(BodyPart {
-                resource: resource,
-                embeddings: embeddings.into_iter().map(|(_,v)| v).collect()
-            })
-        })?;
+/// a rendered (and post-processed) body with the (named) embeddings specific to it
+#[derive(Debug, Clone)]
+pub(crate) struct RenderedBody {
+    pub(crate) media_type: MediaType,
+    pub(crate) body: String,
+    pub(crate) embeddings: Vec<(String, EmbeddedWithCId)>,
+    pub(crate) transfer_encoding: Option<TransferEncoding>,
+}
 
-        let attachments = spec.attachments().iter()
-            .map(|resource| EmbeddedWithCId::attachment(resource.clone(), ctx))
-            .collect();
+/// all rendered bodies of a template with the (named) embeddings used by them
+#[derive(Debug, Clone)]
+pub(crate) struct RenderedParts {
+    pub(crate) bodies: Vec1<RenderedBody>,
+    pub(crate) shared_embeddings: Vec<(String, EmbeddedWithCId)>,
+}
 
-        Ok(MailParts {
-            alternative_bodies: bodies,
-            //TODO collpas embeddings and attachments and use their disposition parma
-            // instead
-            shared_embeddings: shared_embeddings.into_iter().map(|(_, v)| v).collect(),
-            attachments,
-        })
+/// Diagnostic information about a call to `use_template_with_report`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RenderReport {
+    /// names of embeddings which where not referenced by any body
+    ///
+    /// A shared embedding is unused if no body contains it's content id
+    /// and a body specific embedding is unused if the body it's specific
+    /// to doesn't contain it's content id. The names are sorted.
+    pub unused_embeddings: Vec<String>,
+}
+
+impl RenderReport {
+
+    fn for_parts(parts: &RenderedParts) -> Self {
+        let mut unused_embeddings = Vec::new();
+        for body in parts.bodies.iter() {
+            for &(ref name, ref embedding) in body.embeddings.iter() {
+                if !body.body.contains(embedding.content_id().as_str()) {
+                    unused_embeddings.push(name.clone());
+                }
+            }
+        }
+        for &(ref name, ref embedding) in parts.shared_embeddings.iter() {
+            let cid = embedding.content_id().as_str();
+            if !parts.bodies.iter().any(|body| body.body.contains(cid)) {
+                unused_embeddings.push(name.clone());
+            }
+        }
+        unused_embeddings.sort();
+        RenderReport { unused_embeddings }
     }
 }
 
-fn create_embedding(
+impl<R> RenderTemplateEngine<R>
+    where R: RenderEngineBase
+{
+    /// renders all bodies of the spec creating the embeddings (incl. content ids) used by them
+    ///
+    /// If a `variant` is given it's used for all sub-specs which have a variant with that key.
+    pub(crate) fn render_parts<C, D>(
+        &self,
+        spec: &TemplateSpec,
+        variant: Option<&str>,
+        data: &D,
+        ctx: &C
+    ) -> Result<RenderedParts, <R as RenderEngineBase>::RenderError>
+        where C: Context, R: RenderEngine<D>
+    {
+        let shared_embeddings = self.create_shared_embeddings(spec, None, ctx);
+        self.render_parts_with_shared(spec, variant, data, ctx, None, shared_embeddings)
+    }
+
+    /// creates the (shared) template level embeddings of the spec
+    ///
+    /// If a filter is given only embeddings which names pass it are created.
+    fn create_shared_embeddings<C>(
+        &self,
+        spec: &TemplateSpec,
+        embedding_filter: Option<&Fn(&str) -> bool>,
+        ctx: &C
+    ) -> HashMap<String, EmbeddedWithCId>
+        where C: Context
+    {
+        //OPTIMIZE there should be a more efficient way
+        // maybe use Rc<str> as keys? and Rc<Resource> for embeddings?
+        spec.embeddings().iter()
+            .filter(|&(key, _)| embedding_filter.map(|filter| filter(key)).unwrap_or(true))
+            .map(|(key, resource)| {
+                create_embedding(key, resource, spec.embedding_disposition(key), self.deterministic_cids, ctx)
+            })
+            .collect()
+    }
+
+    /// like `render_parts` but uses the given shared embeddings
+    ///
+    /// If a filter is given only sub-template embeddings which names pass it are created.
+    /// checks that markdown bodies don't conflict with the bodies they are expanded into
+    ///
+    /// If a `MarkdownRenderer` is set a spec with a `text/markdown` body can't also
+    /// have a `text/plain` or `text/html` body, as the mail would have two of them.
+    fn check_markdown_bodies(&self, spec: &TemplateSpec) -> Result<(), EncodingError> {
+        if self.markdown_renderer.is_none() || !spec.has_media_type(&self.media_types.text_markdown) {
+            return Ok(());
+        }
+        let conflicting = spec.sub_specs().iter()
+            .map(|sub_spec| sub_spec.media_type())
+            .find(|media_type| {
+                has_same_essence(media_type, &self.media_types.text_plain)
+                    || has_same_essence(media_type, &self.media_types.text_html)
+            });
+        match conflicting {
+            Some(media_type) => Err(EncodingError::ConflictingMarkdownBody {
+                media_type: media_type.full_type().to_string()
+            }),
+            None => Ok(())
+        }
+    }
+
+    fn render_parts_with_shared<C, D>(
+        &self,
+        spec: &TemplateSpec,
+        variant: Option<&str>,
+        data: &D,
+        ctx: &C,
+        embedding_filter: Option<&Fn(&str) -> bool>,
+        shared_embeddings: HashMap<String, EmbeddedWithCId>
+    ) -> Result<RenderedParts, <R as RenderEngineBase>::RenderError>
+        where C: Context, R: RenderEngine<D>
+    {
+        self.check_markdown_bodies(spec).map_err(R::encoding_error)?;
+
+        // serialize the data only once for all bodies, if the engine supports it
+        let preserialized = self.preserialize(data);
+
+        let mut bodies = Vec::with_capacity(spec.sub_specs().len());
+        for sub_spec in spec.sub_specs() {
+
+            if self.strict_embeddings {
+                let shadowed = sub_spec.embeddings().keys()
+                    .find(|name| spec.embeddings().contains_key(*name));
+                if let Some(name) = shadowed {
+                    let name = name.clone();
+                    return Err(R::encoding_error(EncodingError::ShadowedEmbedding { name }));
+                }
+            }
+
+            let embeddings = sub_spec.embeddings().iter()
+                .filter(|&(key, _)| embedding_filter.map(|filter| filter(key)).unwrap_or(true))
+                .map(|(key, resource)| {
+                    create_embedding(key, resource, sub_spec.embedding_disposition(key), self.deterministic_cids, ctx)
+                })
+                .collect::<HashMap<_,_>>();
+
+            let embedding_maps = &[&embeddings, &shared_embeddings];
+            let additional_cids =
+                if self.cid_descriptors {
+                    AdditionalCIds::new(embedding_maps).with_descriptors()
+                } else {
+                    AdditionalCIds::new(embedding_maps)
+                };
+            let rendered = {
+                //OPTIMIZE this clones the embeddings of the sub-spec
+                let variant_spec = variant.and_then(|key| sub_spec.for_variant(key));
+                let to_render = variant_spec.as_ref().unwrap_or(sub_spec);
+                self.render_body(to_render, preserialized.as_ref(), data, additional_cids)?
+            };
+            let wrap = |full_type: &str, body: String| {
+                self.wrap_body(full_type, body, preserialized.as_ref(), data, additional_cids)
+            };
+
+            let cid_sources = &[
+                (sub_spec.embeddings(), &embeddings),
+                (spec.embeddings(), &shared_embeddings)
+            ];
+
+            let markdown_renderer = self.markdown_renderer.as_ref()
+                .filter(|_| has_same_essence(sub_spec.media_type(), &self.media_types.text_markdown));
+
+            if let Some(markdown_renderer) = markdown_renderer {
+                let text = wrap("text/plain", markdown_renderer.render_plain_text(&rendered))?;
+                let html = wrap("text/html", markdown_renderer.render_html(&rendered))?;
+                let html = self.rewrite_cid_references(html, cid_sources);
+                let html = self.inline_css(html, cid_sources).map_err(R::encoding_error)?;
+                let text_plain = self.media_types.text_plain.clone();
+                let text = self.post_process_body(&text_plain, text, sub_spec.allows_empty())
+                    .map_err(R::encoding_error)?;
+                let text_html = self.media_types.text_html.clone();
+                let html = self.post_process_body(&text_html, html, sub_spec.allows_empty())
+                    .map_err(R::encoding_error)?;
+                let embeddings = self.prune_unreferenced(
+                    embeddings, &[&text, &html], |name| sub_spec.embedding_disposition(name));
+                if !self.omits_body(&text, sub_spec.allows_empty()) {
+                    bodies.push(RenderedBody {
+                        media_type: text_plain,
+                        body: text,
+                        embeddings: Vec::new(),
+                        transfer_encoding: sub_spec.transfer_encoding()
+                    });
+                }
+                if !self.omits_body(&html, sub_spec.allows_empty()) {
+                    bodies.push(RenderedBody {
+                        media_type: text_html,
+                        body: html,
+                        embeddings: sorted_by_name(embeddings),
+                        transfer_encoding: sub_spec.transfer_encoding()
+                    });
+                }
+            } else {
+                let full_type = sub_spec.media_type().full_type().to_string().to_lowercase();
+                let rendered = wrap(&full_type, rendered)?;
+                let rendered =
+                    if full_type == "text/html" {
+                        let rendered = self.rewrite_cid_references(rendered, cid_sources);
+                        self.inline_css(rendered, cid_sources).map_err(R::encoding_error)?
+                    } else {
+                        rendered
+                    };
+                let body = self.post_process_body(sub_spec.media_type(), rendered, sub_spec.allows_empty())
+                    .map_err(R::encoding_error)?;
+                let embeddings = self.prune_unreferenced(
+                    embeddings, &[&body], |name| sub_spec.embedding_disposition(name));
+                if !self.omits_body(&body, sub_spec.allows_empty()) {
+                    bodies.push(RenderedBody {
+                        media_type: sub_spec.media_type().clone(),
+                        body,
+                        embeddings: sorted_by_name(embeddings),
+                        transfer_encoding: sub_spec.transfer_encoding()
+                    });
+                }
+            }
+        }
+
+        let shared_embeddings = {
+            let rendered = bodies.iter().map(|body| body.body.as_str()).collect::<Vec<_>>();
+            self.prune_unreferenced(shared_embeddings, &rendered, |name| spec.embedding_disposition(name))
+        };
+
+        // there is at last one body for each sub-spec and at last one sub-spec,
+        // so there are only no bodies if all of them where omitted
+        let bodies = Vec1::from_vec(bodies)
+            .map_err(|_| R::encoding_error(EncodingError::EmptyBody {
+                media_type: spec.sub_specs()[0].media_type().full_type().to_string()
+            }))?;
+
+        Ok(RenderedParts {
+            bodies,
+            shared_embeddings: sorted_by_name(shared_embeddings)
+        })
+    }
+
+    /// true if the body is left out of the mail, see `EmptyBodyPolicy::Omit`
+    fn omits_body(&self, body: &str, allow_empty: bool) -> bool {
+        self.empty_body_policy == EmptyBodyPolicy::Omit && !allow_empty && body.trim().is_empty()
+    }
+
+    /// removes inline embeddings not referenced by any of the bodies, see `set_prune_unreferenced_embeddings`
+    fn prune_unreferenced<F>(
+        &self,
+        embeddings: HashMap<String, EmbeddedWithCId>,
+        bodies: &[&str],
+        disposition: F
+    ) -> HashMap<String, EmbeddedWithCId>
+        where F: Fn(&str) -> EmbeddingDisposition
+    {
+        if !self.prune_unreferenced_embeddings {
+            return embeddings;
+        }
+        embeddings.into_iter()
+            .filter(|&(ref name, ref embedding)| {
+                disposition(name) == EmbeddingDisposition::Attachment
+                    || bodies.iter().any(|body| body.contains(embedding.content_id().as_str()))
+            })
+            .collect()
+    }
+
+    /// adds the rendered prepend/append body wrappers for the media type to the body
+    fn wrap_body<D>(
+        &self,
+        full_type: &str,
+        body: String,
+        preserialized: Option<&Value>,
+        data: &D,
+        additional_cids: AdditionalCIds
+    ) -> Result<String, <R as RenderEngineBase>::RenderError>
+        where R: RenderEngine<D>
+    {
+        let mut prepend = String::new();
+        let mut append = String::new();
+        for wrapper in self.body_wrappers.iter().filter(|wrapper| wrapper.full_type == full_type) {
+            let sub_spec = &wrapper.spec.sub_specs()[0];
+            let rendered = self.render_body(sub_spec, preserialized, data, additional_cids)?;
+            match wrapper.position {
+                WrapperPosition::Prepend => prepend.push_str(&rendered),
+                WrapperPosition::Append => append.push_str(&rendered)
+            }
+        }
+        if prepend.is_empty() && append.is_empty() {
+            Ok(body)
+        } else {
+            prepend.push_str(&body);
+            prepend.push_str(&append);
+            Ok(prepend)
+        }
+    }
+
+    /// the key of the render cache for using the template with the data
+    ///
+    /// It contains the pre-serialized data (incl. the render defaults), the
+    /// globals and the content id domain of the context (which is part of the
+    /// deterministic content ids). As json objects are maps sorted by key the
+    /// serialization of equal data is equal. If the render engine does not support
+    /// pre-serialized data `None` is returned, i.e. the render cache is not used.
+    fn render_cache_key<D>(&self, template_id: &str, data: &D, ctx: &impl Context) -> Option<CacheKey>
+        where R: RenderEngine<D>
+    {
+        let data = self.preserialize(data)?;
+        let input = Value::Array(vec![
+            data,
+            Value::Object(self.globals.clone()),
+            Value::String(content_id_domain(ctx))
+        ]);
+        let serialized = serde_json::to_vec(&input).ok()?;
+        Some((template_id.to_owned(), serialized))
+    }
+
+    /// pre-serializes the data (if the engine supports it) merging it over the render defaults
+    fn preserialize<D>(&self, data: &D) -> Option<Value>
+        where R: RenderEngine<D>
+    {
+        let preserialized = self.render_engine.preserialize(data);
+        match (self.render_defaults.as_ref(), preserialized) {
+            (Some(defaults), Some(data)) => Some(deep_merge(defaults.clone(), data)),
+            (Some(_), None) => {
+                warn!("render defaults are ignored as the render engine does not support pre-serialized data");
+                None
+            },
+            (None, preserialized) => preserialized
+        }
+    }
+
+    /// pre-serializes the data merging it over the render defaults of the sub-spec and the engine
+    fn preserialize_with_sub_spec_defaults<D>(&self, sub_spec_defaults: &Value, data: &D) -> Option<Value>
+        where R: RenderEngine<D>
+    {
+        let data = self.render_engine.preserialize(data)?;
+        let defaults =
+            match self.render_defaults.as_ref() {
+                Some(defaults) => deep_merge(defaults.clone(), sub_spec_defaults.clone()),
+                None => sub_spec_defaults.clone()
+            };
+        Some(deep_merge(defaults, data))
+    }
+
+    /// renders the body using the pre-serialized data if there is some and the engine supports it
+    ///
+    /// If the sub-spec has render defaults the data is pre-serialized again
+    /// to merge it over them.
+    fn render_body<D>(
+        &self,
+        sub_spec: &SubTemplateSpec,
+        preserialized: Option<&Value>,
+        data: &D,
+        additional_cids: AdditionalCIds
+    ) -> Result<String, <R as RenderEngineBase>::RenderError>
+        where R: RenderEngine<D>
+    {
+        let with_sub_spec_defaults;
+        let preserialized =
+            match sub_spec.render_defaults() {
+                Some(defaults) => {
+                    with_sub_spec_defaults = self.preserialize_with_sub_spec_defaults(defaults, data);
+                    if with_sub_spec_defaults.is_none() {
+                        warn!("render defaults of the sub-template are ignored as the render engine does not support pre-serialized data");
+                    }
+                    with_sub_spec_defaults.as_ref()
+                },
+                None => preserialized
+            };
+        if let Some(preserialized) = preserialized {
+            let res = self.render_engine
+                .render_preserialized(sub_spec, preserialized, additional_cids, &self.globals);
+            if let Some(res) = res {
+                return res;
+            }
+        }
+        self.render_engine
+            .render_with_globals(sub_spec, data, additional_cids, &self.globals)
+    }
+
+    /// renders the template for each of the data items
+    ///
+    /// This is like calling `use_template` for each item, but the spec is
+    /// only looked up once and the (shared) template level embeddings are
+    /// only created once and reused for all items, i.e. they have the same
+    /// content ids in all returned `MailParts`. Embeddings specific to a
+    /// sub-template are created for each item.
+    ///
+    /// # Error
+    ///
+    /// If rendering fails for any item the error is returned and the parts
+    /// rendered for the items before it are discarded.
+    pub fn use_template_batch<C, D>(
+        &self,
+        template_id: &str,
+        data: &[D],
+        ctx: &C
+    ) -> Result<Vec<MailParts>, <R as RenderEngineBase>::RenderError>
+        where C: Context, R: RenderEngine<D>
+    {
+        let spec = self.lookup_spec(template_id)
+            .ok_or_else(|| R::unknown_template_id_error(template_id))?;
+
+        let shared_embeddings = self.create_shared_embeddings(spec, None, ctx);
+        let mut all_parts = Vec::with_capacity(data.len());
+        for item in data {
+            let parts = self.render_parts_with_shared(spec, None, item, ctx, None, shared_embeddings.clone())?;
+            all_parts.push(self.create_mail_parts(template_id, spec, parts, ctx).map_err(R::encoding_error)?);
+        }
+        Ok(all_parts)
+    }
+
+    /// returns the data the templates of the spec are rendered with as json, without rendering
+    ///
+    /// This is the data (merged over the render defaults), the content ids
+    /// and the globals structured the way the render engine passes them to
+    /// the templates, e.g. to debug why `{{ cids.logo }}` renders nothing.
+    /// As each body is rendered with the content ids of it's own and of the
+    /// shared embeddings, the returned content ids include the embeddings of
+    /// all bodies. They are generated through `ctx`, so they differ from the
+    /// ones of an actual render unless deterministic content ids are used.
+    ///
+    /// # Error
+    ///
+    /// An error is returned if there is no template with the given id or if
+    /// the render engine doesn't support this (see `RenderEngineBase::render_context`)
+    /// or doesn't pre-serialize the data, in which case it's a `RenderContextNotSupported`
+    /// encoding error.
+    pub fn debug_render_context<C, D>(
+        &self,
+        template_id: &str,
+        data: &D,
+        ctx: &C
+    ) -> Result<Value, <R as RenderEngineBase>::RenderError>
+        where C: Context, R: RenderEngine<D>
+    {
+        let spec = self.lookup_spec(template_id)
+            .ok_or_else(|| R::unknown_template_id_error(template_id))?;
+
+        let not_supported = || R::encoding_error(EncodingError::RenderContextNotSupported);
+        let data = self.preserialize(data).ok_or_else(&not_supported)?;
+
+        let mut embeddings = self.create_shared_embeddings(spec, None, ctx);
+        for sub_spec in spec.sub_specs() {
+            for (key, resource) in sub_spec.embeddings() {
+                let (key, embedding) = create_embedding(
+                    key, resource, sub_spec.embedding_disposition(key), self.deterministic_cids, ctx);
+                embeddings.insert(key, embedding);
+            }
+        }
+
+        let embedding_maps = &[&embeddings];
+        let additional_cids =
+            if self.cid_descriptors {
+                AdditionalCIds::new(embedding_maps).with_descriptors()
+            } else {
+                AdditionalCIds::new(embedding_maps)
+            };
+        self.render_engine.render_context(&data, additional_cids, &self.globals)
+            .ok_or_else(not_supported)
+    }
+
+    /// renders all bodies of the template returning them with their media type
+    ///
+    /// The bodies are rendered and post-processed exactly like with
+    /// `use_template` (incl. content ids generated through `ctx`), but
+    /// they are neither encoded nor turned into `MailParts`. This is
+    /// meant for e.g. showing the alternative bodies side by side in a
+    /// preview tool.
+    pub fn render_all_bodies<C, D>(
+        &self,
+        template_id: &str,
+        data: &D,
+        ctx: &C
+    ) -> Result<Vec<(MediaType, String)>, <R as RenderEngineBase>::RenderError>
+        where C: Context, R: RenderEngine<D>
+    {
+        let spec = self.lookup_spec(template_id)
+            .ok_or_else(|| R::unknown_template_id_error(template_id))?;
+
+        let parts = self.render_parts(spec, None, data, ctx)?;
+        Ok(parts.bodies.into_vec().into_iter()
+            .map(|body| (body.media_type, body.body))
+            .collect())
+    }
+
+    /// renders all templates with the sample data reporting which ones fail
+    ///
+    /// This is meant for periodic health checks, e.g. to find templates which
+    /// broke due to a changed configuration of the render engine. The bodies
+    /// are rendered like with `render_all_bodies`, but neither encoded nor
+    /// turned into `MailParts`. The results are sorted by template id.
+    pub fn health_check<C, D>(
+        &self,
+        sample: &D,
+        ctx: &C
+    ) -> Vec<(String, Result<(), <R as RenderEngineBase>::RenderError>)>
+        where C: Context, R: RenderEngine<D>
+    {
+        let mut results = self.id2spec.iter()
+            .map(|(id, spec)| {
+                let res = self.render_parts(spec, None, sample, ctx).map(|_| ());
+                (id.clone(), res)
+            })
+            .collect::<Vec<_>>();
+        results.sort_by(|a, b| a.0.cmp(&b.0));
+        results
+    }
+
+    /// renders the body with the given media type returning it's encoded bytes
+    ///
+    /// The bytes are exactly what `use_template` would put into the bodies
+    /// `FileBuffer`, i.e. the body is post-processed (e.g. newlines are fixed)
+    /// and encoded using the charset of it's media type. This is useful to
+    /// e.g. write a body to disk or compare it against a golden file.
+    ///
+    /// Only the type and subtype of the media type are compared (e.g. `text/html`
+    /// selects the html body independent of it's charset). As bodies can depend
+    /// on each other (e.g. markdown bodies are expanded into a text and html body)
+    /// all bodies are rendered. Returns `None` if there is no body with the media type.
+    pub fn render_body_bytes<C, D>(
+        &self,
+        template_id: &str,
+        media_type: &MediaType,
+        data: &D,
+        ctx: &C
+    ) -> Result<Option<Vec<u8>>, <R as RenderEngineBase>::RenderError>
+        where C: Context, R: RenderEngine<D>
+    {
+        let spec = self.lookup_spec(template_id)
+            .ok_or_else(|| R::unknown_template_id_error(template_id))?;
+
+        let parts = self.render_parts(spec, None, data, ctx)?;
+        let body = parts.bodies.into_vec().into_iter()
+            .find(|body| has_same_essence(&body.media_type, media_type));
+
+        match body {
+            Some(RenderedBody { media_type, body, .. }) =>
+                encode_body(&media_type, body).map(Some).map_err(R::encoding_error),
+            None => Ok(None)
+        }
+    }
+
+    /// like `use_template` but also renders the header templates of the spec
+    ///
+    /// Returns the header names with their rendered values in the order
+    /// the header templates where added (see `TemplateSpec::insert_header_template`).
+    /// Leading and trailing whitespace is removed from the rendered values
+    /// and line breaks (incl. surrounding whitespace) are replaced with a
+    /// single space, as header values can not contain line breaks.
+    pub fn use_template_with_headers<C, D>(
+        &self,
+        template_id: &str,
+        data: &D,
+        ctx: &C
+    ) -> Result<(MailParts, Vec<(String, String)>), <R as RenderEngineBase>::RenderError>
+        where C: Context, R: RenderEngine<D>
+    {
+        let spec = self.lookup_spec(template_id)
+            .ok_or_else(|| R::unknown_template_id_error(template_id))?;
+
+        let parts = self.render_parts(spec, None, data, ctx)?;
+        let preserialized = self.preserialize(data);
+        let mut headers = Vec::new();
+        for (name, sub_spec) in spec.header_sub_specs() {
+            let value = self.render_body(sub_spec, preserialized.as_ref(), data, AdditionalCIds::new(&[]))?;
+            headers.push((name.to_owned(), single_line_header_value(&value)));
+        }
+        let parts = self.create_mail_parts(template_id, spec, parts, ctx).map_err(R::encoding_error)?;
+        Ok((parts, headers))
+    }
+
+    /// like `use_template` but also returns a `RenderReport`
+    ///
+    /// The report can be used to e.g. find embeddings which are not used
+    /// by any body but are still added to the mail.
+    pub fn use_template_with_report<C, D>(
+        &self,
+        template_id: &str,
+        data: &D,
+        ctx: &C
+    ) -> Result<(MailParts, RenderReport), <R as RenderEngineBase>::RenderError>
+        where C: Context, R: RenderEngine<D>
+    {
+        let spec = self.lookup_spec(template_id)
+            .ok_or_else(|| R::unknown_template_id_error(template_id))?;
+
+        let parts = self.render_parts(spec, None, data, ctx)?;
+        let report = RenderReport::for_parts(&parts);
+        let parts = self.create_mail_parts(template_id, spec, parts, ctx).map_err(R::encoding_error)?;
+        Ok((parts, report))
+    }
+
+    /// renders the template using it's sample data
+    ///
+    /// The sample data (see `TemplateSpec::sample_data`) is passed to the
+    /// render engine like the data passed to `use_template`. If the spec
+    /// has no sample data an empty json object is used.
+    ///
+    /// This makes it easy to e.g. provide a preview endpoint for template
+    /// designers.
+    pub fn render_preview<C>(
+        &self,
+        template_id: &str,
+        ctx: &C
+    ) -> Result<MailParts, <R as RenderEngineBase>::RenderError>
+        where C: Context, R: RenderEngine<Value>
+    {
+        let spec = self.lookup_spec(template_id)
+            .ok_or_else(|| R::unknown_template_id_error(template_id))?;
+
+        let no_data = Value::Object(Map::new());
+        let data = spec.sample_data().unwrap_or(&no_data);
+        let parts = self.render_parts(spec, None, data, ctx)?;
+        self.create_mail_parts(template_id, spec, parts, ctx).map_err(R::encoding_error)
+    }
+
+    /// like `render_preview` but renders undefined variables as placeholders
+    ///
+    /// If rendering fails because a variable is not defined (e.g. `user.name`
+    /// is missing in the sample data) a `"[user.name]"` placeholder is added
+    /// to the data and rendering is retried, so designers can preview
+    /// templates with incomplete sample data. This only works if the render
+    /// engine can tell which variable was undefined (see
+    /// `RenderEngineBase::undefined_variable`), which is the case for the
+    /// tera and handlebars (in strict mode) engines. All other errors are
+    /// returned as is.
+    ///
+    /// This is meant for previews only, normal renders (e.g. `use_template`)
+    /// are not affected and still fail on undefined variables.
+    pub fn render_preview_lenient<C>(
+        &self,
+        template_id: &str,
+        ctx: &C
+    ) -> Result<MailParts, <R as RenderEngineBase>::RenderError>
+        where C: Context, R: RenderEngine<Value>
+    {
+        let spec = self.lookup_spec(template_id)
+            .ok_or_else(|| R::unknown_template_id_error(template_id))?;
+
+        let mut data = spec.sample_data().cloned()
+            .unwrap_or_else(|| Value::Object(Map::new()));
+
+        let mut placeholders = 0;
+        let parts = loop {
+            match self.render_parts(spec, None, &data, ctx) {
+                Ok(parts) => break parts,
+                Err(err) => {
+                    if placeholders >= MAX_PREVIEW_PLACEHOLDERS {
+                        return Err(err);
+                    }
+                    let path = self.render_engine.undefined_variable(&err);
+                    match path {
+                        Some(ref path) if insert_placeholder(&mut data, path) => placeholders += 1,
+                        _ => return Err(err)
+                    }
+                }
+            }
+        };
+        self.create_mail_parts(template_id, spec, parts, ctx).map_err(R::encoding_error)
+    }
+
+    /// like `use_template` but only includes the embeddings which names pass the filter
+    ///
+    /// Embeddings (template level and sub-template specific ones) for which
+    /// the filter returns false are not added to the mail and are not
+    /// available in the templates, e.g. to leave out a seasonal banner for
+    /// some sends without changing the spec. Depending on the render engine
+    /// (and it's settings) referencing a left out embedding in a template
+    /// either renders nothing or fails.
+    pub fn use_template_with_filter<C, D, F>(
+        &self,
+        template_id: &str,
+        data: &D,
+        ctx: &C,
+        embedding_filter: F
+    ) -> Result<MailParts, <R as RenderEngineBase>::RenderError>
+        where C: Context, R: RenderEngine<D>, F: Fn(&str) -> bool
+    {
+        let spec = self.lookup_spec(template_id)
+            .ok_or_else(|| R::unknown_template_id_error(template_id))?;
+
+        let shared_embeddings = self.create_shared_embeddings(spec, Some(&embedding_filter), ctx);
+        let parts = self.render_parts_with_shared(
+            spec, None, data, ctx, Some(&embedding_filter), shared_embeddings)?;
+        self.create_mail_parts(template_id, spec, parts, ctx).map_err(R::encoding_error)
+    }
+
+    /// like `use_template` but renders the variant with the given key
+    ///
+    /// Sub-templates which have a variant with the key (see
+    /// `SubTemplateSpec::insert_variant`) are rendered using the
+    /// variants source, all other sub-templates are rendered normally.
+    pub fn use_template_variant<C, D>(
+        &self,
+        template_id: &str,
+        variant: &str,
+        data: &D,
+        ctx: &C
+    ) -> Result<MailParts, <R as RenderEngineBase>::RenderError>
+        where C: Context, R: RenderEngine<D>
+    {
+        let spec = self.lookup_spec(template_id)
+            .ok_or_else(|| R::unknown_template_id_error(template_id))?;
+
+        let parts = self.render_parts(spec, Some(variant), data, ctx)?;
+        self.create_mail_parts(template_id, spec, parts, ctx).map_err(R::encoding_error)
+    }
+
+    /// like `use_template` but only includes the attachments which names pass the filter
+    ///
+    /// The name of an attachment is it's file name, i.e. the name it's
+    /// attached with (see `TemplateSpec::set_attachment_name`) or else the
+    /// file name of the file it's loaded from. Attachments without a name
+    /// are always included. This allows e.g. attaching an invoice only if
+    /// the data has one, without having a spec with and one without it.
+    pub fn use_template_with_attachment_filter<C, D, F>(
+        &self,
+        template_id: &str,
+        data: &D,
+        ctx: &C,
+        attachment_filter: F
+    ) -> Result<MailParts, <R as RenderEngineBase>::RenderError>
+        where C: Context, R: RenderEngine<D>, F: Fn(&str) -> bool
+    {
+        let spec = self.lookup_spec(template_id)
+            .ok_or_else(|| R::unknown_template_id_error(template_id))?;
+
+        let parts = self.render_parts(spec, None, data, ctx)?;
+        self.create_mail_parts_with_filter(template_id, spec, parts, ctx, Some(&attachment_filter))
+            .map_err(R::encoding_error)
+    }
+
+    /// creates the `MailParts` from the rendered parts adding the attachments of the spec
+    ///
+    /// The bodies are encoded using the charset of their media type and
+    /// turned into resources using the `BodyResourceFactory` (if set).
+    pub(crate) fn create_mail_parts(
+        &self,
+        template_id: &str,
+        spec: &TemplateSpec,
+        parts: RenderedParts,
+        ctx: &impl Context
+    ) -> Result<MailParts, EncodingError> {
+        self.create_mail_parts_with_filter(template_id, spec, parts, ctx, None)
+    }
+
+    /// like `create_mail_parts` but only adds the attachments which names pass the filter (if given)
+    fn create_mail_parts_with_filter(
+        &self,
+        template_id: &str,
+        spec: &TemplateSpec,
+        parts: RenderedParts,
+        ctx: &impl Context,
+        attachment_filter: Option<&Fn(&str) -> bool>
+    ) -> Result<MailParts, EncodingError> {
+        let RenderedParts { bodies, shared_embeddings } = parts;
+
+        let mut body_parts = Vec::with_capacity(bodies.len());
+        for RenderedBody { media_type, body, embeddings, transfer_encoding } in bodies.into_vec() {
+            let body = encode_body(&media_type, body)?;
+            let resource =
+                if let Some(factory) = self.body_resource_factory.as_ref() {
+                    factory.create_resource_with_transfer_encoding(media_type, body, transfer_encoding)
+                } else {
+                    body_resource(media_type, body, transfer_encoding)
+                };
+            body_parts.push(BodyPart {
+                resource,
+                embeddings: embeddings.into_iter().map(|(_, v)| v).collect()
+            });
+        }
+        let bodies = Vec1::from_vec(body_parts)
+            .unwrap_or_else(|_| unreachable!("[BUG] rendered parts without bodies"));
+
+        let compressed =
+            if let Some(min_size) = self.gzip_attachments_above {
+                self.gzip_cache.get_or_create(template_id, || {
+                    spec.attachments().iter()
+                        .map(|resource| gzip_attachment(resource, min_size, &self.media_types.application_gzip))
+                        .collect::<Result<Vec<_>, _>>()
+                })?
+            } else {
+                Vec::new()
+            };
+
+        let mut attachments = Vec::with_capacity(spec.attachments().len());
+        for (idx, resource) in spec.attachments().iter().enumerate() {
+            let excluded = attachment_filter
+                .and_then(|filter| attachment_name(resource).map(|name| !filter(&name)))
+                .unwrap_or(false);
+            if excluded {
+                continue;
+            }
+            let resource = compressed.get(idx).and_then(|compressed| compressed.clone())
+                .unwrap_or_else(|| resource.clone());
+            attachments.push(EmbeddedWithCId::attachment(resource, ctx));
+        }
+
+        Ok(MailParts {
+            alternative_bodies: bodies,
+            //TODO collpas embeddings and attachments and use their disposition parma
+            // instead
+            shared_embeddings: shared_embeddings.into_iter().map(|(_, v)| v).collect(),
+            attachments,
+        })
+    }
+}
+
+/// parses one of the (valid) media type constants
+fn constant_media_type(media_type: &str) -> MediaType {
+    MediaType::parse(media_type)
+        .expect("[BUG] constant media type is invalid")
+}
+
+/// returns the name of the attachment, it's `use_name` or the file name of it's IRI
+fn attachment_name(resource: &Resource) -> Option<String> {
+    let source = resource.source()?;
+    source.use_name.clone()
+        .or_else(|| file_name_of_iri(source.iri.as_str()))
+}
+
+/// returns the content of the resource if it's a stylesheet
+///
+/// Stylesheets are read into the resource when the spec is loaded, a resource
+/// referring to a `.css` file which was not (e.g. one created with
+/// `Resource::new` and inserted through `embeddings_mut`) is an error.
+fn stylesheet(name: &str, resource: &Resource) -> Result<Option<String>, EncodingError> {
+    if let Some(buffer) = resource.get_if_loaded() {
+        if buffer.content_type().full_type() != "text/css" {
+            return Ok(None);
+        }
+        let stylesheet = String::from_utf8(buffer.to_vec())
+            .map_err(|err| EncodingError::StylesheetReadFailed {
+                file: name.to_owned(),
+                cause: err.to_string()
+            })?;
+        return Ok(Some(stylesheet));
+    }
+
+    let is_stylesheet_file = resource.source()
+        .map(|source| source.iri.as_str())
+        .map(|iri| iri.starts_with("path:") && iri.to_lowercase().ends_with(".css"))
+        .unwrap_or(false);
+    if is_stylesheet_file {
+        Err(EncodingError::StylesheetNotLoaded { name: name.to_owned() })
+    } else {
+        Ok(None)
+    }
+}
+
+/// returns a gzip compressed copy of the attachment if it's larger than `min_size`
+///
+/// The content is taken from the buffer of the resource if it's loaded, else
+/// from the file it refers to. Resources which are neither loaded nor refer
+/// to a file are not compressed.
+fn gzip_attachment(resource: &Resource, min_size: u64, gzip_media_type: &MediaType)
+    -> Result<Option<Resource>, EncodingError>
+{
+    let name = attachment_name(resource);
+    let compression_failed = |err: io::Error| EncodingError::AttachmentCompressionFailed {
+        file: name.clone().unwrap_or_else(|| "<unnamed>".to_owned()),
+        cause: err.to_string()
+    };
+
+    let path = resource.source()
+        .map(|source| source.iri.as_str())
+        .filter(|iri| iri.starts_with("path:"))
+        .map(|iri| Path::new(&iri["path:".len()..]));
+
+    let (file_name, content) =
+        if let Some(buffer) = resource.get_if_loaded() {
+            if buffer.len() as u64 <= min_size {
+                return Ok(None);
+            }
+            (name.clone().or_else(|| buffer.file_meta().file_name.clone()), buffer.to_vec())
+        } else if let Some(path) = path {
+            let size = path.metadata().map_err(&compression_failed)?.len();
+            if size <= min_size {
+                return Ok(None);
+            }
+            (name.clone(), fs::read(path).map_err(&compression_failed)?)
+        } else {
+            return Ok(None);
+        };
+
+    let compressed = gzip(&content).map_err(&compression_failed)?;
+    let file_name = file_name.map(|name| format!("{}.gz", name));
+    let file_meta = FileMeta { file_name, ..Default::default() };
+    let buffer = FileBuffer::with_file_meta(gzip_media_type.clone(), compressed, file_meta);
+    Ok(Some(Resource::sourceless_from_buffer(buffer)))
+}
+
+/// the file suffix used for the id of sources created by `insert_spec_str`
+fn source_suffix(media_type: &MediaType) -> String {
+    match media_type.full_type().to_string().to_lowercase().as_str() {
+        "text/html" => "html".to_owned(),
+        "text/plain" => "txt".to_owned(),
+        full_type => full_type.rsplit('/').next().unwrap_or(full_type).to_owned()
+    }
+}
+
+/// trims the header value and replaces line breaks with a single space
+fn single_line_header_value(value: &str) -> String {
+    value.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// turns the embeddings into a `Vec` sorted by name, so that the mail parts have a stable order
+fn sorted_by_name(embeddings: HashMap<String, EmbeddedWithCId>) -> Vec<(String, EmbeddedWithCId)> {
+    let mut embeddings = embeddings.into_iter().collect::<Vec<_>>();
+    embeddings.sort_by(|a, b| a.0.cmp(&b.0));
+    embeddings
+}
+
+/// creates a inline embedding using `ctx` to generate it's content id
+fn create_embedding(
     key: &str,
     resource: &Resource,
+    disposition: EmbeddingDisposition,
+    deterministic_cid: bool,
     ctx: &impl Context
 ) -> (String, EmbeddedWithCId)
 {
-    (key.to_owned(), EmbeddedWithCId::inline(resource.clone(), ctx))
+    let embedding =
+        if deterministic_cid {
+            let content_id = deterministic_content_id(key, resource, ctx);
+            let disposition =
+                match disposition {
+                    EmbeddingDisposition::Inline => Disposition::inline(),
+                    EmbeddingDisposition::Attachment => Disposition::attachment()
+                };
+            Embedded::with_content_id(resource.clone(), disposition, content_id)
+                .assure_content_id_and_copy(ctx)
+        } else {
+            match disposition {
+                EmbeddingDisposition::Inline => EmbeddedWithCId::inline(resource.clone(), ctx),
+                EmbeddingDisposition::Attachment => EmbeddedWithCId::attachment(resource.clone(), ctx)
+            }
+        };
+    (key.to_owned(), embedding)
+}
+
+/// creates a content id derived from the embeddings name and source IRI
+///
+/// The domain is taken from a content id generated by the context.
+fn deterministic_content_id(key: &str, resource: &Resource, ctx: &impl Context) -> ContentId {
+    let seed =
+        if let Some(source) = resource.source() {
+            format!("{}#{}", key, source.iri.as_str())
+        } else {
+            key.to_owned()
+        };
+    let domain = content_id_domain(ctx);
+    ContentId::from_unchecked(format!("rte.{:016x}@{}", stable_hash(&seed), domain))
+}
+
+/// the domain (right part) of the content ids generated by the context
+fn content_id_domain(ctx: &impl Context) -> String {
+    let generated = ctx.generate_content_id();
+    generated.as_str().rsplitn(2, '@').next()
+        .unwrap_or_else(|| unreachable!("[BUG] rsplitn always yields one element"))
+        .to_owned()
 }
\ No newline at end of file
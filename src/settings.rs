@@ -1,13 +1,19 @@
 use std::collections::HashMap;
+use std::sync::Arc;
 use std::path::Path;
+use std::time::Duration;
+use std::thread;
+use std::io;
 
 use failure::Fail;
 use media_type::CHARSET;
 use vec1::Vec1;
+use glob::Pattern;
 
 use headers::components::MediaType;
 
 use ::error::{CreatingSpecError, CreatingSpecErrorVariant};
+use ::fetch::ResourceFetcher;
 use ::utils;
 
 
@@ -29,24 +35,40 @@ lazy_static! {
             base_subtype: "html".to_owned(),
             suffixes: vec1![ ".html".to_owned(), ".htm".to_owned() ],
             charset: Some("utf-8".to_owned()),
+            input_encoding: InputEncoding::Utf8,
+            optional: false,
+            media_type_by_extension: false,
+            template_base_name: "mail".to_owned(),
         };
         let xhtml = Type {
             base_type: "application".to_owned(),
             base_subtype: "xhtml+xml".to_owned(),
             suffixes: vec1![ ".xhtml".to_owned(), ".xml".to_owned() ],
             charset: Some("utf-8".to_owned()),
+            input_encoding: InputEncoding::Utf8,
+            optional: false,
+            media_type_by_extension: false,
+            template_base_name: "mail".to_owned(),
         };
         let enriched = Type {
             base_type: "text".to_owned(),
             base_subtype: "enriched".to_owned(),
             suffixes: vec1![ ".txt".to_owned(), ".text".to_owned() ],
             charset: Some("utf-8".to_owned()),
+            input_encoding: InputEncoding::Utf8,
+            optional: false,
+            media_type_by_extension: false,
+            template_base_name: "mail".to_owned(),
         };
         let text = Type {
             base_type: "text".to_owned(),
             base_subtype: "plain".to_owned(),
             suffixes: vec1![ ".txt".to_owned(), ".text".to_owned() ],
             charset: Some("utf-8".to_owned()),
+            input_encoding: InputEncoding::Utf8,
+            optional: false,
+            media_type_by_extension: false,
+            template_base_name: "mail".to_owned(),
         };
 
         let mut se = LoadSpecSettings::new();
@@ -57,6 +79,29 @@ lazy_static! {
 
         se
     };
+
+    /// the `DEFAULT_SETTINGS` with an additional `markdown` type
+    ///
+    /// Markdown bodies are expanded into a `text/plain` and `text/html` body
+    /// if the `RenderTemplateEngine` has a `MarkdownRenderer`, so templates
+    /// using them should not have `text` or `html` dirs.
+    pub static ref MARKDOWN_SETTINGS: LoadSpecSettings = {
+        let markdown = Type {
+            base_type: "text".to_owned(),
+            base_subtype: "markdown".to_owned(),
+            suffixes: vec1![ ".md".to_owned(), ".markdown".to_owned() ],
+            charset: Some("utf-8".to_owned()),
+            input_encoding: InputEncoding::Utf8,
+            optional: false,
+            media_type_by_extension: false,
+            template_base_name: "mail".to_owned(),
+        };
+
+        let mut se = DEFAULT_SETTINGS.clone();
+        // markdown is readable as plain text, so it's prioritized directly over text
+        se.set_type_lookup("markdown", markdown, Some("text")).unwrap();
+        se
+    };
 }
 
 
@@ -68,12 +113,79 @@ lazy_static! {
 #[derive(Debug, Clone)]
 pub struct LoadSpecSettings {
     type_lookup: HashMap<String, (usize, Type)>,
+    skip_embedding_checks: bool,
+    use_file_names: bool,
+    follow_symlinks: bool,
+    recursive: bool,
+    load_sample_data: bool,
+    partials_dir_name: Option<String>,
+    disabled_marker: Option<String>,
+    path_placeholders: HashMap<String, String>,
+    ignore_patterns: Vec<Pattern>,
+    io_retries: usize,
+    io_retry_backoff: Duration,
+    resource_fetcher: Option<Arc<ResourceFetcher>>,
 }
 
 impl LoadSpecSettings {
 
     pub fn new() -> Self {
-        LoadSpecSettings { type_lookup: HashMap::new() }
+        LoadSpecSettings {
+            type_lookup: HashMap::new(),
+            skip_embedding_checks: false,
+            use_file_names: true,
+            follow_symlinks: false,
+            recursive: false,
+            load_sample_data: false,
+            partials_dir_name: Some("partials".to_owned()),
+            disabled_marker: Some(".disabled".to_owned()),
+            path_placeholders: HashMap::new(),
+            // dotfiles like `.gitkeep` or `.DS_Store`
+            ignore_patterns: vec![
+                Pattern::new(".*").expect("[BUG] constant glob pattern is invalid")
+            ],
+            io_retries: 0,
+            io_retry_backoff: Duration::from_millis(10),
+            resource_fetcher: None,
+        }
+    }
+
+    /// if true the files of embeddings/attachments are not checked on load
+    ///
+    /// By default loading a spec checks that the file of each embedding and
+    /// attachment exists and determines its media type. If this is enabled
+    /// both are skipped, so a missing file is only reported when the
+    /// resource is loaded by the `Context` (i.e. on first use) and the media
+    /// type is determined by the `Context` at that point. The content of
+    /// the files is read by the `Context` in either case, except for
+    /// stylesheets which are always read on load (see `TemplateFs::resource`).
+    ///
+    /// This is disabled by default.
+    pub fn set_skip_embedding_checks(&mut self, enable: bool) {
+        self.skip_embedding_checks = enable;
+    }
+
+    /// returns true if the embedding checks are skipped, see `set_skip_embedding_checks`
+    pub fn skip_embedding_checks(&self) -> bool {
+        self.skip_embedding_checks
+    }
+
+    /// if true the file name is used as name of embeddings/attachments loaded from files
+    ///
+    /// The name is set as `use_name` of the resources `Source` and ends up as
+    /// the `filename` of the parts `Content-Disposition`, e.g. the inline
+    /// part for `logo.png` is named `logo.png`. Some (web)mail clients
+    /// display inline images better if they have a file name. Non ASCII
+    /// file names are kept as they are and encoded when the mail is encoded.
+    ///
+    /// This is enabled by default.
+    pub fn set_use_file_names(&mut self, enable: bool) {
+        self.use_file_names = enable;
+    }
+
+    /// returns true if file names are used as names of resources, see `set_use_file_names`
+    pub fn use_file_names(&self) -> bool {
+        self.use_file_names
     }
 
 
@@ -93,6 +205,24 @@ impl LoadSpecSettings {
             .map(|data| (data.0, &data.1))
     }
 
+    /// returns the type (and its priority idx) which should be used for a body file with given suffix
+    ///
+    /// The suffix can be passed with or without the leading `"."`.
+    ///
+    /// If multiple types accept the suffix (e.g. `"text"` and `"enriched"`
+    /// both accept `".txt"` in the `DEFAULT_SETTINGS`) the type with the
+    /// lowest priority idx is returned, i.e. the most "basic" one.
+    pub fn get_type_for_suffix(&self, suffix: &str) -> Option<(usize, &Type)> {
+        let suffix = suffix.trim_left_matches(".");
+        self.type_lookup.values()
+            .filter(|data| {
+                data.1.suffixes().iter()
+                    .any(|type_suffix| type_suffix.trim_left_matches(".") == suffix)
+            })
+            .min_by_key(|data| data.0)
+            .map(|data| (data.0, &data.1))
+    }
+
     pub fn set_type_lookup<I>(
         &mut self, name: I, type_: Type, prioritize_over: Option<&str>
     ) -> Result<(), CreatingSpecError>
@@ -104,6 +234,12 @@ impl LoadSpecSettings {
     fn _set_type_lookup(&mut self, name: String, type_: Type, prioritize_over: Option<&str>)
         -> Result<(), CreatingSpecError>
     {
+        // bodies can not be multipart, so `from_dir` must never produce such a type
+        if type_.base_type.eq_ignore_ascii_case("multipart") {
+            return Err(CreatingSpecErrorVariant::MultipartMediaType {
+                media_type: format!("{}/{}", type_.base_type, type_.base_subtype)
+            }.into());
+        }
         let new_priority =
             if let Some(other) = prioritize_over {
                 let other_prio = self.get_priority_idx(other)
@@ -157,6 +293,67 @@ impl LoadSpecSettings {
             .map(|data| data.0)
     }
 
+    /// moves the type with the given name to the given priority idx
+    ///
+    /// Priority indices go from `0` (the lowest priority, e.g. `text/plain`)
+    /// to the number of registered types minus one (the highest priority,
+    /// e.g. `text/html`), see `get_priority_idx`. Types between the old and
+    /// the new position are shifted by one. If `priority_idx` is larger than
+    /// the highest priority idx the type is moved to the highest priority.
+    ///
+    /// # Error
+    ///
+    /// An error is returned if there is no type with the given name.
+    pub fn set_priority_idx(&mut self, name: &str, priority_idx: usize) -> Result<(), CreatingSpecError> {
+        let type_ = self.remove_type_lookup(name)
+            .ok_or_else(|| CreatingSpecErrorVariant::MissingTypeInfo { type_name: name.to_owned() })?;
+        let new_priority = priority_idx.min(self.type_lookup.len());
+        for data in self.type_lookup.values_mut() {
+            if data.0 >= new_priority {
+                data.0 += 1;
+            }
+        }
+        self.type_lookup.insert(name.to_owned(), (new_priority, type_));
+        Ok(())
+    }
+
+    /// returns the names of all registered types ordered by priority (lowest first)
+    pub fn type_names_by_priority(&self) -> Vec<&str> {
+        let mut names = self.type_lookup.iter()
+            .map(|(name, data)| (data.0, name.as_str()))
+            .collect::<Vec<_>>();
+        names.sort();
+        names.into_iter().map(|(_, name)| name).collect()
+    }
+
+    /// sets the priorities of all types, `names` is ordered from the lowest to the highest priority
+    ///
+    /// E.g. `["text", "amp", "html"]` makes `text` have the lowest priority
+    /// (idx 0) and `html` the highest.
+    ///
+    /// # Error
+    ///
+    /// An error is returned if `names` contains a name for which no type is
+    /// registered or does not contain all registered types, in which case
+    /// nothing is changed.
+    pub fn reorder(&mut self, names: &[&str]) -> Result<(), CreatingSpecError> {
+        for name in names {
+            if !self.type_lookup.contains_key(*name) {
+                return Err(CreatingSpecErrorVariant::MissingTypeInfo { type_name: name.to_string() }.into());
+            }
+        }
+        let missing = self.type_lookup.keys()
+            .find(|name| !names.contains(&name.as_str()));
+        if let Some(name) = missing {
+            return Err(CreatingSpecErrorVariant::MissingTypeInfo { type_name: name.clone() }.into());
+        }
+        for (idx, name) in names.iter().enumerate() {
+            // UNWRAP_SAFE: we checked that all names are registered
+            self.type_lookup.get_mut(*name).unwrap().0 = idx;
+        }
+        Ok(())
+    }
+
     pub fn remove_type_lookup(&mut self, name: &str) -> Option<Type> {
         if let Some((old_priority, type_)) = self.type_lookup.remove(name) {
             for data in self.type_lookup.values_mut() {
@@ -171,6 +368,352 @@ impl LoadSpecSettings {
     }
 
 
+    /// if true symlinks to directories are followed when loading specs from dirs
+    ///
+    /// By default (`false`) a symlink to a directory is not seen as a directory,
+    /// so e.g. a symlinked sub-template folder will be treated as an embedding
+    /// (which fails as it's not a file). Symlinks to files are always followed.
+    ///
+    /// If enabled directories are determined using metadata which follows symlinks.
+    /// Loading a spec only looks at a fixed depth of directories so symlink cycles
+    /// can not lead to endless loops there. When walking all files of a template
+    /// dir recursively (to remember their state for `TemplateSpec::reload_changed`)
+    /// the canonical path of each visited directory is tracked and directories
+    /// which were already visited are skipped.
+    pub fn set_follow_symlinks(&mut self, enable: bool) {
+        self.follow_symlinks = enable;
+    }
+
+    /// returns true if symlinks to directories are followed, see `set_follow_symlinks`
+    pub fn follow_symlinks(&self) -> bool {
+        self.follow_symlinks
+    }
+
+    /// if true `TemplateSpec::from_dirs` also looks for templates in sub-directories
+    ///
+    /// A directory is seen as template dir if it contains a folder named like
+    /// a registered type (e.g. `html`) or a body file (a file starting with
+    /// `mail.`) directly, all other directories are just traversed. The id of
+    /// a template found this way is the path of it's dir relative to the
+    /// templates dir joined with `/`, e.g. `transactional/welcome`.
+    ///
+    /// This is disabled by default.
+    pub fn set_recursive(&mut self, enable: bool) {
+        self.recursive = enable;
+    }
+
+    /// returns true if `from_dirs` looks for templates recursively, see `set_recursive`
+    pub fn recursive(&self) -> bool {
+        self.recursive
+    }
+
+    /// if true a `preview.json` or `sample.json` file in a template dir is loaded as sample data
+    ///
+    /// The file is parsed as json and made available through
+    /// `TemplateSpec::sample_data`, it's not used as an embedding. If
+    /// both files exist `preview.json` is used. Sample data can be used
+    /// to render previews with `RenderTemplateEngine::render_preview`.
+    ///
+    /// This is disabled by default.
+    pub fn set_load_sample_data(&mut self, enable: bool) {
+        self.load_sample_data = enable;
+    }
+
+    /// returns true if sample data is loaded, see `set_load_sample_data`
+    pub fn load_sample_data(&self) -> bool {
+        self.load_sample_data
+    }
+
+    /// sets the name of the dir in template dirs which contains partials
+    ///
+    /// A dir with this name in a template dir is not treated as sub-template
+    /// dir, instead the files in it are loaded as partials of the spec (see
+    /// `TemplateSpec::insert_partial`), named like the file without it's
+    /// extension (e.g. `partials/header.html` is the partial `header`).
+    /// Passing in `None` disables this.
+    ///
+    /// By default the dir name is `"partials"`.
+    pub fn set_partials_dir_name(&mut self, name: Option<String>) {
+        self.partials_dir_name = name;
+    }
+
+    /// returns the name of the partials dir, see `set_partials_dir_name`
+    pub fn partials_dir_name(&self) -> Option<&str> {
+        self.partials_dir_name.as_ref().map(|name| name.as_str())
+    }
+
+    /// returns true if a dir with the given name in a template dir contains partials
+    pub fn is_partials_dir_name(&self, name: &str) -> bool {
+        self.partials_dir_name() == Some(name)
+    }
+
+    /// sets the marker used to disable template dirs when loading them with `from_dirs`
+    ///
+    /// A template dir which contains a file named like the marker or whose
+    /// name ends with the marker is skipped (with a log message). Skipped
+    /// dirs are not checked for duplicate template ids. The marker file is
+    /// found even if it matches an ignore pattern. Passing in `None`
+    /// disables this.
+    ///
+    /// By default the marker is `".disabled"`.
+    pub fn set_disabled_marker(&mut self, marker: Option<String>) {
+        self.disabled_marker = marker;
+    }
+
+    /// returns the marker for disabled template dirs, see `set_disabled_marker`
+    pub fn disabled_marker(&self) -> Option<&str> {
+        self.disabled_marker.as_ref().map(|marker| marker.as_str())
+    }
+
+    /// sets the encoding of template files of the type with the given name
+    ///
+    /// By default template files are expected to be UTF-8 and are read by
+    /// the render engine. With a different encoding the files are read
+    /// and decoded when loading the spec and passed to the render engine
+    /// as `TemplateSource::Source`. This only affects how template files
+    /// are read, the charset of the generated bodies is still the one of
+    /// the type.
+    ///
+    /// # Error
+    ///
+    /// An error is returned if there is no type with the given name.
+    pub fn set_input_encoding(&mut self, type_name: &str, encoding: InputEncoding)
+        -> Result<(), CreatingSpecError>
+    {
+        let data = self.type_lookup.get_mut(type_name)
+            .ok_or_else(|| CreatingSpecErrorVariant::MissingTypeInfo { type_name: type_name.to_owned() })?;
+        data.1.set_input_encoding(encoding);
+        Ok(())
+    }
+
+    /// marks the type with the given name as optional (or not)
+    ///
+    /// If a sub-template dir of an optional type does not contain a template
+    /// file (`mail.*`) it is skipped (with a warning) when loading a spec with
+    /// `from_dir`, instead of failing with `TemplateFileMissing` (or
+    /// `EmptyTemplateDir`). A spec still has to have at last one sub-template.
+    ///
+    /// By default no type is optional.
+    ///
+    /// # Error
+    ///
+    /// An error is returned if there is no type with the given name.
+    pub fn set_type_optional(&mut self, type_name: &str, optional: bool)
+        -> Result<(), CreatingSpecError>
+    {
+        let data = self.type_lookup.get_mut(type_name)
+            .ok_or_else(|| CreatingSpecErrorVariant::MissingTypeInfo { type_name: type_name.to_owned() })?;
+        data.1.set_optional(optional);
+        Ok(())
+    }
+
+    /// makes bodies of the given type use the media type associated with the body files extension
+    ///
+    /// E.g. with this enabled for `"html"` a `html/mail.xhtml` body file has
+    /// the media type `application/xhtml+xml` instead of `text/html`. The
+    /// charset of the type is still used and if the extension has no known
+    /// media type the types media type is used as fallback.
+    ///
+    /// This is disabled by default, i.e. the type (dir name) decides the media type.
+    ///
+    /// # Error
+    ///
+    /// An error is returned if there is no type with the given name.
+    pub fn set_media_type_by_extension(&mut self, type_name: &str, enable: bool)
+        -> Result<(), CreatingSpecError>
+    {
+        let data = self.type_lookup.get_mut(type_name)
+            .ok_or_else(|| CreatingSpecErrorVariant::MissingTypeInfo { type_name: type_name.to_owned() })?;
+        data.1.set_media_type_by_extension(enable);
+        Ok(())
+    }
+
+    /// sets the name (without suffix) of template files of the type with the given name
+    ///
+    /// E.g. with `"body"` for `"html"` the template file of a html sub-template
+    /// dir is `html/body.html` (or `html/body.htm`) instead of `html/mail.html`.
+    ///
+    /// The template base name of all types is `"mail"` by default.
+    ///
+    /// # Error
+    ///
+    /// An error is returned if there is no type with the given name.
+    pub fn set_template_base_name<N>(&mut self, type_name: &str, name: N)
+        -> Result<(), CreatingSpecError>
+        where N: Into<String>
+    {
+        let data = self.type_lookup.get_mut(type_name)
+            .ok_or_else(|| CreatingSpecErrorVariant::MissingTypeInfo { type_name: type_name.to_owned() })?;
+        data.1.set_template_base_name(name);
+        Ok(())
+    }
+
+    /// sets the accepted suffixes of template files of the type with the given name
+    ///
+    /// Only files named like the template base name followed by one of the
+    /// suffixes (e.g. `mail.html` and `mail.htm` for `".html"` and `".htm"`)
+    /// are used as template file of a sub-template dir of the type. The
+    /// suffixes are also used to find the type of body files which are not
+    /// in a folder named like a type (see `get_type_for_suffix`).
+    ///
+    /// # Error
+    ///
+    /// An error is returned if there is no type with the given name.
+    pub fn set_suffixes(&mut self, type_name: &str, suffixes: Vec1<String>)
+        -> Result<(), CreatingSpecError>
+    {
+        let data = self.type_lookup.get_mut(type_name)
+            .ok_or_else(|| CreatingSpecErrorVariant::MissingTypeInfo { type_name: type_name.to_owned() })?;
+        data.1.set_suffixes(suffixes);
+        Ok(())
+    }
+
+    /// returns true if the file name is the name of a template file of any type
+    ///
+    /// See `Type::is_template_file_name`.
+    pub fn is_template_file_name(&self, file_name: &str) -> bool {
+        self.type_lookup.values()
+            .any(|data| data.1.is_template_file_name(file_name))
+    }
+
+    /// adds a pattern for names of files and directories to ignore when loading specs
+    ///
+    /// The pattern is a glob pattern (e.g. `README.*` or `*.bak`) which is
+    /// matched against the file name only (not the path), a name without
+    /// any special characters only matches exactly that name. Ignored files
+    /// are neither used as embeddings nor as body files and ignored
+    /// directories are not looked into.
+    ///
+    /// By default dotfiles (`.*`) are ignored.
+    pub fn add_ignore_pattern(&mut self, pattern: &str) -> Result<(), CreatingSpecError> {
+        let pattern = Pattern::new(pattern)
+            .map_err(|err| err.context(CreatingSpecErrorVariant::InvalidGlobPattern {
+                pattern: pattern.to_owned()
+            }))?;
+        self.ignore_patterns.push(pattern);
+        Ok(())
+    }
+
+    /// removes all ignore patterns, including the default ones
+    pub fn clear_ignore_patterns(&mut self) {
+        self.ignore_patterns.clear();
+    }
+
+    /// returns true if a file/directory with the given name is ignored, see `add_ignore_pattern`
+    pub fn is_ignored(&self, file_name: &str) -> bool {
+        self.ignore_patterns.iter()
+            .any(|pattern| pattern.matches(file_name))
+    }
+
+    /// sets how often reading a file/directory is retried on transient IO errors
+    ///
+    /// When loading specs reading directories and files (e.g. sample data)
+    /// is retried up to `retries` times if it fails with an error of kind
+    /// `Interrupted` or `WouldBlock`, which can happen e.g. with network
+    /// file systems. All other errors (e.g. `NotFound`) are returned
+    /// immediately. Between retries the thread sleeps, starting with the
+    /// backoff set with `set_io_retry_backoff` which is increased by that
+    /// amount with each retry.
+    ///
+    /// By default no retries are done.
+    pub fn set_io_retries(&mut self, retries: usize) {
+        self.io_retries = retries;
+    }
+
+    /// returns how often IO is retried on transient errors, see `set_io_retries`
+    pub fn io_retries(&self) -> usize {
+        self.io_retries
+    }
+
+    /// sets the backoff used between retries (by default 10ms), see `set_io_retries`
+    pub fn set_io_retry_backoff(&mut self, backoff: Duration) {
+        self.io_retry_backoff = backoff;
+    }
+
+    pub fn io_retry_backoff(&self) -> Duration {
+        self.io_retry_backoff
+    }
+
+    /// runs the IO operation, retrying it on transient errors as configured
+    pub(crate) fn retry_io<T, F>(&self, mut operation: F) -> io::Result<T>
+        where F: FnMut() -> io::Result<T>
+    {
+        let mut retries = 0;
+        loop {
+            match operation() {
+                Err(ref err) if is_transient(err) && retries < self.io_retries => {
+                    retries += 1;
+                    thread::sleep(self.io_retry_backoff * retries as u32);
+                },
+                result => return result
+            }
+        }
+    }
+
+    /// sets the fetcher used for explicitly declared embeddings with a `http(s)://` url
+    ///
+    /// If set, explicitly declared paths (e.g. passed to `TemplateSpec::insert_embedding_from_path`)
+    /// which are `http://` or `https://` urls are fetched with it when creating the spec, see
+    /// `ResourceFetcher` for the implications. If no fetcher is set (the default) declaring
+    /// an embedding with an url is an error.
+    pub fn set_resource_fetcher(&mut self, fetcher: Option<Arc<ResourceFetcher>>) {
+        self.resource_fetcher = fetcher;
+    }
+
+    /// returns the fetcher used for urls, see `set_resource_fetcher`
+    pub fn resource_fetcher(&self) -> Option<&ResourceFetcher> {
+        self.resource_fetcher.as_ref().map(|fetcher| &**fetcher)
+    }
+
+    /// sets the value of a placeholder for explicitly declared paths
+    ///
+    /// Explicitly declared paths (e.g. passed to `TemplateSpec::insert_embedding_from_path`)
+    /// can contain placeholders of the form `${NAME}` which are replaced with the value set
+    /// here, e.g. with a placeholder `ASSET_ROOT` set to `/srv/assets` the path
+    /// `${ASSET_ROOT}/logo.png` becomes `/srv/assets/logo.png`. This does not apply to
+    /// files found when e.g. loading a spec with `from_dir`.
+    ///
+    /// Returns the old value if there was one.
+    pub fn set_path_placeholder<N, V>(&mut self, name: N, value: V) -> Option<String>
+        where N: Into<String>, V: Into<String>
+    {
+        self.path_placeholders.insert(name.into(), value.into())
+    }
+
+    pub fn remove_path_placeholder(&mut self, name: &str) -> Option<String> {
+        self.path_placeholders.remove(name)
+    }
+
+    pub fn path_placeholders(&self) -> &HashMap<String, String> {
+        &self.path_placeholders
+    }
+
+    /// replaces all `${NAME}` placeholders in the path, see `set_path_placeholder`
+    ///
+    /// A `$` not followed by a `{` is kept as is.
+    ///
+    /// # Error
+    ///
+    /// An error is returned if a placeholder is not defined or if a placeholder
+    /// is not terminated by a `}`.
+    pub fn expand_path_placeholders(&self, path: &str) -> Result<String, CreatingSpecError> {
+        let mut out = String::with_capacity(path.len());
+        let mut rest = path;
+        while let Some(start) = rest.find("${") {
+            out.push_str(&rest[..start]);
+            let after_start = &rest[start+2..];
+            let end = after_start.find('}')
+                .ok_or_else(|| CreatingSpecErrorVariant::MalformedPathPlaceholder { path: path.to_owned() })?;
+            let name = &after_start[..end];
+            let value = self.path_placeholders.get(name)
+                .ok_or_else(|| CreatingSpecErrorVariant::UndefinedPathPlaceholder { name: name.to_owned() })?;
+            out.push_str(value);
+            rest = &after_start[end+1..];
+        }
+        out.push_str(rest);
+        Ok(out)
+    }
+
     #[inline]
     pub fn determine_media_type<P>(&self, path: P) -> Result<MediaType, CreatingSpecError>
         where P: AsRef<Path>
@@ -179,24 +722,64 @@ impl LoadSpecSettings {
     }
 }
 
+fn is_transient(err: &io::Error) -> bool {
+    match err.kind() {
+        io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock => true,
+        _ => false
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct Type {
     base_type: String,
     base_subtype: String,
-    //TODO remove
     suffixes: Vec1<String>,
-    charset: Option<String>
+    charset: Option<String>,
+    input_encoding: InputEncoding,
+    optional: bool,
+    media_type_by_extension: bool,
+    template_base_name: String
 }
 
 impl Type {
 
+    /// creates a new type for bodies with the media type `base_type/base_subtype`
+    ///
+    /// The charset (if given) is added as parameter to the media type of
+    /// the bodies, the suffixes are used to find the template file in a
+    /// folder named like the type (e.g. `mail.html` for `".html"`) and to
+    /// find the type of body files which are not in such a folder.
+    ///
+    /// The template base name is `"mail"`, see `set_template_base_name`.
+    pub fn new<T, ST>(base_type: T, base_subtype: ST, suffixes: Vec1<String>, charset: Option<String>) -> Self
+        where T: Into<String>, ST: Into<String>
+    {
+        Type {
+            base_type: base_type.into(),
+            base_subtype: base_subtype.into(),
+            suffixes, charset,
+            input_encoding: InputEncoding::Utf8,
+            optional: false,
+            media_type_by_extension: false,
+            template_base_name: "mail".to_owned()
+        }
+    }
+
     pub fn to_media_type_for<P>(&self, path: P) -> Result<MediaType, CreatingSpecError>
         where P: AsRef<Path>
     {
         self._to_media_type_for(path.as_ref())
     }
 
-    fn _to_media_type_for(&self, _path: &Path) -> Result<MediaType, CreatingSpecError> {
+    fn _to_media_type_for(&self, path: &Path) -> Result<MediaType, CreatingSpecError> {
+        if self.media_type_by_extension {
+            if let Ok(mut media_type) = utils::media_type_from_extension(path) {
+                if let Some(charset) = self.charset.as_ref() {
+                    media_type.set_param(CHARSET, charset);
+                }
+                return Ok(media_type);
+            }
+        }
         //FEAT: consider charset sniffing or validate sniffing, allow other parameters for more
         // unusual bodies
         // for now this is just creating a media type and set a preset charset,
@@ -220,14 +803,121 @@ impl Type {
         &self.suffixes
     }
 
+    /// sets the suffixes of template files of this type, see `LoadSpecSettings::set_suffixes`
+    pub fn set_suffixes(&mut self, suffixes: Vec1<String>) {
+        self.suffixes = suffixes;
+    }
+
+    /// the encoding of template files of this type, see `LoadSpecSettings::set_input_encoding`
+    pub fn input_encoding(&self) -> InputEncoding {
+        self.input_encoding
+    }
+
+    pub fn set_input_encoding(&mut self, encoding: InputEncoding) {
+        self.input_encoding = encoding;
+    }
+
+    /// true if dirs of this type without template file are skipped, see `LoadSpecSettings::set_type_optional`
+    pub fn is_optional(&self) -> bool {
+        self.optional
+    }
+
+    pub fn set_optional(&mut self, optional: bool) {
+        self.optional = optional;
+    }
+
+    /// true if the body media type is derived from the body files extension, see `LoadSpecSettings::set_media_type_by_extension`
+    pub fn media_type_by_extension(&self) -> bool {
+        self.media_type_by_extension
+    }
+
+    pub fn set_media_type_by_extension(&mut self, enable: bool) {
+        self.media_type_by_extension = enable;
+    }
+
+    /// the name of template files of this type without suffix, e.g. `"mail"` for `mail.html`
     pub fn template_base_name(&self) -> &str {
-        "mail"
+        &self.template_base_name
+    }
+
+    /// sets the template base name, see `LoadSpecSettings::set_template_base_name`
+    pub fn set_template_base_name<N>(&mut self, name: N)
+        where N: Into<String>
+    {
+        self.template_base_name = name.into();
+    }
+
+    /// returns true if a file with the given name is a template file of this type
+    ///
+    /// This is the case if the name is the template base name followed by
+    /// one of the suffixes (e.g. `mail.html`). If the media type is derived
+    /// from the extension (see `set_media_type_by_extension`) the template
+    /// base name followed by any suffix (e.g. `mail.xhtml`) is accepted.
+    pub fn is_template_file_name(&self, file_name: &str) -> bool {
+        let suffix =
+            match strip_base_name(file_name, &self.template_base_name) {
+                Some(suffix) => suffix,
+                None => return false
+            };
+        self.media_type_by_extension
+            || self.suffixes.iter().any(|type_suffix| type_suffix.trim_left_matches(".") == suffix)
+    }
+
+    /// returns true if the file name is the template base name followed by any suffix
+    pub(crate) fn has_template_base_name(&self, file_name: &str) -> bool {
+        strip_base_name(file_name, &self.template_base_name).is_some()
+    }
+}
+
+/// returns the suffix (without leading `"."`) if the file name is `<base_name>.<suffix>`
+fn strip_base_name<'a>(file_name: &'a str, base_name: &str) -> Option<&'a str> {
+    if file_name.len() > base_name.len() + 1
+        && file_name.starts_with(base_name)
+        && file_name[base_name.len()..].starts_with(".")
+    {
+        Some(&file_name[base_name.len() + 1..])
+    } else {
+        None
     }
 }
 
 
+/// the text encoding template files are stored in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum InputEncoding {
+    Utf8,
+    /// ISO-8859-1
+    Latin1
+}
+
+impl Default for InputEncoding {
+    fn default() -> Self {
+        InputEncoding::Utf8
+    }
+}
+
+impl InputEncoding {
+
+    /// decodes the bytes into a string
+    ///
+    /// # Error
+    ///
+    /// An io error of kind `InvalidData` is returned if the
+    /// bytes are not valid in this encoding.
+    pub fn decode(&self, bytes: Vec<u8>) -> Result<String, io::Error> {
+        match *self {
+            InputEncoding::Utf8 => String::from_utf8(bytes)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err)),
+            // the first 256 code points of unicode are the latin1 chars
+            InputEncoding::Latin1 => Ok(bytes.into_iter().map(|byte| byte as char).collect())
+        }
+    }
+}
+
 #[cfg(test)]
 mod test {
+    use std::io;
+    use std::time::Duration;
     use super::{LoadSpecSettings, Type};
 
     fn dumy_settings() -> LoadSpecSettings {
@@ -247,6 +937,10 @@ mod test {
             base_subtype: subtype.to_owned(),
             suffixes: vec1![ suffix.to_owned() ],
             charset: Some("utf-8".to_owned()),
+            input_encoding: Default::default(),
+            optional: false,
+            media_type_by_extension: false,
+            template_base_name: "mail".to_owned(),
         }
     }
 
@@ -284,6 +978,63 @@ mod test {
 
     }
 
+    #[test]
+    fn type_for_suffix_prefers_lowest_priority() {
+        let mut se = dumy_settings();
+        se.set_type_lookup("enriched", dumy_type("enriched", ".txt"), Some("text")).unwrap();
+
+        assert_eq!(se.get_type_for_suffix("txt"), Some((0, &dumy_type("text", "txt"))));
+        assert_eq!(se.get_type_for_suffix(".html"), Some((3, &dumy_type("html", "html"))));
+        assert_eq!(se.get_type_for_suffix("png"), None);
+    }
+
+    #[test]
+    fn template_file_names_use_base_name_and_suffixes() {
+        let mut type_ = dumy_type("html", ".html");
+        assert!(type_.is_template_file_name("mail.html"));
+        assert!(!type_.is_template_file_name("mail.xhtml"));
+        assert!(!type_.is_template_file_name("mail"));
+        assert!(!type_.is_template_file_name("mailer.html"));
+
+        type_.set_template_base_name("index");
+        type_.set_suffixes(vec1![ ".html".to_owned(), ".htm".to_owned() ]);
+        assert!(type_.is_template_file_name("index.htm"));
+        assert!(!type_.is_template_file_name("mail.html"));
+
+        type_.set_media_type_by_extension(true);
+        assert!(type_.is_template_file_name("index.xhtml"));
+    }
+
+    #[test]
+    fn expand_path_placeholders() {
+        let mut se = LoadSpecSettings::new();
+        se.set_path_placeholder("ASSET_ROOT", "/srv/assets");
+        se.set_path_placeholder("ENV", "prod");
+
+        assert_eq!(se.expand_path_placeholders("${ASSET_ROOT}/${ENV}/logo.png").unwrap(),
+            "/srv/assets/prod/logo.png");
+        assert_eq!(se.expand_path_placeholders("./$logo.png").unwrap(), "./$logo.png");
+        assert!(se.expand_path_placeholders("${OTHER}/logo.png").is_err());
+        assert!(se.expand_path_placeholders("${ASSET_ROOT/logo.png").is_err());
+    }
+
+    #[test]
+    fn ignore_patterns() {
+        let mut se = LoadSpecSettings::new();
+        assert!(se.is_ignored(".gitkeep"));
+        assert!(!se.is_ignored("README.md"));
+
+        se.add_ignore_pattern("README.*").unwrap();
+        se.add_ignore_pattern("preview.json").unwrap();
+        assert!(se.is_ignored("README.md"));
+        assert!(se.is_ignored("preview.json"));
+        assert!(!se.is_ignored("logo.png"));
+        assert!(se.add_ignore_pattern("[").is_err());
+
+        se.clear_ignore_patterns();
+        assert!(!se.is_ignored(".gitkeep"));
+    }
+
     #[test]
     fn remove_type() {
         let mut se = dumy_settings();
@@ -294,4 +1045,57 @@ mod test {
         assert_eq!(se.get_type_with_priority("html"), Some((1, &dumy_type("html", "html"))));
     }
 
-}
\ No newline at end of file
+    #[test]
+    fn change_priorities() {
+        let mut se = dumy_settings();
+        se.set_priority_idx("html", 0).unwrap();
+        assert_eq!(se.type_names_by_priority(), vec!["html", "text", "xhtml"]);
+
+        se.set_priority_idx("html", 100).unwrap();
+        assert_eq!(se.type_names_by_priority(), vec!["text", "xhtml", "html"]);
+        assert!(se.set_priority_idx("amp", 1).is_err());
+
+        se.reorder(&["xhtml", "html", "text"]).unwrap();
+        assert_eq!(se.type_names_by_priority(), vec!["xhtml", "html", "text"]);
+        assert_eq!(se.get_priority_idx("text"), Some(2));
+
+        assert!(se.reorder(&["xhtml", "html"]).is_err());
+        assert!(se.reorder(&["xhtml", "html", "text", "amp"]).is_err());
+        assert_eq!(se.type_names_by_priority(), vec!["xhtml", "html", "text"]);
+    }
+
+
+    #[test]
+    fn retry_io_on_transient_errors() {
+        let mut se = LoadSpecSettings::new();
+        se.set_io_retries(2);
+        se.set_io_retry_backoff(Duration::from_millis(1));
+
+        let mut calls = 0;
+        let res = se.retry_io(|| {
+            calls += 1;
+            if calls < 3 {
+                Err(io::Error::new(io::ErrorKind::Interrupted, "interrupted"))
+            } else {
+                Ok(calls)
+            }
+        });
+        assert_eq!(res.unwrap(), 3);
+
+        let mut calls = 0;
+        let res: io::Result<()> = se.retry_io(|| {
+            calls += 1;
+            Err(io::Error::new(io::ErrorKind::WouldBlock, "would block"))
+        });
+        assert_eq!(res.unwrap_err().kind(), io::ErrorKind::WouldBlock);
+        assert_eq!(calls, 3);
+
+        let mut calls = 0;
+        let res: io::Result<()> = se.retry_io(|| {
+            calls += 1;
+            Err(io::Error::new(io::ErrorKind::NotFound, "not found"))
+        });
+        assert_eq!(res.unwrap_err().kind(), io::ErrorKind::NotFound);
+        assert_eq!(calls, 1);
+    }
+}
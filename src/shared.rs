@@ -0,0 +1,196 @@
+use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard, PoisonError};
+use std::sync::mpsc::{self, RecvTimeoutError};
+#[cfg(feature="fs")]
+use std::path::Path;
+use std::time::Duration;
+use std::{panic, thread};
+
+use mail::Context;
+use template::{TemplateEngine, MailParts};
+
+use ::error::{LoadingError, InsertionError, RenderTimeoutError};
+use ::spec::TemplateSpec;
+use ::traits::{RenderEngine, RenderEngineBase};
+#[cfg(feature="fs")]
+use ::settings::LoadSpecSettings;
+use ::rte::RenderTemplateEngine;
+
+/// A `RenderTemplateEngine` which can be shared (e.g. between threads)
+///
+/// This wraps a `Arc<RwLock<RenderTemplateEngine<R>>>`, cloning it
+/// is cheap and all clones refer to the same engine. Using a template
+/// (through `TemplateEngine`) read-locks the engine, while methods
+/// which modify it like `insert_spec` or `reload` write-lock it.
+///
+/// A poisoned lock (a panic while it was held) is ignored, as any
+/// modifications are done through methods which either fully succeed
+/// or fail with an error.
+///
+/// The render timeout (see `set_render_timeout`) is not shared, each
+/// clone starts with the timeout of the engine it was cloned from. It's
+/// only applied by `use_template_with_timeout`, not by the `TemplateEngine`
+/// implementation.
+#[derive(Debug)]
+pub struct SharedRenderTemplateEngine<R>
+    where R: RenderEngineBase
+{
+    inner: Arc<RwLock<RenderTemplateEngine<R>>>,
+    render_timeout: Option<Duration>
+}
+
+impl<R> SharedRenderTemplateEngine<R>
+    where R: RenderEngineBase
+{
+    pub fn new(engine: RenderTemplateEngine<R>) -> Self {
+        SharedRenderTemplateEngine {
+            inner: Arc::new(RwLock::new(engine)),
+            render_timeout: None
+        }
+    }
+
+    /// sets the maximal time rendering a template with `use_template_with_timeout` may take
+    ///
+    /// This guards against templates which take (nearly) forever to render,
+    /// e.g. because of a loop over huge user controlled data. As rendering
+    /// then has to happen on a separate thread, which requires owned data,
+    /// the timeout is **only** applied by `use_template_with_timeout`, see
+    /// there for details. `use_template` (of this and the wrapped engine)
+    /// never times out.
+    ///
+    /// By default there is no render timeout.
+    pub fn set_render_timeout(&mut self, timeout: Option<Duration>) {
+        self.render_timeout = timeout
+    }
+
+    /// returns the render timeout, see `set_render_timeout`
+    pub fn render_timeout(&self) -> Option<Duration> {
+        self.render_timeout
+    }
+
+    /// read-locks the engine
+    pub fn read(&self) -> RwLockReadGuard<RenderTemplateEngine<R>> {
+        self.inner.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// write-locks the engine
+    pub fn write(&self) -> RwLockWriteGuard<RenderTemplateEngine<R>> {
+        self.inner.write().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// write-locks the engine and inserts the spec, see `RenderTemplateEngine::insert_spec`
+    pub fn insert_spec(
+        &self,
+        id: String,
+        spec: TemplateSpec
+    ) -> Result<Option<TemplateSpec>, InsertionError<R::LoadingError>> {
+        self.write().insert_spec(id, spec)
+    }
+
+    /// write-locks the engine and removes the spec, see `RenderTemplateEngine::remove_spec`
+    pub fn remove_spec(&self, id: &str) -> Option<TemplateSpec> {
+        self.write().remove_spec(id)
+    }
+
+    /// write-locks the engine and (re-)loads all templates in `templates_dir`
+    ///
+    /// Specs with an id which is already used replace the existing spec,
+    /// see `RenderTemplateEngine::load_templates`. As the engine is locked
+    /// for the whole time rendering is blocked until all specs are loaded.
+    #[cfg(feature="fs")]
+    pub fn reload(
+        &self,
+        templates_dir: impl AsRef<Path>,
+        settings: &LoadSpecSettings
+    ) -> Result<(), LoadingError<R::LoadingError>> {
+        self.write().load_templates(templates_dir, settings)
+    }
+
+    /// like `use_template` but fails if rendering takes longer than the render timeout
+    ///
+    /// If a render timeout is set (see `set_render_timeout`)
+    /// the template is rendered on a new thread and a `TimedOut` error is
+    /// returned if it doesn't finish in time. Render engines can not be
+    /// cancelled mid-render, so on a timeout the thread keeps running (and
+    /// keeps the engine read-locked) until the render completes, the only
+    /// guarantee is that the caller isn't blocked longer than the timeout.
+    /// As the thread can outlive the call the data and context are taken
+    /// by value.
+    ///
+    /// Without a render timeout the template is rendered on the current thread.
+    pub fn use_template_with_timeout<C, D>(
+        &self,
+        template_id: &str,
+        data: D,
+        ctx: C
+    ) -> Result<MailParts, RenderTimeoutError<R::RenderError>>
+        where C: Context, D: Send + 'static, R: RenderEngine<D> + Send + Sync + 'static
+    {
+        let timeout =
+            match self.render_timeout {
+                Some(timeout) => timeout,
+                None => return self.read().use_template(template_id, &data, &ctx)
+                    .map_err(RenderTimeoutError::Render)
+            };
+
+        let engine = self.clone();
+        let template_id = template_id.to_owned();
+        let (sender, receiver) = mpsc::channel();
+        let handle = thread::spawn(move || {
+            let res = engine.read().use_template(&*template_id, &data, &ctx);
+            // the receiver is gone if the render timed out
+            let _ = sender.send(res);
+        });
+
+        match receiver.recv_timeout(timeout) {
+            Ok(res) => res.map_err(RenderTimeoutError::Render),
+            Err(RecvTimeoutError::Timeout) => Err(RenderTimeoutError::TimedOut { timeout }),
+            Err(RecvTimeoutError::Disconnected) => {
+                // the sender is only dropped without sending if rendering panicked
+                match handle.join() {
+                    Err(panic) => panic::resume_unwind(panic),
+                    Ok(()) => unreachable!("[BUG] render thread finished without sending a result")
+                }
+            }
+        }
+    }
+}
+
+impl<R> Clone for SharedRenderTemplateEngine<R>
+    where R: RenderEngineBase
+{
+    fn clone(&self) -> Self {
+        SharedRenderTemplateEngine {
+            inner: self.inner.clone(),
+            render_timeout: self.render_timeout
+        }
+    }
+}
+
+impl<R> From<RenderTemplateEngine<R>> for SharedRenderTemplateEngine<R>
+    where R: RenderEngineBase
+{
+    fn from(engine: RenderTemplateEngine<R>) -> Self {
+        SharedRenderTemplateEngine::new(engine)
+    }
+}
+
+/// renders on the current thread, i.e. without the render timeout
+///
+/// The data and context are only borrowed, so they can't be moved to a
+/// render thread, use `use_template_with_timeout` to apply the timeout.
+impl<C, D, R> TemplateEngine<C, D> for SharedRenderTemplateEngine<R>
+    where C: Context, R: RenderEngine<D>
+{
+    type TemplateId = str;
+    type Error = <R as RenderEngineBase>::RenderError;
+
+    fn use_template(
+        &self,
+        template_id: &str,
+        data: &D,
+        ctx: &C,
+    ) -> Result<MailParts, Self::Error>
+    {
+        self.read().use_template(template_id, data, ctx)
+    }
+}
@@ -0,0 +1,94 @@
+use std::path::PathBuf;
+use std::collections::HashMap;
+
+use vec1::Vec1;
+
+use mail::Resource;
+
+use ::error::{CreatingSpecError, CreatingSpecErrorVariant};
+use super::{TemplateSpec, SubTemplateSpec};
+
+/// A builder for a `TemplateSpec`, see `TemplateSpec::builder`.
+///
+/// The sub-templates, embeddings, attachments and base path are
+/// collected and validated together when calling `build`.
+#[derive(Debug, Default)]
+pub struct TemplateSpecBuilder {
+    templates: Vec<SubTemplateSpec>,
+    embeddings: Vec<(String, Resource)>,
+    attachments: Vec<Resource>,
+    base_path: Option<PathBuf>
+}
+
+impl TemplateSpecBuilder {
+
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// adds a sub-template (i.e. alternative body)
+    ///
+    /// Sub-templates keep the order in which they are added.
+    pub fn sub_spec(mut self, sub_spec: SubTemplateSpec) -> Self {
+        self.templates.push(sub_spec);
+        self
+    }
+
+    /// adds a template level (shared) embedding
+    pub fn embedding<N>(mut self, name: N, resource: Resource) -> Self
+        where N: Into<String>
+    {
+        self.embeddings.push((name.into(), resource));
+        self
+    }
+
+    /// adds a attachment which is always added if the template is used
+    ///
+    /// Attachments don't need unique names, see `TemplateSpec::attachments`.
+    pub fn attachment(mut self, resource: Resource) -> Self {
+        self.attachments.push(resource);
+        self
+    }
+
+    /// sets the base path of the spec
+    pub fn base_path<P>(mut self, base_path: P) -> Self
+        where P: Into<PathBuf>
+    {
+        self.base_path = Some(base_path.into());
+        self
+    }
+
+    /// creates the `TemplateSpec`
+    ///
+    /// # Error
+    ///
+    /// An error is returned if:
+    ///
+    /// - no sub-template was added (a spec needs at last one body)
+    /// - the base path is not valid UTF-8
+    /// - multiple embeddings with the same name were added
+    /// - multiple sub-templates (incl. variants) have sources with the same id
+    pub fn build(self) -> Result<TemplateSpec, CreatingSpecError> {
+        let TemplateSpecBuilder { templates, embeddings, attachments, base_path } = self;
+
+        let templates = Vec1::from_vec(templates)
+            .map_err(|_| CreatingSpecErrorVariant::NoSubTemplates)?;
+
+        let mut embedding_map = HashMap::with_capacity(embeddings.len());
+        for (name, resource) in embeddings {
+            if embedding_map.contains_key(&name) {
+                return Err(CreatingSpecErrorVariant::DuplicateEmbeddingName { name }.into());
+            }
+            embedding_map.insert(name, resource);
+        }
+
+        let mut spec =
+            if let Some(base_path) = base_path {
+                TemplateSpec::new_with_embeddings_and_base_path(templates, embedding_map, base_path)?
+            } else {
+                TemplateSpec::new_with_embeddings(templates, embedding_map)?
+            };
+        spec.attachments_mut().extend(attachments);
+        Ok(spec)
+    }
+}
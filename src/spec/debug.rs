@@ -0,0 +1,99 @@
+use std::collections::HashMap;
+use std::fmt::{self, Debug};
+
+use mail::Resource;
+
+use super::{TemplateSpec, SubTemplateSpec, TemplateSource};
+
+/// prints only metadata (ids, media types, embedding names, resource IRIs, source sizes)
+///
+/// Specs can contain (potentially large) loaded resources and template
+/// sources, which would make the output noisy and could leak their content
+/// into logs.
+impl Debug for TemplateSpec {
+    fn fmt(&self, fter: &mut fmt::Formatter) -> fmt::Result {
+        let header_templates = self.header_templates.iter()
+            .map(|&(ref name, ref sub_spec)| (name, DebugSource(sub_spec.source())))
+            .collect::<Vec<_>>();
+        let partials = self.partials.iter()
+            .map(|&(ref name, ref source)| (name, DebugSource(source)))
+            .collect::<Vec<_>>();
+
+        fter.debug_struct("TemplateSpec")
+            .field("base_path", &self.base_path)
+            .field("templates", &self.templates)
+            .field("embeddings", &DebugResources(&self.embeddings))
+            .field("embedding_dispositions", &self.embedding_dispositions)
+            .field("attachments", &self.attachments.iter().map(DebugResource).collect::<Vec<_>>())
+            .field("has_sample_data", &self.sample_data.is_some())
+            .field("header_templates", &header_templates)
+            .field("partials", &partials)
+            .finish()
+    }
+}
+
+/// prints only metadata, like the `Debug` implementation of `TemplateSpec`
+impl Debug for SubTemplateSpec {
+    fn fmt(&self, fter: &mut fmt::Formatter) -> fmt::Result {
+        let variants = self.variants.iter()
+            .map(|(key, source)| (key, DebugSource(source)))
+            .collect::<Vec<_>>();
+
+        fter.debug_struct("SubTemplateSpec")
+            .field("media_type", &self.media_type.as_str_repr())
+            .field("source", &DebugSource(&self.source))
+            .field("embeddings", &DebugResources(&self.embeddings))
+            .field("variants", &variants)
+            .field("embedding_dispositions", &self.embedding_dispositions)
+            .field("allow_empty", &self.allow_empty)
+            .field("has_render_defaults", &self.render_defaults.is_some())
+            .field("transfer_encoding", &self.transfer_encoding)
+            .finish()
+    }
+}
+
+/// prints the id of the source and the size of it's content (if it's not a path)
+struct DebugSource<'a>(&'a TemplateSource);
+
+impl<'a> Debug for DebugSource<'a> {
+    fn fmt(&self, fter: &mut fmt::Formatter) -> fmt::Result {
+        match *self.0 {
+            TemplateSource::Path(ref path) => fter.debug_tuple("Path").field(path).finish(),
+            TemplateSource::Source { ref id, ref content } => {
+                fter.debug_struct("Source")
+                    .field("id", id)
+                    .field("content_len", &content.len())
+                    .finish()
+            }
+        }
+    }
+}
+
+/// prints the IRI and name of the resources source, but never it's content
+struct DebugResource<'a>(&'a Resource);
+
+impl<'a> Debug for DebugResource<'a> {
+    fn fmt(&self, fter: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(source) = self.0.source() {
+            fter.debug_struct("Resource")
+                .field("iri", &source.iri.as_str())
+                .field("use_name", &source.use_name)
+                .finish()
+        } else {
+            fter.write_str("Resource(<sourceless>)")
+        }
+    }
+}
+
+/// prints the resources sorted by name
+struct DebugResources<'a>(&'a HashMap<String, Resource>);
+
+impl<'a> Debug for DebugResources<'a> {
+    fn fmt(&self, fter: &mut fmt::Formatter) -> fmt::Result {
+        let mut resources = self.0.iter().collect::<Vec<_>>();
+        resources.sort_by(|a, b| a.0.cmp(b.0));
+        fter.debug_map()
+            .entries(resources.into_iter().map(|(name, resource)| (name, DebugResource(resource))))
+            .finish()
+    }
+}
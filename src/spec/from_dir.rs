@@ -1,135 +1,831 @@
 use std::path::{Path, PathBuf};
-use std::collections::HashMap;
-use std::fs::DirEntry;
+use std::collections::{HashMap, BTreeMap, HashSet};
+use std::io;
+use std::ffi::OsString;
+use std::time::SystemTime;
+use std::mem::replace;
 
+use failure::Fail;
+use glob::glob;
 use vec1::Vec1;
+use serde_json::{self, Value};
 
 use mail::context::Source;
-use mail::{Resource, IRI};
+use mail::Resource;
 
 use ::error::{CreatingSpecError, CreatingSpecErrorVariant};
-use ::utils::{new_string_path, new_str_path};
-use ::{TemplateSpec, SubTemplateSpec};
-use ::settings::{LoadSpecSettings, Type};
+use ::utils::new_string_path;
+
+use ::{TemplateSpec, SubTemplateSpec, TemplateSource};
+use ::settings::{LoadSpecSettings, Type, InputEncoding};
+use super::template_fs::{TemplateFs, TemplateFsEntry, StdFs};
+use super::{check_unique_template_ids, iri_from_path};
 
 //TODO missing global template level embeddings
 
+fn std_fs(settings: &LoadSpecSettings) -> StdFs {
+    StdFs::new(settings.follow_symlinks())
+}
+
 pub(crate) fn from_dirs(
     templates_dir: &Path,
     settings: &LoadSpecSettings
 ) -> Result<Vec<(String, TemplateSpec)>, CreatingSpecError>
 {
-    let mut specs = Vec::new();
-    for entry in templates_dir.read_dir()? {
-        let entry = entry?;
-        if entry.metadata()?.is_dir() {
-            let id = entry.file_name()
-                .into_string()
-                .map_err(|file_name| CreatingSpecErrorVariant::NonStringPath(file_name.into()))?;
+    from_fs_dirs(&std_fs(settings), templates_dir, settings, true)
+}
 
-            specs.push((id, TemplateSpec::from_dir(entry.path(), settings)?));
+/// `from_dirs` for any `TemplateFs`
+///
+/// If `on_std_fs` is true the specs keep their `base_path` and remember the
+/// state of their files so that they can be reloaded with `reload_changed`.
+pub(crate) fn from_fs_dirs(
+    fs: &TemplateFs,
+    templates_dir: &Path,
+    settings: &LoadSpecSettings,
+    on_std_fs: bool
+) -> Result<Vec<(String, TemplateSpec)>, CreatingSpecError>
+{
+    if settings.recursive() {
+        from_dirs_with_id_fn(fs, templates_dir, settings, on_std_fs,
+            &mut |dir| id_from_relative_path(templates_dir, dir))
+    } else {
+        from_dirs_with_id_fn(fs, templates_dir, settings, on_std_fs, &mut id_from_dir_name)
+    }
+}
+
+pub(crate) fn from_dirs_with_id_fn(
+    fs: &TemplateFs,
+    templates_dir: &Path,
+    settings: &LoadSpecSettings,
+    on_std_fs: bool,
+    id_fn: &mut FnMut(&Path) -> Result<String, CreatingSpecError>
+) -> Result<Vec<(String, TemplateSpec)>, CreatingSpecError>
+{
+    let mut specs = Vec::new();
+    // the template dir of each id, used to detect duplicate ids
+    let mut id_dirs = Vec::new();
+    {
+        let recording_id_fn = &mut |dir: &Path| -> Result<String, CreatingSpecError> {
+            let id = id_fn(dir)?;
+            id_dirs.push((id.clone(), dir.to_owned()));
+            Ok(id)
+        };
+        if settings.recursive() {
+            let mut visited_dirs = HashSet::new();
+            collect_nested_specs(fs, templates_dir, settings, on_std_fs, recording_id_fn, &mut visited_dirs, &mut specs)?;
+        } else {
+            for entry in sorted_entries(fs, templates_dir, settings)? {
+                if entry.is_dir() {
+                    let path = templates_dir.join(entry.name());
+                    if is_disabled(fs, &path, settings)? {
+                        continue;
+                    }
+                    let id = recording_id_fn(&path)?;
+                    specs.push((id, from_fs_dir(fs, &path, settings, on_std_fs)?));
+                }
+            }
         }
     }
+    check_unique_template_ids(id_dirs.iter().map(|&(ref id, ref dir)| (id.as_str(), Some(dir.as_path()))))?;
     Ok(specs)
 }
 
+/// true if the dir is disabled by the disabled marker, see `set_disabled_marker`
+///
+/// The entries are read directly as the marker normally matches the
+/// ignore patterns (it's a dotfile).
+fn is_disabled(fs: &TemplateFs, dir: &Path, settings: &LoadSpecSettings) -> Result<bool, io::Error> {
+    let marker = match settings.disabled_marker() {
+        Some(marker) => marker,
+        None => return Ok(false)
+    };
+    let name_is_marked = dir.file_name()
+        .map(|name| name.to_string_lossy().ends_with(marker))
+        .unwrap_or(false);
+    let disabled = name_is_marked || settings.retry_io(|| fs.read_dir(dir))?
+        .iter()
+        .any(|entry| !entry.is_dir() && entry.name().to_string_lossy() == marker);
+    if disabled {
+        info!("skipping disabled template dir: {}", dir.display());
+    }
+    Ok(disabled)
+}
+
+/// what a directory is when looking for templates recursively
+enum DirKind {
+    /// contains folders named like registered types, so it's loaded with `from_dir`
+    WithTypeDirs,
+    /// directly contains body files
+    WithBodyFiles(Vec<PathBuf>),
+    /// a directory only used to organize templates
+    Organizational
+}
+
+fn dir_kind(fs: &TemplateFs, dir: &Path, settings: &LoadSpecSettings) -> Result<DirKind, CreatingSpecError> {
+    let mut body_files = Vec::new();
+    for entry in sorted_entries(fs, dir, settings)? {
+        let file_name = entry.name().to_string_lossy();
+        if entry.is_dir() {
+            if !settings.is_partials_dir_name(&*file_name) && settings.get_type(&*file_name).is_some() {
+                return Ok(DirKind::WithTypeDirs);
+            }
+        } else if settings.is_template_file_name(&*file_name) {
+            body_files.push(dir.join(entry.name()));
+        }
+    }
+    if body_files.is_empty() {
+        Ok(DirKind::Organizational)
+    } else {
+        Ok(DirKind::WithBodyFiles(body_files))
+    }
+}
+
+fn collect_nested_specs(
+    fs: &TemplateFs,
+    dir: &Path,
+    settings: &LoadSpecSettings,
+    on_std_fs: bool,
+    id_fn: &mut FnMut(&Path) -> Result<String, CreatingSpecError>,
+    visited_dirs: &mut HashSet<PathBuf>,
+    specs: &mut Vec<(String, TemplateSpec)>
+) -> Result<(), CreatingSpecError>
+{
+    // prevents endless loops with symlink cycles
+    if settings.follow_symlinks() && !visited_dirs.insert(fs.canonicalize(dir)?) {
+        return Ok(());
+    }
+    for entry in sorted_entries(fs, dir, settings)? {
+        if !entry.is_dir() {
+            continue;
+        }
+        let path = dir.join(entry.name());
+        if is_disabled(fs, &path, settings)? {
+            continue;
+        }
+        match dir_kind(fs, &path, settings)? {
+            DirKind::WithTypeDirs => {
+                let id = id_fn(&path)?;
+                specs.push((id, from_fs_dir(fs, &path, settings, on_std_fs)?));
+            },
+            DirKind::WithBodyFiles(body_files) => {
+                let id = id_fn(&path)?;
+                let mut spec = from_body_files(fs, &path, body_files, settings)?;
+                if !on_std_fs {
+                    spec.base_path = None;
+                }
+                specs.push((id, spec));
+            },
+            DirKind::Organizational => {
+                collect_nested_specs(fs, &path, settings, on_std_fs, id_fn, visited_dirs, specs)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// the template id for nested templates, the relative path joined with `/`
+fn id_from_relative_path(templates_dir: &Path, dir: &Path) -> Result<String, CreatingSpecError> {
+    let rel_path = dir.strip_prefix(templates_dir)
+        .map_err(|_| CreatingSpecErrorVariant::NonStringPath(dir.into()))?;
+
+    let mut parts = Vec::new();
+    for part in rel_path.iter() {
+        parts.push(part.to_str()
+            .ok_or_else(|| CreatingSpecErrorVariant::NonStringPath(dir.into()))?);
+    }
+    Ok(parts.join("/"))
+}
+
+/// the default template id, the name of the template dir
+fn id_from_dir_name(dir: &Path) -> Result<String, CreatingSpecError> {
+    let file_name = dir.file_name()
+        .ok_or_else(|| CreatingSpecErrorVariant::NonStringPath(dir.into()))?;
+
+    file_name.to_owned()
+        .into_string()
+        .map_err(|file_name| CreatingSpecErrorVariant::NonStringPath(file_name.into()).into())
+}
+
 pub(crate) fn from_dir(base_path: &Path, settings: &LoadSpecSettings) -> Result<TemplateSpec, CreatingSpecError> {
+    from_fs_dir(&std_fs(settings), base_path, settings, true)
+}
+
+/// `from_dir` for any `TemplateFs`, see `from_fs_dirs` for `on_std_fs`
+pub(crate) fn from_fs_dir(
+    fs: &TemplateFs,
+    base_path: &Path,
+    settings: &LoadSpecSettings,
+    on_std_fs: bool
+) -> Result<TemplateSpec, CreatingSpecError>
+{
+    // taken before reading anything so that changes done while loading are not missed
+    let load_state =
+        if on_std_fs {
+            Some(LoadState::of_dir(base_path, settings)?)
+        } else {
+            None
+        };
     let mut glob_embeddings = HashMap::new();
     let mut sub_template_dirs = Vec::new();
-    for folder in base_path.read_dir()? {
-        let entry = folder?;
-        if entry.file_type()?.is_dir() {
-            let type_name = entry.file_name()
-                .into_string().map_err(|_| CreatingSpecErrorVariant::NonStringPath(entry.path().into()))?;
+    for entry in sorted_entries(fs, base_path, settings)? {
+        let path = base_path.join(entry.name());
+        if entry.is_dir() && settings.is_partials_dir_name(&*entry.name().to_string_lossy()) {
+            // loaded by find_partials
+            continue;
+        } else if entry.is_dir() {
+            let type_name = entry.name().clone()
+                .into_string().map_err(|_| CreatingSpecErrorVariant::NonStringPath(path.clone().into()))?;
             let (prio, type_) = settings.get_type_with_priority(&*type_name)
                 .ok_or_else(|| CreatingSpecErrorVariant::MissingTypeInfo { type_name: type_name.clone() })?;
-            sub_template_dirs.push((prio, entry.path(), type_));
-        } else {
-            let (name, resource_spec) = embedding_from_path(entry.path(), settings)?;
+            sub_template_dirs.push((prio, path, type_));
+        } else if !is_sample_data_file(&entry, settings) {
+            let (name, resource_spec) = embedding_from_path(fs, path, settings)?;
             glob_embeddings.insert(name, resource_spec);
         }
     }
 
+    // the sort is stable so dirs with the same priority stay sorted by name
     sub_template_dirs.sort_by_key(|data| data.0);
 
     let mut sub_specs = Vec::with_capacity(sub_template_dirs.len());
     for (_, dir_path, type_) in sub_template_dirs {
-        sub_specs.push(sub_template_from_dir(&*dir_path, type_, settings)?);
+        if let Some(sub_spec) = sub_template_from_dir(fs, &*dir_path, type_, settings)? {
+            sub_specs.push(sub_spec);
+        }
     }
 
     let sub_specs = Vec1::from_vec(sub_specs)
         .map_err(|_| CreatingSpecErrorVariant::NoSubTemplatesFound { dir: base_path.into() })?;
-    TemplateSpec::new_with_embeddings_and_base_path(
-        sub_specs, glob_embeddings, base_path.to_owned())
+    let mut spec = TemplateSpec::new_with_embeddings_and_base_path(
+        sub_specs, glob_embeddings, base_path.to_owned())?;
+    if !on_std_fs {
+        spec.base_path = None;
+    }
+    spec.load_state = load_state;
+    spec.sample_data = find_sample_data(fs, base_path, settings)?;
+    spec.partials = find_partials(fs, base_path, settings)?;
+    Ok(spec)
+}
+
+/// modification time and size of a file, used to detect changes
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct FileStamp {
+    modified: Option<SystemTime>,
+    len: u64
+}
+
+/// the `FileStamp`s of all files in a template dir (including sub-dirs)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct LoadState {
+    files: BTreeMap<PathBuf, FileStamp>
+}
+
+impl LoadState {
+
+    fn of_dir(dir: &Path, settings: &LoadSpecSettings) -> Result<Self, CreatingSpecError> {
+        let mut files = BTreeMap::new();
+        let mut visited_dirs = HashSet::new();
+        collect_file_stamps(dir, settings, &mut visited_dirs, &mut files)?;
+        Ok(LoadState { files })
+    }
+
+    /// returns the names of all entries in `base_path` which are or contain changed files
+    fn changed_top_level_entries(&self, other: &LoadState, base_path: &Path) -> HashSet<OsString> {
+        let mut changed = HashSet::new();
+        let in_self = self.files.iter()
+            .filter(|&(path, stamp)| other.files.get(path) != Some(stamp))
+            .map(|(path, _)| path);
+        let only_in_other = other.files.keys()
+            .filter(|path| !self.files.contains_key(*path));
+
+        for path in in_self.chain(only_in_other) {
+            let top_level = path.strip_prefix(base_path).ok()
+                .and_then(|rel_path| rel_path.iter().next());
+            if let Some(name) = top_level {
+                changed.insert(name.to_owned());
+            }
+        }
+        changed
+    }
+}
+
+fn collect_file_stamps(
+    dir: &Path,
+    settings: &LoadSpecSettings,
+    visited_dirs: &mut HashSet<PathBuf>,
+    files: &mut BTreeMap<PathBuf, FileStamp>
+) -> Result<(), CreatingSpecError>
+{
+    // prevents endless loops with symlink cycles
+    if settings.follow_symlinks() && !visited_dirs.insert(dir.canonicalize()?) {
+        return Ok(());
+    }
+    for entry in sorted_entries(&std_fs(settings), dir, settings)? {
+        let path = dir.join(entry.name());
+        if entry.is_dir() {
+            collect_file_stamps(&path, settings, visited_dirs, files)?;
+        } else {
+            let metadata =
+                if settings.follow_symlinks() {
+                    path.metadata()?
+                } else {
+                    path.symlink_metadata()?
+                };
+            let stamp = FileStamp {
+                modified: metadata.modified().ok(),
+                len: metadata.len()
+            };
+            files.insert(path, stamp);
+        }
+    }
+    Ok(())
+}
+
+pub(crate) fn reload_changed(spec: &mut TemplateSpec, settings: &LoadSpecSettings)
+    -> Result<bool, CreatingSpecError>
+{
+    let base_path =
+        if let Some(base_path) = spec.base_path() {
+            base_path.to_owned()
+        } else {
+            return Ok(false);
+        };
+
+    let new_state = LoadState::of_dir(&base_path, settings)?;
+
+    let changed_entries =
+        if let Some(old_state) = spec.load_state.as_ref() {
+            if *old_state == new_state {
+                return Ok(false);
+            }
+            Some(old_state.changed_top_level_entries(&new_state, &base_path))
+        } else {
+            None
+        };
+
+    let sub_spec_updates = changed_entries
+        .and_then(|changed| changed_sub_spec_indices(spec, &base_path, changed));
+
+    let new_sub_specs =
+        if let Some(updates) = sub_spec_updates {
+            reload_sub_specs(&base_path, updates, settings)?
+        } else {
+            None
+        };
+
+    if let Some(new_sub_specs) = new_sub_specs {
+        for (idx, mut sub_spec) in new_sub_specs {
+            carry_over_sub_spec_state(&mut spec.templates[idx], &mut sub_spec);
+            spec.templates[idx] = sub_spec;
+        }
+        spec.load_state = Some(new_state);
+    } else {
+        let mut new_spec = from_dir(&base_path, settings)?;
+        new_spec.attachments = replace(&mut spec.attachments, Vec::new());
+        new_spec.embedding_dispositions = replace(&mut spec.embedding_dispositions, HashMap::new());
+        new_spec.header_templates = replace(&mut spec.header_templates, Vec::new());
+        let overlay = replace(&mut spec.overlay, Default::default());
+        overlay.apply(&mut new_spec);
+        new_spec.overlay = overlay;
+        for new_sub_spec in new_spec.templates.iter_mut() {
+            let media_type = new_sub_spec.media_type().clone();
+            if let Some(old_sub_spec) = spec.sub_spec_for_media_type_mut(&media_type) {
+                carry_over_sub_spec_state(old_sub_spec, new_sub_spec);
+            }
+        }
+        *spec = new_spec;
+    }
+    Ok(true)
+}
+
+/// moves the state which is only set programmatically from the old to the reloaded sub-spec
+///
+/// The media type (which has the same type and subtype) is kept as parameters
+/// might have been set on it, e.g. through `set_format_flowed`. The overlay
+/// (embeddings and names set through the setters) is reapplied.
+fn carry_over_sub_spec_state(old: &mut SubTemplateSpec, new: &mut SubTemplateSpec) {
+    new.overlay = replace(&mut old.overlay, Default::default());
+    new.overlay.apply(&mut new.embeddings);
+    new.media_type = old.media_type.clone();
+    new.embedding_dispositions = replace(&mut old.embedding_dispositions, HashMap::new());
+    new.variants = replace(&mut old.variants, BTreeMap::new());
+    new.render_defaults = old.render_defaults.take();
+    new.allow_empty = old.allow_empty;
+    new.transfer_encoding = old.transfer_encoding;
+}
+
+/// loads the sub-specs from the changed dirs
+///
+/// Returns `None` if a dir of an optional type no longer has a template
+/// file, in which case the whole spec has to be reloaded.
+fn reload_sub_specs(base_path: &Path, updates: Vec<(usize, OsString)>, settings: &LoadSpecSettings)
+    -> Result<Option<Vec<(usize, SubTemplateSpec)>>, CreatingSpecError>
+{
+    let mut sub_specs = Vec::with_capacity(updates.len());
+    for (idx, dir_name) in updates {
+        let type_name = new_string_path(&dir_name)?;
+        let type_ = settings.get_type(&type_name)
+            .ok_or_else(|| CreatingSpecErrorVariant::MissingTypeInfo { type_name: type_name.clone() })?;
+        let dir = base_path.join(&dir_name);
+        match sub_template_from_dir(&std_fs(settings), &dir, type_, settings)? {
+            Some(sub_spec) => sub_specs.push((idx, sub_spec)),
+            None => return Ok(None)
+        }
+    }
+    Ok(Some(sub_specs))
+}
+
+/// maps the changed entries to the indices of the sub-specs loaded from them
+///
+/// Returns `None` if any of the changed entries is not the dir of an
+/// existing sub-spec (e.g. a shared embedding changed or a new
+/// sub-template dir was added) in which case the whole spec has to
+/// be reloaded.
+fn changed_sub_spec_indices(
+    spec: &TemplateSpec,
+    base_path: &Path,
+    changed: HashSet<OsString>
+) -> Option<Vec<(usize, OsString)>> {
+    // e.g. the base path was changed since loading
+    if changed.is_empty() {
+        return None;
+    }
+    let mut updates = Vec::with_capacity(changed.len());
+    for dir_name in changed {
+        let dir = base_path.join(&dir_name);
+        if !dir.is_dir() {
+            return None;
+        }
+        let idx = spec.sub_specs().iter().position(|sub_spec| {
+            match *sub_spec.source() {
+                TemplateSource::Path(ref path) => Path::new(path).parent() == Some(&*dir),
+                _ => false
+            }
+        })?;
+        updates.push((idx, dir_name));
+    }
+    Some(updates)
+}
+
+
+pub(crate) fn from_glob(
+    pattern: &str,
+    settings: &LoadSpecSettings
+) -> Result<Vec<(String, TemplateSpec)>, CreatingSpecError>
+{
+    let paths = glob(pattern)
+        .map_err(|err| err.context(CreatingSpecErrorVariant::InvalidGlobPattern {
+            pattern: pattern.to_owned()
+        }))?;
+
+    // template dir => body files, a BTreeMap is used to have a stable output order
+    let mut body_files_by_template_dir = BTreeMap::new();
+    for path in paths {
+        let path = path.map_err(|err| err.context(CreatingSpecErrorVariant::IoError))?;
+        let is_ignored = path.file_name()
+            .map(|name| settings.is_ignored(&*name.to_string_lossy()))
+            .unwrap_or(false);
+        let in_partials_dir = path.parent()
+            .and_then(|parent| parent.file_name())
+            .map(|name| settings.is_partials_dir_name(&*name.to_string_lossy()))
+            .unwrap_or(false);
+        if is_ignored || in_partials_dir || !path.is_file() {
+            continue;
+        }
+        let template_dir = template_dir_of_body_file(&path, settings)?;
+        body_files_by_template_dir.entry(template_dir)
+            .or_insert_with(Vec::new)
+            .push(path);
+    }
+
+    let mut specs = Vec::with_capacity(body_files_by_template_dir.len());
+    for (template_dir, body_files) in body_files_by_template_dir {
+        let id = new_string_path(template_dir.file_name()
+            .ok_or_else(|| CreatingSpecErrorVariant::NoTemplateDir { file: template_dir.clone().into() })?)?;
+        specs.push((id, from_body_files(&std_fs(settings), &template_dir, body_files, settings)?));
+    }
+    Ok(specs)
 }
 
+/// returns the template dir a body file belongs to
+///
+/// If the body file is in a folder named like a type registered in
+/// the settings (e.g. `welcome/html/mail.html`) the parent of that
+/// folder is the template dir (`welcome`), else the folder containing
+/// the body file is the template dir (e.g. `welcome/mail.html`).
+fn template_dir_of_body_file(file: &Path, settings: &LoadSpecSettings)
+    -> Result<PathBuf, CreatingSpecError>
+{
+    let parent = file.parent()
+        .ok_or_else(|| CreatingSpecErrorVariant::NoTemplateDir { file: file.into() })?;
+
+    let is_type_dir = parent.file_name()
+        .and_then(|name| name.to_str())
+        .map(|name| settings.get_type(name).is_some())
+        .unwrap_or(false);
+
+    let template_dir =
+        if is_type_dir {
+            parent.parent()
+                .ok_or_else(|| CreatingSpecErrorVariant::NoTemplateDir { file: file.into() })?
+        } else {
+            parent
+        };
 
-fn sub_template_from_dir(dir: &Path, type_: &Type, settings: &LoadSpecSettings)
-    -> Result<SubTemplateSpec, CreatingSpecError>
+    if template_dir.file_name().is_none() {
+        return Err(CreatingSpecErrorVariant::NoTemplateDir { file: file.into() }.into());
+    }
+    Ok(template_dir.to_owned())
+}
+
+fn from_body_files(
+    fs: &TemplateFs,
+    template_dir: &Path,
+    body_files: Vec<PathBuf>,
+    settings: &LoadSpecSettings
+) -> Result<TemplateSpec, CreatingSpecError>
 {
-    let FindResult { template_file, other_files:embeddings } = find_files(dir, settings)?;
+    let mut sub_specs = Vec::with_capacity(body_files.len());
+    for body_file in body_files {
+        // UNWRAP_SAFE: template_dir_of_body_file already made sure there is a parent
+        let body_dir = body_file.parent().unwrap().to_owned();
+        let (prio, type_) = type_for_body_file(&body_file, &body_dir, settings)?;
+        let media_type = type_.to_media_type_for(&body_file)?;
+        let embeddings =
+            if body_dir != template_dir {
+                find_embeddings(fs, &body_dir, settings)?
+            } else {
+                HashMap::new()
+            };
+        let source = template_source(fs, &body_file, type_, settings)?;
+        sub_specs.push((prio, SubTemplateSpec::new_with_template_source(source, media_type, embeddings)?));
+    }
+
+    sub_specs.sort_by_key(|data| data.0);
+    let sub_specs = sub_specs.into_iter()
+        .map(|(_, sub_spec)| sub_spec)
+        .collect();
+
+    let sub_specs = Vec1::from_vec(sub_specs)
+        .map_err(|_| CreatingSpecErrorVariant::NoSubTemplatesFound { dir: template_dir.into() })?;
+
+    let glob_embeddings = find_embeddings(fs, template_dir, settings)?;
+    let mut spec = TemplateSpec::new_with_embeddings_and_base_path(
+        sub_specs, glob_embeddings, template_dir.to_owned())?;
+    spec.sample_data = find_sample_data(fs, template_dir, settings)?;
+    spec.partials = find_partials(fs, template_dir, settings)?;
+    Ok(spec)
+}
+
+/// loads the files in the partials dir of the template dir (if there is one) as partials
+///
+/// The name of a partial is the file name without it's extension.
+fn find_partials(fs: &TemplateFs, template_dir: &Path, settings: &LoadSpecSettings)
+    -> Result<Vec<(String, TemplateSource)>, CreatingSpecError>
+{
+    let dir_name =
+        match settings.partials_dir_name() {
+            Some(dir_name) => dir_name,
+            None => return Ok(Vec::new())
+        };
+    let has_partials_dir = sorted_entries(fs, template_dir, settings)?.iter()
+        .any(|entry| entry.is_dir() && entry.name() == dir_name);
+    if !has_partials_dir {
+        return Ok(Vec::new());
+    }
+
+    let dir = template_dir.join(dir_name);
+    let mut partials: Vec<(String, TemplateSource)> = Vec::new();
+    for entry in sorted_entries(fs, &dir, settings)? {
+        if entry.is_dir() {
+            continue;
+        }
+        let path = dir.join(entry.name());
+        let name = path.file_stem()
+            .and_then(|stem| stem.to_str())
+            .ok_or_else(|| CreatingSpecErrorVariant::NonStringPath(path.clone().into()))?
+            .to_owned();
+        if partials.iter().any(|&(ref existing, _)| *existing == name) {
+            return Err(CreatingSpecErrorVariant::DuplicatePartialName { name, dir: dir.into() }.into());
+        }
+        let source = fs.template_source(&path)?;
+        partials.push((name, source));
+    }
+    Ok(partials)
+}
+
+const SAMPLE_DATA_FILE_NAMES: &[&str] = &["preview.json", "sample.json"];
+
+/// true if the entry is a sample data file and loading sample data is enabled
+fn is_sample_data_file(entry: &TemplateFsEntry, settings: &LoadSpecSettings) -> bool {
+    settings.load_sample_data()
+        && entry.name().to_str()
+            .map(|name| SAMPLE_DATA_FILE_NAMES.contains(&name))
+            .unwrap_or(false)
+}
+
+/// loads the sample data in the template dir, if loading it is enabled
+fn find_sample_data(fs: &TemplateFs, template_dir: &Path, settings: &LoadSpecSettings)
+    -> Result<Option<Value>, CreatingSpecError>
+{
+    if !settings.load_sample_data() {
+        return Ok(None);
+    }
+    let entries = settings.retry_io(|| fs.read_dir(template_dir))?;
+    for name in SAMPLE_DATA_FILE_NAMES {
+        let is_file = entries.iter()
+            .any(|entry| !entry.is_dir() && entry.name() == *name);
+        if is_file {
+            let path = template_dir.join(name);
+            let content = settings.retry_io(|| fs.read_file(&path))?;
+            let data = serde_json::from_slice(&content)
+                .map_err(|err| err.context(CreatingSpecErrorVariant::InvalidSampleData {
+                    file: path.into()
+                }))?;
+            return Ok(Some(data));
+        }
+    }
+    Ok(None)
+}
+
+/// returns the type for a body file
+///
+/// If the body file is in a folder named like a registered type
+/// that type is used (as in `from_dir`), else the type is inferred
+/// from the suffix of the body file.
+fn type_for_body_file<'a>(file: &Path, body_dir: &Path, settings: &'a LoadSpecSettings)
+    -> Result<(usize, &'a Type), CreatingSpecError>
+{
+    let by_dir_name = body_dir.file_name()
+        .and_then(|name| name.to_str())
+        .and_then(|name| settings.get_type_with_priority(name));
+
+    if let Some(found) = by_dir_name {
+        return Ok(found);
+    }
+
+    file.extension()
+        .and_then(|suffix| suffix.to_str())
+        .and_then(|suffix| settings.get_type_for_suffix(suffix))
+        .ok_or_else(|| CreatingSpecErrorVariant::NoTypeForBodyFile { file: file.into() }.into())
+}
+
+/// creates embeddings for all files in the dir, ignoring template files and sub-dirs
+fn find_embeddings(fs: &TemplateFs, in_dir: &Path, settings: &LoadSpecSettings)
+    -> Result<HashMap<String, Resource>, CreatingSpecError>
+{
+    let mut embeddings = HashMap::new();
+    for entry in sorted_entries(fs, in_dir, settings)? {
+        if entry.is_dir()
+            || is_template_file(&entry, settings)
+            || is_sample_data_file(&entry, settings)
+        {
+            continue;
+        }
+        let (key, value) = embedding_from_path(fs, in_dir.join(entry.name()), settings)?;
+        insert_embedding(&mut embeddings, key, value)?;
+    }
+    Ok(embeddings)
+}
+
+fn insert_embedding(
+    embeddings: &mut HashMap<String, Resource>,
+    key: String,
+    value: Resource
+) -> Result<(), CreatingSpecError>
+{
+    use std::collections::hash_map::Entry::*;
+
+    match embeddings.entry(key) {
+        Occupied(oe) => {
+            Err(CreatingSpecErrorVariant::DuplicateEmbeddingName { name: oe.key().clone() }.into())
+        },
+        Vacant(ve) => {
+            ve.insert(value);
+            Ok(())
+        }
+    }
+}
+
+/// creates the sub-template for the dir
+///
+/// Returns `None` if the type is optional and the dir has no template file.
+fn sub_template_from_dir(fs: &TemplateFs, dir: &Path, type_: &Type, settings: &LoadSpecSettings)
+    -> Result<Option<SubTemplateSpec>, CreatingSpecError>
+{
+    let FindResult { template_file, other_files:embeddings, file_names } = find_files(fs, dir, type_, settings)?;
+    let template_file =
+        match template_file {
+            Some(template_file) => template_file,
+            None if type_.is_optional() => {
+                warn!("skipping sub-template dir of optional type without template file: {}",
+                    dir.display());
+                return Ok(None);
+            },
+            None if file_names.is_empty() => {
+                return Err(CreatingSpecErrorVariant::EmptyTemplateDir { dir: dir.into() }.into());
+            },
+            None => {
+                return Err(CreatingSpecErrorVariant::TemplateFileMissing {
+                    dir: dir.into(),
+                    found_files: file_names
+                }.into());
+            }
+        };
     let media_type = type_.to_media_type_for(&template_file)?;
+    let source = template_source(fs, &template_file, type_, settings)?;
+
+    SubTemplateSpec::new_with_template_source(source, media_type, embeddings).map(Some)
+}
 
-    SubTemplateSpec::new(template_file, media_type, embeddings)
+/// creates the template source for a body file
+///
+/// If the type has a non UTF-8 input encoding the file is read and
+/// decoded here, as the render engines can only read UTF-8 files.
+fn template_source(fs: &TemplateFs, file: &Path, type_: &Type, settings: &LoadSpecSettings)
+    -> Result<TemplateSource, CreatingSpecError>
+{
+    match type_.input_encoding() {
+        InputEncoding::Utf8 => fs.template_source(file),
+        encoding => {
+            let bytes = settings.retry_io(|| fs.read_file(file))?;
+            Ok(TemplateSource::Source {
+                id: new_string_path(file)?,
+                content: encoding.decode(bytes)?
+            })
+        }
+    }
 }
 
 
-fn is_template_file(entry: &DirEntry) -> bool {
-    entry.file_name()
+/// returns the entries of the dir which are not ignored sorted by file name
+///
+/// The order in which `read_dir` returns entries depends on the platform
+/// and file system, sorting them makes loading specs deterministic.
+/// Entries are ignored if their name matches an ignore pattern of the settings.
+fn sorted_entries(fs: &TemplateFs, dir: &Path, settings: &LoadSpecSettings)
+    -> Result<Vec<TemplateFsEntry>, io::Error>
+{
+    let mut entries = settings.retry_io(|| fs.read_dir(dir))?;
+    entries.retain(|entry| !settings.is_ignored(&*entry.name().to_string_lossy()));
+    entries.sort_by(|a, b| a.name().cmp(b.name()));
+    Ok(entries)
+}
+
+/// true if the entry is a template file of any type
+fn is_template_file(entry: &TemplateFsEntry, settings: &LoadSpecSettings) -> bool {
+    entry.name()
         .to_str()
-        .map(|name| name.starts_with("mail."))
+        .map(|name| settings.is_template_file_name(name))
         .unwrap_or(false)
 }
 
 struct FindResult {
-    template_file: PathBuf,
+    template_file: Option<PathBuf>,
     other_files: HashMap<String, Resource>,
-
+    /// the (sorted) names of all files in the dir which are not ignored
+    file_names: Vec<String>
 }
 
-fn find_files(in_dir: &Path, settings: &LoadSpecSettings)
+/// finds the template file of the type and the embeddings in a sub-template dir
+///
+/// Files with the template base name but a suffix not accepted by the
+/// type (e.g. `mail.txt` in a `html` dir) are skipped with a warning.
+fn find_files(fs: &TemplateFs, in_dir: &Path, type_: &Type, settings: &LoadSpecSettings)
     -> Result<FindResult, CreatingSpecError>
 {
-    use std::collections::hash_map::Entry::*;
-
     let mut template_file = None;
     let mut other_files = HashMap::new();
-    for entry in in_dir.read_dir()? {
-        let entry = entry?;
-        if is_template_file(&entry) {
+    let mut file_names = Vec::new();
+    for entry in sorted_entries(fs, in_dir, settings)? {
+        file_names.push(entry.name().to_string_lossy().into_owned());
+        let path = in_dir.join(entry.name());
+        let name = entry.name().to_str().unwrap_or("");
+        if type_.is_template_file_name(name) {
             if template_file.is_none() {
-                template_file = Some(entry.path())
+                template_file = Some(path)
             } else {
                 return Err(CreatingSpecErrorVariant::MultipleTemplateFiles { dir: in_dir.into() }.into());
             }
+        } else if type_.has_template_base_name(name) {
+            warn!("skipping file with a suffix not accepted by the type: {}", path.display());
         } else {
-            let (key, value) = embedding_from_path(entry.path(), settings)?;
-             match other_files.entry(key) {
-                Occupied(oe) => {
-                    return Err(CreatingSpecErrorVariant::DuplicateEmbeddingName { name: oe.key().clone() }.into());
-                },
-                Vacant(ve) => {ve.insert(value);}
-            }
+            let (key, value) = embedding_from_path(fs, path, settings)?;
+            insert_embedding(&mut other_files, key, value)?;
         }
     }
 
-    if let Some(template_file) = template_file {
-        Ok(FindResult {
-            template_file,
-            other_files
-        })
-    } else {
-        Err(CreatingSpecErrorVariant::TemplateFileMissing { dir: in_dir.into() }.into())
-    }
+    Ok(FindResult {
+        template_file,
+        other_files,
+        file_names
+    })
 }
 
-fn embedding_from_path(path: PathBuf, settings: &LoadSpecSettings)
+fn embedding_from_path(fs: &TemplateFs, path: PathBuf, settings: &LoadSpecSettings)
                        -> Result<(String, Resource), CreatingSpecError>
 {
-    if !path.is_file() {
-        return Err(CreatingSpecErrorVariant::NotAFile(path.into()).into());
-    }
-
     let file_name = new_string_path(
         path.file_name()
         // UNWRAP_SAFE: file_name returns the file (,dir,symlink) name which
@@ -142,31 +838,37 @@ fn embedding_from_path(path: PathBuf, settings: &LoadSpecSettings)
         .unwrap()
         .to_owned();
 
-    //TODO we can remove the media type sniffing from here
-    let media_type = settings.determine_media_type(&path)?;
+    let resource = fs.resource(&path, settings)?;
+
+    Ok((name, resource))
+}
+
+pub(crate) fn resource_from_path(path: PathBuf, settings: &LoadSpecSettings)
+    -> Result<Resource, CreatingSpecError>
+{
+    // see `set_skip_embedding_checks`
+    let media_type =
+        if settings.skip_embedding_checks() {
+            None
+        } else {
+            if !path.is_file() {
+                return Err(CreatingSpecErrorVariant::NotAFile(path.into()).into());
+            }
+            Some(settings.determine_media_type(&path)?)
+        };
+
+    let use_name =
+        if settings.use_file_names() {
+            path.file_name().and_then(|name| name.to_str()).map(|name| name.to_owned())
+        } else {
+            None
+        };
 
     let source = Source {
         iri: iri_from_path(path)?,
-        use_name: None,
-        use_media_type: Some(media_type)
+        use_name,
+        use_media_type: media_type
     };
 
-    let resource = Resource::new(source);
-
-    Ok((name, resource))
+    Ok(Resource::new(source))
 }
-
-fn iri_from_path<IP: AsRef<Path> + Into<PathBuf>>(path: IP) -> Result<IRI, CreatingSpecError> {
-    {
-        let path_ref = path.as_ref();
-        if let Ok(strfy) = new_str_path(&path_ref) {
-            if let Ok(iri) = IRI::from_parts("path", strfy) {
-                return Ok(iri)
-            }
-        }
-    }
-    Err(CreatingSpecErrorVariant::IRIConstructionFailed {
-        scheme: "path",
-        tail: path.into().into()
-    }.into())
-}
\ No newline at end of file
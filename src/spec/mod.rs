@@ -1,17 +1,40 @@
 use std::path::{Path, PathBuf};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, BTreeMap};
 use std::mem::replace;
+use std::fmt::{self, Display};
+use std::io::{self, Read};
+#[cfg(feature="fs")]
+use std::convert::TryFrom;
 
 use vec1::Vec1;
+use serde_json::Value;
 
-use mail::Resource;
+use mail::{Resource, IRI};
+use mail::context::Source;
 use headers::components::MediaType;
 
-use ::error::CreatingSpecError;
-use ::utils::{new_string_path, check_string_path};
-use ::settings::LoadSpecSettings;
+use ::error::{CreatingSpecError, CreatingSpecErrorVariant, EncodingError};
+use ::utils::{
+    new_string_path, new_str_path, check_string_path, has_same_essence,
+    is_multipart, is_format_flowed, is_valid_media_type_param, encode_body
+};
+#[cfg(feature="fs")]
+use ::settings::{LoadSpecSettings, DEFAULT_SETTINGS};
+#[cfg(feature="fs")]
+use ::fetch::{is_url, resource_from_url};
 
+#[cfg(feature="fs")]
 mod from_dir;
+#[cfg(feature="fs")]
+mod template_fs;
+mod builder;
+mod debug;
+
+#[cfg(feature="fs")]
+use self::from_dir::LoadState;
+#[cfg(feature="fs")]
+pub use self::template_fs::{TemplateFs, TemplateFsEntry, StdFs, MemoryFs};
+pub use self::builder::TemplateSpecBuilder;
 
 /// A type representing a (mail) Template
 ///
@@ -29,7 +52,6 @@ mod from_dir;
 ///
 /// - It also has an optional `base_path` which is
 ///   the root folder it was loaded from using `from_dir`.
-#[derive(Debug)]
 pub struct TemplateSpec {
     /// the `base_path` which was used to construct the template from,
     /// e.g. with `TemplateSpec::from_dir` and which is used for reloading
@@ -38,8 +60,22 @@ pub struct TemplateSpec {
     templates: Vec1<SubTemplateSpec>,
     /// template level embeddings, i.e. embeddings shared between alternative bodies
     embeddings: HashMap<String, Resource>,
+    /// the dispositions of template level embeddings which are not inline
+    embedding_dispositions: HashMap<String, EmbeddingDisposition>,
     /// attachments to always add if this template is used
-    attachments: Vec<Resource>
+    attachments: Vec<Resource>,
+    /// the state of the files in `base_path` when loaded with `from_dir`,
+    /// used by `reload_changed`
+    #[cfg(feature="fs")]
+    load_state: Option<LoadState>,
+    /// data which can be used to render a preview of the template
+    sample_data: Option<Value>,
+    /// templates for header values by header name, rendered like text/plain bodies
+    header_templates: Vec<(String, SubTemplateSpec)>,
+    /// templates usable from the sub-templates (e.g. includes/partials) by name
+    partials: Vec<(String, TemplateSource)>,
+    /// the state set through the setters, reapplied by `reload_changed`
+    overlay: SpecOverlay
 }
 
 impl TemplateSpec {
@@ -52,8 +88,10 @@ impl TemplateSpec {
     /// name specifies the media type to use (through mapping it
     /// in settings e.g. `"text" -> "text/plain; charset=utf-8"`).
     ///
-    /// In each sub-folder it looks for a `mail.*` file and uses
-    /// it as the templates source code, any other file in it is
+    /// In each sub-folder it looks for a file named like the template
+    /// base name of the type followed by one of it's suffixes (by default
+    /// e.g. `mail.html` or `mail.htm` for `html`, see `Type::is_template_file_name`)
+    /// and uses it as the templates source code, any other file in it is
     /// used as an additional alt-body specific embedding.
     ///
     /// Additional files in the templates folder are interpreted
@@ -98,6 +136,7 @@ impl TemplateSpec {
     /// support names containing a ".".
     ///
     ///
+    #[cfg(feature="fs")]
     #[inline]
     pub fn from_dir<P>(base_path: P, settings: &LoadSpecSettings)
         -> Result<TemplateSpec, CreatingSpecError>
@@ -106,7 +145,23 @@ impl TemplateSpec {
         self::from_dir::from_dir(base_path.as_ref(), settings)
     }
 
+    /// `from_dir` using the `DEFAULT_SETTINGS`
+    #[cfg(feature="fs")]
+    #[inline]
+    pub fn from_dir_default<P>(base_path: P) -> Result<TemplateSpec, CreatingSpecError>
+        where P: AsRef<Path>
+    {
+        Self::from_dir(base_path, &*DEFAULT_SETTINGS)
+    }
+
     /// Derive a template from each dir in the dir specified by `templates_dir`
+    ///
+    /// If `settings.recursive()` is true sub-directories are searched for
+    /// templates, too (see `LoadSpecSettings::set_recursive`).
+    ///
+    /// If multiple template dirs have the same id a `DuplicateTemplateId`
+    /// error is returned.
+    #[cfg(feature="fs")]
     pub fn from_dirs<P>(templates_dir: P, settings: &LoadSpecSettings)
         -> Result<Vec<(String, TemplateSpec)>, CreatingSpecError>
         where P: AsRef<Path>
@@ -114,21 +169,162 @@ impl TemplateSpec {
         self::from_dir::from_dirs(templates_dir.as_ref(), settings)
     }
 
+    /// `from_dirs` using the `DEFAULT_SETTINGS`
+    #[cfg(feature="fs")]
+    #[inline]
+    pub fn from_dirs_default<P>(templates_dir: P)
+        -> Result<Vec<(String, TemplateSpec)>, CreatingSpecError>
+        where P: AsRef<Path>
+    {
+        Self::from_dirs(templates_dir, &*DEFAULT_SETTINGS)
+    }
+
+    /// Like `from_dirs` but derives the template ids using `id_fn`.
+    ///
+    /// `id_fn` is called with the path of each template dir, e.g. with
+    /// `|dir| format!("emails.{}", dir.file_name().unwrap().to_string_lossy())`
+    /// the template in `templates/welcome` would have the id `emails.welcome`.
+    /// (`from_dirs` uses the name of the template dir as id.)
+    #[cfg(feature="fs")]
+    pub fn from_dirs_with_id_fn<P, F>(templates_dir: P, settings: &LoadSpecSettings, id_fn: F)
+        -> Result<Vec<(String, TemplateSpec)>, CreatingSpecError>
+        where P: AsRef<Path>, F: Fn(&Path) -> String
+    {
+        let fs = StdFs::new(settings.follow_symlinks());
+        self::from_dir::from_dirs_with_id_fn(
+            &fs, templates_dir.as_ref(), settings, true, &mut |path| Ok(id_fn(path)))
+    }
+
+    /// Like `from_dir` but reads the files through the given `TemplateFs`.
+    ///
+    /// This allows loading templates which are not on the real file system,
+    /// e.g. a bundle compiled into the binary (see `MemoryFs`). The body files
+    /// and embeddings are read directly (through `TemplateFs::template_source`
+    /// and `TemplateFs::resource`), with the default implementation bodies
+    /// become `TemplateSource::Source` and embeddings contain the files content.
+    ///
+    /// The returned template has no `base_path`, so it can not be reloaded
+    /// using `reload_changed`.
+    #[cfg(feature="fs")]
+    pub fn from_fs_dir<P>(fs: &TemplateFs, base_path: P, settings: &LoadSpecSettings)
+        -> Result<TemplateSpec, CreatingSpecError>
+        where P: AsRef<Path>
+    {
+        self::from_dir::from_fs_dir(fs, base_path.as_ref(), settings, false)
+    }
+
+    /// Like `from_dirs` but reads the files through the given `TemplateFs`.
+    ///
+    /// See `from_fs_dir` for more details.
+    #[cfg(feature="fs")]
+    pub fn from_fs_dirs<P>(fs: &TemplateFs, templates_dir: P, settings: &LoadSpecSettings)
+        -> Result<Vec<(String, TemplateSpec)>, CreatingSpecError>
+        where P: AsRef<Path>
+    {
+        self::from_dir::from_fs_dirs(fs, templates_dir.as_ref(), settings, false)
+    }
+
+    /// Derive templates from all body files matching the given glob pattern.
+    ///
+    /// The matched body files are grouped into templates by their template dir.
+    /// If a body file is in a folder named like a type registered in the settings
+    /// (e.g. `templates/welcome/html/mail.html`) the parent of that folder is the
+    /// template dir, else the folder containing the body file is the template dir
+    /// (e.g. `templates/welcome/mail.html`). The name of the template dir is used
+    /// as the templates id/name.
+    ///
+    /// The type of a body file is taken from the name of it's folder if it's a
+    /// registered type, else it's inferred from the body files suffix (see
+    /// `LoadSpecSettings::get_type_for_suffix`).
+    ///
+    /// Embeddings are discovered the same way as in `from_dir`, files placed
+    /// alongside a body file in a type folder are embeddings specific to that body,
+    /// other files in the template dir are embeddings shared between all bodies.
+    /// (Files starting with `mail.` are never treated as embeddings.)
+    ///
+    /// Note that the glob syntax is the one of the `glob` crate, which does
+    /// not support alternatives like `mail.{html,txt}`, use e.g.
+    /// `templates/*/*/mail.*` instead.
+    #[cfg(feature="fs")]
+    pub fn from_glob(pattern: &str, settings: &LoadSpecSettings)
+        -> Result<Vec<(String, TemplateSpec)>, CreatingSpecError>
+    {
+        self::from_dir::from_glob(pattern, settings)
+    }
+
+    /// Reloads the parts of a template loaded with `from_dir` which changed.
+    ///
+    /// When loading a template with `from_dir` the modification time and
+    /// size of each file in the templates folder is remembered. This
+    /// compares them with the current state and:
+    ///
+    /// - does nothing and returns `false` if nothing changed
+    /// - only reloads the sub-templates whose folders contain changed
+    ///   (incl. added or removed) files if no other file changed
+    /// - reloads the whole template in all other cases, e.g. if a shared
+    ///   embedding changed or a sub-template folder was added. Attachments
+    ///   and embedding dispositions are kept in this case.
+    ///
+    /// If anything was reloaded `true` is returned. Note that this only
+    /// reloads the spec, to make the render engine use changed template
+    /// files the spec has to be re-inserted into the `RenderTemplateEngine`.
+    ///
+    /// If the template has no `base_path` nothing is done and `false` is
+    /// returned. If it has a `base_path` but was not loaded with `from_dir`
+    /// it is reloaded completely (using `from_dir`).
+    ///
+    /// # Error
+    ///
+    /// If reloading fails an error is returned, in which case the template
+    /// might have been partially reloaded.
+    #[cfg(feature="fs")]
+    pub fn reload_changed(&mut self, settings: &LoadSpecSettings)
+        -> Result<bool, CreatingSpecError>
+    {
+        self::from_dir::reload_changed(self, settings)
+    }
+
     /// creates a new Template from a list of sub-templates (for alternate bodies)
-    pub fn new(templates: Vec1<SubTemplateSpec>) -> Self {
+    ///
+    /// # Error
+    ///
+    /// A `DuplicateSourceId` error is returned if the source ids of the
+    /// sub-templates are not unique.
+    pub fn new(templates: Vec1<SubTemplateSpec>) -> Result<Self, CreatingSpecError> {
         Self::new_with_embeddings(templates, Default::default())
     }
 
+    /// returns a builder for a `TemplateSpec`
+    ///
+    /// Prefer this over the `new_with_*` constructors when combining
+    /// sub-templates with embeddings, attachments and/or a base path.
+    pub fn builder() -> TemplateSpecBuilder {
+        TemplateSpecBuilder::new()
+    }
+
     /// creates a new Template from a list of sub-templates and embeddings
+    ///
+    /// # Error
+    ///
+    /// A `DuplicateSourceId` error is returned if the source ids of the
+    /// sub-templates are not unique.
     pub fn new_with_embeddings(
         templates: Vec1<SubTemplateSpec>,
         embeddings: HashMap<String, Resource>
-    ) -> Self {
-        TemplateSpec {
+    ) -> Result<Self, CreatingSpecError> {
+        check_unique_source_ids(templates.iter().flat_map(|sub_spec| sub_spec.all_sources()))?;
+        Ok(TemplateSpec {
             base_path: None,
             templates, embeddings,
-            attachments: Vec::new()
-        }
+            embedding_dispositions: HashMap::new(),
+            attachments: Vec::new(),
+            #[cfg(feature="fs")]
+            load_state: None,
+            sample_data: None,
+            header_templates: Vec::new(),
+            partials: Vec::new(),
+            overlay: SpecOverlay::default()
+        })
     }
 
     /// creates a new Template from a list of sub-templates and a base path
@@ -142,6 +338,8 @@ impl TemplateSpec {
     }
 
     /// creates a new Template from a list of sub-templates, embedding mappings and a base path
+    ///
+    /// Consider using `TemplateSpec::builder` instead.
     pub fn new_with_embeddings_and_base_path<P>(
         templates: Vec1<SubTemplateSpec>,
         embeddings: HashMap<String, Resource>,
@@ -151,10 +349,18 @@ impl TemplateSpec {
     {
         let path = base_path.as_ref().to_owned();
         check_string_path(&*path)?;
+        check_unique_source_ids(templates.iter().flat_map(|sub_spec| sub_spec.all_sources()))?;
         Ok(TemplateSpec {
             base_path: Some(path),
             templates, embeddings,
-            attachments: Vec::new()
+            embedding_dispositions: HashMap::new(),
+            attachments: Vec::new(),
+            #[cfg(feature="fs")]
+            load_state: None,
+            sample_data: None,
+            header_templates: Vec::new(),
+            partials: Vec::new(),
+            overlay: SpecOverlay::default()
         })
     }
 
@@ -166,6 +372,62 @@ impl TemplateSpec {
         &mut self.templates
     }
 
+    /// the number of sub-templates, i.e. alternative bodies
+    #[inline]
+    pub fn body_count(&self) -> usize {
+        self.templates.len()
+    }
+
+    /// the number of (shared) template level embeddings
+    ///
+    /// Embeddings specific to a sub-template are not included, see
+    /// `SubTemplateSpec::embedding_count` and `resource_count`.
+    #[inline]
+    pub fn embedding_count(&self) -> usize {
+        self.embeddings.len()
+    }
+
+    #[inline]
+    pub fn attachment_count(&self) -> usize {
+        self.attachments.len()
+    }
+
+    /// returns true if there is a sub-template with the given media type
+    ///
+    /// Only the type and subtype are compared, parameters like the
+    /// charset are ignored, e.g. `text/plain; charset=utf-8` matches
+    /// `text/plain`.
+    pub fn has_media_type(&self, media_type: &MediaType) -> bool {
+        self.templates.iter()
+            .any(|sub_spec| has_same_essence(sub_spec.media_type(), media_type))
+    }
+
+    /// returns the (first) sub-template with the given media type
+    ///
+    /// Like with `has_media_type` only the type and subtype are compared,
+    /// so e.g. `text/html` returns the html sub-template independent of
+    /// it's charset and position.
+    pub fn sub_spec_for_media_type(&self, media_type: &MediaType) -> Option<&SubTemplateSpec> {
+        self.templates.iter()
+            .find(|sub_spec| has_same_essence(sub_spec.media_type(), media_type))
+    }
+
+    /// like `sub_spec_for_media_type` but returns a mutable reference
+    pub fn sub_spec_for_media_type_mut(&mut self, media_type: &MediaType) -> Option<&mut SubTemplateSpec> {
+        self.templates.iter_mut()
+            .find(|sub_spec| has_same_essence(sub_spec.media_type(), media_type))
+    }
+
+    /// returns true if there is a `text/html` sub-template
+    pub fn has_html(&self) -> bool {
+        self.has_media_type(&constant_media_type("text/html"))
+    }
+
+    /// returns true if there is a `text/plain` sub-template
+    pub fn has_plain_text(&self) -> bool {
+        self.has_media_type(&constant_media_type("text/plain"))
+    }
+
     pub fn embeddings(&self) -> &HashMap<String, Resource> {
         &self.embeddings
     }
@@ -186,6 +448,69 @@ impl TemplateSpec {
         Ok(replace(&mut self.base_path, Some(path.to_owned())))
     }
 
+    /// moves all paths in the spec from `old_root` to `new_root`
+    ///
+    /// This rewrites the base path, the `TemplateSource::Path` sources
+    /// (incl. variants and header templates) and the `path:` IRIs of all
+    /// embeddings and attachments which are in `old_root`, e.g. with
+    /// `old_root = "./templates"` and `new_root = "/opt/app/templates"`
+    /// `./templates/welcome/html/mail.html` becomes
+    /// `/opt/app/templates/welcome/html/mail.html`. Paths not in
+    /// `old_root` are not changed.
+    ///
+    /// This is meant for templates which are loaded and then moved before
+    /// they are used. As the state of the loaded files is dropped the next
+    /// `reload_changed` reloads the spec completely.
+    ///
+    /// # Error
+    ///
+    /// An error is returned if a rebased path is not valid UTF-8, in which
+    /// case the spec might have been partially rebased.
+    pub fn rebase<P1, P2>(&mut self, old_root: P1, new_root: P2) -> Result<(), CreatingSpecError>
+        where P1: AsRef<Path>, P2: AsRef<Path>
+    {
+        let (old_root, new_root) = (old_root.as_ref(), new_root.as_ref());
+
+        let new_base_path = self.base_path.as_ref()
+            .and_then(|path| rebase_path(path, old_root, new_root));
+        if let Some(new_base_path) = new_base_path {
+            self.set_base_path(new_base_path)?;
+        }
+
+        for sub_spec in self.templates.iter_mut() {
+            rebase_source(&mut sub_spec.source, old_root, new_root)?;
+            for source in sub_spec.variants.values_mut() {
+                rebase_source(source, old_root, new_root)?;
+            }
+            for resource in sub_spec.embeddings.values_mut() {
+                rebase_resource(resource, old_root, new_root)?;
+            }
+            sub_spec.overlay.rebase(old_root, new_root)?;
+        }
+        for &mut (_, ref mut sub_spec) in self.header_templates.iter_mut() {
+            rebase_source(&mut sub_spec.source, old_root, new_root)?;
+        }
+        for &mut (_, ref mut source) in self.partials.iter_mut().chain(self.overlay.partials.iter_mut()) {
+            rebase_source(source, old_root, new_root)?;
+        }
+        for resource in self.embeddings.values_mut().chain(self.attachments.iter_mut()) {
+            rebase_resource(resource, old_root, new_root)?;
+        }
+        self.overlay.embeddings.rebase(old_root, new_root)?;
+
+        #[cfg(feature="fs")]
+        {
+            self.load_state = None;
+        }
+        Ok(())
+    }
+
+    /// the attachments which are added to every mail using this template
+    ///
+    /// Unlike embeddings attachments are not referenced by name from the
+    /// templates, so there is no uniqueness requirement for their names,
+    /// e.g. `report.2023.pdf` and `report.2024.pdf` can both be attached
+    /// even through they would map to the same embedding name (`report`).
     pub fn attachments(&self) -> &Vec<Resource> {
         &self.attachments
     }
@@ -194,6 +519,325 @@ impl TemplateSpec {
         &mut self.attachments
     }
 
+    /// iterates over all resources referenced by this template
+    ///
+    /// This are the shared embeddings, the embeddings of each sub-template
+    /// and the attachments (in that order).
+    pub fn iter_resources<'a>(&'a self) -> impl Iterator<Item=&'a Resource> + 'a {
+        self.embeddings.values()
+            .chain(self.templates.iter().flat_map(|sub_spec| sub_spec.embeddings.values()))
+            .chain(self.attachments.iter())
+    }
+
+    /// the number of resources returned by `iter_resources`
+    pub fn resource_count(&self) -> usize {
+        self.embeddings.len()
+            + self.templates.iter().map(|sub_spec| sub_spec.embeddings.len()).sum::<usize>()
+            + self.attachments.len()
+    }
+
+    /// inserts a (shared) embedding for the file at the explicitly declared path
+    ///
+    /// Placeholders in the path are expanded using the settings, see
+    /// `LoadSpecSettings::set_path_placeholder`. If a `ResourceFetcher` is set
+    /// (see `LoadSpecSettings::set_resource_fetcher`) the path can also be a
+    /// `http://` or `https://` url in which case the content is fetched now.
+    ///
+    /// Returns the embedding previously associated with the name, if there was one.
+    #[cfg(feature="fs")]
+    pub fn insert_embedding_from_path<N>(
+        &mut self,
+        name: N,
+        path: &str,
+        settings: &LoadSpecSettings
+    ) -> Result<Option<Resource>, CreatingSpecError>
+        where N: Into<String>
+    {
+        let name = name.into();
+        let resource = resource_from_declared_path(path, settings)?;
+        self.overlay.embeddings.insert(&name, &resource);
+        Ok(self.embeddings.insert(name, resource))
+    }
+
+    /// sets the file name the attachment at given index will have in the mail
+    ///
+    /// This replaces the attachment with a new `Resource` using the same source
+    /// but with `use_name` set to the given name.
+    ///
+    /// The name is kept as is, i.e. it can contain non ASCII characters (e.g.
+    /// `"Rechnung_Müller.pdf"`), encoding it for the `Content-Disposition`
+    /// header (RFC 2231) is done when the mail is encoded.
+    ///
+    /// # Error
+    ///
+    /// An error is returned if there is no attachment at the given index or if
+    /// the attachment was not created from a source.
+    pub fn set_attachment_name<I>(&mut self, index: usize, name: I) -> Result<(), CreatingSpecError>
+        where I: Into<String>
+    {
+        let attachment = self.attachments.get_mut(index)
+            .ok_or_else(|| CreatingSpecErrorVariant::NoSuchAttachment { index })?;
+        *attachment = with_use_name(attachment, name.into())?;
+        Ok(())
+    }
+
+    /// sets the file name the (shared) embedding will have in the mail
+    ///
+    /// This is the same as `set_attachment_name` but for the template
+    /// level embedding with the given (in-template) name.
+    pub fn set_embedding_name<I>(&mut self, embedding: &str, name: I) -> Result<(), CreatingSpecError>
+        where I: Into<String>
+    {
+        let name = name.into();
+        set_embedding_name(&mut self.embeddings, embedding, name.clone())?;
+        self.overlay.embeddings.set_name(embedding, name);
+        Ok(())
+    }
+
+    /// sets if the (shared) embedding is added inline or as attachment
+    ///
+    /// By default embeddings are added as inline embeddings, if set to
+    /// `EmbeddingDisposition::Attachment` the embedding is added as an
+    /// attachment instead. It still has a content id, which is accessible
+    /// through `cids` in the template, so a template can both link to the
+    /// file and have it attached.
+    ///
+    /// Returns an error if there is no embedding with the given name.
+    pub fn set_embedding_disposition(
+        &mut self,
+        embedding: &str,
+        disposition: EmbeddingDisposition
+    ) -> Result<(), CreatingSpecError> {
+        set_embedding_disposition(
+            &self.embeddings, &mut self.embedding_dispositions, embedding, disposition)
+    }
+
+    /// returns the disposition of the (shared) embedding
+    ///
+    /// If no disposition was set this is `EmbeddingDisposition::Inline`,
+    /// even if there is no embedding with this name.
+    pub fn embedding_disposition(&self, embedding: &str) -> EmbeddingDisposition {
+        self.embedding_dispositions.get(embedding).cloned().unwrap_or_default()
+    }
+
+    /// returns the sample data used to render previews
+    ///
+    /// See `LoadSpecSettings::set_load_sample_data` and
+    /// `RenderTemplateEngine::render_preview`.
+    pub fn sample_data(&self) -> Option<&Value> {
+        self.sample_data.as_ref()
+    }
+
+    /// sets the sample data, returning the previous sample data
+    pub fn set_sample_data(&mut self, data: Option<Value>) -> Option<Value> {
+        self.overlay.sample_data = Some(data.clone());
+        replace(&mut self.sample_data, data)
+    }
+
+    /// adds a template for the value of the header with the given name
+    ///
+    /// Header templates are loaded into the render engine like the
+    /// sources of the sub-templates and are rendered with the same data
+    /// (as if they were `text/plain` bodies) by
+    /// `RenderTemplateEngine::use_template_with_headers`. This allows
+    /// keeping e.g. a `List-Unsubscribe` header or a campaign id
+    /// together with the template. The id of the source has to be
+    /// unique like the ids of the body sources.
+    ///
+    /// If there already is a template for the header it's replaced
+    /// and returned.
+    pub fn insert_header_template<N>(&mut self, name: N, source: TemplateSource) -> Option<TemplateSource>
+        where N: Into<String>
+    {
+        let name = name.into();
+        // UNWRAP_SAFE: the media type is a constant non-multipart type
+        let sub_spec = SubTemplateSpec::new_with_template_source(
+            source, header_media_type(), HashMap::new()).unwrap();
+        let existing = self.header_templates.iter_mut()
+            .find(|&&mut (ref existing, _)| existing.eq_ignore_ascii_case(&name));
+        if let Some(&mut (_, ref mut old)) = existing {
+            return Some(replace(old, sub_spec).source);
+        }
+        self.header_templates.push((name, sub_spec));
+        None
+    }
+
+    /// removes the template for the header with the given name, returning it's source
+    pub fn remove_header_template(&mut self, name: &str) -> Option<TemplateSource> {
+        let idx = self.header_templates.iter()
+            .position(|&(ref existing, _)| existing.eq_ignore_ascii_case(name))?;
+        Some(self.header_templates.remove(idx).1.source)
+    }
+
+    /// iterates over the header names and the sources of their templates (in insertion order)
+    pub fn header_templates<'a>(&'a self) -> impl Iterator<Item=(&'a str, &'a TemplateSource)> + 'a {
+        self.header_templates.iter()
+            .map(|&(ref name, ref sub_spec)| (name.as_str(), sub_spec.source()))
+    }
+
+    /// the header templates as sub-templates, used to load and render them
+    pub(crate) fn header_sub_specs<'a>(&'a self) -> impl Iterator<Item=(&'a str, &'a SubTemplateSpec)> + 'a {
+        self.header_templates.iter()
+            .map(|&(ref name, ref sub_spec)| (name.as_str(), sub_spec))
+    }
+
+    /// inserts a partial, i.e. a template which can be used by name from the other templates
+    ///
+    /// Partials are registered in the render engine under their name when
+    /// the spec is loaded, e.g. as template usable with `{% include %}`/
+    /// `{% extends %}` in tera or as partial in handlebars. Unlike the other
+    /// sources they are not rendered on their own. As they are registered by
+    /// name the name has to be unique across all loaded specs (and base
+    /// templates). `from_dir` loads the files in the partials dir of a
+    /// template dir as partials, see `LoadSpecSettings::set_partials_dir_name`.
+    ///
+    /// If there already is a partial with the name it's replaced and returned.
+    pub fn insert_partial<N>(&mut self, name: N, source: TemplateSource) -> Option<TemplateSource>
+        where N: Into<String>
+    {
+        let name = name.into();
+        insert_partial(&mut self.overlay.partials, name.clone(), source.clone());
+        insert_partial(&mut self.partials, name, source)
+    }
+
+    /// removes the partial with the given name, returning it's source
+    pub fn remove_partial(&mut self, name: &str) -> Option<TemplateSource> {
+        remove_partial(&mut self.overlay.partials, name);
+        remove_partial(&mut self.partials, name)
+    }
+
+    /// the partials with their names (in insertion order), see `insert_partial`
+    pub fn partials(&self) -> &[(String, TemplateSource)] {
+        &self.partials
+    }
+
+    /// returns all sources which have to be loaded into the render engine
+    ///
+    /// This are the sources of the sub-templates (incl. variants) followed
+    /// by the sources of the header templates.
+    pub fn all_sources<'a>(&'a self) -> impl Iterator<Item=&'a TemplateSource> + 'a {
+        self.templates.iter()
+            .flat_map(|sub_spec| sub_spec.all_sources())
+            .chain(self.header_templates.iter().map(|&(_, ref sub_spec)| sub_spec.source()))
+    }
+
+    /// checks that the ids of all sources of this spec are unique
+    ///
+    /// Render engines use the source ids to associate the parsed templates
+    /// with the sub-templates, so two sources with the same id would collide
+    /// when the spec is loaded. This is checked by the constructors and the
+    /// builder, but as sources can be changed afterwards (e.g. through
+    /// `sub_specs_mut`) it can be re-checked with this method.
+    ///
+    /// # Error
+    ///
+    /// A `DuplicateSourceId` error with the first duplicate id is returned.
+    pub fn check_unique_source_ids(&self) -> Result<(), CreatingSpecError> {
+        check_unique_source_ids(self.all_sources())
+    }
+
+    /// compares the content of two specs
+    ///
+    /// Two specs are seen as content equal if:
+    ///
+    /// - they have the same sub-specs in the same order, where sub-specs
+    ///   are equal if their sources (incl. variants) and media types are
+    ///   equal and they have embeddings with the same names and source IRIs
+    /// - they have shared embeddings with the same names and source IRIs
+    /// - they have attachments with the same source IRIs in the same order
+    /// - they have header templates for the same headers with the same sources
+    ///   in the same order
+    /// - they have the same partials in the same order
+    ///
+    /// The base path, dispositions and custom names of embeddings are ignored.
+    /// Resources are only compared by the IRI of their source, e.g. whether
+    /// or not they are already loaded doesn't matter. Resources without source
+    /// are never equal.
+    ///
+    /// Note that sources are compared by identity, i.e. if a template file
+    /// changes the spec is still content equal to the one loaded before the
+    /// change.
+    pub fn content_eq(&self, other: &TemplateSpec) -> bool {
+        self.templates.len() == other.templates.len()
+            && self.templates.iter().zip(other.templates.iter())
+                .all(|(this, other)| this.content_eq(other))
+            && embeddings_content_eq(&self.embeddings, &other.embeddings)
+            && self.attachments.len() == other.attachments.len()
+            && self.attachments.iter().zip(other.attachments.iter())
+                .all(|(this, other)| resource_content_eq(this, other))
+            && self.header_templates().eq(other.header_templates())
+            && self.partials == other.partials
+    }
+
+    /// returns a short human readable summary of the spec
+    ///
+    /// E.g. `[2 bodies: text/plain, text/html] [2 embeddings: logo, portfolio] [0 attachments] @ ./templates/a`.
+    /// Embedding names include the body specific embeddings and are sorted, the
+    /// base path is only included if there is one. This is the same as the
+    /// `Display` implementation.
+    pub fn summary(&self) -> String {
+        self.to_string()
+    }
+}
+
+impl Display for TemplateSpec {
+    fn fmt(&self, fter: &mut fmt::Formatter) -> fmt::Result {
+        let media_types = self.templates.iter()
+            .map(|sub_spec| sub_spec.media_type().full_type().to_string())
+            .collect::<Vec<_>>();
+
+        let mut embedding_names = self.templates.iter()
+            .flat_map(|sub_spec| sub_spec.embeddings().keys())
+            .chain(self.embeddings.keys())
+            .map(|name| &**name)
+            .collect::<Vec<_>>();
+        embedding_names.sort();
+
+        write!(fter, "[{} {}: {}]",
+            media_types.len(), plural(media_types.len(), "body", "bodies"),
+            media_types.join(", "))?;
+
+        write!(fter, " [{} {}", embedding_names.len(),
+            plural(embedding_names.len(), "embedding", "embeddings"))?;
+        if !embedding_names.is_empty() {
+            write!(fter, ": {}", embedding_names.join(", "))?;
+        }
+
+        write!(fter, "] [{} {}]", self.attachments.len(),
+            plural(self.attachments.len(), "attachment", "attachments"))?;
+
+        if let Some(base_path) = self.base_path.as_ref() {
+            write!(fter, " @ {}", base_path.display())?;
+        }
+        Ok(())
+    }
+}
+
+/// loads the spec from the dir using the `DEFAULT_SETTINGS`, like `TemplateSpec::from_dir_default`
+///
+/// Use `TemplateSpec::from_dir` to load a spec with other settings.
+#[cfg(feature="fs")]
+impl<'a> TryFrom<&'a Path> for TemplateSpec {
+    type Error = CreatingSpecError;
+
+    fn try_from(base_path: &'a Path) -> Result<Self, Self::Error> {
+        TemplateSpec::from_dir_default(base_path)
+    }
+}
+
+/// the media type used to render header templates
+fn header_media_type() -> MediaType {
+    constant_media_type("text/plain; charset=utf-8")
+}
+
+/// parses one of the (valid) media type constants
+fn constant_media_type(media_type: &str) -> MediaType {
+    MediaType::parse(media_type)
+        .expect("[BUG] constant media type is invalid")
+}
+
+fn plural(count: usize, singular: &'static str, plural: &'static str) -> &'static str {
+    if count == 1 { singular } else { plural }
 }
 
 /// A type representing the part of a template which represents a alternate mail body
@@ -202,7 +846,6 @@ impl TemplateSpec {
 /// a the content of an specific handlebars file) the media type which
 /// this alternate body should have, and a mappings of embeddings specific
 /// to this alternate body
-#[derive(Debug)]
 pub struct SubTemplateSpec {
     media_type: MediaType,
     source: TemplateSource,
@@ -210,6 +853,18 @@ pub struct SubTemplateSpec {
     // resource spec use_name which would
     //  e.g. be logo.png but referring to the file long_logo_name.png
     embeddings: HashMap<String, Resource>,//todo use insert order keeping map
+    // alternative sources which can be selected by key when rendering, sorted by key
+    variants: BTreeMap<String, TemplateSource>,
+    // the dispositions of embeddings which are not inline
+    embedding_dispositions: HashMap<String, EmbeddingDisposition>,
+    // if true the rendered body can be empty even if empty bodies are rejected
+    allow_empty: bool,
+    // data merged under the data when rendering this body
+    render_defaults: Option<Value>,
+    // the preferred transfer encoding, chosen automatically if `None`
+    transfer_encoding: Option<TransferEncoding>,
+    // the embeddings and names set through the setters, reapplied on reload
+    overlay: EmbeddingOverlay,
 }
 
 impl SubTemplateSpec {
@@ -224,15 +879,31 @@ impl SubTemplateSpec {
         where P: AsRef<Path>
     {
         let source = TemplateSource::Path(new_string_path(path.as_ref())?);
-        Ok(SubTemplateSpec::new_with_template_source(source, media_type, embeddings))
+        SubTemplateSpec::new_with_template_source(source, media_type, embeddings)
     }
 
+    /// creates a new sub-template from the given source
+    ///
+    /// # Error
+    ///
+    /// An error is returned if the media type is a multipart type,
+    /// as a body can not be multipart (the template engine places
+    /// the bodies in a multipart body).
     pub fn new_with_template_source(
         source: TemplateSource,
         media_type: MediaType,
         embeddings: HashMap<String, Resource>
-    ) -> Self {
-        SubTemplateSpec { source, media_type, embeddings }
+    ) -> Result<Self, CreatingSpecError> {
+        check_not_multipart(&media_type)?;
+        Ok(SubTemplateSpec {
+            source, media_type, embeddings,
+            variants: BTreeMap::new(),
+            embedding_dispositions: HashMap::new(),
+            allow_empty: false,
+            render_defaults: None,
+            transfer_encoding: None,
+            overlay: EmbeddingOverlay::default()
+        })
     }
 
     pub fn source(&self) -> &TemplateSource {
@@ -243,25 +914,530 @@ impl SubTemplateSpec {
         replace(&mut self.source, source)
     }
 
+    /// returns the alternative sources of this sub-template by their key (sorted by key)
+    pub fn variants(&self) -> &BTreeMap<String, TemplateSource> {
+        &self.variants
+    }
+
+    /// adds an alternative source which is used instead of `source` if selected
+    ///
+    /// Variants can be selected when rendering a template through
+    /// `RenderTemplateEngine::use_template_variant`, if a sub-template
+    /// has no variant for the selected key it's normal source is used.
+    /// All variants share the embeddings of this sub-template.
+    ///
+    /// The ids of the variant sources have to be unique like the id
+    /// of the normal source, as all are loaded into the render engine.
+    pub fn insert_variant<K>(&mut self, key: K, source: TemplateSource) -> Option<TemplateSource>
+        where K: Into<String>
+    {
+        self.variants.insert(key.into(), source)
+    }
+
+    /// removes the variant with given key
+    pub fn remove_variant(&mut self, key: &str) -> Option<TemplateSource> {
+        self.variants.remove(key)
+    }
+
+    /// if true the rendered body is allowed to be empty
+    ///
+    /// This opts the body out of `RenderTemplateEngine::set_empty_body_policy`,
+    /// e.g. for a text alternative which is legitimately empty for some data.
+    ///
+    /// This is disabled by default.
+    pub fn set_allow_empty(&mut self, allow_empty: bool) {
+        self.allow_empty = allow_empty;
+    }
+
+    /// returns true if the rendered body can be empty, see `set_allow_empty`
+    pub fn allows_empty(&self) -> bool {
+        self.allow_empty
+    }
+
+    /// sets default data merged under the data when rendering this body
+    ///
+    /// This works like `RenderTemplateEngine::set_render_defaults` but only
+    /// for this body (and it's variants), e.g. for a fallback image url only
+    /// used by the html body. The data wins over these defaults which win
+    /// over the defaults of the engine. Like the defaults of the engine they
+    /// are only used if the render engine supports pre-serialized data.
+    ///
+    /// By default (`None`) there are no defaults.
+    pub fn set_render_defaults(&mut self, defaults: Option<Value>) -> Option<Value> {
+        replace(&mut self.render_defaults, defaults)
+    }
+
+    /// returns the render defaults of this body, see `set_render_defaults`
+    pub fn render_defaults(&self) -> Option<&Value> {
+        self.render_defaults.as_ref()
+    }
+
+    /// sets the transfer encoding preferred for the rendered body
+    ///
+    /// The `FileBuffer` of the body is transfer encoded with it, if a
+    /// `BodyResourceFactory` is set on the `RenderTemplateEngine` the
+    /// preference is passed to it (see `create_resource_with_transfer_encoding`).
+    ///
+    /// By default (`None`) the transfer encoding is chosen automatically.
+    pub fn set_transfer_encoding(&mut self, encoding: Option<TransferEncoding>) -> Option<TransferEncoding> {
+        replace(&mut self.transfer_encoding, encoding)
+    }
+
+    /// returns the preferred transfer encoding, see `set_transfer_encoding`
+    pub fn transfer_encoding(&self) -> Option<TransferEncoding> {
+        self.transfer_encoding
+    }
+
+    /// returns the source and the sources of all variants (sorted by their key)
+    pub fn all_sources<'a>(&'a self) -> impl Iterator<Item=&'a TemplateSource> + 'a {
+        Some(&self.source).into_iter().chain(self.variants.values())
+    }
+
+    /// creates a copy of this sub-template using the source of the given variant
+    ///
+    /// Returns `None` if there is no variant with given key.
+    pub(crate) fn for_variant(&self, key: &str) -> Option<SubTemplateSpec> {
+        let source = self.variants.get(key)?;
+        Some(SubTemplateSpec {
+            source: source.clone(),
+            media_type: self.media_type.clone(),
+            embeddings: self.embeddings.clone(),
+            variants: BTreeMap::new(),
+            embedding_dispositions: self.embedding_dispositions.clone(),
+            allow_empty: self.allow_empty,
+            render_defaults: self.render_defaults.clone(),
+            transfer_encoding: self.transfer_encoding,
+            overlay: EmbeddingOverlay::default()
+        })
+    }
+
     pub fn media_type(&self) -> &MediaType {
         &self.media_type
     }
 
-    pub fn set_media_type(&mut self, media_type: MediaType) -> MediaType {
-        //we might want to add restrictions at some point,e.g. no multi-part media type
-        replace(&mut self.media_type, media_type)
+    /// sets the media type returning the old one
+    ///
+    /// # Error
+    ///
+    /// An error is returned if the media type is a multipart type.
+    pub fn set_media_type(&mut self, media_type: MediaType) -> Result<MediaType, CreatingSpecError> {
+        check_not_multipart(&media_type)?;
+        Ok(replace(&mut self.media_type, media_type))
+    }
+
+    /// adds a parameter to the media type, replacing it if it already exists
+    ///
+    /// # Error
+    ///
+    /// An error is returned if the media type is a multipart type, as
+    /// bodies can not be multipart (they are placed into one by the
+    /// template engine), or if the name or value are not valid.
+    pub fn set_media_type_param(&mut self, name: &str, value: &str) -> Result<(), CreatingSpecError> {
+        check_not_multipart(&self.media_type)?;
+        if !is_valid_media_type_param(name, value) {
+            return Err(CreatingSpecErrorVariant::InvalidMediaTypeParam {
+                name: name.to_owned(),
+                value: value.to_owned()
+            }.into());
+        }
+        self.media_type.set_param(name, value);
+        Ok(())
+    }
+
+    /// makes this a `format=flowed` (RFC 3676) body
+    ///
+    /// Rendered flowed bodies have trailing spaces removed and are wrapped
+    /// using soft line breaks if `RenderTemplateEngine::set_text_line_wrap`
+    /// is used, which makes them display better on e.g. mobile clients.
+    ///
+    /// # Error
+    ///
+    /// An error is returned if this is not a `text/plain` body.
+    pub fn set_format_flowed(&mut self) -> Result<(), CreatingSpecError> {
+        if self.media_type.full_type() != "text/plain" {
+            return Err(CreatingSpecErrorVariant::NotPlainText {
+                media_type: self.media_type.as_str_repr().to_owned()
+            }.into());
+        }
+        self.set_media_type_param("format", "flowed")
+    }
+
+    /// returns true if the media type has a `format=flowed` parameter
+    pub fn is_format_flowed(&self) -> bool {
+        is_format_flowed(&self.media_type)
+    }
+
+    /// encodes the rendered body using the charset declared in the media type
+    ///
+    /// Render engines always produce UTF-8 strings, this converts them to the
+    /// bytes placed into the mail. Supported charsets are `utf-8` (used if
+    /// no charset is declared), `us-ascii` and `iso-8859-1`.
+    ///
+    /// # Error
+    ///
+    /// An error is returned if the charset is not supported or if the
+    /// body contains characters which can not be represented in it.
+    pub fn encode_body(&self, rendered: String) -> Result<Vec<u8>, EncodingError> {
+        encode_body(&self.media_type, rendered)
     }
 
     pub fn embeddings(&self) -> &HashMap<String, Resource> {
         &self.embeddings
     }
 
+    /// the number of embeddings specific to this sub-template
+    #[inline]
+    pub fn embedding_count(&self) -> usize {
+        self.embeddings.len()
+    }
+
     pub fn embedding_mut(&mut self) -> &mut HashMap<String, Resource> {
         &mut self.embeddings
     }
 
+    /// inserts an embedding for the file at the explicitly declared path
+    ///
+    /// See `TemplateSpec::insert_embedding_from_path` for more details.
+    #[cfg(feature="fs")]
+    pub fn insert_embedding_from_path<N>(
+        &mut self,
+        name: N,
+        path: &str,
+        settings: &LoadSpecSettings
+    ) -> Result<Option<Resource>, CreatingSpecError>
+        where N: Into<String>
+    {
+        let name = name.into();
+        let resource = resource_from_declared_path(path, settings)?;
+        self.overlay.insert(&name, &resource);
+        Ok(self.embeddings.insert(name, resource))
+    }
+
+    /// sets the file name the embedding will have in the mail
+    ///
+    /// See `TemplateSpec::set_attachment_name` for more details.
+    pub fn set_embedding_name<I>(&mut self, embedding: &str, name: I) -> Result<(), CreatingSpecError>
+        where I: Into<String>
+    {
+        let name = name.into();
+        set_embedding_name(&mut self.embeddings, embedding, name.clone())?;
+        self.overlay.set_name(embedding, name);
+        Ok(())
+    }
+
+    /// sets if the embedding is added inline or as attachment
+    ///
+    /// See `TemplateSpec::set_embedding_disposition` for more details.
+    pub fn set_embedding_disposition(
+        &mut self,
+        embedding: &str,
+        disposition: EmbeddingDisposition
+    ) -> Result<(), CreatingSpecError> {
+        set_embedding_disposition(
+            &self.embeddings, &mut self.embedding_dispositions, embedding, disposition)
+    }
+
+    /// returns the disposition of the embedding, see `TemplateSpec::embedding_disposition`
+    pub fn embedding_disposition(&self, embedding: &str) -> EmbeddingDisposition {
+        self.embedding_dispositions.get(embedding).cloned().unwrap_or_default()
+    }
+
+    /// compares the content of two sub-specs, see `TemplateSpec::content_eq`
+    pub fn content_eq(&self, other: &SubTemplateSpec) -> bool {
+        self.source == other.source
+            && self.variants == other.variants
+            && self.render_defaults == other.render_defaults
+            && self.transfer_encoding == other.transfer_encoding
+            && self.media_type.as_str_repr() == other.media_type.as_str_repr()
+            && embeddings_content_eq(&self.embeddings, &other.embeddings)
+    }
+}
+
+/// returns a `DuplicateSourceId` error for the first source id used more than once
+fn check_unique_source_ids<'a, I>(sources: I) -> Result<(), CreatingSpecError>
+    where I: IntoIterator<Item=&'a TemplateSource>
+{
+    let mut seen = HashSet::new();
+    for source in sources {
+        if !seen.insert(source.id()) {
+            let id = source.id().to_owned();
+            return Err(CreatingSpecErrorVariant::DuplicateSourceId { id }.into());
+        }
+    }
+    Ok(())
+}
+
+/// bodies can not have a multipart media type
+fn check_not_multipart(media_type: &MediaType) -> Result<(), CreatingSpecError> {
+    if is_multipart(media_type) {
+        Err(CreatingSpecErrorVariant::MultipartMediaType {
+            media_type: media_type.as_str_repr().to_owned()
+        }.into())
+    } else {
+        Ok(())
+    }
+}
+
+fn embeddings_content_eq(a: &HashMap<String, Resource>, b: &HashMap<String, Resource>) -> bool {
+    a.len() == b.len()
+        && a.iter().all(|(name, resource)| {
+            b.get(name)
+                .map(|other| resource_content_eq(resource, other))
+                .unwrap_or(false)
+        })
+}
+
+fn resource_content_eq(a: &Resource, b: &Resource) -> bool {
+    match (a.source(), b.source()) {
+        (Some(a), Some(b)) => a.iri.as_str() == b.iri.as_str(),
+        _ => false
+    }
+}
+
+/// the state of a spec set through it's setters
+///
+/// Reloading a spec (see `TemplateSpec::reload_changed`) rebuilds it from the
+/// files, the overlay is moved to the rebuilt spec and reapplied to it.
+#[derive(Default)]
+struct SpecOverlay {
+    /// the shared embeddings and their names
+    embeddings: EmbeddingOverlay,
+    /// partials inserted through `insert_partial`
+    partials: Vec<(String, TemplateSource)>,
+    /// the sample data set through `set_sample_data`
+    #[cfg_attr(not(feature="fs"), allow(dead_code))]
+    sample_data: Option<Option<Value>>
+}
+
+impl SpecOverlay {
+
+    #[cfg(feature="fs")]
+    fn apply(&self, spec: &mut TemplateSpec) {
+        self.embeddings.apply(&mut spec.embeddings);
+        for &(ref name, ref source) in self.partials.iter() {
+            insert_partial(&mut spec.partials, name.clone(), source.clone());
+        }
+        if let Some(ref data) = self.sample_data {
+            spec.sample_data = data.clone();
+        }
+    }
+}
+
+/// the embeddings inserted through `insert_embedding_from_path` and the names
+/// set through `set_embedding_name`, see `SpecOverlay`
+#[derive(Default)]
+struct EmbeddingOverlay {
+    embeddings: HashMap<String, Resource>,
+    names: HashMap<String, String>
+}
+
+impl EmbeddingOverlay {
+
+    #[cfg(feature="fs")]
+    fn insert(&mut self, name: &str, resource: &Resource) {
+        // the new resource replaces the renamed one
+        self.names.remove(name);
+        self.embeddings.insert(name.to_owned(), resource.clone());
+    }
+
+    fn set_name(&mut self, embedding: &str, name: String) {
+        self.names.insert(embedding.to_owned(), name);
+    }
+
+    #[cfg(feature="fs")]
+    fn apply(&self, embeddings: &mut HashMap<String, Resource>) {
+        for (name, resource) in self.embeddings.iter() {
+            embeddings.insert(name.clone(), resource.clone());
+        }
+        for (embedding, name) in self.names.iter() {
+            // the embedding might have been removed from the files, in
+            // which case there is nothing left to rename
+            let _ = set_embedding_name(embeddings, embedding, name.clone());
+        }
+    }
+
+    fn rebase(&mut self, old_root: &Path, new_root: &Path) -> Result<(), CreatingSpecError> {
+        for resource in self.embeddings.values_mut() {
+            rebase_resource(resource, old_root, new_root)?;
+        }
+        Ok(())
+    }
+}
+
+/// inserts the partial replacing (and returning) the one with the same name
+fn insert_partial(partials: &mut Vec<(String, TemplateSource)>, name: String, source: TemplateSource)
+    -> Option<TemplateSource>
+{
+    let existing = partials.iter_mut()
+        .find(|&&mut (ref existing, _)| *existing == name);
+    if let Some(&mut (_, ref mut old)) = existing {
+        return Some(replace(old, source));
+    }
+    partials.push((name, source));
+    None
 }
 
+fn remove_partial(partials: &mut Vec<(String, TemplateSource)>, name: &str) -> Option<TemplateSource> {
+    let idx = partials.iter()
+        .position(|&(ref existing, _)| existing == name)?;
+    Some(partials.remove(idx).1)
+}
+
+/// Describes if an embedding is added inline or as attachment to a mail
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum EmbeddingDisposition {
+    /// the embedding is added as inline embedding (the default)
+    Inline,
+    /// the embedding is added as attachment (which still has a content id)
+    Attachment
+}
+
+impl Default for EmbeddingDisposition {
+    fn default() -> Self {
+        EmbeddingDisposition::Inline
+    }
+}
+
+/// The transfer encoding preferred for a body, see `SubTemplateSpec::set_transfer_encoding`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TransferEncoding {
+    /// quoted-printable, e.g. for mostly ascii text
+    QuotedPrintable,
+    /// base64, e.g. for bodies with many non ascii chars
+    Base64
+}
+
+fn set_embedding_disposition(
+    embeddings: &HashMap<String, Resource>,
+    dispositions: &mut HashMap<String, EmbeddingDisposition>,
+    embedding: &str,
+    disposition: EmbeddingDisposition
+) -> Result<(), CreatingSpecError> {
+    if !embeddings.contains_key(embedding) {
+        return Err(CreatingSpecErrorVariant::NoSuchEmbedding { name: embedding.to_owned() }.into());
+    }
+    if disposition == EmbeddingDisposition::Inline {
+        dispositions.remove(embedding);
+    } else {
+        dispositions.insert(embedding.to_owned(), disposition);
+    }
+    Ok(())
+}
+
+#[cfg(feature="fs")]
+fn resource_from_declared_path(path: &str, settings: &LoadSpecSettings)
+    -> Result<Resource, CreatingSpecError>
+{
+    let path = settings.expand_path_placeholders(path)?;
+    if is_url(&path) {
+        let fetcher = settings.resource_fetcher()
+            .ok_or_else(|| CreatingSpecErrorVariant::NoResourceFetcher { url: path.clone() })?;
+        return resource_from_url(&path, fetcher);
+    }
+    StdFs::new(settings.follow_symlinks()).resource(Path::new(&path), settings)
+}
+
+fn set_embedding_name(
+    embeddings: &mut HashMap<String, Resource>,
+    embedding: &str,
+    name: String
+) -> Result<(), CreatingSpecError> {
+    let resource = embeddings.get_mut(embedding)
+        .ok_or_else(|| CreatingSpecErrorVariant::NoSuchEmbedding { name: embedding.to_owned() })?;
+    *resource = with_use_name(resource, name)?;
+    Ok(())
+}
+
+/// returns the path moved from `old_root` to `new_root`, or `None` if it's not in `old_root`
+fn rebase_path(path: &Path, old_root: &Path, new_root: &Path) -> Option<PathBuf> {
+    path.strip_prefix(old_root).ok()
+        .map(|rel_path| new_root.join(rel_path))
+}
+
+fn rebase_source(source: &mut TemplateSource, old_root: &Path, new_root: &Path)
+    -> Result<(), CreatingSpecError>
+{
+    if let TemplateSource::Path(ref mut path) = *source {
+        if let Some(new_path) = rebase_path(Path::new(path.as_str()), old_root, new_root) {
+            *path = new_string_path(new_path)?;
+        }
+    }
+    Ok(())
+}
+
+/// replaces the resource with one with a rebased IRI if it refers to a file in `old_root`
+fn rebase_resource(resource: &mut Resource, old_root: &Path, new_root: &Path)
+    -> Result<(), CreatingSpecError>
+{
+    let new_source = match resource.source() {
+        Some(source) if source.iri.as_str().starts_with("path:") => {
+            let path = Path::new(&source.iri.as_str()["path:".len()..]);
+            if let Some(new_path) = rebase_path(path, old_root, new_root) {
+                Some(Source {
+                    iri: iri_from_path(new_path)?,
+                    use_name: source.use_name.clone(),
+                    use_media_type: source.use_media_type.clone()
+                })
+            } else {
+                None
+            }
+        },
+        _ => None
+    };
+    if let Some(new_source) = new_source {
+        *resource = Resource::new(new_source);
+    }
+    Ok(())
+}
+
+/// returns an `DuplicateTemplateId` error if an id is used more than once
+///
+/// The paths are the (template) dirs the specs where loaded from, if known.
+pub(crate) fn check_unique_template_ids<'a, I>(ids: I) -> Result<(), CreatingSpecError>
+    where I: IntoIterator<Item=(&'a str, Option<&'a Path>)>
+{
+    let mut paths_by_id: BTreeMap<&str, Vec<Option<&Path>>> = BTreeMap::new();
+    for (id, path) in ids {
+        paths_by_id.entry(id).or_insert_with(Vec::new).push(path);
+    }
+    let duplicate = paths_by_id.into_iter()
+        .find(|&(_, ref paths)| paths.len() > 1);
+    if let Some((id, paths)) = duplicate {
+        return Err(CreatingSpecErrorVariant::DuplicateTemplateId {
+            id: id.to_owned(),
+            paths: paths.into_iter().filter_map(|path| path).map(Into::into).collect()
+        }.into());
+    }
+    Ok(())
+}
+
+/// creates a `path:` IRI for the path
+pub(crate) fn iri_from_path<IP: AsRef<Path> + Into<PathBuf>>(path: IP) -> Result<IRI, CreatingSpecError> {
+    {
+        let path_ref = path.as_ref();
+        if let Ok(strfy) = new_str_path(&path_ref) {
+            if let Ok(iri) = IRI::from_parts("path", strfy) {
+                return Ok(iri)
+            }
+        }
+    }
+    Err(CreatingSpecErrorVariant::IRIConstructionFailed {
+        scheme: "path",
+        tail: path.into().into()
+    }.into())
+}
+
+/// creates a new resource with the same source as `resource` but given `use_name`
+fn with_use_name(resource: &Resource, name: String) -> Result<Resource, CreatingSpecError> {
+    let source = resource.source()
+        .ok_or_else(|| CreatingSpecErrorVariant::SourcelessResource)?;
+
+    Ok(Resource::new(Source {
+        iri: source.iri.clone(),
+        use_name: Some(name),
+        use_media_type: source.use_media_type.clone()
+    }))
+}
 
 /// Describes how to get the source of an render template.
 ///
@@ -270,7 +1446,7 @@ impl SubTemplateSpec {
 /// - reading the source from a file specified by an path
 /// - the source is directly given as an `String`
 ///
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum TemplateSource {
     //TODO have some `StringPath` type
     /// This uses string paths as the render engine might want to uses
@@ -294,6 +1470,18 @@ pub enum TemplateSource {
 
 impl TemplateSource {
 
+    /// creates a `Source` variant with the content read from the reader
+    ///
+    /// The reader is read until EOF, if it's content is not valid utf-8
+    /// an io error of kind `InvalidData` is returned.
+    pub fn from_reader<I, R>(id: I, mut reader: R) -> io::Result<TemplateSource>
+        where I: Into<String>, R: Read
+    {
+        let mut content = String::new();
+        reader.read_to_string(&mut content)?;
+        Ok(TemplateSource::Source { id: id.into(), content })
+    }
+
     /// returns the id for this source
     ///
     /// - If the source if a `Path` the id _is_
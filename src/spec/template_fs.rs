@@ -0,0 +1,244 @@
+use std::path::{Path, PathBuf};
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::io;
+
+use mail::Resource;
+use mail::file_buffer::FileBuffer;
+
+use ::error::CreatingSpecError;
+use ::utils::{new_string_path, media_type_from_extension};
+use ::settings::LoadSpecSettings;
+use super::TemplateSource;
+use super::from_dir::resource_from_path;
+
+/// A (read only) file system templates can be loaded from.
+///
+/// This is used by `TemplateSpec::from_fs_dir` and `TemplateSpec::from_fs_dirs`
+/// which apply the same logic as `from_dir`/`from_dirs` to the given file
+/// system. This allows loading templates e.g. from a bundle compiled into
+/// the binary (see `MemoryFs`) instead of the real file system (see `StdFs`).
+pub trait TemplateFs {
+
+    /// returns the entries of the given dir, the order does not matter
+    fn read_dir(&self, dir: &Path) -> io::Result<Vec<TemplateFsEntry>>;
+
+    /// returns the content of the given file
+    fn read_file(&self, file: &Path) -> io::Result<Vec<u8>>;
+
+    /// returns a path uniquely identifying the given dir
+    ///
+    /// This is used to detect cycles if `follow_symlinks` is enabled,
+    /// by default the path is returned as is.
+    fn canonicalize(&self, dir: &Path) -> io::Result<PathBuf> {
+        Ok(dir.to_owned())
+    }
+
+    /// creates the template source for the given body file
+    ///
+    /// By default the file is read and a `TemplateSource::Source` with the
+    /// files content and the path as id is returned.
+    fn template_source(&self, file: &Path) -> Result<TemplateSource, CreatingSpecError> {
+        let id = new_string_path(file)?;
+        let content = String::from_utf8(self.read_file(file)?)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        Ok(TemplateSource::Source { id, content })
+    }
+
+    /// creates the resource for the given embedding file
+    ///
+    /// By default the file is read into a resource without source, using
+    /// the media type associated with the files suffix. Reading the file
+    /// is retried as configured with `LoadSpecSettings::set_io_retries`.
+    ///
+    /// Stylesheets (`.css` files) have to be read into the resource, as
+    /// they are read from it when inlining styles (see `CssInliner`).
+    fn resource(&self, file: &Path, settings: &LoadSpecSettings)
+        -> Result<Resource, CreatingSpecError>
+    {
+        buffered_resource(self, file, settings)
+    }
+}
+
+/// reads the file into a resource without source, see `TemplateFs::resource`
+fn buffered_resource<F>(fs: &F, file: &Path, settings: &LoadSpecSettings)
+    -> Result<Resource, CreatingSpecError>
+    where F: TemplateFs + ?Sized
+{
+    let media_type = media_type_from_extension(file)?;
+    let content = settings.retry_io(|| fs.read_file(file))?;
+    Ok(Resource::sourceless_from_buffer(FileBuffer::new(media_type, content)))
+}
+
+/// returns true if the file is a stylesheet (`.css` file)
+fn is_stylesheet(file: &Path) -> bool {
+    file.extension()
+        .and_then(|extension| extension.to_str())
+        .map(|extension| extension.eq_ignore_ascii_case("css"))
+        .unwrap_or(false)
+}
+
+/// an entry of a dir in a `TemplateFs`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TemplateFsEntry {
+    name: OsString,
+    is_dir: bool
+}
+
+impl TemplateFsEntry {
+
+    pub fn new<N>(name: N, is_dir: bool) -> Self
+        where N: Into<OsString>
+    {
+        TemplateFsEntry { name: name.into(), is_dir }
+    }
+
+    /// the file name of the entry
+    pub fn name(&self) -> &OsString {
+        &self.name
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.is_dir
+    }
+}
+
+/// The `TemplateFs` for the real file system.
+///
+/// Body files are used as `TemplateSource::Path` and embeddings
+/// are resources referring to the files path, i.e. they are loaded
+/// lazily, which is the same as `TemplateSpec::from_dir` does. Only
+/// stylesheets are read on load, see `TemplateFs::resource`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct StdFs {
+    follow_symlinks: bool
+}
+
+impl StdFs {
+
+    /// creates a new instance, see `LoadSpecSettings::set_follow_symlinks`
+    pub fn new(follow_symlinks: bool) -> Self {
+        StdFs { follow_symlinks }
+    }
+
+    /// returns true if symlinks to directories are followed
+    pub fn follow_symlinks(&self) -> bool {
+        self.follow_symlinks
+    }
+}
+
+impl TemplateFs for StdFs {
+
+    fn read_dir(&self, dir: &Path) -> io::Result<Vec<TemplateFsEntry>> {
+        let mut entries = Vec::new();
+        for entry in dir.read_dir()? {
+            let entry = entry?;
+            let is_dir =
+                if self.follow_symlinks {
+                    entry.path().metadata()?.is_dir()
+                } else {
+                    entry.file_type()?.is_dir()
+                };
+            entries.push(TemplateFsEntry::new(entry.file_name(), is_dir));
+        }
+        Ok(entries)
+    }
+
+    fn read_file(&self, file: &Path) -> io::Result<Vec<u8>> {
+        use std::fs;
+        fs::read(file)
+    }
+
+    fn canonicalize(&self, dir: &Path) -> io::Result<PathBuf> {
+        dir.canonicalize()
+    }
+
+    fn template_source(&self, file: &Path) -> Result<TemplateSource, CreatingSpecError> {
+        Ok(TemplateSource::Path(new_string_path(file)?))
+    }
+
+    fn resource(&self, file: &Path, settings: &LoadSpecSettings)
+        -> Result<Resource, CreatingSpecError>
+    {
+        if is_stylesheet(file) {
+            buffered_resource(self, file, settings)
+        } else {
+            resource_from_path(file.to_owned(), settings)
+        }
+    }
+}
+
+/// An in-memory `TemplateFs`, e.g. for templates compiled into the binary.
+///
+/// Dirs are implied by the paths of the inserted files, e.g. inserting
+/// `templates/welcome/html/mail.html` makes `templates`, `templates/welcome`
+/// and `templates/welcome/html` dirs.
+///
+/// ```
+/// # use mail_render_template_engine::MemoryFs;
+/// let mut fs = MemoryFs::new();
+/// fs.insert_file("templates/welcome/text/mail.txt", &b"Hy {{name}}."[..]);
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct MemoryFs {
+    files: BTreeMap<PathBuf, Vec<u8>>
+}
+
+impl MemoryFs {
+
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// inserts a file, returning the previous content if there was one
+    pub fn insert_file<P, C>(&mut self, path: P, content: C) -> Option<Vec<u8>>
+        where P: Into<PathBuf>, C: Into<Vec<u8>>
+    {
+        self.files.insert(path.into(), content.into())
+    }
+
+    /// removes a file, returning it's content if there was one
+    pub fn remove_file(&mut self, path: &Path) -> Option<Vec<u8>> {
+        self.files.remove(path)
+    }
+}
+
+impl TemplateFs for MemoryFs {
+
+    fn read_dir(&self, dir: &Path) -> io::Result<Vec<TemplateFsEntry>> {
+        let mut entries: Vec<TemplateFsEntry> = Vec::new();
+        let mut found_dir = false;
+        for path in self.files.keys() {
+            let mut components = match path.strip_prefix(dir) {
+                Ok(rel_path) => rel_path.iter(),
+                Err(_) => continue
+            };
+            let name =
+                if let Some(name) = components.next() {
+                    name
+                } else {
+                    // dir is a file
+                    continue
+                };
+            found_dir = true;
+            let is_dir = components.next().is_some();
+            // the files are sorted so entries for the same name are adjacent
+            if entries.last().map(|last| last.name() != name).unwrap_or(true) {
+                entries.push(TemplateFsEntry::new(name, is_dir));
+            }
+        }
+        if found_dir {
+            Ok(entries)
+        } else {
+            Err(io::Error::new(io::ErrorKind::NotFound,
+                format!("no such dir: {}", dir.display())))
+        }
+    }
+
+    fn read_file(&self, file: &Path) -> io::Result<Vec<u8>> {
+        self.files.get(file)
+            .cloned()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound,
+                format!("no such file: {}", file.display())))
+    }
+}
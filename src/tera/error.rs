@@ -1,6 +1,8 @@
 use failure::Backtrace;
 use tera_crate;
 
+use ::error::{EncodingError, ErrorKind, RenderErrorKind};
+
 
 #[derive(Debug, Fail)]
 pub enum TeraError {
@@ -11,6 +13,13 @@ pub enum TeraError {
     #[fail(display="template id is used multiple times for different templates: {}", id)]
     TemplateIdCollision { id: String },
 
+    #[fail(display="{}", _0)]
+    Encoding(EncodingError),
+
+    /// a variable used in the template is not defined, `msg` is the message of the tera error
+    #[fail(display="{}", msg)]
+    UndefinedVariable { name: String, msg: String },
+
     #[fail(display="{}", kind)]
     RenderError {
         kind: tera_crate::ErrorKind,
@@ -19,13 +28,48 @@ pub enum TeraError {
 }
 
 
+impl_into_boxed_std_error!(TeraError);
+
+impl RenderErrorKind for TeraError {
+    fn kind(&self) -> ErrorKind {
+        match *self {
+            TeraError::UnknowTemplateId { .. } => ErrorKind::NotFound,
+            TeraError::TemplateIdCollision { .. } => ErrorKind::InvalidTemplate,
+            TeraError::Encoding(ref err) => err.kind(),
+            TeraError::UndefinedVariable { .. } => ErrorKind::InvalidData,
+            TeraError::RenderError { ref kind, .. } => match *kind {
+                // the data could not be serialized
+                tera_crate::ErrorKind::Json(_) => ErrorKind::InvalidData,
+                _ => ErrorKind::InvalidTemplate
+            }
+        }
+    }
+}
+
 //TODO/BUG actually impl a real from
 impl From<tera_crate::Error> for TeraError {
     fn from(err: tera_crate::Error) -> Self {
+        // the undefined variable error is normally wrapped in a "failed to render" error
+        let undefined = err.iter()
+            .map(|err| err.to_string())
+            .filter_map(|msg| undefined_variable_name(&msg).map(|name| (name, msg)))
+            .next();
+        if let Some((name, msg)) = undefined {
+            return TeraError::UndefinedVariable { name, msg };
+        }
+
         let tera_crate::Error(kind, _state) = err;
         TeraError::RenderError {
             kind,
             backtrace: Backtrace::new()
         }
     }
-}
\ No newline at end of file
+}
+
+/// extracts the name from tera's "Variable `<name>` not found in context ..." message
+fn undefined_variable_name(msg: &str) -> Option<String> {
+    let start = msg.find("Variable `")? + "Variable `".len();
+    let rest = &msg[start..];
+    let end = rest.find("` not found")?;
+    Some(rest[..end].to_owned())
+}
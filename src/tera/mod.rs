@@ -1,15 +1,27 @@
+use std::collections::HashSet;
+
 use tera_crate::{Tera, TesterFn, FilterFn, GlobalFn};
 use serde::Serialize;
+use serde_json::{self, Map, Value};
 
-use ::traits::{RenderEngine, RenderEngineBase, AdditionalCIds};
+use ::traits::{RenderEngine, RenderEngineBase, AdditionalCIds, InheritanceSupport};
+use ::error::EncodingError;
 use ::spec::{TemplateSpec, SubTemplateSpec, TemplateSource};
+use ::data_wrapper::{DataWrapper, path_in_data};
 
 use self::error::TeraError;
 
 pub mod error;
 
+/// the name of the template rendered by `render_once`, it's only added to a copy of the `Tera` instance
+const RENDER_ONCE_NAME: &str = "__render_once__.html";
+
 pub struct TeraRenderEngine {
-    tera: Tera
+    tera: Tera,
+    flatten_data: bool,
+    data_key: String,
+    /// the names of templates which are not part of any spec
+    base_templates: HashSet<String>
 }
 
 impl TeraRenderEngine {
@@ -26,8 +38,66 @@ impl TeraRenderEngine {
     ///
     pub fn new(base_templats_glob: &str) -> Result<Self, TeraError> {
         let tera = Tera::new(base_templats_glob)?;
+        let base_templates = tera.templates.keys().cloned().collect();
+
+        Ok(TeraRenderEngine { tera, flatten_data: true, data_key: "data".to_owned(), base_templates })
+    }
 
-        Ok(TeraRenderEngine { tera })
+    /// returns the (sorted) names of all base templates
+    ///
+    /// Base templates are the templates loaded from the glob passed to `new`
+    /// and the templates added with `register_base_template`. They are not
+    /// part of any spec, so they are not affected by unloading/reloading specs.
+    pub fn base_template_names(&self) -> Vec<&str> {
+        let mut names = self.base_templates.iter()
+            .map(|name| name.as_str())
+            .collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+
+    /// returns the (sorted) names of all templates loaded from specs, i.e. all non base templates
+    pub fn preloaded_template_names(&self) -> Vec<&str> {
+        let mut names = self.tera.templates.keys()
+            .map(|name| name.as_str())
+            .filter(|name| !self.base_templates.contains(*name))
+            .collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+
+    /// returns true if a base template with the given name exists, see `base_template_names`
+    pub fn is_base_template(&self, name: &str) -> bool {
+        self.base_templates.contains(name)
+    }
+
+    /// if enabled (the default) the data is passed to templates at the top level
+    ///
+    /// By default the fields of the data are at the top level in the templates
+    /// (e.g. `{{ name }}`), if disabled the data is nested in `data` instead
+    /// (e.g. `{{ data.name }}`, see `RenderEngineBase::set_data_key` for using
+    /// another key), which was the behavior of previous versions. In both cases
+    /// content ids are accessible through `cids` and globals through `globals`.
+    ///
+    /// If enabled the data has to serialize to a map (e.g. be a struct) and
+    /// `cids`, `cid_info` and `globals` are reserved, if the data has a field
+    /// with one of this names it's overridden.
+    pub fn set_flatten_data(&mut self, enabled: bool) {
+        self.flatten_data = enabled;
+    }
+
+    /// returns true if the data is passed at the top level, see `set_flatten_data`
+    pub fn flatten_data(&self) -> bool {
+        self.flatten_data
+    }
+
+    /// the key the data is nested under, or `None` if it's passed at the top level
+    fn nested_data_key(&self) -> Option<&str> {
+        if self.flatten_data {
+            None
+        } else {
+            Some(&self.data_key)
+        }
     }
 
     /// expose `Tera::register_filter`
@@ -50,6 +120,72 @@ impl TeraRenderEngine {
         self.tera.autoescape_on(suffixes)
     }
 
+    /// renders a template string without registering it
+    ///
+    /// The source is added to a copy of the engine's `Tera` instance and
+    /// rendered with it, so registered filters, testers and global functions
+    /// can be used, as well as the base templates (e.g. through `{% extends %}`),
+    /// while the templates of the engine are not changed. The source is rendered
+    /// as html template, i.e. it's autoescaped if `.html` is one of the autoescape
+    /// suffixes (the default, see `set_autoescape_file_suffixes`).
+    ///
+    /// The data is passed to the template the same way as in `render` i.e.
+    /// it's accessible at the top level (or through `data`, see `set_flatten_data`)
+    /// and the content ids through `cids`.
+    ///
+    /// Note that copying the `Tera` instance copies all loaded templates, so
+    /// this should not be used in a hot path.
+    pub fn render_once<D>(
+        &self,
+        source: &str,
+        data: &D,
+        cids: AdditionalCIds
+    ) -> Result<String, TeraError>
+        where D: Serialize
+    {
+        let mut tera = self.tera.clone();
+        tera.add_raw_template(RENDER_ONCE_NAME, source)?;
+        let globals = Map::new();
+        let data = &DataWrapper::new(data, cids, &globals, self.nested_data_key());
+        Ok(tera.render(RENDER_ONCE_NAME, data)?)
+    }
+
+    fn load_spec_templates(&mut self, spec: &TemplateSpec) -> Result<Vec<String>, TeraError> {
+        implement_load_helper! {
+            input::<Tera>(spec, &mut self.tera);
+            error(TeraError);
+            collision_error_fn(|id| { TeraError::TemplateIdCollision { id } });
+            has_template_fn(|tera, id| { tera.templates.contains_key(id) });
+            remove_fn(|tera, id| { tera.templates.remove(*id) });
+            add_file_fn(|tera, path| { Ok(tera.add_template_file(path, None)?) });
+            add_content_fn(|tera, id, content| { Ok(tera.add_raw_template(id, content)?) });
+        }
+    }
+
+    /// adds the partials of the spec as templates named like the partials
+    ///
+    /// If adding a partial fails the partials added before are removed again.
+    fn load_partials(&mut self, spec: &TemplateSpec) -> Result<(), TeraError> {
+        for (idx, &(ref name, ref source)) in spec.partials().iter().enumerate() {
+            let result =
+                if self.tera.templates.contains_key(name) {
+                    Err(TeraError::TemplateIdCollision { id: name.clone() })
+                } else {
+                    match *source {
+                        TemplateSource::Path(ref path) => self.tera.add_template_file(path, Some(name)),
+                        TemplateSource::Source { ref content, .. } => self.tera.add_raw_template(name, content)
+                    }.map_err(TeraError::from)
+                };
+            if let Err(err) = result {
+                for &(ref name, _) in &spec.partials()[..idx] {
+                    self.tera.templates.remove(name);
+                }
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+
 }
 
 impl RenderEngineBase for TeraRenderEngine {
@@ -60,40 +196,99 @@ impl RenderEngineBase for TeraRenderEngine {
     type RenderError = TeraError;
     type LoadingError = TeraError;
 
-    fn load_templates(&mut self, spec: &TemplateSpec) -> Result<(), Self::LoadingError> {
-        implement_load_helper! {
-            input::<Tera>(spec, &mut self.tera);
-            error(TeraError);
-            collision_error_fn(|id| { TeraError::TemplateIdCollision { id } });
-            has_template_fn(|tera, id| { tera.templates.contains_key(id) });
-            remove_fn(|tera, id| { tera.templates.remove(*id) });
-            add_file_fn(|tera, path| { Ok(tera.add_template_file(path, None)?) });
-            add_content_fn(|tera, id, content| { Ok(tera.add_raw_template(id, content)?) });
+    /// loads the partials of the spec (first, so they can be extended) and then it's sources
+    fn load_templates(&mut self, spec: &TemplateSpec) -> Result<Vec<String>, Self::LoadingError> {
+        self.load_partials(spec)?;
+        match self.load_spec_templates(spec) {
+            Ok(loaded) => Ok(loaded),
+            Err(err) => {
+                for &(ref name, _) in spec.partials() {
+                    self.tera.templates.remove(name);
+                }
+                Err(err)
+            }
         }
     }
 
 
     /// This can be used to reload a templates.
     fn unload_templates(&mut self, spec: &TemplateSpec) {
-        for sub_spec in spec.sub_specs() {
-            let id = sub_spec.source().id();
-            self.tera.templates.remove(id);
+        for source in spec.all_sources() {
+            self.tera.templates.remove(source.id());
         }
+        for &(ref name, _) in spec.partials() {
+            self.tera.templates.remove(name);
+        }
+    }
+
+
+    /// builds the inheritance chains of all templates
+    fn prewarm(&mut self) -> Result<(), Self::LoadingError> {
+        Ok(self.tera.build_inheritance_chains()?)
+    }
+
+    fn supports_inheritance(&self) -> InheritanceSupport {
+        InheritanceSupport::Extends
+    }
+
+    /// disables `flatten_data` and nests the data under the key instead of `data`
+    fn set_data_key(&mut self, key: &str) -> bool {
+        self.flatten_data = false;
+        self.data_key = key.to_owned();
+        true
     }
 
+    /// adds the base template like the templates matched by the glob passed to `new`
+    fn register_base_template(&mut self, name: &str, source: &str)
+        -> Option<Result<(), Self::LoadingError>>
+    {
+        if self.tera.templates.contains_key(name) {
+            return Some(Err(TeraError::TemplateIdCollision { id: name.to_owned() }));
+        }
+        let res = self.tera.add_raw_template(name, source).map_err(TeraError::from);
+        if res.is_ok() {
+            self.base_templates.insert(name.to_owned());
+        }
+        Some(res)
+    }
 
     fn unknown_template_id_error(id: &str) -> Self::RenderError {
         TeraError::UnknowTemplateId { id: id.to_owned() }
     }
-}
 
+    fn encoding_error(err: EncodingError) -> Self::RenderError {
+        TeraError::Encoding(err)
+    }
+
+    fn undefined_variable(&self, err: &Self::RenderError) -> Option<String> {
+        match *err {
+            TeraError::UndefinedVariable { ref name, .. } =>
+                path_in_data(name, self.nested_data_key()).map(|path| path.to_owned()),
+            _ => None
+        }
+    }
+
+    fn render_preserialized(
+        &self,
+        spec: &SubTemplateSpec,
+        data: &Value,
+        cids: AdditionalCIds,
+        globals: &Map<String, Value>
+    ) -> Option<Result<String, Self::RenderError>> {
+        Some(RenderEngine::<Value>::render_with_globals(self, spec, data, cids, globals))
+    }
 
-#[derive(Serialize)]
-struct DataWrapper<'a,D: Serialize + 'a> {
-    data: &'a D,
-    cids: AdditionalCIds<'a>
+    fn render_context(
+        &self,
+        data: &Value,
+        cids: AdditionalCIds,
+        globals: &Map<String, Value>
+    ) -> Option<Value> {
+        serde_json::to_value(&DataWrapper::new(data, cids, globals, self.nested_data_key())).ok()
+    }
 }
 
+
 impl<D> RenderEngine<D> for TeraRenderEngine
     where D: Serialize
 {
@@ -103,9 +298,27 @@ impl<D> RenderEngine<D> for TeraRenderEngine
         data: &D,
         cids: AdditionalCIds
     ) -> Result<String, Self::RenderError> {
-        let data = &DataWrapper { data, cids };
+        self.render_with_globals(spec, data, cids, &Map::new())
+    }
+
+    fn render_with_globals(
+        &self,
+        spec: &SubTemplateSpec,
+        data: &D,
+        cids: AdditionalCIds,
+        globals: &Map<String, Value>
+    ) -> Result<String, Self::RenderError> {
+        let data = &DataWrapper::new(data, cids, globals, self.nested_data_key());
         let id = spec.source().id();
         Ok(self.tera.render(id, data)?)
     }
+
+    /// serializes the data into a `serde_json::Value`
+    ///
+    /// If serialization fails `None` is returned so that the error
+    /// is reported when rendering.
+    fn preserialize(&self, data: &D) -> Option<Value> {
+        serde_json::to_value(data).ok()
+    }
 }
 
@@ -0,0 +1,25 @@
+//! Helpers for testing code which uses this crate, e.g. to render templates.
+//!
+//! This module is only available with the `test-util` feature.
+use ::context::{SimpleContext, new_simple_context};
+
+/// the domain used by `test_context`
+pub const TEST_DOMAIN: &str = "rte.test";
+
+/// creates a `Context` which can be used to render templates in tests
+///
+/// Content ids (and message ids) generated by it are under the domain
+/// `TEST_DOMAIN`.
+pub fn test_context() -> SimpleContext {
+    test_context_with_domain(TEST_DOMAIN)
+}
+
+/// creates a `Context` generating content ids under the given domain
+///
+/// # Panics
+///
+/// If the domain is not a valid domain.
+pub fn test_context_with_domain(domain: &str) -> SimpleContext {
+    new_simple_context(domain)
+        .expect("failed to setup test context")
+}
@@ -1,11 +1,22 @@
 use std::collections::{HashMap, HashSet};
+use std::fmt::Debug;
+#[cfg(feature="fs")]
+use std::path::Path;
 
 use failure::Fail;
 use serde::{Serializer, Serialize};
-use headers::components::ContentId;
+use serde::ser::SerializeStruct;
+use serde_json::{Map, Value};
+use headers::components::{ContentId, MediaType};
+use mail::Resource;
+use mail::file_buffer::{FileBuffer, TransferEncodedFileBuffer, TransferEncodingHint};
 use template::EmbeddedWithCId;
 
-use ::spec::{TemplateSpec, SubTemplateSpec};
+use ::spec::{TemplateSpec, SubTemplateSpec, TransferEncoding};
+use ::error::EncodingError;
+use ::utils::file_name_of_iri;
+#[cfg(feature="fs")]
+use ::utils::media_type_from_extension;
 
 /// Trait implemented by any `RenderEngine`
 ///
@@ -30,6 +41,11 @@ pub trait RenderEngineBase {
 
     /// loads the templates associated with the given spec.
     ///
+    /// Returns the ids of all templates which where registered,
+    /// in the order of the sub-templates (for each sub-template the
+    /// id of it's source followed by the ids of it's variants).
+    /// The partials of the spec (see `TemplateSpec::insert_partial`)
+    /// are registered under their names but not included in the ids.
     ///
     /// # Error
     ///
@@ -44,7 +60,7 @@ pub trait RenderEngineBase {
     /// - the template in the file is malformed
     /// - permissions to read the file are missing
     ///
-    fn load_templates(&mut self, spec: &TemplateSpec) -> Result<(), Self::LoadingError>;
+    fn load_templates(&mut self, spec: &TemplateSpec) -> Result<Vec<String>, Self::LoadingError>;
 
     /// unloads templates (if loaded)
     ///
@@ -54,6 +70,67 @@ pub trait RenderEngineBase {
     /// This can be used to reload a templates.
     fn unload_templates(&mut self, spec: &TemplateSpec);
 
+    /// reloads the templates associated with the given spec
+    ///
+    /// This re-reads the underlying template files, so changes to
+    /// them are picked up. The default implementation unloads the
+    /// templates and then loads them again, returning the ids of
+    /// the loaded templates like `load_templates`.
+    ///
+    /// # Error
+    ///
+    /// The same errors as for `load_templates` can occur, in which
+    /// case the templates of the spec are no longer loaded.
+    fn reload_templates(&mut self, spec: &TemplateSpec) -> Result<Vec<String>, Self::LoadingError> {
+        self.unload_templates(spec);
+        self.load_templates(spec)
+    }
+
+    /// makes sure all loaded templates are fully parsed/compiled
+    ///
+    /// Some render engines do part of the work lazily, which means the
+    /// first render of a template is slower. This can be used to do that
+    /// work up front (e.g. after loading all templates at startup).
+    ///
+    /// The default implementation does nothing, which is fine for engines
+    /// which parse templates completely when loading them (e.g. handlebars).
+    fn prewarm(&mut self) -> Result<(), Self::LoadingError> {
+        Ok(())
+    }
+
+    /// describes how the engine supports sharing templates, e.g. a common layout
+    ///
+    /// The default implementation returns `InheritanceSupport::None`.
+    fn supports_inheritance(&self) -> InheritanceSupport {
+        InheritanceSupport::None
+    }
+
+    /// makes the engine pass the data to templates nested under the given key
+    ///
+    /// This is used for `RenderTemplateEngineConfig::data_key`. Returns `false`
+    /// if the engine doesn't wrap the data passed to templates, which is what
+    /// the default implementation does.
+    fn set_data_key(&mut self, key: &str) -> bool {
+        let _ = key;
+        false
+    }
+
+    /// registers a template which can be used by the templates of all specs
+    ///
+    /// Depending on the engine (see `supports_inheritance`) the template can be
+    /// extended by other templates (e.g. `{% extends "base_mail.html" %}` with
+    /// tera) or used as partial (e.g. `{{> base_mail }}` with handlebars). Base
+    /// templates are not part of any spec and are not unloaded with specs.
+    ///
+    /// Returns `None` if the engine doesn't support base templates, which is
+    /// what the default implementation does.
+    fn register_base_template(&mut self, name: &str, source: &str)
+        -> Option<Result<(), Self::LoadingError>>
+    {
+        let _ = (name, source);
+        None
+    }
+
     /// create a error representing that not template for given id was found
     ///
     /// Note that the id is _not_ a template name but the id of an
@@ -64,6 +141,71 @@ pub trait RenderEngineBase {
     /// split into multiple smaller templates which are rendered
     /// separately and then glued together again.
     fn unknown_template_id_error(id: &str) -> Self::RenderError;
+
+    /// create a error representing that a rendered body could not be encoded
+    ///
+    /// This is used if the rendered body contains characters which can not
+    /// be represented in the charset of the bodies media type (or the charset
+    /// is not supported), see `SubTemplateSpec::encode_body`.
+    fn encoding_error(err: EncodingError) -> Self::RenderError;
+
+    /// returns the path (e.g. `"user.name"`) of the undefined variable which caused the error
+    ///
+    /// The path is relative to the data passed to the engine. This is used
+    /// by `RenderTemplateEngine::render_preview_lenient` to fill in
+    /// placeholders for missing data. The default implementation returns
+    /// `None`, in which case lenient previews fail like normal renders.
+    fn undefined_variable(&self, err: &Self::RenderError) -> Option<String> {
+        let _ = err;
+        None
+    }
+
+    /// renders the template with data pre-serialized by `RenderEngine::preserialize`
+    ///
+    /// This is used by the `RenderTemplateEngine` for all bodies of a template
+    /// if `preserialize` returned a value. The default implementation returns
+    /// `None` in which case the `RenderTemplateEngine` falls back to calling
+    /// `RenderEngine::render_with_globals` with the original data.
+    fn render_preserialized(
+        &self,
+        template: &SubTemplateSpec,
+        data: &Value,
+        additional_cids: AdditionalCIds,
+        globals: &Map<String, Value>
+    ) -> Option<Result<String, Self::RenderError>> {
+        let _ = (template, data, additional_cids, globals);
+        None
+    }
+
+    /// returns the data templates are rendered with as json, without rendering anything
+    ///
+    /// This is the (pre-serialized) data combined with the content ids and
+    /// globals the way the engine passes them to the templates, e.g. with
+    /// `cids` and `globals` next to the flattened data. It's used by
+    /// `RenderTemplateEngine::debug_render_context`. The default
+    /// implementation returns `None`.
+    fn render_context(
+        &self,
+        data: &Value,
+        additional_cids: AdditionalCIds,
+        globals: &Map<String, Value>
+    ) -> Option<Value> {
+        let _ = (data, additional_cids, globals);
+        None
+    }
+}
+
+
+
+/// How a render engine supports sharing templates between templates
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum InheritanceSupport {
+    /// templates can not use other templates
+    None,
+    /// templates can extend base templates and override blocks of them (e.g. tera)
+    Extends,
+    /// templates can include base templates as partials (e.g. handlebars)
+    Partials
 }
 
 
@@ -88,8 +230,103 @@ pub trait RenderEngine<D>: RenderEngineBase {
         additional_cids: AdditionalCIds
     ) -> Result<String, <Self as RenderEngineBase>::RenderError>;
 
+    /// renders the template making the engine-wide globals available to it
+    ///
+    /// This is used by the `RenderTemplateEngine` (see
+    /// `RenderTemplateEngine::set_global`). The default implementation
+    /// ignores the globals and calls `render`, the tera and handlebars
+    /// render engines make them accessible through `globals`.
+    fn render_with_globals(
+        &self,
+        template: &SubTemplateSpec,
+        data: &D,
+        additional_cids: AdditionalCIds,
+        globals: &Map<String, Value>
+    ) -> Result<String, <Self as RenderEngineBase>::RenderError> {
+        let _ = globals;
+        self.render(template, data, additional_cids)
+    }
+
+    /// serializes the data once so that it can be reused for all bodies of a template
+    ///
+    /// Templates often have multiple bodies (e.g. text and html) and by
+    /// default the data is serialized anew for each of them. If this returns
+    /// a value the `RenderTemplateEngine` renders all bodies of the template
+    /// using `RenderEngineBase::render_preserialized` with it instead.
+    ///
+    /// The default implementation returns `None`, i.e. the data is passed to
+    /// `render_with_globals` for each body.
+    fn preserialize(&self, data: &D) -> Option<Value> {
+        let _ = data;
+        None
+    }
+}
+
+
+/// Trait for converting rendered markdown bodies into html and plain text
+///
+/// If a `MarkdownRenderer` is set on a `RenderTemplateEngine` each
+/// sub-template with a `text/markdown` media type is first rendered
+/// with the render engine and the result is then turned into two
+/// alternative bodies, a `text/plain` and a `text/html` one. The
+/// embeddings of the sub-template are added to the html body.
+///
+/// This crate does not provide an implementation, so that the
+/// markdown crate to use can be freely chosen.
+pub trait MarkdownRenderer: Debug + Send + Sync {
+
+    /// converts the markdown to html
+    fn render_html(&self, markdown: &str) -> String;
+
+    /// converts the markdown to plain text
+    ///
+    /// As markdown is meant to be readable as plain text the default
+    /// implementation uses the markdown as is.
+    fn render_plain_text(&self, markdown: &str) -> String {
+        markdown.to_owned()
+    }
+}
+
+/// Trait for moving the styles of html bodies into inline `style` attributes
+///
+/// Many mail clients ignore `<style>` blocks, so html mails need inlined
+/// styles. If a `CssInliner` is set on a `RenderTemplateEngine` it's called
+/// with each rendered `text/html` body (incl. the html body generated from
+/// markdown) and the content of the stylesheets embedded for the body, i.e.
+/// of the `text/css` embeddings (which are read from `.css` files when the
+/// spec is loaded), ordered by embedding name.
+///
+/// This crate does not provide an implementation, so that the css inliner
+/// crate to use can be freely chosen.
+pub trait CssInliner: Debug + Send + Sync {
+
+    /// returns the html with the styles of it's `<style>` blocks and of the stylesheets inlined
+    fn inline(&self, html: String, stylesheets: &[String]) -> String;
+}
+
+/// Trait for processing rendered bodies, see `RenderTemplateEngine::add_body_post_processor`
+///
+/// This can be used to e.g. inject a footer or minify html bodies. A
+/// processor is called with the media type of each body, so it has to
+/// skip bodies it doesn't apply to itself. A list of processors is a
+/// processor applying them in order, so chains can be composed.
+pub trait BodyPostProcessor: Debug + Send + Sync {
+
+    /// returns the processed body
+    ///
+    /// Errors should be `EncodingError::PostProcessingFailed` errors.
+    fn process(&self, media_type: &MediaType, body: String) -> Result<String, EncodingError>;
 }
 
+impl BodyPostProcessor for Vec<Box<BodyPostProcessor>> {
+    fn process(&self, media_type: &MediaType, body: String) -> Result<String, EncodingError> {
+        let mut body = body;
+        for processor in self.iter() {
+            body = processor.process(media_type, body)?;
+        }
+        Ok(body)
+    }
+}
 
 /// A type aggregating multiple `String => EmbeddedWithCId` mappings
 ///
@@ -100,14 +337,45 @@ pub trait RenderEngine<D>: RenderEngineBase {
 /// It allows template engines to present a single `cid` (or similar)
 /// field through which all template provided `cid` can be accessed
 /// through their name.
+///
+/// It always serializes as a `name => content id` map, so e.g.
+/// `{{ cids.logo }}` is the content id. With `with_descriptors` the
+/// render engines additionally pass a `name => descriptor` map as
+/// `cid_info` (see `descriptors`), so e.g. `{{ cid_info.logo.mime }}`
+/// can be used, too.
+#[derive(Clone, Copy)]
 pub struct AdditionalCIds<'a> {
-    additional_resources: &'a [&'a HashMap<String, EmbeddedWithCId>]
+    additional_resources: &'a [&'a HashMap<String, EmbeddedWithCId>],
+    descriptors: bool
 }
 
 impl<'a> AdditionalCIds<'a> {
 
     pub fn new(additional_resources: &'a [&'a HashMap<String, EmbeddedWithCId>]) -> Self {
-        AdditionalCIds { additional_resources }
+        AdditionalCIds { additional_resources, descriptors: false }
+    }
+
+    /// makes the render engines pass descriptors of the content ids as `cid_info`, see `descriptors`
+    pub fn with_descriptors(mut self) -> Self {
+        self.descriptors = true;
+        self
+    }
+
+    /// true if descriptors are passed to the templates, see `with_descriptors`
+    pub fn uses_descriptors(&self) -> bool {
+        self.descriptors
+    }
+
+    /// returns the `name => descriptor` map if descriptors are enabled
+    ///
+    /// Each descriptor is an object with the fields `cid` (the content id),
+    /// `mime` (the media type, if known) and `filename` (if known).
+    pub fn descriptors(&self) -> Option<CIdDescriptors<'a>> {
+        if self.descriptors {
+            Some(CIdDescriptors(*self))
+        } else {
+            None
+        }
     }
 
 
@@ -139,6 +407,64 @@ impl<'a> Serialize for AdditionalCIds<'a> {
     }
 }
 
+/// serializes the content ids of a `AdditionalCIds` as `name => descriptor` map
+#[derive(Clone, Copy)]
+pub struct CIdDescriptors<'a>(AdditionalCIds<'a>);
+
+impl<'a> Serialize for CIdDescriptors<'a> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+        where S: Serializer
+    {
+        let mut existing_keys = HashSet::new();
+        serializer.collect_map(
+            self.0.additional_resources
+            .iter()
+            .flat_map(|m| m.iter())
+            .filter(|&(key, _)| existing_keys.insert(key))
+            .map(|(key, embedding)| (key, CIdDescriptor(embedding)))
+        )
+    }
+}
+
+/// serializes a embedding as `{ cid, mime, filename }` object
+struct CIdDescriptor<'a>(&'a EmbeddedWithCId);
+
+/// the media type associated with the extension of the file name
+#[cfg(feature="fs")]
+fn media_type_of_file_name(file_name: &str) -> Option<String> {
+    media_type_from_extension(Path::new(file_name)).ok()
+        .map(|media_type| media_type.full_type().to_string())
+}
+
+/// without the `fs` feature there is no extension to media type mapping
+#[cfg(not(feature="fs"))]
+fn media_type_of_file_name(_file_name: &str) -> Option<String> {
+    None
+}
+
+impl<'a> Serialize for CIdDescriptor<'a> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+        where S: Serializer
+    {
+        let source = self.0.resource().source();
+        let file_name = source.and_then(|source| {
+            source.use_name.clone()
+                .or_else(|| file_name_of_iri(source.iri.as_str()))
+        });
+        let media_type = source.and_then(|source| {
+            source.use_media_type.as_ref()
+                .map(|media_type| media_type.full_type().to_string())
+                .or_else(|| media_type_of_file_name(file_name.as_ref()?))
+        });
+
+        let mut state = serializer.serialize_struct("CIdDescriptor", 3)?;
+        state.serialize_field("cid", self.0.content_id().as_str())?;
+        state.serialize_field("mime", &media_type)?;
+        state.serialize_field("filename", &file_name)?;
+        state.end()
+    }
+}
+
 
 
 /// This macros helps implementing `RenderEngineBase::load_templates`
@@ -164,8 +490,8 @@ macro_rules! implement_load_helper {
     ) => ({
         let mut loaded = Vec::new();
 
-        for sub_spec in $spec.sub_specs() {
-            match *sub_spec.source() {
+        for source in $spec.all_sources() {
+            match *source {
                 TemplateSource::Path(ref path) => {
                     let $path = path;
                     try_add_sub_template(
@@ -187,7 +513,7 @@ macro_rules! implement_load_helper {
                 }
             }
         }
-        return Ok(());
+        return Ok(loaded.into_iter().map(|id| id.to_owned()).collect());
 
         fn try_add_sub_template<'s, 'l: 's>(
             $ht_engine: &'s mut $EType,
@@ -215,4 +541,87 @@ macro_rules! implement_load_helper {
             }
         }
     });
-}
\ No newline at end of file
+}
+
+/// Trait for turning the rendered and encoded bodies into resources
+///
+/// If a `BodyResourceFactory` is set on a `RenderTemplateEngine` it's
+/// used instead of wrapping the body in a `FileBuffer` with the bodies
+/// media type, e.g. to instrument bodies or to add parameters hinting
+/// at the transfer encoding to use.
+pub trait BodyResourceFactory: Debug + Send + Sync {
+
+    /// creates the resource for a body with the given media type and (encoded) content
+    fn create_resource(&self, media_type: MediaType, body: Vec<u8>) -> Resource;
+
+    /// like `create_resource` but with the transfer encoding preferred by the sub-template
+    ///
+    /// This is called for every body. By default the resource is created
+    /// with `create_resource` and the preference (if any) is applied to it
+    /// using `apply_transfer_encoding`. Override this to create them differently.
+    fn create_resource_with_transfer_encoding(
+        &self,
+        media_type: MediaType,
+        body: Vec<u8>,
+        transfer_encoding: Option<TransferEncoding>
+    ) -> Resource {
+        let resource = self.create_resource(media_type, body);
+        match transfer_encoding {
+            Some(encoding) => apply_transfer_encoding(resource, encoding),
+            None => resource
+        }
+    }
+}
+
+/// applies the preferred transfer encoding to the resource created for a body
+///
+/// If the resource contains a loaded buffer which is not yet transfer encoded
+/// (e.g. one created with `Resource::sourceless_from_buffer`) a resource with
+/// the buffer encoded using the preference is returned. Other resources are
+/// returned unchanged, e.g. if they are already transfer encoded.
+pub fn apply_transfer_encoding(resource: Resource, encoding: TransferEncoding) -> Resource {
+    let buffer =
+        match resource.get_if_loaded() {
+            Some(buffer) => FileBuffer::clone(&buffer),
+            None => return resource
+        };
+    let hint = transfer_encoding_hint(encoding);
+    let encoded = TransferEncodedFileBuffer::encode_buffer(buffer, Some(&hint));
+    Resource::sourceless_from_encoded_buffer(encoded)
+}
+
+/// wraps the (encoded) body in a `FileBuffer` using the preferred transfer encoding (if any)
+///
+/// This is how bodies are turned into resources if no `BodyResourceFactory` is set.
+pub fn body_resource(media_type: MediaType, body: Vec<u8>, transfer_encoding: Option<TransferEncoding>) -> Resource {
+    let buffer = FileBuffer::new(media_type, body);
+    match transfer_encoding {
+        Some(encoding) => {
+            let hint = transfer_encoding_hint(encoding);
+            let encoded = TransferEncodedFileBuffer::encode_buffer(buffer, Some(&hint));
+            Resource::sourceless_from_encoded_buffer(encoded)
+        },
+        None => Resource::sourceless_from_buffer(buffer)
+    }
+}
+
+fn transfer_encoding_hint(encoding: TransferEncoding) -> TransferEncodingHint {
+    match encoding {
+        TransferEncoding::QuotedPrintable => TransferEncodingHint::UseQuotedPrintable,
+        TransferEncoding::Base64 => TransferEncodingHint::UseBase64
+    }
+}
+
+/// Trait for transforming specs when they are inserted into a `RenderTemplateEngine`
+///
+/// If a `SpecTransform` is set it's called in `RenderTemplateEngine::insert_spec`
+/// before the templates of the spec are loaded into the render engine. This
+/// allows e.g. minifying html templates or stripping comments once at load
+/// time instead of with every render, by replacing the sources of the
+/// sub-templates (e.g. with a `TemplateSource::Source` containing the
+/// minified template).
+pub trait SpecTransform: Debug + Send + Sync {
+
+    /// transforms the spec which is about to be inserted with the given id
+    fn transform(&self, template_id: &str, spec: &mut TemplateSpec);
+}
@@ -1,16 +1,23 @@
 use std::path::Path;
 use std::ffi::OsStr;
+#[cfg(feature="fs")]
 use std::process::Command;
-use std::io;
+use std::io::{self, Write};
+use std::mem::replace;
 
 use failure::Fail;
 
+#[cfg(feature="fs")]
 use conduit_mime_types::Types as TypesBySuffix;
+use flate2::Compression;
+use flate2::write::GzEncoder;
+use serde_json::{Map, Value};
 
 use headers::components::MediaType;
 
-use ::error::{CreatingSpecError, CreatingSpecErrorVariant};
+use ::error::{CreatingSpecError, CreatingSpecErrorVariant, EncodingError};
 
+#[cfg(feature="fs")]
 lazy_static! {
     static ref TYPES_BY_SUFFIX: TypesBySuffix = {
         TypesBySuffix::new()
@@ -49,7 +56,7 @@ pub(crate) fn check_string_path(path: &Path) -> Result<(), CreatingSpecError> {
     }
 }
 
-
+#[cfg(feature="fs")]
 pub(crate) fn sniff_media_type(path: &Path) -> Result<MediaType, CreatingSpecError> {
     //this does not work for
     // 1. multi part extensions like .tar.gz
@@ -85,6 +92,100 @@ pub(crate) fn sniff_media_type(path: &Path) -> Result<MediaType, CreatingSpecErr
 }
 
 
+/// a hash of the string which is the same across processes and rust versions (FNV-1a)
+pub(crate) fn stable_hash(data: &str) -> u64 {
+    const OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+    const PRIME: u64 = 0x100000001b3;
+    data.bytes().fold(OFFSET_BASIS, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(PRIME)
+    })
+}
+
+/// merges `overlay` into `base`, values in `overlay` take precedence
+///
+/// Objects are merged recursively, any other value in `overlay`
+/// (incl. arrays and `null`) replaces the value in `base`.
+pub(crate) fn deep_merge(base: Value, overlay: Value) -> Value {
+    match (base, overlay) {
+        (Value::Object(mut base), Value::Object(overlay)) => {
+            for (key, value) in overlay {
+                let merged =
+                    if let Some(base_value) = base.remove(&key) {
+                        deep_merge(base_value, value)
+                    } else {
+                        value
+                    };
+                base.insert(key, merged);
+            }
+            Value::Object(base)
+        },
+        (_, overlay) => overlay
+    }
+}
+
+/// inserts a `"[<path>]"` placeholder string at the dot separated path
+///
+/// Missing objects on the path are created. Returns false (and doesn't
+/// change anything) if there already is a value at the path or if a value
+/// on the path is not an object.
+pub(crate) fn insert_placeholder(data: &mut Value, path: &str) -> bool {
+    let mut current = data;
+    let mut keys = path.split('.').peekable();
+    while let Some(key) = keys.next() {
+        let map =
+            match *current {
+                Value::Object(ref mut map) => map,
+                _ => return false
+            };
+
+        if keys.peek().is_none() {
+            if map.contains_key(key) {
+                return false;
+            }
+            map.insert(key.to_owned(), Value::String(format!("[{}]", path)));
+            return true;
+        }
+
+        current = map.entry(key.to_owned())
+            .or_insert_with(|| Value::Object(Map::new()));
+    }
+    false
+}
+
+/// returns the file name of the path of a (e.g. `path:`) IRI
+pub(crate) fn file_name_of_iri(iri: &str) -> Option<String> {
+    let tail = iri.splitn(2, ':').nth(1)?;
+    Path::new(tail).file_name()
+        .and_then(|name| name.to_str())
+        .map(|name| name.to_owned())
+}
+
+/// compresses the data using gzip
+pub(crate) fn gzip(data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(data)?;
+    encoder.finish()
+}
+
+/// returns the media type associated with the suffix of the file
+///
+/// Unlike `sniff_media_type` this does not look at the file (content) at all.
+#[cfg(feature="fs")]
+pub(crate) fn media_type_from_extension(path: &Path) -> Result<MediaType, CreatingSpecError> {
+    let extension = path.extension()
+        .and_then(|extension| extension.to_str())
+        .ok_or_else(|| CreatingSpecErrorVariant::NoValidFileStem { file: path.into() })?;
+
+    let media_type = TYPES_BY_SUFFIX
+        .get_mime_type(extension)
+        .ok_or_else(|| CreatingSpecErrorVariant::NoMediaTypeFor { stem: extension.to_owned() })?;
+
+    let media_type = MediaType::parse(media_type)
+        .map_err(|err| err.context(CreatingSpecErrorVariant::NotAMediaType))?;
+    Ok(media_type)
+}
+
+#[cfg(feature="fs")]
 pub(crate) fn sniff_with_file_cmd(path: &Path) -> Result<MediaType, CreatingSpecError> {
     let out = Command::new("file")
         .args(&["-b", "--mime"])
@@ -119,6 +220,76 @@ pub(crate) fn sniff_with_file_cmd(path: &Path) -> Result<MediaType, CreatingSpec
     Ok(media_type)
 }
 
+/// returns true if the media type is a multipart type (e.g. `multipart/mixed`)
+pub(crate) fn is_multipart(media_type: &MediaType) -> bool {
+    media_type.full_type().to_string()
+        .to_lowercase()
+        .starts_with("multipart/")
+}
+
+/// returns the value of the parameter with the given name (case insensitive)
+pub(crate) fn media_type_param<'a>(media_type: &'a MediaType, name: &str) -> Option<&'a str> {
+    media_type.as_str_repr()
+        .split(';')
+        .skip(1)
+        .filter_map(|param| {
+            let mut parts = param.splitn(2, '=');
+            Some((parts.next()?.trim(), parts.next()?.trim().trim_matches('"')))
+        })
+        .find(|&(param_name, _)| param_name.eq_ignore_ascii_case(name))
+        .map(|(_, value)| value)
+}
+
+/// returns true if the media type has a `format=flowed` parameter
+pub(crate) fn is_format_flowed(media_type: &MediaType) -> bool {
+    media_type_param(media_type, "format")
+        .map(|value| value.eq_ignore_ascii_case("flowed"))
+        .unwrap_or(false)
+}
+
+/// encodes the rendered body using the charset of the media type
+///
+/// Supported are `utf-8` (also used if there is no charset), `us-ascii`
+/// and `iso-8859-1` (latin1).
+pub(crate) fn encode_body(media_type: &MediaType, rendered: String) -> Result<Vec<u8>, EncodingError> {
+    let charset = media_type_param(media_type, "charset")
+        .unwrap_or("utf-8")
+        .to_lowercase();
+
+    let max_char =
+        match &*charset {
+            "utf-8" | "utf8" => return Ok(rendered.into_bytes()),
+            "us-ascii" | "ascii" => '\u{7f}',
+            "iso-8859-1" | "latin1" | "latin-1" => '\u{ff}',
+            _ => return Err(EncodingError::UnsupportedCharset { charset })
+        };
+
+    if let Some(ch) = rendered.chars().find(|&ch| ch > max_char) {
+        return Err(EncodingError::UnrepresentableChar { ch, charset });
+    }
+    if max_char == '\u{7f}' {
+        Ok(rendered.into_bytes())
+    } else {
+        Ok(rendered.chars().map(|ch| ch as u8).collect())
+    }
+}
+
+/// returns true if name is a valid (token) media type parameter name
+/// and value a valid (printable us-ascii) parameter value
+pub(crate) fn is_valid_media_type_param(name: &str, value: &str) -> bool {
+    let is_token_char = |ch: char| {
+        ch.is_ascii_graphic() && !"()<>@,;:\\\"/[]?=".contains(ch)
+    };
+    !name.is_empty()
+        && name.chars().all(is_token_char)
+        && value.chars().all(|ch| ch == ' ' || ch.is_ascii_graphic())
+}
+
+/// compares the type/subtype of the media types ignoring any parameters (e.g. charset)
+pub(crate) fn has_same_essence(a: &MediaType, b: &MediaType) -> bool {
+    a.full_type() == &*b.full_type().to_string()
+}
+
 /// replace any orphan \r,\n chars with \r\n if needed
 ///
 /// If the there is no need to replace anything the input String will be returned,
@@ -131,6 +302,27 @@ pub(crate) fn sniff_with_file_cmd(path: &Path) -> Result<MediaType, CreatingSpec
 /// used should be changed to match on a Cow returning the input if it is Cow::Borrowed or returning
 /// the new value and droping the input if it is Cow::Owned
 pub(crate) fn fix_newlines(text: String) -> String {
+    let (offset, hit_cr) = find_orphan_newline(&text);
+
+    if let Some(offset) = offset {
+        _fix_newlines_from(&*text, offset)
+    } else if hit_cr {
+        let mut out = text;
+        out.push('\n');
+        out
+    } else {
+        text
+    }
+}
+
+/// returns true if the text contains a `\r` or `\n` which is not part of a `\r\n`
+pub(crate) fn has_orphan_newlines(text: &str) -> bool {
+    let (offset, hit_cr) = find_orphan_newline(text);
+    offset.is_some() || hit_cr
+}
+
+/// returns the offset at which the first orphan CR/NL was detected and if the text ends with a CR
+fn find_orphan_newline(text: &str) -> (Option<usize>, bool) {
     let mut hit_cr = false;
     let offset = text.bytes().position(|bch| {
         match bch {
@@ -149,17 +341,7 @@ pub(crate) fn fix_newlines(text: String) -> String {
             }
         }
     });
-
-
-    if let Some(offset) = offset {
-        _fix_newlines_from(&*text, offset)
-    } else if hit_cr {
-        let mut out = text;
-        out.push('\n');
-        out
-    } else {
-        text
-    }
+    (offset, hit_cr)
 }
 
 // note this expect offset to be a bad char i.e. if text[offset] is \n
@@ -217,9 +399,257 @@ fn _fix_newlines_from(text: &str, offset: usize) -> String {
     buff
 }
 
+/// wraps lines longer than `width` chars at spaces
+///
+/// Existing newlines are kept and `newline` is used for the inserted
+/// line breaks. Words longer than `width` are not split, so lines
+/// can still be longer than `width` if they contain such a word.
+pub(crate) fn wrap_lines(text: String, width: usize, newline: &str) -> String {
+    if text.split('\n').all(|line| line.chars().count() <= width) {
+        return text;
+    }
+
+    let mut out = String::with_capacity(text.len() + text.len() / width.max(1) * newline.len());
+    let mut lines = text.split('\n').peekable();
+    while let Some(line) = lines.next() {
+        let (line, cr) =
+            if line.ends_with('\r') { (&line[..line.len() - 1], "\r") } else { (line, "") };
+
+        let mut line_len = 0;
+        for word in line.split(' ') {
+            let word_len = word.chars().count();
+            if line_len == 0 {
+                out.push_str(word);
+                line_len = word_len;
+            } else if line_len + 1 + word_len <= width {
+                out.push(' ');
+                out.push_str(word);
+                line_len += 1 + word_len;
+            } else if word_len > 0 {
+                out.push_str(newline);
+                out.push_str(word);
+                line_len = word_len;
+            }
+        }
+
+        out.push_str(cr);
+        if lines.peek().is_some() {
+            out.push('\n');
+        }
+    }
+    out
+}
+
+/// formats the text as `format=flowed` (RFC 3676) text
+///
+/// Trailing spaces are removed from all lines, if a `width` is given lines
+/// longer than it are wrapped at spaces using soft line breaks (a space
+/// followed by a newline). Lines starting with a space or `From ` are
+/// space-stuffed. `newline` is used for all line breaks.
+pub(crate) fn format_flowed(text: &str, width: Option<usize>, newline: &str) -> String {
+    let mut out = String::with_capacity(text.len() + text.len() / 16);
+    let mut lines = text.split('\n').peekable();
+    while let Some(line) = lines.next() {
+        let line = line.trim_right_matches('\r').trim_right_matches(' ');
+        let mut segments = Vec::new();
+        match width {
+            Some(width) => {
+                let mut segment = String::new();
+                let mut segment_len = 0;
+                let mut first = true;
+                for word in line.split(' ') {
+                    let word_len = word.chars().count();
+                    if !first && word_len > 0 && segment_len + 1 + word_len > width {
+                        // the space is kept at the end of the line making it a soft line break
+                        segment.push(' ');
+                        segments.push(replace(&mut segment, word.to_owned()));
+                        segment_len = word_len;
+                    } else {
+                        if !first {
+                            segment.push(' ');
+                            segment_len += 1;
+                        }
+                        segment.push_str(word);
+                        segment_len += word_len;
+                    }
+                    first = false;
+                }
+                segments.push(segment);
+            },
+            None => segments.push(line.to_owned())
+        }
+
+        let last = segments.len() - 1;
+        for (idx, segment) in segments.into_iter().enumerate() {
+            if segment.starts_with(' ') || segment.starts_with("From ") {
+                out.push(' ');
+            }
+            out.push_str(&segment);
+            if idx != last {
+                out.push_str(newline);
+            }
+        }
+        if lines.peek().is_some() {
+            out.push_str(newline);
+        }
+    }
+    out
+}
+
 
 #[cfg(test)]
 mod test {
+    mod deep_merge {
+        use serde_json::json;
+        use super::super::deep_merge;
+
+        #[test]
+        fn data_overrides_defaults() {
+            let defaults = json!({
+                "company": { "name": "ACME", "support": "help@acme.test" },
+                "tags": ["a", "b"],
+                "greeting": "Hy"
+            });
+            let data = json!({
+                "company": { "name": "ACME Ltd." },
+                "tags": ["c"],
+                "name": "Liz"
+            });
+            assert_eq!(deep_merge(defaults, data), json!({
+                "company": { "name": "ACME Ltd.", "support": "help@acme.test" },
+                "tags": ["c"],
+                "greeting": "Hy",
+                "name": "Liz"
+            }));
+        }
+    }
+
+    mod insert_placeholder {
+        use serde_json::json;
+        use super::super::insert_placeholder;
+
+        #[test]
+        fn creates_missing_objects() {
+            let mut data = json!({ "name": "Liz" });
+            assert!(insert_placeholder(&mut data, "company.name"));
+            assert_eq!(data, json!({ "name": "Liz", "company": { "name": "[company.name]" } }));
+        }
+
+        #[test]
+        fn does_not_override_values() {
+            let mut data = json!({ "name": "Liz", "tags": [] });
+            assert!(!insert_placeholder(&mut data, "name"));
+            assert!(!insert_placeholder(&mut data, "tags.first"));
+            assert_eq!(data, json!({ "name": "Liz", "tags": [] }));
+        }
+    }
+
+    mod gzip {
+        use std::io::Read;
+        use flate2::read::GzDecoder;
+        use super::super::gzip;
+
+        #[test]
+        fn roundtrip() {
+            let data = "a,b,c\n".repeat(100);
+            let compressed = gzip(data.as_bytes()).unwrap();
+            assert!(compressed.len() < data.len());
+            assert_eq!(&compressed[..2], &[0x1f, 0x8b]);
+
+            let mut decompressed = String::new();
+            GzDecoder::new(&compressed[..]).read_to_string(&mut decompressed).unwrap();
+            assert_eq!(decompressed, data);
+        }
+    }
+
+    mod stable_hash {
+        use super::super::stable_hash;
+
+        #[test]
+        fn is_fnv1a() {
+            assert_eq!(stable_hash(""), 0xcbf29ce484222325);
+            assert_eq!(stable_hash("a"), 0xaf63dc4c8601ec8c);
+            assert_ne!(stable_hash("logo#path:./logo.png"), stable_hash("logo#path:./logo2.png"));
+        }
+    }
+
+    mod has_orphan_newlines {
+        use super::super::has_orphan_newlines;
+
+        #[test]
+        fn detects_lone_cr_and_nl() {
+            assert!(!has_orphan_newlines("a\r\nb\r\n"));
+            assert!(has_orphan_newlines("a\nb"));
+            assert!(has_orphan_newlines("a\rb"));
+            assert!(has_orphan_newlines("a\r"));
+            assert!(has_orphan_newlines("a\r\r\n"));
+        }
+    }
+
+    mod wrap_lines {
+        use super::super::wrap_lines;
+
+        #[test]
+        fn wraps_long_lines_at_spaces() {
+            assert_eq!(wrap_lines("aaa bbb ccc".to_owned(), 7, "\r\n"), "aaa bbb\r\nccc");
+            assert_eq!(wrap_lines("aaa bbb\r\nccc ddd".to_owned(), 5, "\r\n"),
+                "aaa\r\nbbb\r\nccc\r\nddd");
+        }
+
+        #[test]
+        fn keeps_short_lines_and_long_words() {
+            assert_eq!(wrap_lines("aaa\nbbb".to_owned(), 5, "\n"), "aaa\nbbb");
+            assert_eq!(wrap_lines("aaaaaaa b".to_owned(), 5, "\n"), "aaaaaaa\nb");
+        }
+    }
+
+    mod encode_body {
+        use headers::components::MediaType;
+        use ::error::EncodingError;
+        use super::super::encode_body;
+
+        fn media_type(repr: &str) -> MediaType {
+            MediaType::parse(repr).unwrap()
+        }
+
+        #[test]
+        fn encodes_using_the_declared_charset() {
+            let text = "caf\u{e9}".to_owned();
+            assert_eq!(encode_body(&media_type("text/plain; charset=utf-8"), text.clone()).unwrap(),
+                b"caf\xc3\xa9".to_vec());
+            assert_eq!(encode_body(&media_type("text/plain"), text.clone()).unwrap(),
+                b"caf\xc3\xa9".to_vec());
+            assert_eq!(encode_body(&media_type("text/plain; charset=ISO-8859-1"), text.clone()).unwrap(),
+                b"caf\xe9".to_vec());
+        }
+
+        #[test]
+        fn fails_for_unrepresentable_chars_and_unknown_charsets() {
+            let err = encode_body(&media_type("text/plain; charset=us-ascii"), "caf\u{e9}".to_owned());
+            assert_eq!(err, Err(EncodingError::UnrepresentableChar {
+                ch: '\u{e9}',
+                charset: "us-ascii".to_owned()
+            }));
+            assert!(encode_body(&media_type("text/plain; charset=koi8-r"), "a".to_owned()).is_err());
+        }
+    }
+
+    mod format_flowed {
+        use super::super::format_flowed;
+
+        #[test]
+        fn uses_soft_line_breaks_when_wrapping() {
+            assert_eq!(format_flowed("aaa bbb ccc", Some(7), "\r\n"), "aaa bbb \r\nccc");
+            assert_eq!(format_flowed("aaa\nbbb ccc", Some(5), "\n"), "aaa\nbbb \nccc");
+        }
+
+        #[test]
+        fn removes_trailing_spaces_and_stuffs_lines() {
+            assert_eq!(format_flowed("hard  \r\n From x\nFrom y", None, "\r\n"),
+                "hard\r\n  From x\r\n From y");
+        }
+    }
+
     mod fix_newlines {
         use super::super::fix_newlines;
 
@@ -242,6 +672,30 @@ mod test {
         }
 
     }
+    #[cfg(unix)]
+    mod string_path {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+        use std::path::Path;
+        use ::error::CreatingSpecErrorVariant;
+        use super::super::check_string_path;
+
+        #[test]
+        fn non_utf8_path_error_explains_the_problem() {
+            let path = Path::new(OsStr::from_bytes(b"templates/caf\xe9"));
+            let err = check_string_path(path).unwrap_err();
+            if let &CreatingSpecErrorVariant::NonStringPath(ref path) = err.variant() {
+                assert_eq!(path.to_string_lossy(), "templates/caf\u{FFFD}");
+            } else {
+                panic!("unexpected error: {}", err);
+            }
+            let msg = err.variant().to_string();
+            assert!(msg.contains("UTF-8"));
+            assert!(msg.ends_with("templates/caf\u{FFFD}"));
+        }
+    }
+
+    #[cfg(feature="fs")]
     mod sniff_media_type {
         use std::path::Path;
         use ::error::CreatingSpecErrorVariant;
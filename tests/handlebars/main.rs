@@ -0,0 +1,250 @@
+extern crate mail_common as common;
+extern crate mail_headers as headers;
+extern crate mail_types as mail;
+#[macro_use]
+extern crate mail_template as template;
+extern crate mail_render_template_engine as render_template_engine;
+extern crate futures;
+extern crate regex;
+extern crate handlebars;
+#[macro_use]
+extern crate serde_derive;
+#[macro_use]
+extern crate vec1;
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::{env, fs};
+
+use regex::Regex;
+use futures::Future;
+use handlebars::{Handlebars, Helper, HelperDef, HelperResult, RenderContext, Output};
+
+use common::MailType;
+use common::encoder::EncodingBuffer;
+use headers::HeaderTryFrom;
+use headers::components::{Email, MediaType};
+use template::{MailSendData, TemplateEngine, InspectEmbeddedResources, Embedded};
+
+use render_template_engine::{
+    RenderTemplateEngine, DEFAULT_SETTINGS,
+    TemplateSpec, SubTemplateSpec, TemplateSource,
+    RenderEngineBase, InheritanceSupport
+};
+use render_template_engine::handlebars::{HandlebarsRenderEngine, LoadingError};
+use render_template_engine::test_support::{test_context, TEST_DOMAIN};
+
+#[derive(Serialize, InspectEmbeddedResources)]
+struct UserData {
+    name: &'static str
+}
+
+fn setup_template_engine() -> RenderTemplateEngine<HandlebarsRenderEngine> {
+    let mut rte = RenderTemplateEngine::new(HandlebarsRenderEngine::new());
+    let spec = TemplateSpec
+        ::from_dir("./test_resources/templates/template_a", &*DEFAULT_SETTINGS)
+        .unwrap();
+    rte.insert_spec("template_a".to_owned(), spec).unwrap();
+    rte
+}
+
+#[test]
+fn use_template_produces_expected_mail_parts() {
+    let context = test_context();
+    let engine = setup_template_engine();
+    let data = UserData { name: "Liz" };
+
+    let parts = engine.use_template("template_a", &data, &context).unwrap();
+
+    assert_eq!(parts.alternative_bodies.len(), 2);
+    assert!(parts.alternative_bodies[0].embeddings.is_empty());
+    assert_eq!(parts.alternative_bodies[1].embeddings.len(), 1);
+    assert_eq!(parts.shared_embeddings.len(), 1);
+    assert!(parts.attachments.is_empty());
+
+    let cids = parts.alternative_bodies[1].embeddings.iter()
+        .chain(parts.shared_embeddings.iter())
+        .map(|embedding| embedding.content_id().as_str().to_owned());
+    for cid in cids {
+        assert!(cid.contains(TEST_DOMAIN), "unexpected content id: {}", cid);
+    }
+}
+
+#[test]
+fn cids_in_template_resolve_to_embeddings() {
+    let context = test_context();
+    let engine = setup_template_engine();
+
+    let from = Email::try_from("a@b.c").unwrap().into();
+    let to = Email::try_from("d@e.f").unwrap().into();
+    let send_data = MailSendData::simple_new(
+        from, to, "Dear randomness",
+        Cow::Borrowed("template_a"), UserData { name: "Liz" }
+    );
+    let mail = send_data.compose(&context, &engine).unwrap();
+
+    let mut encoder = EncodingBuffer::new(MailType::Ascii);
+    let encodable_mail = mail.into_encodeable_mail(context.clone()).wait().unwrap();
+    encodable_mail.encode(&mut encoder).unwrap();
+    let out = encoder.to_string().unwrap();
+
+    let used_cid = Regex::new(r#"src=3D"cid:([^"]+)""#).unwrap()
+        .captures(&out)
+        .expect("html body does not reference the logo")
+        .get(1).unwrap().as_str().to_owned();
+
+    assert!(used_cid.ends_with(TEST_DOMAIN));
+    assert!(out.contains(&format!("Content-Id: <{}>", used_cid)));
+}
+
+#[test]
+fn render_preview_with_sample_data() {
+    let dir = env::temp_dir().join(format!("rte_test_preview_{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(dir.join("text")).unwrap();
+    fs::write(dir.join("text").join("mail.txt"), "Hy {{name}}.").unwrap();
+    fs::write(dir.join("preview.json"), r#"{ "name": "Liz" }"#).unwrap();
+
+    let mut settings = DEFAULT_SETTINGS.clone();
+    settings.set_load_sample_data(true);
+    let spec = TemplateSpec::from_dir(&dir, &settings).unwrap();
+    assert!(spec.embeddings().is_empty());
+    assert_eq!(spec.sample_data().unwrap()["name"], "Liz");
+
+    let mut engine = RenderTemplateEngine::new(HandlebarsRenderEngine::new());
+    engine.insert_spec("preview_mail".to_owned(), spec).unwrap();
+    let parts = engine.render_preview("preview_mail", &test_context()).unwrap();
+    assert_eq!(parts.alternative_bodies.len(), 1);
+
+    // strict mode is enabled so rendering fails if the sample data is not used
+    let mut spec = engine.remove_spec("preview_mail").unwrap();
+    spec.set_sample_data(None);
+    engine.insert_spec("preview_mail".to_owned(), spec).unwrap();
+    assert!(engine.render_preview("preview_mail", &test_context()).is_err());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn unknown_cid_references_are_rejected_on_load() {
+    let mut hbs = HandlebarsRenderEngine::new();
+    hbs.set_validate_cid_references(true);
+    let mut engine = RenderTemplateEngine::new(hbs);
+
+    let spec = TemplateSpec
+        ::from_dir("./test_resources/templates/template_a", &*DEFAULT_SETTINGS)
+        .unwrap();
+    engine.insert_spec("template_a".to_owned(), spec).unwrap();
+
+    let source = TemplateSource::Source {
+        id: "typo_mail.html".to_owned(),
+        content: "<img src=\"cid:{{cids.logoo}}\">".to_owned()
+    };
+    let media_type = MediaType::parse("text/html; charset=utf-8").unwrap();
+    let sub_spec = SubTemplateSpec::new_with_template_source(source, media_type, HashMap::new()).unwrap();
+    let err = engine.insert_spec("typo_mail".to_owned(), TemplateSpec::new(vec1![sub_spec]).unwrap())
+        .unwrap_err();
+
+    match *err.error() {
+        LoadingError::UnknownCidReference { ref template, ref name } => {
+            assert_eq!(template, "typo_mail.html");
+            assert_eq!(name, "logoo");
+        },
+        ref other => panic!("unexpected error: {}", other)
+    }
+    assert!(err.old_spec().is_none());
+    assert!(engine.lookup_spec("typo_mail").is_none());
+
+    let failed_spec = err.into_failed_spec();
+    assert_eq!(failed_spec.sub_specs()[0].source().id(), "typo_mail.html");
+}
+
+#[test]
+fn free_templates_can_be_removed_by_prefix() {
+    let mut hbs = HandlebarsRenderEngine::new();
+    hbs.register_free_partial("layout/header", "<header/>").unwrap();
+    hbs.register_free_partial("layout/footer", "<footer/>").unwrap();
+    hbs.register_free_template_string("signature", "Liz").unwrap();
+    assert_eq!(hbs.free_template_ids(), vec!["layout/footer", "layout/header", "signature"]);
+
+    hbs.unregister_free_templates_with_prefix("layout/");
+    assert_eq!(hbs.free_template_ids(), vec!["signature"]);
+
+    hbs.unregister_free_template("signature");
+    assert!(hbs.free_template_ids().is_empty());
+}
+
+#[test]
+fn base_templates_are_registered_as_partials() {
+    let context = test_context();
+    let mut engine = RenderTemplateEngine::new(HandlebarsRenderEngine::new());
+    engine.register_base_template("layout", "<body>{{> @partial-block }}</body>").unwrap().unwrap();
+
+    let media_type = MediaType::parse("text/html; charset=utf-8").unwrap();
+    engine.insert_spec_str("with_layout", media_type, "{{#> layout }}Hy {{name}}.{{/layout}}").unwrap();
+
+    let data = UserData { name: "Liz" };
+    let bodies = engine.render_all_bodies("with_layout", &data, &context).unwrap();
+    assert_eq!(bodies[0].1, "<body>Hy Liz.</body>");
+
+    assert_eq!(HandlebarsRenderEngine::new().supports_inheritance(), InheritanceSupport::Partials);
+}
+
+#[test]
+fn partials_of_the_spec_are_registered_by_name() {
+    let context = test_context();
+    let mut engine = RenderTemplateEngine::new(HandlebarsRenderEngine::new());
+
+    let media_type = MediaType::parse("text/html; charset=utf-8").unwrap();
+    let source = TemplateSource::Source {
+        id: "greeting_mail.html".to_owned(),
+        content: "<p>{{> greeting }}</p>".to_owned()
+    };
+    let sub_spec = SubTemplateSpec::new_with_template_source(source, media_type, HashMap::new()).unwrap();
+    let mut spec = TemplateSpec::new(vec1![sub_spec]).unwrap();
+    spec.insert_partial("greeting", TemplateSource::Source {
+        id: "partials/greeting.html".to_owned(),
+        content: "Hy {{name}}.".to_owned()
+    });
+    engine.insert_spec("greeting_mail".to_owned(), spec).unwrap();
+
+    let data = UserData { name: "Liz" };
+    let bodies = engine.render_all_bodies("greeting_mail", &data, &context).unwrap();
+    assert_eq!(bodies[0].1, "<p>Hy Liz.</p>");
+
+    // the partial is unloaded with the spec, so it can be inserted again
+    let spec = engine.remove_spec("greeting_mail").unwrap();
+    engine.insert_spec("greeting_mail".to_owned(), spec).unwrap();
+}
+
+fn shout(helper: &Helper, _: &Handlebars, _: &mut RenderContext, out: &mut Output) -> HelperResult {
+    let text = helper.param(0).and_then(|param| param.value().as_str()).unwrap_or("");
+    out.write(&text.to_uppercase())?;
+    Ok(())
+}
+
+#[test]
+fn helpers_can_be_registered_with_fallible_creation() {
+    let context = test_context();
+    let mut hbs = HandlebarsRenderEngine::new();
+
+    let res = hbs.try_register_helper("broken", || Err("regex does not compile"));
+    match res {
+        Err(LoadingError::HelperCreationFailed { name, cause }) => {
+            assert_eq!(name, "broken");
+            assert_eq!(cause, "regex does not compile");
+        },
+        other => panic!("unexpected result: {:?}", other.map(|old| old.is_some()))
+    }
+
+    let res = hbs.try_register_helper("shout", || Ok::<_, String>(Box::new(shout) as Box<HelperDef>));
+    assert!(res.unwrap().is_none());
+
+    let mut engine = RenderTemplateEngine::new(hbs);
+    let media_type = MediaType::parse("text/plain; charset=utf-8").unwrap();
+    engine.insert_spec_str("shouting", media_type, "Hy {{shout name}}.").unwrap();
+
+    let data = UserData { name: "Liz" };
+    let bodies = engine.render_all_bodies("shouting", &data, &context).unwrap();
+    assert_eq!(bodies[0].1, "Hy LIZ.");
+}
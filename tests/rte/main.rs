@@ -1,11 +1,37 @@
 extern crate mail_template as compos;
 extern crate mail_types as mail;
+extern crate mail_headers as headers;
 extern crate mail_render_template_engine;
+extern crate vec1;
+#[macro_use]
+extern crate serde_json;
 
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::convert::TryFrom;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::env;
+use std::sync::Arc;
 
-use mail_render_template_engine::{TemplateSpec, DEFAULT_SETTINGS};
+use vec1::Vec1;
 
+use headers::components::MediaType;
+use mail_render_template_engine::{
+    TemplateSpec, SubTemplateSpec, TemplateSource, MemoryFs, InputEncoding, Type,
+    ResourceFetcher, DEFAULT_SETTINGS, MARKDOWN_SETTINGS
+};
+use mail_render_template_engine::error::CreatingSpecErrorVariant;
+
+
+#[test]
+fn specs_can_be_created_from_a_path() {
+    let path = Path::new("./test_resources/templates/template_a");
+    let spec = TemplateSpec::try_from(path).unwrap();
+    let expected = TemplateSpec::from_dir(path, &*DEFAULT_SETTINGS).unwrap();
+    assert!(spec.content_eq(&expected));
+
+    assert!(TemplateSpec::try_from(Path::new("./test_resources/does_not_exist")).is_err());
+}
 
 #[test]
 fn load_template_a() {
@@ -44,3 +70,844 @@ fn load_template_a() {
 
 }
 
+#[test]
+fn sub_specs_can_be_looked_up_by_media_type() {
+    let settings = &*DEFAULT_SETTINGS;
+    let mut a_spec = TemplateSpec::from_dir("./test_resources/templates/template_a", settings).unwrap();
+
+    let html = a_spec.sub_spec_for_media_type(&MediaType::parse("text/html").unwrap()).unwrap();
+    assert_eq!(html.source().id(), "./test_resources/templates/template_a/html/mail.html");
+    assert!(html.embeddings().contains_key("logo"));
+
+    let enriched = MediaType::parse("text/enriched; charset=utf-8").unwrap();
+    assert!(a_spec.sub_spec_for_media_type(&enriched).is_none());
+
+    let text = MediaType::parse("text/plain; charset=us-ascii").unwrap();
+    a_spec.sub_spec_for_media_type_mut(&text).unwrap().set_allow_empty(true);
+    assert!(a_spec.sub_specs()[0].allows_empty());
+}
+
+#[test]
+fn debug_output_does_not_contain_resource_bytes() {
+    let mut fs = MemoryFs::new();
+    fs.insert_file("big/text/mail.txt", "Hy ".repeat(1000));
+    fs.insert_file("big/blob.png", vec![42u8; 4096]);
+
+    let spec = TemplateSpec::from_fs_dir(&fs, "big", &*DEFAULT_SETTINGS).unwrap();
+    let debug = format!("{:?}", spec);
+    assert!(debug.contains("\"blob\""));
+    assert!(debug.contains("text/plain"));
+    assert!(debug.contains("content_len: 3000"));
+    assert!(!debug.contains("42, 42"));
+    assert!(!debug.contains("Hy Hy"));
+    assert!(debug.len() < 1000);
+}
+
+#[test]
+fn summary_of_template_a() {
+    let settings = &*DEFAULT_SETTINGS;
+    let a_spec = TemplateSpec::from_dir("./test_resources/templates/template_a", settings).unwrap();
+
+    assert_eq!(
+        a_spec.summary(),
+        "[2 bodies: text/plain, text/html] [2 embeddings: logo, portfolio] [0 attachments] \
+         @ ./test_resources/templates/template_a"
+    );
+}
+
+#[test]
+fn content_eq_of_specs() {
+    let settings = &*DEFAULT_SETTINGS;
+    let a_spec = TemplateSpec::from_dir("./test_resources/templates/template_a", settings).unwrap();
+    let mut other = TemplateSpec::from_dir("./test_resources/templates/template_a", settings).unwrap();
+    assert!(a_spec.content_eq(&other));
+
+    other.embeddings_mut().remove("portfolio");
+    assert!(!a_spec.content_eq(&other));
+
+    let source = a_spec.sub_specs()[0].source().clone();
+    assert_eq!(&source, a_spec.sub_specs()[0].source());
+    assert_ne!(&source, a_spec.sub_specs()[1].source());
+}
+
+#[test]
+fn template_source_from_reader() {
+    let file = fs::File::open("./test_resources/ascii_text.txt").unwrap();
+    let source = TemplateSource::from_reader("ascii_text", file).unwrap();
+
+    let expected = fs::read_to_string("./test_resources/ascii_text.txt").unwrap();
+    match source {
+        TemplateSource::Source { ref id, ref content } => {
+            assert_eq!(id, "ascii_text");
+            assert_eq!(content, &expected);
+        },
+        _ => panic!("unexpected source: {:?}", source)
+    }
+
+    let non_utf8: &[u8] = b"caf\xe9";
+    let err = TemplateSource::from_reader("non_utf8", non_utf8).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+}
+
+#[test]
+fn all_sources_returns_the_variants_sorted_by_key() {
+    let mut spec = TemplateSpec::from_dir("./test_resources/templates/template_a", &*DEFAULT_SETTINGS).unwrap();
+    let html = &mut spec.sub_specs_mut()[1];
+    for key in &["short", "long", "medium", "a11y"] {
+        html.insert_variant(*key, TemplateSource::Source {
+            id: format!("variant.{}.html", key),
+            content: "<p>Hy</p>".to_owned()
+        });
+    }
+
+    let ids = html.all_sources().map(|source| source.id().to_owned()).collect::<Vec<_>>();
+    assert_eq!(&ids[1..], &[
+        "variant.a11y.html", "variant.long.html", "variant.medium.html", "variant.short.html"
+    ]);
+    assert_eq!(ids[0], html.source().id());
+}
+
+#[test]
+fn custom_template_ids_with_from_dirs() {
+    let settings = &*DEFAULT_SETTINGS;
+    let specs = TemplateSpec::from_dirs_with_id_fn("./test_resources/templates", settings, |dir| {
+        format!("emails.{}", dir.file_name().unwrap().to_str().unwrap())
+    }).unwrap();
+
+    assert_eq!(specs.len(), 1);
+    assert_eq!(specs[0].0, "emails.template_a");
+}
+
+#[test]
+fn load_template_a_from_glob() {
+    let settings = &*DEFAULT_SETTINGS;
+    let mut specs = TemplateSpec::from_glob("./test_resources/templates/*/*/mail.*", settings).unwrap();
+
+    assert_eq!(specs.len(), 1);
+    let (id, a_spec) = specs.pop().unwrap();
+    assert_eq!(id, "template_a");
+
+    let embeddings = a_spec.embeddings();
+    assert_eq!(embeddings.len(), 1);
+    assert!(embeddings.contains_key("portfolio"));
+
+    let sub_specs = a_spec.sub_specs();
+    assert_eq!(sub_specs.len(), 2);
+    let text = &sub_specs[0];
+    let html = &sub_specs[1];
+
+    assert_eq!(text.media_type().as_str_repr(), "text/plain; charset=utf-8");
+    assert!(text.embeddings().is_empty());
+
+    assert_eq!(html.media_type().as_str_repr(), "text/html; charset=utf-8");
+    let embeddings = html.embeddings();
+    assert_eq!(embeddings.len(), 1);
+    assert!(embeddings.contains_key("logo"));
+}
+
+
+fn copy_dir(from: &Path, to: &Path) {
+    fs::create_dir_all(to).unwrap();
+    for entry in from.read_dir().unwrap() {
+        let entry = entry.unwrap();
+        let target = to.join(entry.file_name());
+        if entry.file_type().unwrap().is_dir() {
+            copy_dir(&entry.path(), &target);
+        } else {
+            fs::copy(entry.path(), target).unwrap();
+        }
+    }
+}
+
+fn temp_template_copy(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("rte_test_{}_{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    copy_dir(Path::new("./test_resources/templates/template_a"), &dir);
+    dir
+}
+
+#[test]
+fn load_nested_template_dirs() {
+    let root = env::temp_dir().join(format!("rte_test_nested_{}", std::process::id()));
+    let _ = fs::remove_dir_all(&root);
+    let template_a = Path::new("./test_resources/templates/template_a");
+    copy_dir(template_a, &root.join("transactional").join("welcome"));
+    copy_dir(template_a, &root.join("marketing").join("promo"));
+
+    let mut settings = DEFAULT_SETTINGS.clone();
+    settings.set_recursive(true);
+    let mut specs = TemplateSpec::from_dirs(&root, &settings).unwrap();
+    specs.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let ids = specs.iter().map(|&(ref id, _)| id.as_str()).collect::<Vec<_>>();
+    assert_eq!(ids, vec!["marketing/promo", "transactional/welcome"]);
+    assert_eq!(specs[0].1.sub_specs().len(), 2);
+    assert_eq!(specs[1].1.base_path().unwrap(), &*root.join("transactional").join("welcome"));
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn ignored_files_are_not_used_as_embeddings() {
+    let dir = temp_template_copy("ignored_files");
+    fs::write(dir.join("README.md"), "# Template A").unwrap();
+    fs::write(dir.join(".gitkeep"), "").unwrap();
+    fs::write(dir.join("html").join(".gitkeep"), "").unwrap();
+
+    let mut settings = DEFAULT_SETTINGS.clone();
+    settings.add_ignore_pattern("README.*").unwrap();
+    let spec = TemplateSpec::from_dir(&dir, &settings).unwrap();
+
+    assert_eq!(spec.embeddings().len(), 1);
+    assert!(spec.embeddings().contains_key("portfolio"));
+    assert_eq!(spec.sub_specs()[1].embeddings().len(), 1);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn reload_changed_only_reloads_if_something_changed() {
+    let settings = &*DEFAULT_SETTINGS;
+    let dir = temp_template_copy("reload_changed");
+    let mut spec = TemplateSpec::from_dir(&dir, settings).unwrap();
+
+    assert_eq!(spec.reload_changed(settings).unwrap(), false);
+
+    {
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(dir.join("html").join("mail.html"))
+            .unwrap();
+        file.write_all(b"<!-- changed -->").unwrap();
+    }
+
+    assert_eq!(spec.reload_changed(settings).unwrap(), true);
+    assert_eq!(spec.sub_specs().len(), 2);
+    assert_eq!(spec.sub_specs()[1].embeddings().len(), 1);
+    assert_eq!(spec.reload_changed(settings).unwrap(), false);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+fn append_to_file(path: &Path, content: &[u8]) {
+    let mut file = OpenOptions::new().append(true).open(path).unwrap();
+    file.write_all(content).unwrap();
+}
+
+#[test]
+fn reload_changed_keeps_the_overrides_set_through_setters() {
+    let settings = &*DEFAULT_SETTINGS;
+    let dir = temp_template_copy("reload_keeps_overrides");
+    let logo_path = dir.join("html").join("logo.png");
+    let logo_path = logo_path.to_str().unwrap();
+    let mut spec = TemplateSpec::from_dir(&dir, settings).unwrap();
+    spec.set_embedding_name("portfolio", "Portfolio 2024.pdf").unwrap();
+    spec.insert_embedding_from_path("banner", logo_path, settings).unwrap();
+    spec.set_embedding_name("banner", "banner.png").unwrap();
+    spec.insert_partial("footer", TemplateSource::Source {
+        id: "reload_keeps_overrides.footer".to_owned(),
+        content: "-- ACME".to_owned()
+    });
+    spec.set_sample_data(Some(json!({ "name": "Liz" })));
+    spec.sub_specs_mut()[1].set_embedding_name("logo", "acme.png").unwrap();
+    spec.sub_specs_mut()[1].insert_embedding_from_path("icon", logo_path, settings).unwrap();
+
+    let use_name = |resource: &mail::Resource| resource.source().unwrap().use_name.clone();
+    let assert_overrides = |spec: &TemplateSpec| {
+        assert_eq!(use_name(&spec.embeddings()["portfolio"]), Some("Portfolio 2024.pdf".to_owned()));
+        assert_eq!(use_name(&spec.embeddings()["banner"]), Some("banner.png".to_owned()));
+        assert_eq!(spec.partials().len(), 1);
+        assert_eq!(spec.partials()[0].0, "footer");
+        assert_eq!(spec.sample_data(), Some(&json!({ "name": "Liz" })));
+        let html = &spec.sub_specs()[1];
+        assert_eq!(use_name(&html.embeddings()["logo"]), Some("acme.png".to_owned()));
+        assert!(html.embeddings().contains_key("icon"));
+    };
+
+    // only the html sub-spec is reloaded
+    append_to_file(&dir.join("html").join("mail.html"), b"<!-- changed -->");
+    assert_eq!(spec.reload_changed(settings).unwrap(), true);
+    assert_overrides(&spec);
+
+    // the whole spec is reloaded
+    append_to_file(&dir.join("portfolio.pdf"), b"%changed");
+    assert_eq!(spec.reload_changed(settings).unwrap(), true);
+    assert_overrides(&spec);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn reload_changed_keeps_programmatically_set_state() {
+    let settings = &*DEFAULT_SETTINGS;
+    let dir = temp_template_copy("reload_keeps_state");
+    let mut spec = TemplateSpec::from_dir(&dir, settings).unwrap();
+    spec.insert_header_template("X-Campaign", TemplateSource::Source {
+        id: "reload_keeps_state.campaign".to_owned(),
+        content: "welcome".to_owned()
+    });
+    spec.sub_specs_mut()[1].insert_variant("short", TemplateSource::Source {
+        id: "reload_keeps_state.short.html".to_owned(),
+        content: "<p>Hy</p>".to_owned()
+    });
+    spec.sub_specs_mut()[1].set_render_defaults(Some(json!({ "image": "fallback.png" })));
+    spec.sub_specs_mut()[1].set_allow_empty(true);
+    spec.sub_specs_mut()[1].set_media_type_param("x-layout", "compact").unwrap();
+    spec.sub_specs_mut()[0].set_format_flowed().unwrap();
+    spec.sub_specs_mut()[0].set_transfer_encoding(Some(TransferEncoding::Base64));
+
+    // only the html sub-spec is reloaded
+    append_to_file(&dir.join("html").join("mail.html"), b"<!-- changed -->");
+    assert_eq!(spec.reload_changed(settings).unwrap(), true);
+    assert!(spec.sub_specs()[1].variants().contains_key("short"));
+    assert_eq!(spec.sub_specs()[1].render_defaults(), Some(&json!({ "image": "fallback.png" })));
+    assert!(spec.sub_specs()[1].allows_empty());
+    assert!(spec.sub_specs()[1].media_type().as_str_repr().contains("x-layout=compact"));
+
+    // the whole spec is reloaded
+    append_to_file(&dir.join("portfolio.pdf"), b"%changed");
+    assert_eq!(spec.reload_changed(settings).unwrap(), true);
+    assert!(spec.sub_specs()[1].variants().contains_key("short"));
+    assert_eq!(spec.sub_specs()[1].render_defaults(), Some(&json!({ "image": "fallback.png" })));
+    assert!(spec.sub_specs()[1].allows_empty());
+    assert!(!spec.sub_specs()[0].allows_empty());
+    assert!(spec.sub_specs()[0].media_type().as_str_repr().contains("format=flowed"));
+    assert_eq!(spec.sub_specs()[0].transfer_encoding(), Some(TransferEncoding::Base64));
+    assert!(spec.sub_specs()[1].media_type().as_str_repr().contains("x-layout=compact"));
+    let headers = spec.header_templates().map(|(name, _)| name).collect::<Vec<_>>();
+    assert_eq!(headers, vec!["X-Campaign"]);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn skipped_embedding_checks_do_not_determine_media_type_on_load() {
+    let mut settings = DEFAULT_SETTINGS.clone();
+    settings.set_skip_embedding_checks(true);
+    let a_spec = TemplateSpec::from_dir("./test_resources/templates/template_a", &settings).unwrap();
+
+    let portfolio = a_spec.embeddings().get("portfolio").unwrap();
+    assert!(portfolio.source().unwrap().use_media_type.is_none());
+    let logo = a_spec.sub_specs()[1].embeddings().get("logo").unwrap();
+    assert!(logo.source().unwrap().use_media_type.is_none());
+}
+
+#[test]
+fn skipped_embedding_checks_do_not_check_that_the_file_exists() {
+    let mut a_spec = TemplateSpec::from_dir("./test_resources/templates/template_a", &*DEFAULT_SETTINGS).unwrap();
+    let path = "./test_resources/templates/template_a/missing.png";
+    assert!(a_spec.insert_embedding_from_path("missing", path, &*DEFAULT_SETTINGS).is_err());
+
+    let mut settings = DEFAULT_SETTINGS.clone();
+    settings.set_skip_embedding_checks(true);
+    a_spec.insert_embedding_from_path("missing", path, &settings).unwrap();
+    assert!(a_spec.embeddings().contains_key("missing"));
+}
+
+#[test]
+fn embeddings_are_named_like_their_files() {
+    let mut settings = DEFAULT_SETTINGS.clone();
+    let spec = TemplateSpec::from_dir("./test_resources/templates/template_a", &settings).unwrap();
+    let logo = spec.sub_specs()[1].embeddings().get("logo").unwrap().source().unwrap();
+    assert_eq!(logo.use_name.as_ref().map(|s| &**s), Some("logo.png"));
+
+    settings.set_use_file_names(false);
+    let spec = TemplateSpec::from_dir("./test_resources/templates/template_a", &settings).unwrap();
+    let logo = spec.sub_specs()[1].embeddings().get("logo").unwrap().source().unwrap();
+    assert!(logo.use_name.is_none());
+}
+
+#[test]
+fn custom_names_for_embeddings() {
+    let settings = &*DEFAULT_SETTINGS;
+    let mut a_spec = TemplateSpec::from_dir("./test_resources/templates/template_a", settings).unwrap();
+
+    a_spec.set_embedding_name("portfolio", "Portfolio.pdf").unwrap();
+    a_spec.sub_specs_mut()[1].set_embedding_name("logo", "Company Logo.png").unwrap();
+    assert!(a_spec.set_embedding_name("not_there", "x.pdf").is_err());
+    assert!(a_spec.set_attachment_name(0, "x.pdf").is_err());
+
+    let portfolio = a_spec.embeddings().get("portfolio").unwrap().source().unwrap();
+    assert_eq!(portfolio.use_name.as_ref().map(|s| &**s), Some("Portfolio.pdf"));
+    assert_eq!(
+        portfolio.iri.as_str(),
+        "path:./test_resources/templates/template_a/portfolio.pdf"
+    );
+    let logo = a_spec.sub_specs()[1].embeddings().get("logo").unwrap().source().unwrap();
+    assert_eq!(logo.use_name.as_ref().map(|s| &**s), Some("Company Logo.png"));
+}
+
+#[test]
+fn check_for_media_types() {
+    let settings = &*DEFAULT_SETTINGS;
+    let a_spec = TemplateSpec::from_dir("./test_resources/templates/template_a", settings).unwrap();
+
+    assert!(a_spec.has_html());
+    assert!(a_spec.has_plain_text());
+    assert!(a_spec.has_media_type(&MediaType::parse("text/plain").unwrap()));
+    assert!(a_spec.has_media_type(&MediaType::parse("text/html; charset=us-ascii").unwrap()));
+    assert!(!a_spec.has_media_type(&MediaType::parse("text/enriched").unwrap()));
+}
+
+#[cfg(unix)]
+#[test]
+fn follow_symlinked_sub_template_dir() {
+    use std::os::unix::fs::symlink;
+
+    let dir = temp_template_copy("follow_symlinks");
+    let html_dir = dir.join("html");
+    let html_target = dir.with_file_name(format!("rte_test_symlink_target_{}", std::process::id()));
+    let _ = fs::remove_dir_all(&html_target);
+    fs::rename(&html_dir, &html_target).unwrap();
+    symlink(&html_target, &html_dir).unwrap();
+
+    let mut settings = DEFAULT_SETTINGS.clone();
+    assert!(TemplateSpec::from_dir(&dir, &settings).is_err());
+
+    settings.set_follow_symlinks(true);
+    let spec = TemplateSpec::from_dir(&dir, &settings).unwrap();
+    assert_eq!(spec.sub_specs().len(), 2);
+    assert!(spec.has_html());
+    assert_eq!(spec.embeddings().len(), 1);
+
+    fs::remove_dir_all(&dir).unwrap();
+    fs::remove_dir_all(&html_target).unwrap();
+}
+
+#[test]
+fn load_templates_from_memory_fs() {
+    let mut fs = MemoryFs::new();
+    fs.insert_file("templates/welcome/html/mail.html", &b"<h1>Hy {{name}}</h1>"[..]);
+    fs.insert_file("templates/welcome/text/mail.txt", &b"Hy {{name}}."[..]);
+    fs.insert_file("templates/welcome/portfolio.pdf", &b"%PDF-"[..]);
+    fs.insert_file("templates/goodbye/text/mail.txt", &b"Bye {{name}}."[..]);
+
+    let specs = TemplateSpec::from_fs_dirs(&fs, "templates", &*DEFAULT_SETTINGS).unwrap();
+    let ids = specs.iter().map(|&(ref id, _)| id.as_str()).collect::<Vec<_>>();
+    assert_eq!(ids, vec!["goodbye", "welcome"]);
+
+    let welcome = &specs[1].1;
+    assert!(welcome.base_path().is_none());
+    assert_eq!(welcome.sub_specs().len(), 2);
+    assert!(welcome.embeddings().contains_key("portfolio"));
+
+    let text = &welcome.sub_specs()[0];
+    assert_eq!(text.source(), &TemplateSource::Source {
+        id: "templates/welcome/text/mail.txt".to_owned(),
+        content: "Hy {{name}}.".to_owned()
+    });
+    assert_eq!(text.media_type().as_str_repr(), "text/plain; charset=utf-8");
+
+    assert!(TemplateSpec::from_fs_dir(&fs, "templates/unknown", &*DEFAULT_SETTINGS).is_err());
+}
+
+#[test]
+fn disabled_template_dirs_are_skipped() {
+    let mut fs = MemoryFs::new();
+    fs.insert_file("templates/welcome/text/mail.txt", &b"Hy"[..]);
+    fs.insert_file("templates/old_welcome/text/mail.txt", &b"Hy"[..]);
+    fs.insert_file("templates/old_welcome/.disabled", &b""[..]);
+    fs.insert_file("templates/goodbye.disabled/text/mail.txt", &b"Bye"[..]);
+
+    let specs = TemplateSpec::from_fs_dirs(&fs, "templates", &*DEFAULT_SETTINGS).unwrap();
+    let ids = specs.iter().map(|&(ref id, _)| id.as_str()).collect::<Vec<_>>();
+    assert_eq!(ids, vec!["welcome"]);
+
+    let mut settings = DEFAULT_SETTINGS.clone();
+    settings.set_disabled_marker(None);
+    let specs = TemplateSpec::from_fs_dirs(&fs, "templates", &settings).unwrap();
+    assert_eq!(specs.len(), 3);
+}
+
+#[test]
+fn template_base_name_and_suffixes_can_be_set_per_type() {
+    let mut settings = DEFAULT_SETTINGS.clone();
+    settings.set_template_base_name("html", "index").unwrap();
+    settings.set_suffixes("text", Vec1::new(".text".to_owned())).unwrap();
+    assert!(settings.set_template_base_name("amp", "index").is_err());
+
+    let mut fs = MemoryFs::new();
+    fs.insert_file("welcome/html/index.htm", &b"<p>Hy</p>"[..]);
+    fs.insert_file("welcome/html/logo.png", &b"png"[..]);
+    fs.insert_file("welcome/text/mail.text", &b"Hy"[..]);
+
+    let spec = TemplateSpec::from_fs_dir(&fs, "welcome", &settings).unwrap();
+    let sources = spec.sub_specs().iter()
+        .map(|sub_spec| sub_spec.source().id().to_owned())
+        .collect::<Vec<_>>();
+    assert_eq!(sources.len(), 2);
+    assert!(sources[0].ends_with("mail.text"));
+    assert!(sources[1].ends_with("index.htm"));
+    assert!(spec.sub_specs()[1].embeddings().contains_key("logo"));
+
+    // `mail.txt` is no longer accepted as text template file
+    let mut fs = MemoryFs::new();
+    fs.insert_file("welcome/text/mail.txt", &b"Hy"[..]);
+    assert!(TemplateSpec::from_fs_dir(&fs, "welcome", &settings).is_err());
+}
+
+#[test]
+fn files_in_the_partials_dir_are_loaded_as_partials() {
+    let mut fs = MemoryFs::new();
+    fs.insert_file("welcome/html/mail.html", &b"<p>Hy</p>"[..]);
+    fs.insert_file("welcome/partials/header.html", &b"<h1>Hy</h1>"[..]);
+    fs.insert_file("welcome/partials/footer.html", &b"<p>Bye</p>"[..]);
+
+    let spec = TemplateSpec::from_fs_dir(&fs, "welcome", &*DEFAULT_SETTINGS).unwrap();
+    assert_eq!(spec.sub_specs().len(), 1);
+    let names = spec.partials().iter()
+        .map(|&(ref name, _)| name.as_str())
+        .collect::<Vec<_>>();
+    assert_eq!(names, vec!["footer", "header"]);
+
+    // without a partials dir name `partials` is a dir of an unknown type
+    let mut settings = DEFAULT_SETTINGS.clone();
+    settings.set_partials_dir_name(None);
+    assert!(TemplateSpec::from_fs_dir(&fs, "welcome", &settings).is_err());
+
+    fs.insert_file("welcome/partials/footer.txt", &b"Bye"[..]);
+    assert!(TemplateSpec::from_fs_dir(&fs, "welcome", &*DEFAULT_SETTINGS).is_err());
+}
+
+#[test]
+fn markdown_dirs_are_only_loaded_with_the_markdown_settings() {
+    assert!(DEFAULT_SETTINGS.get_type("markdown").is_none());
+    assert_eq!(MARKDOWN_SETTINGS.get_priority_idx("markdown"), Some(1));
+
+    let mut fs = MemoryFs::new();
+    fs.insert_file("news/markdown/mail.md", &b"# News"[..]);
+    assert!(TemplateSpec::from_fs_dir(&fs, "news", &*DEFAULT_SETTINGS).is_err());
+
+    let spec = TemplateSpec::from_fs_dir(&fs, "news", &*MARKDOWN_SETTINGS).unwrap();
+    assert_eq!(spec.sub_specs()[0].media_type().full_type().to_string(), "text/markdown");
+}
+
+#[test]
+fn custom_type_with_intermediate_priority() {
+    let mut settings = MARKDOWN_SETTINGS.clone();
+    let amp = Type::new("text", "x-amp-html", Vec1::new(".amp.html".to_owned()), Some("utf-8".to_owned()));
+    settings.set_type_lookup("amp", amp, None).unwrap();
+    settings.reorder(&["text", "markdown", "amp", "enriched", "xhtml", "html"]).unwrap();
+
+    let mut fs = MemoryFs::new();
+    fs.insert_file("newsletter/html/mail.html", &b"<h1>News</h1>"[..]);
+    fs.insert_file("newsletter/amp/mail.amp.html", &b"<h1 amp>News</h1>"[..]);
+    fs.insert_file("newsletter/text/mail.txt", &b"News"[..]);
+
+    let spec = TemplateSpec::from_fs_dir(&fs, "newsletter", &settings).unwrap();
+    let media_types = spec.sub_specs().iter()
+        .map(|sub_spec| sub_spec.media_type().full_type().to_string())
+        .collect::<Vec<_>>();
+    assert_eq!(media_types, vec!["text/plain", "text/x-amp-html", "text/html"]);
+}
+
+#[derive(Debug)]
+struct StaticFetcher;
+
+impl ResourceFetcher for StaticFetcher {
+    fn fetch(&self, url: &str) -> io::Result<Vec<u8>> {
+        if url == "https://cdn.example.com/logo.png" {
+            Ok(b"\x89PNG".to_vec())
+        } else {
+            Err(io::Error::new(io::ErrorKind::NotFound, "not found"))
+        }
+    }
+}
+
+#[test]
+fn embeddings_can_be_declared_with_urls() {
+    let mut spec = TemplateSpec::from_dir("./test_resources/templates/template_a", &*DEFAULT_SETTINGS).unwrap();
+    assert!(spec.insert_embedding_from_path("cdn_logo", "https://cdn.example.com/logo.png", &*DEFAULT_SETTINGS).is_err());
+
+    let mut settings = DEFAULT_SETTINGS.clone();
+    settings.set_resource_fetcher(Some(Arc::new(StaticFetcher)));
+    spec.insert_embedding_from_path("cdn_logo", "https://cdn.example.com/logo.png", &settings).unwrap();
+    assert!(spec.embeddings().contains_key("cdn_logo"));
+
+    assert!(spec.insert_embedding_from_path("missing", "https://cdn.example.com/missing.png", &settings).is_err());
+}
+
+#[test]
+fn optional_types_without_template_file_are_skipped() {
+    let mut fs = MemoryFs::new();
+    fs.insert_file("migrating/html/mail.html", &b"<h1>Hy {{name}}</h1>"[..]);
+    fs.insert_file("migrating/text/notes.txt", &b"text body follows"[..]);
+
+    assert!(TemplateSpec::from_fs_dir(&fs, "migrating", &*DEFAULT_SETTINGS).is_err());
+
+    let mut settings = DEFAULT_SETTINGS.clone();
+    settings.set_type_optional("text", true).unwrap();
+    let spec = TemplateSpec::from_fs_dir(&fs, "migrating", &settings).unwrap();
+    assert_eq!(spec.sub_specs().len(), 1);
+    assert_eq!(spec.sub_specs()[0].media_type().full_type().to_string(), "text/html");
+
+    let mut fs = MemoryFs::new();
+    fs.insert_file("empty/text/notes.txt", &b"text body follows"[..]);
+    assert!(TemplateSpec::from_fs_dir(&fs, "empty", &settings).is_err());
+}
+
+#[test]
+fn media_type_can_be_derived_from_the_body_file_extension() {
+    let mut fs = MemoryFs::new();
+    fs.insert_file("legacy/html/mail.xhtml", &b"<p>Hy {{name}}</p>"[..]);
+
+    let spec = TemplateSpec::from_fs_dir(&fs, "legacy", &*DEFAULT_SETTINGS).unwrap();
+    assert_eq!(spec.sub_specs()[0].media_type().full_type().to_string(), "text/html");
+
+    let mut settings = DEFAULT_SETTINGS.clone();
+    assert!(settings.set_media_type_by_extension("unknown", true).is_err());
+    settings.set_media_type_by_extension("html", true).unwrap();
+    let spec = TemplateSpec::from_fs_dir(&fs, "legacy", &settings).unwrap();
+    assert_eq!(spec.sub_specs()[0].media_type().full_type().to_string(), "application/xhtml+xml");
+}
+
+#[test]
+fn specs_can_be_created_with_a_builder() {
+    let a_spec = TemplateSpec::from_dir("./test_resources/templates/template_a", &*DEFAULT_SETTINGS).unwrap();
+    let portfolio = a_spec.embeddings().get("portfolio").unwrap().clone();
+    let text_body = || {
+        let source = TemplateSource::Source { id: "built/mail.txt".to_owned(), content: "Hy".to_owned() };
+        let media_type = MediaType::parse("text/plain; charset=utf-8").unwrap();
+        SubTemplateSpec::new_with_template_source(source, media_type, Default::default()).unwrap()
+    };
+
+    let spec = TemplateSpec::builder()
+        .sub_spec(text_body())
+        .embedding("portfolio", portfolio.clone())
+        .attachment(portfolio.clone())
+        .base_path("./built")
+        .build()
+        .unwrap();
+    assert_eq!(spec.body_count(), 1);
+    assert_eq!(spec.embedding_count(), 1);
+    assert_eq!(spec.attachment_count(), 1);
+    assert_eq!(spec.base_path(), Some(Path::new("./built")));
+
+    assert!(TemplateSpec::builder().base_path("./built").build().is_err());
+    assert!(TemplateSpec::builder()
+        .sub_spec(text_body())
+        .embedding("portfolio", portfolio.clone())
+        .embedding("portfolio", portfolio)
+        .build()
+        .is_err());
+}
+
+#[test]
+fn source_ids_have_to_be_unique_within_a_spec() {
+    let body = |media_type: &str, content: &str| {
+        let source = TemplateSource::Source { id: "shared_id".to_owned(), content: content.to_owned() };
+        let media_type = MediaType::parse(media_type).unwrap();
+        SubTemplateSpec::new_with_template_source(source, media_type, Default::default()).unwrap()
+    };
+
+    let err = TemplateSpec::builder()
+        .sub_spec(body("text/plain; charset=utf-8", "Hy"))
+        .sub_spec(body("text/html; charset=utf-8", "<p>Hy</p>"))
+        .build()
+        .unwrap_err();
+    match *err.variant() {
+        CreatingSpecErrorVariant::DuplicateSourceId { ref id } => assert_eq!(id, "shared_id"),
+        ref other => panic!("unexpected error: {}", other)
+    }
+
+    let templates = || Vec1::from_vec(vec![
+        body("text/plain; charset=utf-8", "Hy"),
+        body("text/html; charset=utf-8", "<p>Hy</p>")
+    ]).unwrap();
+    assert!(TemplateSpec::new_with_base_path(templates(), "./built").is_err());
+    match TemplateSpec::new(templates()).map(|_| ()).unwrap_err().variant() {
+        &CreatingSpecErrorVariant::DuplicateSourceId { ref id } => assert_eq!(id, "shared_id"),
+        other => panic!("unexpected error: {}", other)
+    }
+    assert!(TemplateSpec::new_with_embeddings(templates(), Default::default()).is_err());
+}
+
+#[test]
+fn empty_sub_template_dirs_are_distinguished_from_missing_template_files() {
+    let mut fs = MemoryFs::new();
+    fs.insert_file("empty/html/.gitkeep", &b""[..]);
+    let err = TemplateSpec::from_fs_dir(&fs, "empty", &*DEFAULT_SETTINGS).unwrap_err();
+    match *err.variant() {
+        CreatingSpecErrorVariant::EmptyTemplateDir { .. } => {},
+        ref other => panic!("unexpected error: {}", other)
+    }
+
+    let mut fs = MemoryFs::new();
+    fs.insert_file("misnamed/html/body.html", &b"<p>Hy</p>"[..]);
+    fs.insert_file("misnamed/html/logo.png", &b""[..]);
+    let err = TemplateSpec::from_fs_dir(&fs, "misnamed", &*DEFAULT_SETTINGS).unwrap_err();
+    match *err.variant() {
+        CreatingSpecErrorVariant::TemplateFileMissing { ref found_files, .. } => {
+            assert_eq!(found_files, &vec!["body.html".to_owned(), "logo.png".to_owned()]);
+        },
+        ref other => panic!("unexpected error: {}", other)
+    }
+}
+
+#[test]
+fn non_ascii_file_names_are_kept_as_is() {
+    let dir = temp_template_copy("non_ascii_names");
+    fs::write(dir.join("Rechnung_Müller.pdf"), "%PDF").unwrap();
+
+    let mut spec = TemplateSpec::from_dir(&dir, &*DEFAULT_SETTINGS).unwrap();
+    let invoice = spec.embeddings().get("Rechnung_Müller").unwrap().clone();
+    assert_eq!(invoice.source().unwrap().use_name, Some("Rechnung_Müller.pdf".to_owned()));
+
+    spec.attachments_mut().push(invoice);
+    spec.set_attachment_name(0, "Überweisung_März.pdf").unwrap();
+    assert_eq!(spec.attachments()[0].source().unwrap().use_name, Some("Überweisung_März.pdf".to_owned()));
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn attachments_can_share_a_base_name() {
+    let a_spec = TemplateSpec::from_dir("./test_resources/templates/template_a", &*DEFAULT_SETTINGS).unwrap();
+    let portfolio = a_spec.embeddings().get("portfolio").unwrap().clone();
+    let text = SubTemplateSpec::new_with_template_source(
+        TemplateSource::Source { id: "reports/mail.txt".to_owned(), content: "Reports".to_owned() },
+        MediaType::parse("text/plain; charset=utf-8").unwrap(),
+        Default::default()
+    ).unwrap();
+
+    let mut spec = TemplateSpec::builder()
+        .sub_spec(text)
+        .attachment(portfolio.clone())
+        .attachment(portfolio)
+        .build()
+        .unwrap();
+    spec.set_attachment_name(0, "report.2023.pdf").unwrap();
+    spec.set_attachment_name(1, "report.2024.pdf").unwrap();
+
+    let names = spec.attachments().iter()
+        .map(|attachment| attachment.source().unwrap().use_name.clone().unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(names, vec!["report.2023.pdf".to_owned(), "report.2024.pdf".to_owned()]);
+}
+
+#[test]
+fn specs_can_be_rebased() {
+    let mut spec = TemplateSpec::from_dir("./test_resources/templates/template_a", &*DEFAULT_SETTINGS).unwrap();
+    spec.rebase("./test_resources/templates", "/opt/app/templates").unwrap();
+
+    assert_eq!(spec.base_path().unwrap(), Path::new("/opt/app/templates/template_a"));
+    match *spec.sub_specs()[1].source() {
+        TemplateSource::Path(ref path) => assert_eq!(path, "/opt/app/templates/template_a/html/mail.html"),
+        ref other => panic!("unexpected source: {:?}", other)
+    }
+    assert_eq!(
+        spec.sub_specs()[1].embeddings().get("logo").unwrap().source().unwrap().iri.as_str(),
+        "path:/opt/app/templates/template_a/html/logo.png"
+    );
+    assert_eq!(
+        spec.embeddings().get("portfolio").unwrap().source().unwrap().iri.as_str(),
+        "path:/opt/app/templates/template_a/portfolio.pdf"
+    );
+
+    // paths outside of the old root are kept
+    spec.rebase("./other", "/somewhere").unwrap();
+    assert_eq!(spec.base_path().unwrap(), Path::new("/opt/app/templates/template_a"));
+}
+
+#[test]
+fn duplicate_template_ids_are_detected() {
+    let root = env::temp_dir().join(format!("rte_test_duplicate_ids_{}", std::process::id()));
+    let _ = fs::remove_dir_all(&root);
+    let template_a = Path::new("./test_resources/templates/template_a");
+    copy_dir(template_a, &root.join("transactional"));
+    copy_dir(template_a, &root.join("marketing"));
+
+    let err = TemplateSpec::from_dirs_with_id_fn(&root, &*DEFAULT_SETTINGS, |_| "welcome".to_owned())
+        .unwrap_err();
+    match *err.variant() {
+        CreatingSpecErrorVariant::DuplicateTemplateId { ref id, ref paths } => {
+            assert_eq!(id, "welcome");
+            assert_eq!(paths.len(), 2);
+            assert_eq!(paths[0].as_path(), &*root.join("marketing"));
+        },
+        ref other => panic!("unexpected error: {}", other)
+    }
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn format_flowed_is_only_allowed_for_plain_text() {
+    let mut spec = TemplateSpec::from_dir("./test_resources/templates/template_a", &*DEFAULT_SETTINGS).unwrap();
+    {
+        let text = &mut spec.sub_specs_mut()[0];
+        assert!(!text.is_format_flowed());
+        text.set_format_flowed().unwrap();
+        assert!(text.is_format_flowed());
+        assert!(text.set_media_type_param("bad name", "x").is_err());
+    }
+    let html = &mut spec.sub_specs_mut()[1];
+    assert!(html.set_format_flowed().is_err());
+    assert!(!html.is_format_flowed());
+}
+
+#[test]
+fn multipart_media_types_are_rejected_for_bodies() {
+    let source = TemplateSource::Source { id: "multi".to_owned(), content: "".to_owned() };
+    let multipart = MediaType::parse("multipart/mixed; boundary=abc").unwrap();
+    let res = SubTemplateSpec::new_with_template_source(source, multipart.clone(), Default::default());
+    assert!(res.is_err());
+
+    let mut spec = TemplateSpec::from_dir("./test_resources/templates/template_a", &*DEFAULT_SETTINGS).unwrap();
+    let text = &mut spec.sub_specs_mut()[0];
+    assert!(text.set_media_type(multipart).is_err());
+    assert_eq!(text.media_type().as_str_repr(), "text/plain; charset=utf-8");
+}
+
+#[test]
+fn latin1_templates_are_decoded_when_loading() {
+    let dir = "./test_resources/latin1_template";
+    let mut settings = DEFAULT_SETTINGS.clone();
+    settings.set_input_encoding("text", InputEncoding::Latin1).unwrap();
+    assert!(settings.set_input_encoding("not_a_type", InputEncoding::Latin1).is_err());
+
+    let spec = TemplateSpec::from_dir(dir, &settings).unwrap();
+    assert_eq!(spec.sub_specs()[0].source(), &TemplateSource::Source {
+        id: "./test_resources/latin1_template/text/mail.txt".to_owned(),
+        content: "Gr\u{fc}\u{df}e {{name}}.".to_owned()
+    });
+
+    let spec = TemplateSpec::from_dir(dir, &*DEFAULT_SETTINGS).unwrap();
+    assert_eq!(spec.sub_specs()[0].source(),
+        &TemplateSource::Path("./test_resources/latin1_template/text/mail.txt".to_owned()));
+}
+
+#[test]
+fn load_with_default_settings() {
+    let spec = TemplateSpec::from_dir_default("./test_resources/templates/template_a").unwrap();
+    assert!(spec.content_eq(
+        &TemplateSpec::from_dir("./test_resources/templates/template_a", &*DEFAULT_SETTINGS).unwrap()));
+
+    let specs = TemplateSpec::from_dirs_default("./test_resources/templates").unwrap();
+    assert_eq!(specs.len(), TemplateSpec::from_dirs("./test_resources/templates", &*DEFAULT_SETTINGS).unwrap().len());
+}
+
+#[test]
+fn count_bodies_embeddings_and_attachments() {
+    let spec = TemplateSpec::from_dir("./test_resources/templates/template_a", &*DEFAULT_SETTINGS).unwrap();
+    assert_eq!(spec.body_count(), 2);
+    assert_eq!(spec.embedding_count(), 1);
+    assert_eq!(spec.attachment_count(), 0);
+    assert_eq!(spec.sub_specs()[0].embedding_count(), 0);
+    assert_eq!(spec.sub_specs()[1].embedding_count(), 1);
+}
+
+#[test]
+fn iterate_all_resources_of_a_spec() {
+    let spec = TemplateSpec::from_dir("./test_resources/templates/template_a", &*DEFAULT_SETTINGS).unwrap();
+    assert_eq!(spec.resource_count(), 2);
+
+    let mut iris = spec.iter_resources()
+        .map(|resource| resource.source().unwrap().iri.as_str().to_owned())
+        .collect::<Vec<_>>();
+    iris.sort();
+    assert_eq!(iris, vec![
+        "path:./test_resources/templates/template_a/html/logo.png".to_owned(),
+        "path:./test_resources/templates/template_a/portfolio.pdf".to_owned()
+    ]);
+}
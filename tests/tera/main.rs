@@ -7,37 +7,49 @@ extern crate mail_render_template_engine as render_template_engine;
 extern crate soft_ascii_string;
 extern crate futures;
 extern crate regex;
+extern crate serde;
+#[macro_use]
+extern crate serde_json;
 #[macro_use]
 extern crate serde_derive;
-
-//TODO use custom integration test target for this
-#[cfg(not(feature = "tera-engine"))]
-compile_error!("need feature \"tera-engine\" to run tera integration tests");
-
+#[macro_use]
+extern crate vec1;
 
 use std::result::{Result as StdResult};
 use std::io::{BufRead, BufReader};
 use std::fs::File;
 use std::collections::HashMap;
 use std::borrow::Cow;
+use std::cell::Cell;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
 
 use regex::Regex;
+use serde::{Serialize, Serializer};
 use futures::Future;
 use soft_ascii_string::SoftAsciiString;
 
 use common::MailType;
 use common::encoder::EncodingBuffer;
-use mail::{Mail, Context};
+use mail::{Mail, Context, Resource};
+use mail::file_buffer::FileBuffer;
 use mail::default_impl::simple_context;
-use headers::components::{Email, Domain};
+use headers::components::{Email, Domain, MediaType, FileMeta};
 use headers::HeaderTryFrom;
-use template::{MailSendData, InspectEmbeddedResources, Embedded};
+use template::{MailSendData, InspectEmbeddedResources, Embedded, TemplateEngine};
 
 use render_template_engine::{
     RenderTemplateEngine, DEFAULT_SETTINGS,
-    TemplateSpec
+    TemplateSpec, SubTemplateSpec, TemplateSource,
+    MarkdownRenderer, RenderEngineBase, RenderEngine, AdditionalCIds,
+    SharedRenderTemplateEngine, EmbeddingDisposition, CompositeRenderEngine,
+    InheritanceSupport, BodyResourceFactory, MailRenderEngine, MailTemplateEngine,
+    WrapperPosition, SpecTransform, RenderTemplateEngineConfig, CssInliner, BodyPostProcessor,
+    TransferEncoding, EmptyBodyPolicy, MemoryFs
 };
 use render_template_engine::tera::TeraRenderEngine;
+use render_template_engine::error::{RenderTimeoutError, EncodingError, ErrorKind, RenderErrorKind};
 
 
 #[derive(Serialize, InspectEmbeddedResources)]
@@ -98,6 +110,1401 @@ fn use_tera_template_a() {
     assert_mail_out_is_as_expected(out_string);
 }
 
+#[test]
+fn render_once_uses_the_base_templates_and_autoescape_setting() {
+    let mut tera = TeraRenderEngine::new("./test_resources/tera_base/**/*").unwrap();
+    let data = UserData { name: "<Liz>" };
+    let source = "{% extends \"base_mail.html\" %}{% block body %}Hy {{ name }}.{% endblock body %}";
+
+    let rendered = tera.render_once(source, &data, AdditionalCIds::new(&[])).unwrap();
+    assert!(rendered.contains("<!DOCTYPE html>"));
+    assert!(rendered.contains("Hy &lt;Liz&gt;."));
+    assert!(tera.preloaded_template_names().is_empty());
+    assert_eq!(tera.base_template_names(), vec!["base_mail.html"]);
+
+    tera.set_autoescape_file_suffixes(vec![]);
+    let rendered = tera.render_once("Hy {{ name }}.", &data, AdditionalCIds::new(&[])).unwrap();
+    assert_eq!(rendered, "Hy <Liz>.");
+}
+
+#[test]
+fn loading_templates_returns_the_registered_ids() {
+    let mut tera = TeraRenderEngine::new("./test_resources/tera_base/**/*").unwrap();
+    let spec = TemplateSpec
+        ::from_dir("./test_resources/templates/template_a", &*DEFAULT_SETTINGS)
+        .unwrap();
+
+    let ids = tera.load_templates(&spec).unwrap();
+    assert_eq!(ids, vec![
+        "./test_resources/templates/template_a/text/mail.txt".to_owned(),
+        "./test_resources/templates/template_a/html/mail.html".to_owned()
+    ]);
+}
+
+#[test]
+fn mail_render_engine_produces_complete_mails() {
+    let context = setup_context();
+    let engine = MailRenderEngine::new(setup_template_engine());
+
+    let from        = Email::try_from("a@b.c").unwrap().into();
+    let to          = Email::try_from("d@e.f").unwrap().into();
+    let template_id = Cow::Borrowed("template_a");
+    let data        = UserData { name: "Liz" };
+    let send_data = MailSendData::simple_new(from, to, "Dear randomness", template_id, data);
+
+    let mail = engine.render_mail(send_data, &context).unwrap();
+    let out_string = send_mail_to_string(mail, context.clone());
+    assert_mail_out_is_as_expected(out_string);
+
+    let data = UserData { name: "Liz" };
+    let parts = engine.use_template("template_a", &data, &context).unwrap();
+    assert_eq!(parts.alternative_bodies.len(), 2);
+}
+
+#[test]
+fn renders_taking_longer_than_the_render_timeout_fail() {
+    let mut engine = SharedRenderTemplateEngine::new(setup_template_engine());
+    let text = MediaType::parse("text/plain; charset=utf-8").unwrap();
+    // takes well above the timeout but finishes soon after it
+    engine.write().insert_spec_str("slow_mail", text, concat!(
+        "{% for i in range(end=1000) %}{% for j in range(end=1000) %}",
+        "{% endfor %}{% endfor %}")).unwrap();
+
+    // no timeout by default
+    assert_eq!(engine.render_timeout(), None);
+    engine.set_render_timeout(Some(Duration::from_secs(30)));
+    let data = UserData { name: "Liz" };
+    let parts = engine.use_template_with_timeout("template_a", data, setup_context()).unwrap();
+    assert_eq!(parts.alternative_bodies.len(), 2);
+
+    engine.set_render_timeout(Some(Duration::from_millis(5)));
+    let data = UserData { name: "Liz" };
+    match engine.use_template_with_timeout("slow_mail", data, setup_context()) {
+        Err(RenderTimeoutError::TimedOut { timeout }) => assert_eq!(timeout, Duration::from_millis(5)),
+        other => panic!("unexpected result: {:?}", other.map(|_| ()))
+    }
+
+    // the render thread keeps the engine read-locked until it finishes
+    assert!(engine.write().remove_spec("slow_mail").is_some());
+}
+
+#[test]
+fn engines_can_be_created_with_a_config() {
+    let tera = TeraRenderEngine::new("./test_resources/tera_base/**/*").unwrap();
+    let engine = RenderTemplateEngine::new(tera);
+    let config = engine.config();
+    // tera doesn't guarantee valid newlines
+    assert_eq!(config.fix_newlines, Some(true));
+    assert_eq!(config.empty_body_policy, EmptyBodyPolicy::Keep);
+    assert!(!config.strict_embeddings);
+    assert!(!config.prune_unreferenced_embeddings);
+    assert_eq!(config.data_key, None);
+    assert_eq!(config.text_line_wrap, None);
+
+    let config = RenderTemplateEngineConfig {
+        fix_newlines: Some(false),
+        strict_newlines: true,
+        strict_embeddings: true,
+        empty_body_policy: EmptyBodyPolicy::Reject,
+        prune_unreferenced_embeddings: true,
+        data_key: Some("user".to_owned()),
+        text_line_wrap: Some(78),
+        ..Default::default()
+    };
+    let tera = TeraRenderEngine::new("./test_resources/tera_base/**/*").unwrap();
+    let mut engine = RenderTemplateEngine::with_config(tera, config.clone());
+    assert!(!engine.does_fix_newlines());
+    assert!(engine.strict_newlines());
+    assert!(engine.strict_embeddings());
+    assert_eq!(engine.empty_body_policy(), EmptyBodyPolicy::Reject);
+    assert!(engine.prune_unreferenced_embeddings());
+    assert_eq!(engine.text_line_wrap(), Some(78));
+    assert_eq!(engine.config(), config);
+
+    let text = MediaType::parse("text/plain; charset=utf-8").unwrap();
+    engine.insert_spec_str("data_key", text, "Hy {{ user.name }}.").unwrap();
+    let bodies = engine.render_all_bodies("data_key", &UserData { name: "Liz" }, &setup_context()).unwrap();
+    assert_eq!(bodies[0].1, "Hy Liz.");
+}
+
+fn logo_embedding() -> HashMap<String, Resource> {
+    let a_spec = TemplateSpec::from_dir("./test_resources/templates/template_a", &*DEFAULT_SETTINGS).unwrap();
+    let logo = a_spec.sub_specs()[1].embeddings().get("logo").unwrap().clone();
+    let mut embeddings = HashMap::new();
+    embeddings.insert("logo".to_owned(), logo);
+    embeddings
+}
+
+#[test]
+fn strict_embeddings_reject_shadowed_shared_embeddings() {
+    let context = setup_context();
+    let mut engine = setup_template_engine();
+    engine.set_strict_embeddings(true);
+
+    let source = TemplateSource::Source { id: "shadowing.txt".to_owned(), content: "{{ cids.logo }}".to_owned() };
+    let media_type = MediaType::parse("text/plain; charset=utf-8").unwrap();
+    let sub_spec = SubTemplateSpec::new_with_template_source(source, media_type, logo_embedding()).unwrap();
+    let spec = TemplateSpec::new_with_embeddings(vec1![sub_spec], logo_embedding()).unwrap();
+    engine.insert_spec("shadowing".to_owned(), spec).unwrap();
+
+    let data = UserData { name: "Liz" };
+    assert!(engine.render_all_bodies("shadowing", &data, &context).is_err());
+    engine.set_strict_embeddings(false);
+    assert!(engine.render_all_bodies("shadowing", &data, &context).is_ok());
+}
+
+#[test]
+fn unreferenced_embeddings_can_be_pruned() {
+    let context = setup_context();
+    let mut engine = setup_template_engine();
+    engine.set_prune_unreferenced_embeddings(true);
+
+    let body = |id: &str, content: &str| {
+        let source = TemplateSource::Source { id: id.to_owned(), content: content.to_owned() };
+        let media_type = MediaType::parse("text/html; charset=utf-8").unwrap();
+        SubTemplateSpec::new_with_template_source(source, media_type, logo_embedding()).unwrap()
+    };
+    let mut shared = logo_embedding();
+    let shared_logo = shared.remove("logo").unwrap();
+    shared.insert("banner".to_owned(), shared_logo);
+    let spec = TemplateSpec::new_with_embeddings(vec1![
+        body("pruned/referenced.html", "<img src=\"cid:{{ cids.logo }}\">"),
+        body("pruned/unreferenced.html", "<p>Hy</p>")
+    ], shared).unwrap();
+    engine.insert_spec("pruned".to_owned(), spec).unwrap();
+
+    let data = UserData { name: "Liz" };
+    let parts = engine.use_template("pruned", &data, &context).unwrap();
+    assert_eq!(parts.alternative_bodies[0].embeddings.len(), 1);
+    assert_eq!(parts.alternative_bodies[1].embeddings.len(), 0);
+    assert_eq!(parts.shared_embeddings.len(), 0);
+
+    engine.set_prune_unreferenced_embeddings(false);
+    let parts = engine.use_template("pruned", &data, &context).unwrap();
+    assert_eq!(parts.alternative_bodies[1].embeddings.len(), 1);
+    assert_eq!(parts.shared_embeddings.len(), 1);
+}
+
+#[test]
+fn shared_engine_can_be_used_from_multiple_threads() {
+    let engine = SharedRenderTemplateEngine::new(setup_template_engine());
+
+    let handles = (0..2).map(|_| {
+        let engine = engine.clone();
+        std::thread::spawn(move || {
+            let data = UserData { name: "Liz" };
+            let parts = engine.use_template("template_a", &data, &setup_context()).unwrap();
+            assert_eq!(parts.alternative_bodies.len(), 2);
+        })
+    }).collect::<Vec<_>>();
+
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    let spec = engine.remove_spec("template_a").unwrap();
+    assert!(engine.read().lookup_spec("template_a").is_none());
+    engine.insert_spec("template_a".to_owned(), spec).unwrap();
+    assert!(engine.read().lookup_spec("template_a").is_some());
+}
+
+#[test]
+fn inserting_specs_with_duplicate_ids_fails() {
+    let mut engine = RenderTemplateEngine::new(TeraRenderEngine::new("./test_resources/tera_base/**/*").unwrap());
+    let specs = vec![
+        ("template_a".to_owned(), TemplateSpec::from_dir("./test_resources/templates/template_a", &*DEFAULT_SETTINGS).unwrap()),
+        ("template_a".to_owned(), TemplateSpec::from_dir("./test_resources/templates/template_a", &*DEFAULT_SETTINGS).unwrap())
+    ];
+    assert!(engine.insert_specs(specs).is_err());
+    assert!(engine.lookup_spec("template_a").is_none());
+}
+
+#[test]
+fn specs_can_be_removed_by_predicate() {
+    let mut engine = setup_template_engine();
+    let media_type = MediaType::parse("text/plain; charset=utf-8").unwrap();
+    engine.insert_spec_str("marketing.spring", media_type.clone(), "Spring sale").unwrap();
+    engine.insert_spec_str("marketing.autumn", media_type, "Autumn sale").unwrap();
+
+    let removed = engine.remove_specs_matching(|id| id.starts_with("marketing."));
+    let removed_ids = removed.iter().map(|&(ref id, _)| id.as_str()).collect::<Vec<_>>();
+    assert_eq!(removed_ids, vec!["marketing.autumn", "marketing.spring"]);
+    assert!(engine.lookup_spec("marketing.spring").is_none());
+    assert!(engine.lookup_spec("template_a").is_some());
+
+    assert!(engine.remove_specs_matching(|id| id.starts_with("marketing.")).is_empty());
+}
+
+#[test]
+fn embeddings_can_be_filtered_per_render() {
+    let context = setup_context();
+    let engine = setup_template_engine();
+    let data = UserData { name: "Liz" };
+
+    let parts = engine.use_template("template_a", &data, &context).unwrap();
+    assert_eq!(parts.shared_embeddings.len(), 1);
+
+    let parts = engine.use_template_with_filter("template_a", &data, &context, |name| name != "portfolio")
+        .unwrap();
+    assert!(parts.shared_embeddings.is_empty());
+    assert_eq!(parts.alternative_bodies[1].embeddings.len(), 1);
+
+    // the html body references the logo
+    assert!(engine.use_template_with_filter("template_a", &data, &context, |name| name != "logo").is_err());
+}
+
+#[test]
+fn content_ids_can_be_passed_as_descriptors() {
+    let context = setup_context();
+    let mut engine = setup_template_engine();
+    engine.set_cid_descriptors(true);
+
+    let a_spec = TemplateSpec::from_dir("./test_resources/templates/template_a", &*DEFAULT_SETTINGS).unwrap();
+    let logo = a_spec.sub_specs()[1].embeddings().get("logo").unwrap().clone();
+    let mut embeddings = HashMap::new();
+    embeddings.insert("logo".to_owned(), logo);
+
+    let source = TemplateSource::Source {
+        id: "cid_descriptors/mail.txt".to_owned(),
+        content: "{{ cids.logo }}|{{ cid_info.logo.cid }}|{{ cid_info.logo.mime }}|{{ cid_info.logo.filename }}".to_owned()
+    };
+    let media_type = MediaType::parse("text/plain; charset=utf-8").unwrap();
+    let sub_spec = SubTemplateSpec::new_with_template_source(source, media_type, embeddings).unwrap();
+    engine.insert_spec("cid_descriptors".to_owned(), TemplateSpec::new(vec1![sub_spec]).unwrap()).unwrap();
+
+    let data = UserData { name: "Liz" };
+    let bodies = engine.render_all_bodies("cid_descriptors", &data, &context).unwrap();
+    let fields = bodies[0].1.split('|').collect::<Vec<_>>();
+    assert!(fields[0].ends_with("@company_a.test"));
+    assert_eq!(fields[1], fields[0]);
+    assert_eq!(&fields[2..], &["image/png", "logo.png"]);
+
+    engine.set_cid_descriptors(false);
+    assert!(engine.render_all_bodies("cid_descriptors", &data, &context).is_err());
+}
+
+#[test]
+fn embeddings_can_be_added_as_attachments() {
+    let context = setup_context();
+    let mut engine = setup_template_engine();
+
+    let mut spec = engine.remove_spec("template_a").unwrap();
+    spec.set_embedding_disposition("portfolio", EmbeddingDisposition::Attachment).unwrap();
+    assert!(spec.set_embedding_disposition("no_such_embedding", EmbeddingDisposition::Attachment).is_err());
+    engine.insert_spec("template_a".to_owned(), spec).unwrap();
+
+    let from        = Email::try_from("a@b.c").unwrap().into();
+    let to          = Email::try_from("d@e.f").unwrap().into();
+    let template_id = Cow::Borrowed("template_a");
+    let data        = UserData { name: "Liz" };
+    let send_data = MailSendData::simple_new(from, to, "attached", template_id, data);
+
+    let mail = send_data.compose(&context, &engine).unwrap();
+    let out_string = send_mail_to_string(mail, context.clone());
+
+    assert!(out_string.contains("Content-Disposition: attachment"));
+    assert!(out_string.contains("Content-Disposition: inline"));
+}
+
+#[test]
+fn non_ascii_attachment_names_are_encoded() {
+    let context = setup_context();
+    let mut engine = setup_template_engine();
+
+    let mut spec = engine.remove_spec("template_a").unwrap();
+    let portfolio = spec.embeddings().get("portfolio").unwrap().clone();
+    spec.attachments_mut().push(portfolio);
+    spec.set_attachment_name(0, "Rechnung_Müller.pdf").unwrap();
+    engine.insert_spec("template_a".to_owned(), spec).unwrap();
+
+    let from        = Email::try_from("a@b.c").unwrap().into();
+    let to          = Email::try_from("d@e.f").unwrap().into();
+    let template_id = Cow::Borrowed("template_a");
+    let data        = UserData { name: "Liz" };
+    let send_data = MailSendData::simple_new(from, to, "invoice", template_id, data);
+
+    let mail = send_data.compose(&context, &engine).unwrap();
+    let out_string = send_mail_to_string(mail, context.clone());
+
+    // RFC 2231 encoded, e.g. `filename*=utf-8''Rechnung_M%C3%BCller.pdf`
+    assert!(!out_string.contains("Müller"));
+    let encoded_name = Regex::new(r"(?i)filename\*=utf-?8''Rechnung_M%C3%BCller\.pdf").unwrap();
+    assert!(encoded_name.is_match(&out_string));
+}
+
+#[test]
+fn render_defaults_are_merged_under_the_data() {
+    let context = setup_context();
+    let mut engine = setup_template_engine();
+    engine.set_render_defaults(Some(json!({
+        "name": "customer",
+        "company": { "name": "ACME", "support": "help@acme.test" }
+    })));
+
+    let media_type = MediaType::parse("text/plain; charset=utf-8").unwrap();
+    engine.insert_spec_str("with_defaults", media_type,
+        "Hy {{ name }}, {{ company.name }} ({{ company.support }})").unwrap();
+
+    let data = UserData { name: "Liz" };
+    let bodies = engine.render_all_bodies("with_defaults", &data, &context).unwrap();
+    assert_eq!(bodies[0].1, "Hy Liz, ACME (help@acme.test)");
+
+    engine.set_render_defaults(None);
+    assert!(engine.render_all_bodies("with_defaults", &data, &context).is_err());
+}
+
+#[test]
+fn render_defaults_of_sub_specs_are_only_used_for_their_body() {
+    let context = setup_context();
+    let mut engine = setup_template_engine();
+    engine.set_render_defaults(Some(json!({ "image": "engine.png", "name": "customer" })));
+
+    let text = SubTemplateSpec::new_with_template_source(
+        TemplateSource::Source { id: "sub_defaults.txt".to_owned(), content: "Hy {{ name }} {{ image }}".to_owned() },
+        MediaType::parse("text/plain; charset=utf-8").unwrap(),
+        HashMap::new()
+    ).unwrap();
+    let mut html = SubTemplateSpec::new_with_template_source(
+        TemplateSource::Source { id: "sub_defaults.html".to_owned(), content: "<p>Hy {{ name }} {{ image }}</p>".to_owned() },
+        MediaType::parse("text/html; charset=utf-8").unwrap(),
+        HashMap::new()
+    ).unwrap();
+    html.set_render_defaults(Some(json!({ "image": "fallback.png", "name": "you" })));
+    engine.insert_spec("sub_defaults".to_owned(), TemplateSpec::new(vec1![text, html]).unwrap()).unwrap();
+
+    let data = UserData { name: "Liz" };
+    let bodies = engine.render_all_bodies("sub_defaults", &data, &context).unwrap();
+    assert_eq!(bodies[0].1, "Hy Liz engine.png");
+    assert_eq!(bodies[1].1, "<p>Hy Liz fallback.png</p>");
+}
+
+#[test]
+fn body_wrappers_are_added_to_bodies_with_their_media_type() {
+    let context = setup_context();
+    let mut engine = setup_template_engine();
+
+    let text = MediaType::parse("text/plain; charset=utf-8").unwrap();
+    let html = MediaType::parse("text/html; charset=utf-8").unwrap();
+    engine.set_body_wrapper(text.clone(), WrapperPosition::Append, "\n--\nSent to {{ name }}").unwrap();
+    engine.set_body_wrapper(text.clone(), WrapperPosition::Prepend, "[ACME] ").unwrap();
+    engine.insert_spec_str("wrapped_text", text.clone(), "Hy {{ name }}.").unwrap();
+    engine.insert_spec_str("wrapped_html", html, "<p>Hy</p>").unwrap();
+
+    let data = UserData { name: "Liz" };
+    let bodies = engine.render_all_bodies("wrapped_text", &data, &context).unwrap();
+    assert_eq!(bodies[0].1, "[ACME] Hy Liz.\r\n--\r\nSent to Liz");
+    let bodies = engine.render_all_bodies("wrapped_html", &data, &context).unwrap();
+    assert_eq!(bodies[0].1, "<p>Hy</p>");
+
+    // replacing a wrapper
+    engine.set_body_wrapper(text, WrapperPosition::Append, " (footer)").unwrap();
+    let bodies = engine.render_all_bodies("wrapped_text", &data, &context).unwrap();
+    assert_eq!(bodies[0].1, "[ACME] Hy Liz. (footer)");
+
+    assert!(engine.remove_body_wrapper("text/plain", WrapperPosition::Prepend));
+    assert!(!engine.remove_body_wrapper("text/plain", WrapperPosition::Prepend));
+    let bodies = engine.render_all_bodies("wrapped_text", &data, &context).unwrap();
+    assert_eq!(bodies[0].1, "Hy Liz. (footer)");
+}
+
+#[test]
+fn lenient_previews_use_placeholders_for_undefined_variables() {
+    let context = setup_context();
+    let mut engine = setup_template_engine();
+    let text = MediaType::parse("text/plain; charset=utf-8").unwrap();
+    engine.insert_spec_str("lenient_preview", text, "Hy {{ name }} from {{ company.name }}.").unwrap();
+    let mut spec = engine.remove_spec("lenient_preview").unwrap();
+    spec.set_sample_data(Some(json!({ "name": "Liz" })));
+    engine.insert_spec("lenient_preview".to_owned(), spec).unwrap();
+
+    assert!(engine.render_preview("lenient_preview", &context).is_err());
+    let parts = engine.render_preview_lenient("lenient_preview", &context).unwrap();
+    assert_eq!(parts.alternative_bodies.len(), 1);
+
+    // normal renders stay strict
+    let data = UserData { name: "Liz" };
+    assert!(engine.use_template("lenient_preview", &data, &context).is_err());
+}
+
+#[test]
+fn bodies_can_be_rendered_to_encoded_bytes() {
+    let context = setup_context();
+    let mut engine = setup_template_engine();
+    let latin1 = MediaType::parse("text/plain; charset=iso-8859-1").unwrap();
+    engine.insert_spec_str("latin1_mail", latin1, "Grüße {{ name }}\n").unwrap();
+
+    let data = UserData { name: "Liz" };
+    let text = MediaType::parse("text/plain").unwrap();
+    let bytes = engine.render_body_bytes("latin1_mail", &text, &data, &context).unwrap().unwrap();
+    assert_eq!(bytes, b"Gr\xfc\xdfe Liz\r\n".to_vec());
+
+    let html = MediaType::parse("text/html").unwrap();
+    assert_eq!(engine.render_body_bytes("latin1_mail", &html, &data, &context).unwrap(), None);
+    let bytes = engine.render_body_bytes("template_a", &html, &data, &context).unwrap().unwrap();
+    assert!(String::from_utf8(bytes).unwrap().contains("Liz"));
+}
+
+#[test]
+fn globals_are_available_in_templates() {
+    let context = setup_context();
+    let mut engine = setup_template_engine();
+    engine.set_global("app_version", "1.2.3".into());
+
+    let source = TemplateSource::Source {
+        id: "globals_mail.txt".to_owned(),
+        content: "{{ globals.app_version }}".to_owned()
+    };
+    let media_type = MediaType::parse("text/plain; charset=utf-8").unwrap();
+    let mut sub_spec = SubTemplateSpec::new_with_template_source(source, media_type, HashMap::new()).unwrap();
+    // fails to render if (and only if) the globals are not passed in
+    sub_spec.insert_variant("missing", TemplateSource::Source {
+        id: "globals_mail.missing.txt".to_owned(),
+        content: "{{ globals.not_set }}".to_owned()
+    });
+    engine.insert_spec("globals_mail".to_owned(), TemplateSpec::new(vec1![sub_spec]).unwrap()).unwrap();
+
+    let data = UserData { name: "Liz" };
+    assert!(engine.use_template("globals_mail", &data, &context).is_ok());
+    assert!(engine.use_template_variant("globals_mail", "missing", &data, &context).is_err());
+
+    engine.remove_global("app_version");
+    assert!(engine.use_template("globals_mail", &data, &context).is_err());
+}
+
+fn setup_flat_mail_engine(flatten_data: bool) -> RenderTemplateEngine<TeraRenderEngine> {
+    let mut tera = TeraRenderEngine::new("./test_resources/tera_base/**/*").unwrap();
+    tera.set_flatten_data(flatten_data);
+    let mut engine = RenderTemplateEngine::new(tera);
+
+    let source = TemplateSource::Source {
+        id: "flat_mail.txt".to_owned(),
+        content: "Hy {{ name }}.".to_owned()
+    };
+    let media_type = MediaType::parse("text/plain; charset=utf-8").unwrap();
+    let mut sub_spec = SubTemplateSpec::new_with_template_source(source, media_type, HashMap::new()).unwrap();
+    sub_spec.insert_variant("nested", TemplateSource::Source {
+        id: "flat_mail.nested.txt".to_owned(),
+        content: "Hy {{ data.name }}.".to_owned()
+    });
+    engine.insert_spec("flat_mail".to_owned(), TemplateSpec::new(vec1![sub_spec]).unwrap()).unwrap();
+    engine
+}
+
+#[test]
+fn data_is_accessible_at_the_top_level_by_default() {
+    let context = setup_context();
+    let engine = setup_flat_mail_engine(true);
+
+    let data = UserData { name: "Liz" };
+    assert!(engine.use_template("flat_mail", &data, &context).is_ok());
+    assert!(engine.use_template_variant("flat_mail", "nested", &data, &context).is_err());
+}
+
+#[test]
+fn nested_data_can_be_restored() {
+    let context = setup_context();
+    let engine = setup_flat_mail_engine(false);
+
+    let data = UserData { name: "Liz" };
+    assert!(engine.use_template("flat_mail", &data, &context).is_err());
+    assert!(engine.use_template_variant("flat_mail", "nested", &data, &context).is_ok());
+}
+
+#[test]
+fn reloading_templates_rereads_the_files() {
+    let dir = std::env::temp_dir().join(format!("rte_test_tera_reload_{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(dir.join("text")).unwrap();
+    std::fs::write(dir.join("text").join("mail.txt"), "version 1").unwrap();
+
+    let mut tera = TeraRenderEngine::new("./test_resources/tera_base/**/*").unwrap();
+    let spec = TemplateSpec::from_dir(&dir, &*DEFAULT_SETTINGS).unwrap();
+    tera.load_templates(&spec).unwrap();
+
+    let data = UserData { name: "Liz" };
+    let render = |tera: &TeraRenderEngine| {
+        tera.render(&spec.sub_specs()[0], &data, AdditionalCIds::new(&[])).unwrap()
+    };
+    assert_eq!(render(&tera), "version 1");
+
+    std::fs::write(dir.join("text").join("mail.txt"), "version 2").unwrap();
+    assert_eq!(render(&tera), "version 1");
+    tera.reload_templates(&spec).unwrap();
+    assert_eq!(render(&tera), "version 2");
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn composite_engine_dispatches_by_media_type() {
+    let primary = TeraRenderEngine::new("./test_resources/tera_base/**/*").unwrap();
+    let secondary = TeraRenderEngine::new("./test_resources/tera_base/**/*").unwrap();
+    let mut composite = CompositeRenderEngine::new(primary, secondary);
+    composite.route_to_secondary("text/plain");
+
+    let spec = TemplateSpec::from_dir("./test_resources/templates/template_a", &*DEFAULT_SETTINGS).unwrap();
+    let ids = composite.load_templates(&spec).unwrap();
+    assert_eq!(ids, vec![
+        "./test_resources/templates/template_a/text/mail.txt".to_owned(),
+        "./test_resources/templates/template_a/html/mail.html".to_owned()
+    ]);
+
+    let text = &spec.sub_specs()[0];
+    assert!(composite.uses_secondary(text.media_type()));
+    let data = UserData { name: "Liz" };
+    let rendered = composite.render(text, &data, AdditionalCIds::new(&[])).unwrap();
+    assert_eq!(rendered, "Hy Liz.");
+
+    assert!(composite.secondary().render(text, &data, AdditionalCIds::new(&[])).is_ok());
+    assert!(composite.primary().render(text, &data, AdditionalCIds::new(&[])).is_err());
+
+    composite.unload_templates(&spec);
+    assert!(composite.render(text, &data, AdditionalCIds::new(&[])).is_err());
+}
+
+#[test]
+fn prewarmed_templates_can_be_used() {
+    let mut fix = setup_template_engine();
+    fix.prewarm().unwrap();
+
+    let data = UserData { name: "Liz" };
+    assert!(fix.use_template("template_a", &data, &setup_context()).is_ok());
+}
+
+#[test]
+fn content_ids_are_generated_by_the_given_context() {
+    let msg_id_domain = Domain::try_from("cids.company_b.test").unwrap();
+    let unique_part = SoftAsciiString::from_string("c1d5").unwrap();
+    let context = simple_context::new(msg_id_domain, unique_part).unwrap();
+    let engine = setup_template_engine();
+    let data = UserData { name: "Liz" };
+
+    let parts = engine.use_template("template_a", &data, &context).unwrap();
+
+    let cids = parts.alternative_bodies.iter()
+        .flat_map(|body| body.embeddings.iter())
+        .chain(parts.shared_embeddings.iter())
+        .map(|embedding| embedding.content_id().as_str().to_owned())
+        .collect::<Vec<_>>();
+
+    // logo (html body) + portfolio (shared)
+    assert_eq!(cids.len(), 2);
+    for cid in cids {
+        assert!(cid.contains("@cids.company_b.test"), "unexpected content id: {}", cid);
+    }
+}
+
+/// counts the processed bodies
+#[derive(Debug)]
+struct CountingProcessor(Arc<AtomicUsize>);
+
+impl BodyPostProcessor for CountingProcessor {
+    fn process(&self, _media_type: &MediaType, body: String) -> StdResult<String, EncodingError> {
+        self.0.fetch_add(1, Ordering::SeqCst);
+        Ok(body)
+    }
+}
+
+#[test]
+fn cached_renders_reuse_the_rendered_bodies() {
+    let context = setup_context();
+    let mut engine = setup_template_engine();
+    let rendered = Arc::new(AtomicUsize::new(0));
+    engine.add_body_post_processor(Box::new(CountingProcessor(rendered.clone())));
+    engine.set_deterministic_content_ids(true);
+    engine.enable_render_cache(4);
+    let data = UserData { name: "Liz" };
+    let count = || rendered.load(Ordering::SeqCst);
+
+    // template_a has a text and a html body
+    engine.use_template("template_a", &data, &context).unwrap();
+    engine.use_template("template_a", &data, &context).unwrap();
+    assert_eq!(count(), 2);
+
+    engine.clear_render_cache();
+    engine.use_template("template_a", &data, &context).unwrap();
+    assert_eq!(count(), 4);
+
+    // the domain of the content ids is part of the cache key
+    let msg_id_domain = Domain::try_from("cids.company_b.test").unwrap();
+    let unique_part = SoftAsciiString::from_string("c1d5").unwrap();
+    let other_context = simple_context::new(msg_id_domain, unique_part).unwrap();
+    let parts = engine.use_template("template_a", &data, &other_context).unwrap();
+    assert_eq!(count(), 6);
+    let logo_cid = parts.alternative_bodies[1].embeddings[0].content_id().as_str().to_owned();
+    assert!(logo_cid.ends_with("@cids.company_b.test"), "unexpected content id: {}", logo_cid);
+
+    // the globals are part of the cache key
+    engine.set_global("company", json!("ACME"));
+    engine.use_template("template_a", &data, &context).unwrap();
+    assert_eq!(count(), 8);
+
+    // without deterministic content ids the cache is not used
+    engine.set_deterministic_content_ids(false);
+    engine.use_template("template_a", &data, &context).unwrap();
+    engine.use_template("template_a", &data, &context).unwrap();
+    assert_eq!(count(), 12);
+}
+
+#[test]
+fn cached_renders_are_dropped_when_the_spec_changes() {
+    let context = setup_context();
+    let mut engine = setup_template_engine();
+    engine.set_deterministic_content_ids(true);
+    engine.enable_render_cache(4);
+    let media_type = MediaType::parse("text/plain; charset=utf-8").unwrap();
+    engine.insert_spec_str("changing", media_type.clone(), "Hy {{ name }}.").unwrap();
+
+    let render = |engine: &RenderTemplateEngine<TeraRenderEngine>| {
+        let from = Email::try_from("a@b.c").unwrap().into();
+        let to = Email::try_from("d@e.f").unwrap().into();
+        let send_data = MailSendData::simple_new(
+            from, to, "cached", Cow::Borrowed("changing"), UserData { name: "Liz" });
+        let mail = send_data.compose(&context, engine).unwrap();
+        send_mail_to_string(mail, context.clone())
+    };
+    assert!(render(&engine).contains("Hy Liz."));
+    assert!(render(&engine).contains("Hy Liz."));
+
+    engine.insert_spec_str("changing", media_type, "Bye {{ name }}.").unwrap();
+    assert!(render(&engine).contains("Bye Liz."));
+
+    let data = UserData { name: "Liz" };
+    engine.remove_spec("changing");
+    assert!(engine.use_template("changing", &data, &context).is_err());
+}
+
+#[test]
+fn report_unused_embeddings() {
+    let context = setup_context();
+    let engine = setup_template_engine();
+    let data = UserData { name: "Liz" };
+
+    let (parts, report) = engine.use_template_with_report("template_a", &data, &context).unwrap();
+
+    assert_eq!(parts.alternative_bodies.len(), 2);
+    // the logo is used in the html body but the portfolio is not referenced at all
+    assert_eq!(report.unused_embeddings, vec!["portfolio".to_owned()]);
+}
+
+#[test]
+fn variants_are_selected_by_key() {
+    let context = setup_context();
+    let mut engine = setup_template_engine();
+
+    let source = TemplateSource::Source {
+        id: "variant_mail.txt".to_owned(),
+        content: "Welcome {{ name }}.".to_owned()
+    };
+    let media_type = MediaType::parse("text/plain; charset=utf-8").unwrap();
+    let mut sub_spec = SubTemplateSpec::new_with_template_source(source, media_type, HashMap::new()).unwrap();
+    // uses a field not in the data, so rendering fails if (and only if) it is selected
+    sub_spec.insert_variant("broken", TemplateSource::Source {
+        id: "variant_mail.broken.txt".to_owned(),
+        content: "Welcome back {{ missing_field }}.".to_owned()
+    });
+    engine.insert_spec("variant_mail".to_owned(), TemplateSpec::new(vec1![sub_spec]).unwrap()).unwrap();
+
+    let data = UserData { name: "Liz" };
+    assert!(engine.use_template("variant_mail", &data, &context).is_ok());
+    assert!(engine.use_template_variant("variant_mail", "unknown", &data, &context).is_ok());
+    assert!(engine.use_template_variant("variant_mail", "broken", &data, &context).is_err());
+}
+
+#[derive(Debug)]
+struct ParagraphMarkdown;
+
+impl MarkdownRenderer for ParagraphMarkdown {
+    fn render_html(&self, markdown: &str) -> String {
+        format!("<p>{}</p>", markdown)
+    }
+}
+
+#[derive(Debug)]
+struct CountingFactory(Arc<AtomicUsize>);
+
+impl BodyResourceFactory for CountingFactory {
+    fn create_resource(&self, media_type: MediaType, body: Vec<u8>) -> Resource {
+        self.0.fetch_add(1, Ordering::SeqCst);
+        Resource::sourceless_from_buffer(FileBuffer::new(media_type, body))
+    }
+}
+
+#[test]
+fn bodies_are_created_through_the_body_resource_factory() {
+    let context = setup_context();
+    let mut engine = setup_template_engine();
+    let count = Arc::new(AtomicUsize::new(0));
+    engine.set_body_resource_factory(Some(Box::new(CountingFactory(count.clone()))));
+    assert!(engine.body_resource_factory().is_some());
+
+    let data = UserData { name: "Liz" };
+    let parts = engine.use_template("template_a", &data, &context).unwrap();
+    assert_eq!(parts.alternative_bodies.len(), 2);
+    assert_eq!(count.load(Ordering::SeqCst), 2);
+}
+
+/// records the transfer encoding passed in for each body
+#[derive(Debug)]
+struct RecordingFactory(Arc<Mutex<Vec<Option<TransferEncoding>>>>);
+
+impl BodyResourceFactory for RecordingFactory {
+    fn create_resource(&self, media_type: MediaType, body: Vec<u8>) -> Resource {
+        self.create_resource_with_transfer_encoding(media_type, body, None)
+    }
+
+    fn create_resource_with_transfer_encoding(
+        &self,
+        media_type: MediaType,
+        body: Vec<u8>,
+        transfer_encoding: Option<TransferEncoding>
+    ) -> Resource {
+        self.0.lock().unwrap().push(transfer_encoding);
+        Resource::sourceless_from_buffer(FileBuffer::new(media_type, body))
+    }
+}
+
+#[test]
+fn transfer_encodings_of_sub_specs_are_passed_to_the_body_resource_factory() {
+    let context = setup_context();
+    let mut engine = setup_template_engine();
+    let recorded = Arc::new(Mutex::new(Vec::new()));
+    engine.set_body_resource_factory(Some(Box::new(RecordingFactory(recorded.clone()))));
+
+    let mut spec = engine.remove_spec("template_a").unwrap();
+    let html = MediaType::parse("text/html").unwrap();
+    spec.sub_spec_for_media_type_mut(&html).unwrap()
+        .set_transfer_encoding(Some(TransferEncoding::QuotedPrintable));
+    engine.insert_spec("template_a".to_owned(), spec).unwrap();
+
+    let data = UserData { name: "Liz" };
+    engine.use_template("template_a", &data, &context).unwrap();
+    assert_eq!(*recorded.lock().unwrap(), vec![None, Some(TransferEncoding::QuotedPrintable)]);
+}
+
+#[test]
+fn transfer_encodings_of_sub_specs_are_used_for_the_bodies() {
+    let context = setup_context();
+    let mut engine = setup_template_engine();
+
+    let from        = Email::try_from("a@b.c").unwrap().into();
+    let to          = Email::try_from("d@e.f").unwrap().into();
+    let template_id = Cow::Borrowed("template_a");
+    let send_data = MailSendData::simple_new(from, to, "encoded", template_id.clone(), UserData { name: "Liz" });
+    let mail = send_data.compose(&context, &engine).unwrap();
+    // the embeddings are base64 encoded anyway
+    let base64_parts = send_mail_to_string(mail, context.clone())
+        .matches("Content-Transfer-Encoding: base64").count();
+
+    let mut spec = engine.remove_spec("template_a").unwrap();
+    let text = MediaType::parse("text/plain").unwrap();
+    spec.sub_spec_for_media_type_mut(&text).unwrap()
+        .set_transfer_encoding(Some(TransferEncoding::Base64));
+    engine.insert_spec("template_a".to_owned(), spec).unwrap();
+
+    let from        = Email::try_from("a@b.c").unwrap().into();
+    let to          = Email::try_from("d@e.f").unwrap().into();
+    let send_data = MailSendData::simple_new(from, to, "encoded", template_id, UserData { name: "Liz" });
+    let mail = send_data.compose(&context, &engine).unwrap();
+    let out_string = send_mail_to_string(mail, context.clone());
+    assert_eq!(out_string.matches("Content-Transfer-Encoding: base64").count(), base64_parts + 1);
+    assert!(!out_string.contains("\r\nHy Liz."));
+}
+
+#[test]
+fn transfer_encodings_are_applied_to_the_bodies_of_the_body_resource_factory() {
+    let context = setup_context();
+    let mut engine = setup_template_engine();
+    let count = Arc::new(AtomicUsize::new(0));
+    engine.set_body_resource_factory(Some(Box::new(CountingFactory(count.clone()))));
+
+    let mut spec = engine.remove_spec("template_a").unwrap();
+    let text = MediaType::parse("text/plain").unwrap();
+    spec.sub_spec_for_media_type_mut(&text).unwrap()
+        .set_transfer_encoding(Some(TransferEncoding::Base64));
+    engine.insert_spec("template_a".to_owned(), spec).unwrap();
+
+    let from        = Email::try_from("a@b.c").unwrap().into();
+    let to          = Email::try_from("d@e.f").unwrap().into();
+    let template_id = Cow::Borrowed("template_a");
+    let send_data = MailSendData::simple_new(from, to, "encoded", template_id, UserData { name: "Liz" });
+    let mail = send_data.compose(&context, &engine).unwrap();
+    // the factory is used for the body with a preferred transfer encoding, too
+    assert_eq!(count.load(Ordering::SeqCst), 2);
+    let out_string = send_mail_to_string(mail, context.clone());
+    assert!(!out_string.contains("\r\nHy Liz."));
+}
+
+#[test]
+fn buffered_attachments_are_gzipped() {
+    let context = setup_context();
+    let mut engine = setup_template_engine();
+    engine.set_gzip_attachments_above(Some(64));
+
+    let mut spec = engine.remove_spec("template_a").unwrap();
+    let media_type = MediaType::parse("text/plain; charset=utf-8").unwrap();
+    let file_meta = FileMeta { file_name: Some("report.txt".to_owned()), ..Default::default() };
+    let content = "report line\r\n".repeat(100).into_bytes();
+    let report = FileBuffer::with_file_meta(media_type, content, file_meta);
+    spec.attachments_mut().push(Resource::sourceless_from_buffer(report));
+    engine.insert_spec("template_a".to_owned(), spec).unwrap();
+
+    for _ in 0..2 {
+        let from        = Email::try_from("a@b.c").unwrap().into();
+        let to          = Email::try_from("d@e.f").unwrap().into();
+        let template_id = Cow::Borrowed("template_a");
+        let send_data = MailSendData::simple_new(from, to, "report", template_id, UserData { name: "Liz" });
+        let mail = send_data.compose(&context, &engine).unwrap();
+        let out_string = send_mail_to_string(mail, context.clone());
+        assert!(out_string.contains("application/gzip"));
+        assert!(out_string.contains("report.txt.gz"));
+        assert!(!out_string.contains("report line"));
+    }
+}
+
+/// removes html comments from the sources of html sub-templates
+#[derive(Debug)]
+struct StripHtmlComments;
+
+impl SpecTransform for StripHtmlComments {
+    fn transform(&self, _template_id: &str, spec: &mut TemplateSpec) {
+        let comment = Regex::new(r"(?s)<!--.*?-->").unwrap();
+        for sub_spec in spec.sub_specs_mut().iter_mut() {
+            if sub_spec.media_type().full_type() != "text/html" {
+                continue;
+            }
+            let stripped =
+                match *sub_spec.source() {
+                    TemplateSource::Source { ref id, ref content } => TemplateSource::Source {
+                        id: id.clone(),
+                        content: comment.replace_all(content, "").into_owned()
+                    },
+                    _ => continue
+                };
+            sub_spec.set_source(stripped);
+        }
+    }
+}
+
+#[test]
+fn specs_are_transformed_before_loading() {
+    let context = setup_context();
+    let mut engine = setup_template_engine();
+    engine.set_spec_transform(Some(Box::new(StripHtmlComments)));
+
+    let html = MediaType::parse("text/html; charset=utf-8").unwrap();
+    engine.insert_spec_str("commented_html", html, "<p>Hy<!-- todo: {{ name }} --></p>").unwrap();
+    let text = MediaType::parse("text/plain; charset=utf-8").unwrap();
+    engine.insert_spec_str("commented_text", text, "Hy<!-- {{ name }} -->").unwrap();
+
+    let data = UserData { name: "Liz" };
+    let bodies = engine.render_all_bodies("commented_html", &data, &context).unwrap();
+    assert_eq!(bodies[0].1, "<p>Hy</p>");
+    let bodies = engine.render_all_bodies("commented_text", &data, &context).unwrap();
+    assert_eq!(bodies[0].1, "Hy<!-- Liz -->");
+}
+
+#[test]
+fn render_context_contains_data_cids_and_globals() {
+    let context = setup_context();
+    let mut engine = setup_template_engine();
+    engine.set_global("company", json!("ACME"));
+
+    let data = UserData { name: "Liz" };
+    let render_context = engine.debug_render_context("template_a", &data, &context).unwrap();
+    assert_eq!(render_context["name"], json!("Liz"));
+    assert_eq!(render_context["globals"]["company"], json!("ACME"));
+    assert!(render_context["cids"]["logo"].is_string());
+    assert!(render_context["cids"]["portfolio"].is_string());
+
+    assert!(engine.debug_render_context("unknown", &data, &context).is_err());
+}
+
+#[test]
+fn errors_can_be_classified_by_kind() {
+    let context = setup_context();
+    let mut engine = setup_template_engine();
+    let data = UserData { name: "Liz" };
+
+    let err = engine.use_template("unknown", &data, &context).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::NotFound);
+    assert_eq!(err.kind().http_status(), 404);
+
+    let media_type = MediaType::parse("text/plain; charset=us-ascii").unwrap();
+    engine.insert_spec_str("ascii", media_type, "Hy {{ name }}.").unwrap();
+    let data = UserData { name: "Zoë" };
+    let err = engine.use_template("ascii", &data, &context).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidData);
+    assert_eq!(err.kind().http_status(), 400);
+
+    let multipart = MediaType::parse("multipart/mixed; boundary=xx").unwrap();
+    let err = engine.insert_spec_str("multipart", multipart, "").unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidTemplate);
+    assert_eq!(err.kind().http_status(), 500);
+}
+
+#[test]
+fn health_check_reports_the_result_of_each_template() {
+    let context = setup_context();
+    let mut engine = setup_template_engine();
+    let media_type = MediaType::parse("text/plain; charset=utf-8").unwrap();
+    engine.insert_spec_str("needs_company", media_type, "Hy {{ company }}").unwrap();
+
+    let data = UserData { name: "Liz" };
+    let results = engine.health_check(&data, &context);
+    let ids = results.iter().map(|&(ref id, _)| id.as_str()).collect::<Vec<_>>();
+    assert_eq!(ids, vec!["needs_company", "template_a"]);
+    assert!(results[0].1.is_err());
+    assert!(results[1].1.is_ok());
+}
+
+#[test]
+fn attachments_can_be_filtered_by_name() {
+    let context = setup_context();
+    let mut engine = setup_template_engine();
+
+    let a_spec = TemplateSpec::from_dir("./test_resources/templates/template_a", &*DEFAULT_SETTINGS).unwrap();
+    let portfolio = a_spec.embeddings().get("portfolio").unwrap().clone();
+    let text = SubTemplateSpec::new_with_template_source(
+        TemplateSource::Source { id: "order/mail.txt".to_owned(), content: "Hy {{ name }}.".to_owned() },
+        MediaType::parse("text/plain; charset=utf-8").unwrap(),
+        HashMap::new()
+    ).unwrap();
+    let mut spec = TemplateSpec::builder()
+        .sub_spec(text)
+        .attachment(portfolio.clone())
+        .attachment(portfolio)
+        .build()
+        .unwrap();
+    spec.set_attachment_name(1, "invoice.pdf").unwrap();
+    engine.insert_spec("order".to_owned(), spec).unwrap();
+
+    let data = UserData { name: "Liz" };
+    let parts = engine.use_template_with_attachment_filter("order", &data, &context, |name| {
+        name != "invoice.pdf"
+    }).unwrap();
+    assert_eq!(parts.attachments.len(), 1);
+
+    let parts = engine.use_template_with_attachment_filter("order", &data, &context, |_| true).unwrap();
+    assert_eq!(parts.attachments.len(), 2);
+}
+
+/// appends the text to `text/plain` bodies
+#[derive(Debug)]
+struct AppendToText(&'static str);
+
+impl BodyPostProcessor for AppendToText {
+    fn process(&self, media_type: &MediaType, body: String) -> StdResult<String, EncodingError> {
+        if media_type.full_type() == "text/plain" {
+            Ok(body + self.0)
+        } else {
+            Ok(body)
+        }
+    }
+}
+
+#[test]
+fn body_post_processors_are_applied_in_order() {
+    let context = setup_context();
+    let mut engine = setup_template_engine();
+    engine.add_body_post_processor(Box::new(AppendToText(" -- 1")));
+    let chain: Vec<Box<BodyPostProcessor>> = vec![
+        Box::new(AppendToText(" -- 2")),
+        Box::new(AppendToText(" -- 3"))
+    ];
+    engine.add_body_post_processor(Box::new(chain));
+    assert_eq!(engine.body_post_processors().len(), 2);
+
+    let text = MediaType::parse("text/plain; charset=utf-8").unwrap();
+    engine.insert_spec_str("post_processed", text, "Hy {{ name }}.").unwrap();
+
+    let data = UserData { name: "Liz" };
+    let bodies = engine.render_all_bodies("post_processed", &data, &context).unwrap();
+    assert_eq!(bodies[0].1, "Hy Liz. -- 1 -- 2 -- 3");
+
+    engine.clear_body_post_processors();
+    let bodies = engine.render_all_bodies("post_processed", &data, &context).unwrap();
+    assert_eq!(bodies[0].1, "Hy Liz.");
+}
+
+#[test]
+fn newlines_added_by_body_post_processors_are_fixed() {
+    let context = setup_context();
+    let mut engine = setup_template_engine();
+    engine.add_body_post_processor(Box::new(AppendToText("\n-- footer\n")));
+
+    let text = MediaType::parse("text/plain; charset=utf-8").unwrap();
+    engine.insert_spec_str("footer", text, "Hy {{ name }}.").unwrap();
+
+    let data = UserData { name: "Liz" };
+    let bodies = engine.render_all_bodies("footer", &data, &context).unwrap();
+    assert_eq!(bodies[0].1, "Hy Liz.\r\n-- footer\r\n");
+    assert_eq!(bodies[0].1.matches('\n').count(), bodies[0].1.matches("\r\n").count());
+}
+
+/// puts the stylesheets into a `style` attribute of a wrapping `div`
+#[derive(Debug)]
+struct DivStyleInliner;
+
+impl CssInliner for DivStyleInliner {
+    fn inline(&self, html: String, stylesheets: &[String]) -> String {
+        let styles = stylesheets.iter().map(|css| css.trim()).collect::<Vec<_>>();
+        format!("<div style=\"{}\">{}</div>", styles.join(" "), html)
+    }
+}
+
+#[test]
+fn stylesheets_are_inlined_into_html_bodies() {
+    let dir = std::env::temp_dir().join(format!("rte_test_tera_css_{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(dir.join("html")).unwrap();
+    std::fs::create_dir_all(dir.join("text")).unwrap();
+    std::fs::write(dir.join("html").join("mail.html"), "<p>{{ name }}</p>").unwrap();
+    std::fs::write(dir.join("html").join("style.css"), "color: red;\n").unwrap();
+    std::fs::write(dir.join("text").join("mail.txt"), "{{ name }}").unwrap();
+
+    let context = setup_context();
+    let mut engine = setup_template_engine();
+    engine.set_css_inliner(Some(Box::new(DivStyleInliner)));
+    let spec = TemplateSpec::from_dir(&dir, &*DEFAULT_SETTINGS).unwrap();
+    engine.insert_spec("styled".to_owned(), spec).unwrap();
+    // stylesheets are read when the spec is loaded
+    std::fs::remove_file(dir.join("html").join("style.css")).unwrap();
+
+    let data = UserData { name: "Liz" };
+    let bodies = engine.render_all_bodies("styled", &data, &context).unwrap();
+    let bodies = bodies.iter()
+        .map(|&(ref media_type, ref body)| (media_type.full_type().to_string(), body.as_str()))
+        .collect::<HashMap<_, _>>();
+    assert_eq!(bodies["text/html"], "<div style=\"color: red;\"><p>Liz</p></div>");
+    assert_eq!(bodies["text/plain"], "Liz");
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn stylesheets_loaded_from_a_memory_fs_are_inlined() {
+    let mut fs = MemoryFs::new();
+    fs.insert_file("styled/html/mail.html", &b"<p>{{ name }}</p>"[..]);
+    fs.insert_file("styled/html/style.css", &b"color: red;"[..]);
+    fs.insert_file("styled/text/mail.txt", &b"{{ name }}"[..]);
+
+    let context = setup_context();
+    let mut engine = setup_template_engine();
+    engine.set_css_inliner(Some(Box::new(DivStyleInliner)));
+    let spec = TemplateSpec::from_fs_dir(&fs, "styled", &*DEFAULT_SETTINGS).unwrap();
+    engine.insert_spec("styled".to_owned(), spec).unwrap();
+
+    let data = UserData { name: "Liz" };
+    let bodies = engine.render_all_bodies("styled", &data, &context).unwrap();
+    let html = bodies.iter()
+        .find(|&&(ref media_type, _)| media_type.full_type() == "text/html")
+        .map(|&(_, ref body)| body.as_str());
+    assert_eq!(html, Some("<div style=\"color: red;\"><p>Liz</p></div>"));
+}
+
+#[test]
+fn markdown_bodies_are_expanded_into_text_and_html() {
+    let context = setup_context();
+    let mut engine = setup_template_engine();
+    engine.set_markdown_renderer(Some(Box::new(ParagraphMarkdown)));
+
+    let source = TemplateSource::Source {
+        id: "markdown_mail.md".to_owned(),
+        content: "Hy *{{ name }}*.".to_owned()
+    };
+    let media_type = MediaType::parse("text/markdown; charset=utf-8").unwrap();
+    let sub_spec = SubTemplateSpec::new_with_template_source(source, media_type, HashMap::new()).unwrap();
+    engine.insert_spec("markdown_mail".to_owned(), TemplateSpec::new(vec1![sub_spec]).unwrap()).unwrap();
+
+    let data = UserData { name: "Liz" };
+    let parts = engine.use_template("markdown_mail", &data, &context).unwrap();
+    assert_eq!(parts.alternative_bodies.len(), 2);
+
+    let bodies = engine.render_all_bodies("markdown_mail", &data, &context).unwrap()
+        .into_iter()
+        .map(|(media_type, body)| (media_type.full_type().to_string(), body))
+        .collect::<Vec<_>>();
+    assert_eq!(bodies, vec![
+        ("text/plain".to_owned(), "Hy *Liz*.".to_owned()),
+        ("text/html".to_owned(), "<p>Hy *Liz*.</p>".to_owned())
+    ]);
+}
+
+#[test]
+fn markdown_bodies_conflict_with_text_bodies() {
+    let context = setup_context();
+    let mut engine = setup_template_engine();
+
+    let markdown = TemplateSource::Source {
+        id: "conflicting_mail.md".to_owned(),
+        content: "Hy *{{ name }}*.".to_owned()
+    };
+    let text = TemplateSource::Source {
+        id: "conflicting_mail.txt".to_owned(),
+        content: "Hy {{ name }}.".to_owned()
+    };
+    let markdown_type = MediaType::parse("text/markdown; charset=utf-8").unwrap();
+    let text_type = MediaType::parse("text/plain; charset=utf-8").unwrap();
+    let sub_specs = vec1![
+        SubTemplateSpec::new_with_template_source(text, text_type, HashMap::new()).unwrap(),
+        SubTemplateSpec::new_with_template_source(markdown, markdown_type, HashMap::new()).unwrap()
+    ];
+    engine.insert_spec("conflicting_mail".to_owned(), TemplateSpec::new(sub_specs).unwrap()).unwrap();
+
+    // without a markdown renderer the markdown body is used as is
+    let data = UserData { name: "Liz" };
+    assert!(engine.use_template("conflicting_mail", &data, &context).is_ok());
+
+    engine.set_markdown_renderer(Some(Box::new(ParagraphMarkdown)));
+    let err = engine.use_template("conflicting_mail", &data, &context).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidTemplate);
+}
+
+#[test]
+fn strict_newlines_reject_lone_newlines() {
+    let context = setup_context();
+    let mut engine = setup_template_engine();
+    engine.set_fix_newlines(false);
+
+    let source = TemplateSource::Source {
+        id: "lone_newlines.txt".to_owned(),
+        content: "Hy\n{{ name }}.".to_owned()
+    };
+    let media_type = MediaType::parse("text/plain; charset=utf-8").unwrap();
+    let sub_spec = SubTemplateSpec::new_with_template_source(source, media_type, HashMap::new()).unwrap();
+    engine.insert_spec("lone_newlines".to_owned(), TemplateSpec::new(vec1![sub_spec]).unwrap()).unwrap();
+
+    let data = UserData { name: "Liz" };
+    assert!(engine.use_template("lone_newlines", &data, &context).is_ok());
+
+    engine.set_strict_newlines(true);
+    assert!(engine.use_template("lone_newlines", &data, &context).is_err());
+
+    engine.set_fix_newlines(true);
+    assert!(engine.use_template("lone_newlines", &data, &context).is_ok());
+}
+
+#[test]
+fn render_all_bodies_returns_the_bodies_with_media_types() {
+    let context = setup_context();
+    let engine = setup_template_engine();
+
+    let data = UserData { name: "Liz" };
+    let bodies = engine.render_all_bodies("template_a", &data, &context).unwrap();
+
+    let media_types = bodies.iter()
+        .map(|&(ref media_type, _)| media_type.full_type().to_string())
+        .collect::<Vec<_>>();
+    assert_eq!(media_types, vec!["text/plain", "text/html"]);
+    assert!(bodies.iter().all(|&(_, ref body)| body.contains("Liz")));
+}
+
+#[test]
+fn deterministic_content_ids_are_the_same_for_each_render() {
+    let context = setup_context();
+    let mut engine = setup_template_engine();
+    let data = UserData { name: "Liz" };
+
+    let first = engine.render_all_bodies("template_a", &data, &context).unwrap();
+    let second = engine.render_all_bodies("template_a", &data, &context).unwrap();
+    assert_ne!(first[1].1, second[1].1);
+
+    engine.set_deterministic_content_ids(true);
+    let first = engine.render_all_bodies("template_a", &data, &context).unwrap();
+    let second = engine.render_all_bodies("template_a", &data, &context).unwrap();
+    assert_eq!(first[1].1, second[1].1);
+    assert!(first[1].1.contains("cid:rte."));
+}
+
+#[test]
+fn specs_can_be_inserted_from_a_string() {
+    let context = setup_context();
+    let mut engine = setup_template_engine();
+
+    let media_type = MediaType::parse("text/html; charset=utf-8").unwrap();
+    engine.insert_spec_str("inline_html", media_type, "<p>Hy {{ name }}.</p>").unwrap();
+
+    let data = UserData { name: "<Liz>" };
+    let bodies = engine.render_all_bodies("inline_html", &data, &context).unwrap();
+    assert_eq!(bodies.len(), 1);
+    assert_eq!(bodies[0].1, "<p>Hy &lt;Liz&gt;.</p>");
+
+    let multipart = MediaType::parse("multipart/mixed; boundary=abc").unwrap();
+    assert!(engine.insert_spec_str("multipart", multipart, "").is_err());
+}
+
+#[test]
+fn base_templates_can_be_extended() {
+    let context = setup_context();
+    let mut engine = setup_template_engine();
+    engine.register_base_template("layout.html", "<body>{% block content %}{% endblock content %}</body>")
+        .unwrap().unwrap();
+
+    let media_type = MediaType::parse("text/html; charset=utf-8").unwrap();
+    engine.insert_spec_str("with_layout", media_type,
+        "{% extends \"layout.html\" %}{% block content %}Hy {{ name }}.{% endblock content %}").unwrap();
+
+    let data = UserData { name: "Liz" };
+    let bodies = engine.render_all_bodies("with_layout", &data, &context).unwrap();
+    assert_eq!(bodies[0].1, "<body>Hy Liz.</body>");
+
+    let tera = TeraRenderEngine::new("./test_resources/tera_base/**/*").unwrap();
+    assert_eq!(tera.supports_inheritance(), InheritanceSupport::Extends);
+}
+
+#[test]
+fn batches_share_the_shared_embeddings() {
+    let context = setup_context();
+    let engine = setup_template_engine();
+
+    let data = vec![UserData { name: "Liz" }, UserData { name: "Max" }];
+    let all_parts = engine.use_template_batch("template_a", &data, &context).unwrap();
+
+    assert_eq!(all_parts.len(), 2);
+    assert_eq!(all_parts[0].shared_embeddings[0].content_id(), all_parts[1].shared_embeddings[0].content_id());
+    assert_ne!(all_parts[0].alternative_bodies[1].embeddings[0].content_id(),
+        all_parts[1].alternative_bodies[1].embeddings[0].content_id());
+
+    assert!(engine.use_template_batch("unknown", &data, &context).is_err());
+}
+
+#[test]
+fn base_and_spec_templates_are_distinguished() {
+    let mut tera = TeraRenderEngine::new("./test_resources/tera_base/**/*").unwrap();
+    assert_eq!(tera.base_template_names(), vec!["base_mail.html"]);
+    assert!(tera.preloaded_template_names().is_empty());
+
+    let spec = TemplateSpec::from_dir("./test_resources/templates/template_a", &*DEFAULT_SETTINGS).unwrap();
+    tera.load_templates(&spec).unwrap();
+    tera.register_base_template("layout.html", "{% block content %}{% endblock content %}").unwrap().unwrap();
+    assert_eq!(tera.base_template_names(), vec!["base_mail.html", "layout.html"]);
+    assert_eq!(tera.preloaded_template_names().len(), 2);
+    assert!(tera.is_base_template("layout.html"));
+
+    tera.unload_templates(&spec);
+    assert_eq!(tera.base_template_names(), vec!["base_mail.html", "layout.html"]);
+    assert!(tera.preloaded_template_names().is_empty());
+}
+
+#[test]
+fn header_templates_are_rendered_with_the_data() {
+    let context = setup_context();
+    let mut engine = setup_template_engine();
+
+    let text = SubTemplateSpec::new_with_template_source(
+        TemplateSource::Source { id: "campaign.txt".to_owned(), content: "Hy {{ name }}.".to_owned() },
+        MediaType::parse("text/plain; charset=utf-8").unwrap(),
+        HashMap::new()
+    ).unwrap();
+    let mut spec = TemplateSpec::new(vec1![text]).unwrap();
+    spec.insert_header_template("List-Unsubscribe", TemplateSource::Source {
+        id: "campaign.list-unsubscribe".to_owned(),
+        content: "<https://example.com/unsubscribe/{{ name }}>\n".to_owned()
+    });
+    spec.insert_header_template("X-Campaign-Id", TemplateSource::Source {
+        id: "campaign.x-campaign-id".to_owned(),
+        content: "welcome-{{ name }}".to_owned()
+    });
+    engine.insert_spec("campaign".to_owned(), spec).unwrap();
+
+    let data = UserData { name: "Liz" };
+    let (parts, headers) = engine.use_template_with_headers("campaign", &data, &context).unwrap();
+
+    assert_eq!(parts.alternative_bodies.len(), 1);
+    assert_eq!(headers, vec![
+        ("List-Unsubscribe".to_owned(), "<https://example.com/unsubscribe/Liz>".to_owned()),
+        ("X-Campaign-Id".to_owned(), "welcome-Liz".to_owned())
+    ]);
+}
+
+#[test]
+fn empty_bodies_can_be_rejected() {
+    let context = setup_context();
+    let mut engine = setup_template_engine();
+    engine.set_empty_body_policy(EmptyBodyPolicy::Reject);
+
+    let conditional_spec = |id: &str, allow_empty: bool| {
+        let source = TemplateSource::Source {
+            id: format!("{}.txt", id),
+            content: "{% if name == \"Max\" %}Hy Max.{% endif %}\n".to_owned()
+        };
+        let media_type = MediaType::parse("text/plain; charset=utf-8").unwrap();
+        let mut sub_spec = SubTemplateSpec::new_with_template_source(source, media_type, HashMap::new()).unwrap();
+        sub_spec.set_allow_empty(allow_empty);
+        TemplateSpec::new(vec1![sub_spec]).unwrap()
+    };
+    engine.insert_spec("conditional".to_owned(), conditional_spec("conditional", false)).unwrap();
+    engine.insert_spec("optional".to_owned(), conditional_spec("optional", true)).unwrap();
+
+    assert!(engine.use_template("conditional", &UserData { name: "Max" }, &context).is_ok());
+    assert!(engine.use_template("conditional", &UserData { name: "Liz" }, &context).is_err());
+    assert!(engine.use_template("optional", &UserData { name: "Liz" }, &context).is_ok());
+}
+
+#[test]
+fn empty_bodies_can_be_omitted() {
+    let context = setup_context();
+    let mut engine = setup_template_engine();
+    engine.set_empty_body_policy(EmptyBodyPolicy::Omit);
+
+    let body = |id: &str, media_type: &str, content: &str| {
+        let source = TemplateSource::Source { id: id.to_owned(), content: content.to_owned() };
+        let media_type = MediaType::parse(media_type).unwrap();
+        SubTemplateSpec::new_with_template_source(source, media_type, HashMap::new()).unwrap()
+    };
+    let spec = TemplateSpec::new(vec1![
+        body("omitted.txt", "text/plain; charset=utf-8", "{% if name == \"Max\" %}Hy Max.{% endif %}"),
+        body("omitted.html", "text/html; charset=utf-8", "{% if name != \"Bob\" %}<p>Hy {{ name }}.</p>{% endif %}")
+    ]).unwrap();
+    engine.insert_spec("omitted".to_owned(), spec).unwrap();
+
+    let bodies = engine.render_all_bodies("omitted", &UserData { name: "Max" }, &context).unwrap();
+    assert_eq!(bodies.len(), 2);
+    let bodies = engine.render_all_bodies("omitted", &UserData { name: "Liz" }, &context).unwrap();
+    assert_eq!(bodies.len(), 1);
+    assert!(bodies[0].0.full_type() == "text/html");
+    assert!(engine.render_all_bodies("omitted", &UserData { name: "Bob" }, &context).is_err());
+}
+
+struct CountingData {
+    name: &'static str,
+    serialized: Cell<usize>
+}
+
+impl Serialize for CountingData {
+    fn serialize<S>(&self, serializer: S) -> StdResult<S::Ok, S::Error>
+        where S: Serializer
+    {
+        self.serialized.set(self.serialized.get() + 1);
+        UserData { name: self.name }.serialize(serializer)
+    }
+}
+
+#[test]
+fn data_is_serialized_once_per_template() {
+    let context = setup_context();
+    let mut engine = setup_template_engine();
+
+    let text = SubTemplateSpec::new_with_template_source(
+        TemplateSource::Source { id: "counting.txt".to_owned(), content: "Hy {{ name }}.".to_owned() },
+        MediaType::parse("text/plain; charset=utf-8").unwrap(),
+        HashMap::new()
+    ).unwrap();
+    let html = SubTemplateSpec::new_with_template_source(
+        TemplateSource::Source { id: "counting.html".to_owned(), content: "<p>Hy {{ name }}.</p>".to_owned() },
+        MediaType::parse("text/html; charset=utf-8").unwrap(),
+        HashMap::new()
+    ).unwrap();
+    engine.insert_spec("counting".to_owned(), TemplateSpec::new(vec1![text, html]).unwrap()).unwrap();
+
+    let data = CountingData { name: "Liz", serialized: Cell::new(0) };
+    let parts = engine.use_template("counting", &data, &context).unwrap();
+
+    assert_eq!(parts.alternative_bodies.len(), 2);
+    assert_eq!(data.serialized.get(), 1);
+}
+
 fn assert_mail_out_is_as_expected(mail_out: String) {
     let mut line_iter = mail_out.lines();
     let mut capture_map = HashMap::new();